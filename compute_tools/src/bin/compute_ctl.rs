@@ -9,14 +9,15 @@
 //! - Sync safekeepers and get commit LSN.
 //! - Get `basebackup` from pageserver using the returned on the previous step LSN.
 //! - Try to start `postgres` and wait until it is ready to accept connections.
-//! - Check and alter/drop/create roles and databases.
+//! - Check and alter/drop/create roles, databases and extensions.
 //! - Hang waiting on the `postmaster` process to exit.
 //!
 //! Also `compute_ctl` spawns two separate service threads:
 //! - `compute-monitor` checks the last Postgres activity timestamp and saves it
 //!   into the shared `ComputeNode`;
 //! - `http-endpoint` runs a Hyper HTTP API server, which serves readiness and the
-//!   last activity requests.
+//!   last activity requests, accepts new specs for the running compute, and
+//!   terminates or restarts it.
 //!
 //! Usage example:
 //! ```sh
@@ -47,10 +48,9 @@ use compute_tools::pg_helpers::*;
 use compute_tools::spec::*;
 use url::Url;
 
-fn main() -> Result<()> {
-    // TODO: re-use `utils::logging` later
-    init_logger(DEFAULT_LOG_LEVEL)?;
+const TERMINATED_GRACE_PERIOD: Duration = Duration::from_secs(1);
 
+fn main() -> Result<()> {
     // Env variable is set by `cargo`
     let version: Option<&str> = option_env!("CARGO_PKG_VERSION");
     let matches = clap::App::new("compute_ctl")
@@ -87,8 +87,25 @@ fn main() -> Result<()> {
                 .long("spec-path")
                 .value_name("SPEC_PATH"),
         )
+        .arg(
+            Arg::new("log-format")
+                .long("log-format")
+                .value_name("plain|json")
+                .default_value("plain"),
+        )
+        .arg(
+            Arg::new("log-sink")
+                .long("log-sink")
+                .value_name("stderr|syslog|LOG_FILE")
+                .default_value("stderr"),
+        )
         .get_matches();
 
+    // TODO: re-use `utils::logging` later
+    let log_format: LogFormat = matches.value_of_t_or_exit("log-format");
+    let log_sink: LogSink = matches.value_of_t_or_exit("log-sink");
+    init_logger(DEFAULT_LOG_LEVEL, log_format, &log_sink)?;
+
     let pgdata = matches.value_of("pgdata").expect("PGDATA path is required");
     let connstr = matches
         .value_of("connstr")
@@ -114,6 +131,8 @@ fn main() -> Result<()> {
         }
     };
 
+    spec.validate().context("invalid cluster spec")?;
+
     let pageserver_connstr = spec
         .cluster
         .settings
@@ -129,13 +148,15 @@ fn main() -> Result<()> {
         .settings
         .find("neon.timeline_id")
         .expect("tenant id should be provided");
+    set_log_context(&tenant, &timeline);
+    set_operation_uuid(spec.operation_uuid.as_deref());
 
     let compute_state = ComputeNode {
         start_time: Utc::now(),
         connstr: Url::parse(connstr).context("cannot parse connstr as a URL")?,
         pgdata: pgdata.to_string(),
         pgbin: pgbin.to_string(),
-        spec,
+        spec: RwLock::new(spec),
         tenant,
         timeline,
         pageserver_connstr,
@@ -151,6 +172,13 @@ fn main() -> Result<()> {
 
     // Run compute (Postgres) and hang waiting on it.
     match compute.prepare_and_run() {
+        Ok(_) if compute.get_status() == ComputeStatus::Terminated => {
+            // The `/terminate` request is waiting for this status, let it respond.
+            info!("compute was terminated, giving control plane a moment to collect the status");
+            thread::sleep(TERMINATED_GRACE_PERIOD);
+            info!("shutting down");
+            exit(0)
+        }
         Ok(ec) => {
             let code = ec.code().unwrap_or(1);
             info!("Postgres exited with code {}, shutting down", code);
@@ -18,18 +18,25 @@ use std::fs;
 use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
 use std::process::{Command, ExitStatus, Stdio};
+use std::str::FromStr;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::RwLock;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use chrono::{DateTime, Utc};
-use log::info;
+use log::{error, info, warn};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
 use postgres::{Client, NoTls};
 use serde::{Serialize, Serializer};
 
 use crate::checker::create_writablity_check_data;
 use crate::config;
+use crate::file_cache;
+use crate::logger::{launch_postgres_logger, set_operation_uuid};
+use crate::metrics::{observe_spec_apply, POSTGRES_UP};
 use crate::pg_helpers::*;
+use crate::prewarm;
 use crate::spec::*;
 
 /// Compute node info shared across several `compute_ctl` threads.
@@ -39,7 +46,9 @@ pub struct ComputeNode {
     pub connstr: url::Url,
     pub pgdata: String,
     pub pgbin: String,
-    pub spec: ComputeSpec,
+    /// Spec the compute was started with, or the last one successfully
+    /// applied via `/configure`.
+    pub spec: RwLock<ComputeSpec>,
     pub tenant: String,
     pub timeline: String,
     pub pageserver_connstr: String,
@@ -65,6 +74,13 @@ pub struct ComputeState {
     #[serde(serialize_with = "rfc3339_serialize")]
     pub last_active: DateTime<Utc>,
     pub error: Option<String>,
+    /// Set when Postgres is being shut down by compute_ctl itself, which
+    /// then syncs safekeepers once Postgres exits.
+    #[serde(skip)]
+    pub shutdown_requested: bool,
+    /// Start Postgres again after the requested shutdown.
+    #[serde(skip)]
+    pub restart_requested: bool,
 }
 
 impl ComputeState {
@@ -73,6 +89,8 @@ impl ComputeState {
             status: ComputeStatus::Init,
             last_active: Utc::now(),
             error: None,
+            shutdown_requested: false,
+            restart_requested: false,
         }
     }
 }
@@ -88,9 +106,39 @@ impl Default for ComputeState {
 pub enum ComputeStatus {
     Init,
     Running,
+    /// A new spec is being applied to the running compute.
+    Configuration,
+    /// Postgres is being shut down by compute_ctl, see `ComputeNode::shutdown`.
+    TerminationPending,
+    /// Postgres was shut down and safekeepers synced, compute_ctl is about to exit.
+    Terminated,
     Failed,
 }
 
+/// How to shut Postgres down, see Postgres `pg_ctl stop` modes.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ShutdownMode {
+    /// Roll back active transactions, checkpoint and exit.
+    Fast,
+    /// Exit without a checkpoint, the WAL is replayed on the next start.
+    Immediate,
+}
+
+impl FromStr for ShutdownMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "fast" => Ok(Self::Fast),
+            "immediate" => Ok(Self::Immediate),
+            _ => bail!(
+                "invalid shutdown mode '{}', expected 'fast' or 'immediate'",
+                s
+            ),
+        }
+    }
+}
+
 #[derive(Serialize)]
 pub struct ComputeMetrics {
     pub sync_safekeepers_ms: AtomicU64,
@@ -215,12 +263,12 @@ impl ComputeNode {
     /// Do all the preparations like PGDATA directory creation, configuration,
     /// safekeepers sync, basebackup, etc.
     pub fn prepare_pgdata(&self) -> Result<()> {
-        let spec = &self.spec;
+        let spec = self.spec.read().unwrap().clone();
         let pgdata_path = Path::new(&self.pgdata);
 
         // Remove/create an empty pgdata directory and put configuration there.
         self.create_pgdata()?;
-        config::write_postgres_conf(&pgdata_path.join("postgresql.conf"), spec)?;
+        config::write_postgres_conf(&pgdata_path.join("postgresql.conf"), &spec)?;
 
         info!("starting safekeepers syncing");
         let lsn = self
@@ -239,8 +287,12 @@ impl ComputeNode {
             )
         })?;
 
-        // Update pg_hba.conf received with basebackup.
-        update_pg_hba(pgdata_path)?;
+        // Replace pg_hba.conf received with basebackup.
+        update_pg_hba(pgdata_path, &spec)?;
+
+        if let Some(cache) = &spec.local_file_cache {
+            file_cache::prepare(cache)?;
+        }
 
         Ok(())
     }
@@ -250,22 +302,27 @@ impl ComputeNode {
     pub fn run(&self) -> Result<ExitStatus> {
         let start_time = Utc::now();
 
+        let spec = self.spec.read().unwrap().clone();
         let pgdata_path = Path::new(&self.pgdata);
 
         // Run postgres as a child process.
         let mut pg = Command::new(&self.pgbin)
             .args(&["-D", &self.pgdata])
+            .stderr(Stdio::piped())
             .spawn()
             .expect("cannot start postgres process");
+        if let Some(stderr) = pg.stderr.take() {
+            launch_postgres_logger(stderr).expect("cannot launch postgres logger thread");
+        }
 
         // Try default Postgres port if it is not provided
-        let port = self
-            .spec
+        let port = spec
             .cluster
             .settings
             .find("port")
             .unwrap_or_else(|| "5432".to_string());
         wait_for_postgres(&mut pg, &port, pgdata_path)?;
+        POSTGRES_UP.set(1);
 
         // If connection fails,
         // it may be the old node with `zenith_admin` superuser.
@@ -296,11 +353,12 @@ impl ComputeNode {
             Ok(client) => client,
         };
 
-        handle_roles(&self.spec, &mut client)?;
-        handle_databases(&self.spec, &mut client)?;
-        handle_role_deletions(self, &mut client)?;
-        handle_grants(self, &mut client)?;
+        self.apply_spec(&spec, &mut client)?;
+        verify_client_auth(&spec, &mut client)?;
         create_writablity_check_data(&mut client)?;
+        if spec.local_file_cache.is_some() || spec.prewarm_buffers == Some(true) {
+            create_neon_extension(&mut client)?;
+        }
 
         // 'Close' connection
         drop(client);
@@ -327,28 +385,251 @@ impl ComputeNode {
 
         info!(
             "finished configuration of compute for project {}",
-            self.spec.cluster.cluster_id
+            spec.cluster.cluster_id
         );
 
+        // Prefetch what was hot before the restart, now that the compute is
+        // already serving queries.
+        if let Some(cache) = &spec.local_file_cache {
+            let relations = file_cache::load_hot_relations(cache);
+            if !relations.is_empty() {
+                file_cache::launch_prewarm(&self.connstr, relations)?;
+            }
+        }
+        if spec.prewarm_buffers == Some(true) {
+            prewarm::launch_prewarm(self)?;
+        }
+
         // Wait for child Postgres process basically forever. In this state Ctrl+C
         // will propagate to Postgres and it will be shut down as well.
         let ecode = pg
             .wait()
             .expect("failed to start waiting on Postgres process");
+        POSTGRES_UP.set(0);
+
+        let state = self.state.read().unwrap();
+        let (shutdown_requested, restart_requested) =
+            (state.shutdown_requested, state.restart_requested);
+        drop(state);
+
+        if shutdown_requested {
+            // Everything Postgres has committed is in the WAL. Make sure
+            // safekeepers agree on it, so nothing is lost when we exit.
+            info!("Postgres was shut down by compute_ctl, syncing safekeepers");
+            let lsn = self
+                .sync_safekeepers()
+                .with_context(|| "failed to sync safekeepers after shutdown")?;
+            info!("safekeepers synced at LSN {}", lsn);
+
+            if !restart_requested {
+                self.set_status(ComputeStatus::Terminated);
+            }
+        }
 
         Ok(ecode)
     }
 
+    // Bring roles, databases, grants and extensions of the running Postgres in line with `spec`.
+    fn apply_spec(&self, spec: &ComputeSpec, client: &mut Client) -> Result<()> {
+        observe_spec_apply("roles", || handle_roles(spec, client))?;
+        observe_spec_apply("databases", || handle_databases(spec, client))?;
+        observe_spec_apply("role_deletions", || {
+            handle_role_deletions(spec, &self.connstr, client)
+        })?;
+        observe_spec_apply("privileges", || {
+            handle_privileges(spec, &self.connstr, client)
+        })?;
+        observe_spec_apply("grants", || handle_grants(spec, &self.connstr, client))?;
+        observe_spec_apply("extensions", || handle_extensions(spec, &self.connstr))?;
+
+        Ok(())
+    }
+
+    /// Apply a new spec to the already running compute: update roles, databases,
+    /// grants and extensions, then rewrite `postgresql.conf` and ask Postgres to reload it.
+    /// Settings that require a restart (e.g. `shared_preload_libraries`) will
+    /// only take effect on the next start.
+    ///
+    /// The status is `Configuration` while the spec is being applied, and goes
+    /// back to `Running` afterwards. If it fails, the error is saved into the
+    /// state and the previous spec is kept.
+    pub fn reconfigure(&self, spec: ComputeSpec) -> Result<()> {
+        // Tenant and timeline are baked into the data directory, so they
+        // cannot be changed without a restart.
+        for (name, current) in [
+            ("neon.tenant_id", &self.tenant),
+            ("neon.timeline_id", &self.timeline),
+        ] {
+            match spec.cluster.settings.find(name) {
+                Some(ref new) if new == current => {}
+                new => bail!(
+                    "cannot change {} of the running compute from {} to {:?}",
+                    name,
+                    current,
+                    new
+                ),
+            }
+        }
+
+        {
+            let mut state = self.state.write().unwrap();
+            if state.status != ComputeStatus::Running {
+                bail!("compute is not running, cannot apply a new spec");
+            }
+            state.status = ComputeStatus::Configuration;
+        }
+
+        set_operation_uuid(spec.operation_uuid.as_deref());
+        info!(
+            "applying new spec for project {}, operation {}",
+            spec.cluster.cluster_id,
+            spec.operation_uuid.as_deref().unwrap_or("none"),
+        );
+
+        let result = self.apply_new_spec(&spec);
+
+        let mut state = self.state.write().unwrap();
+        state.status = ComputeStatus::Running;
+        match &result {
+            Ok(()) => {
+                info!("finished reconfiguration of compute");
+                state.error = None;
+                *self.spec.write().unwrap() = spec;
+            }
+            Err(e) => {
+                error!("could not apply new spec: {:?}", e);
+                state.error = Some(format!("{:?}", e));
+            }
+        }
+
+        result
+    }
+
+    fn apply_new_spec(&self, spec: &ComputeSpec) -> Result<()> {
+        let mut client = Client::connect(self.connstr.as_str(), NoTls)?;
+        self.apply_spec(spec, &mut client)?;
+
+        let pgdata_path = Path::new(&self.pgdata);
+        let backup = config::ConfigBackup::save(pgdata_path)?;
+        let written = config::write_postgres_conf(&pgdata_path.join("postgresql.conf"), spec)
+            .and_then(|_| update_pg_hba(pgdata_path, spec))
+            // The reload is asynchronous, so only the new pg_hba.conf can be
+            // checked right away, settings are checked on the next start.
+            .and_then(|_| verify_pg_hba(&mut client));
+        if let Err(e) = written {
+            // Otherwise a rejected pg_hba.conf would stop Postgres from starting
+            // next time, and the next reload would pick up the new settings.
+            if let Err(restore_err) = backup.restore() {
+                error!("could not restore configuration files: {:?}", restore_err);
+            }
+            return Err(e);
+        }
+        client.simple_query("SELECT pg_reload_conf()")?;
+
+        Ok(())
+    }
+
+    /// Shut the running Postgres down. Once it exits, `run()` syncs safekeepers
+    /// and sets the `Terminated` status, or, if `restart` is set, `prepare_and_run()`
+    /// starts it again from a fresh basebackup.
+    pub fn shutdown(&self, mode: ShutdownMode, restart: bool) -> Result<()> {
+        {
+            let mut state = self.state.write().unwrap();
+            if state.status != ComputeStatus::Running {
+                bail!("compute is not running, cannot shut it down");
+            }
+            state.status = ComputeStatus::TerminationPending;
+            state.shutdown_requested = true;
+            state.restart_requested = restart;
+        }
+
+        if let Err(e) = self.signal_postmaster(mode) {
+            // Postgres keeps running, so let it be shut down or restarted again.
+            let mut state = self.state.write().unwrap();
+            if state.status == ComputeStatus::TerminationPending {
+                state.status = ComputeStatus::Running;
+            }
+            state.shutdown_requested = false;
+            state.restart_requested = false;
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    // Prepare Postgres for the shutdown in `mode` and send the corresponding signal.
+    fn signal_postmaster(&self, mode: ShutdownMode) -> Result<()> {
+        let signal = match mode {
+            ShutdownMode::Fast => {
+                match Client::connect(self.connstr.as_str(), NoTls) {
+                    Ok(mut client) => {
+                        let file_cache = self.spec.read().unwrap().local_file_cache.clone();
+                        if let Some(cache) = &file_cache {
+                            if let Err(e) = file_cache::save_hot_relations(cache, &mut client) {
+                                warn!("could not save hot relations of the file cache: {:?}", e);
+                            }
+                        }
+
+                        // Shutdown checkpoint is done by Postgres anyway, but it's faster
+                        // if most of the dirty buffers are already written out.
+                        if let Err(e) = client.simple_query("CHECKPOINT") {
+                            warn!("could not checkpoint before shutdown: {}", e);
+                        }
+                    }
+                    Err(e) => warn!("could not connect to Postgres before shutdown: {}", e),
+                }
+                Signal::SIGINT
+            }
+            ShutdownMode::Immediate => Signal::SIGQUIT,
+        };
+
+        let pid = self.postmaster_pid()?;
+        info!(
+            "sending {} ({:?} shutdown) to postmaster {}",
+            signal, mode, pid
+        );
+        kill(pid, signal)?;
+
+        Ok(())
+    }
+
+    // Read the postmaster PID from the first line of `postmaster.pid`.
+    fn postmaster_pid(&self) -> Result<Pid> {
+        let pid_path = Path::new(&self.pgdata).join("postmaster.pid");
+        let content = fs::read_to_string(&pid_path)
+            .with_context(|| format!("failed to read {}", pid_path.display()))?;
+        let pid = content
+            .lines()
+            .next()
+            .and_then(|line| line.trim().parse().ok())
+            .with_context(|| format!("no postmaster PID in {}", pid_path.display()))?;
+
+        Ok(Pid::from_raw(pid))
+    }
+
     pub fn prepare_and_run(&self) -> Result<ExitStatus> {
+        let spec = self.spec.read().unwrap().clone();
         info!(
             "starting compute for project {}, operation {}, tenant {}, timeline {}",
-            self.spec.cluster.cluster_id,
-            self.spec.operation_uuid.as_ref().unwrap(),
+            spec.cluster.cluster_id,
+            spec.operation_uuid.as_ref().unwrap(),
             self.tenant,
             self.timeline,
         );
 
-        self.prepare_pgdata()?;
-        self.run()
+        loop {
+            self.prepare_pgdata()?;
+            let ecode = self.run()?;
+
+            let mut state = self.state.write().unwrap();
+            if !state.restart_requested {
+                return Ok(ecode);
+            }
+
+            info!("restarting compute with a new basebackup");
+            state.status = ComputeStatus::Init;
+            state.shutdown_requested = false;
+            state.restart_requested = false;
+        }
     }
 }
@@ -1,12 +1,36 @@
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::io;
 use std::io::prelude::*;
-use std::path::Path;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 
-use crate::pg_helpers::PgOptionsSerialize;
-use crate::spec::ComputeSpec;
+use crate::pg_helpers::{GenericOption, GenericOptions, PgOptionsSerialize};
+use crate::spec::{ComputeSpec, HbaRule, LocalFileCache, SslConfig};
+
+/// Rules which always come first in `pg_hba.conf`. They are the same as in the
+/// `pg_hba.conf` of basebackup, and let compute_ctl itself connect without a password.
+const PG_HBA_LOCAL_RULES: &[[&str; 5]] = &[
+    ["local", "all", "all", "", "trust"],
+    ["host", "all", "all", "127.0.0.1/32", "trust"],
+    ["host", "all", "all", "::1/128", "trust"],
+    ["local", "replication", "all", "", "trust"],
+    ["host", "replication", "all", "127.0.0.1/32", "trust"],
+    ["host", "replication", "all", "::1/128", "trust"],
+];
+
+/// File names of the SSL certificate and key given in the spec, in the data directory.
+const SSL_CERT_FILE: &str = "server.crt";
+const SSL_KEY_FILE: &str = "server.key";
+
+/// Files in the data directory which are rewritten when a new spec is applied.
+const CONFIG_FILES: &[&str] = &[
+    "postgresql.conf",
+    "pg_hba.conf",
+    SSL_CERT_FILE,
+    SSL_KEY_FILE,
+];
 
 /// Check that `line` is inside a text file and put it there if it is not.
 /// Create file if it doesn't exist.
@@ -36,11 +60,235 @@ pub fn write_postgres_conf(path: &Path, spec: &ComputeSpec) -> Result<()> {
     // File::create() destroys the file content if it exists.
     let mut postgres_conf = File::create(path)?;
 
-    write_auto_managed_block(&mut postgres_conf, &spec.cluster.settings.as_pg_settings())?;
+    let mut settings = with_preload_libraries(&spec.cluster.settings, &spec.preload_libraries());
+    if let Some(cache) = &spec.local_file_cache {
+        settings = with_file_cache(&settings, cache);
+    }
+    settings = with_settings(&settings, auth_settings(spec));
+    write_auto_managed_block(&mut postgres_conf, &settings.as_pg_settings())?;
 
     Ok(())
 }
 
+/// Return a copy of `settings` with `libraries` appended to `shared_preload_libraries`,
+/// unless they are already there.
+pub fn with_preload_libraries(settings: &GenericOptions, libraries: &[&str]) -> GenericOptions {
+    if libraries.is_empty() {
+        return settings.clone();
+    }
+
+    let mut settings = settings.clone().unwrap_or_default();
+    let setting = match settings
+        .iter_mut()
+        .find(|s| s.name == "shared_preload_libraries")
+    {
+        Some(setting) => setting,
+        None => {
+            settings.push(GenericOption {
+                name: "shared_preload_libraries".to_string(),
+                value: None,
+                vartype: "string".to_string(),
+            });
+            settings.last_mut().unwrap()
+        }
+    };
+
+    let mut value: Vec<String> = setting
+        .value
+        .iter()
+        .flat_map(|v| v.split(','))
+        .map(|l| l.trim().to_string())
+        .filter(|l| !l.is_empty())
+        .collect();
+    for library in libraries {
+        if !value.iter().any(|l| l == library) {
+            value.push(library.to_string());
+        }
+    }
+    setting.value = Some(value.join(","));
+
+    Some(settings)
+}
+
+/// Return a copy of `settings` with `overrides` added, replacing the settings
+/// of the same name that might already be there.
+pub fn with_settings(settings: &GenericOptions, overrides: Vec<GenericOption>) -> GenericOptions {
+    if overrides.is_empty() {
+        return settings.clone();
+    }
+
+    let mut settings = settings.clone().unwrap_or_default();
+    settings.retain(|s| !overrides.iter().any(|o| o.name == s.name));
+    settings.extend(overrides);
+
+    Some(settings)
+}
+
+fn setting(name: &str, value: &str, vartype: &str) -> GenericOption {
+    GenericOption {
+        name: name.to_string(),
+        value: Some(value.to_string()),
+        vartype: vartype.to_string(),
+    }
+}
+
+/// Return a copy of `settings` with the `neon` extension's file cache settings
+/// set to `cache`, replacing the ones that might already be there.
+pub fn with_file_cache(settings: &GenericOptions, cache: &LocalFileCache) -> GenericOptions {
+    with_settings(
+        settings,
+        vec![
+            setting(
+                "neon.file_cache_path",
+                &cache.cache_file().display().to_string(),
+                "string",
+            ),
+            setting(
+                "neon.max_file_cache_size",
+                &cache.size_mb.to_string(),
+                "integer",
+            ),
+        ],
+    )
+}
+
+/// Settings for the SSL and `password_encryption` parts of the spec.
+pub fn auth_settings(spec: &ComputeSpec) -> Vec<GenericOption> {
+    let mut settings = vec![];
+
+    if let Some(ssl) = &spec.ssl {
+        let cert_file = ssl.cert_path.as_deref().unwrap_or(SSL_CERT_FILE);
+        let key_file = ssl.key_path.as_deref().unwrap_or(SSL_KEY_FILE);
+        settings.push(setting("ssl", "on", "bool"));
+        settings.push(setting("ssl_cert_file", cert_file, "string"));
+        settings.push(setting("ssl_key_file", key_file, "string"));
+    }
+    if let Some(encryption) = spec.password_encryption {
+        settings.push(setting(
+            "password_encryption",
+            encryption.as_str(),
+            "string",
+        ));
+    }
+
+    settings
+}
+
+/// Render `pg_hba.conf` with the local rules followed by `rules`. The output
+/// only depends on the rules, so it's the same on every start.
+pub fn render_pg_hba(rules: &[HbaRule]) -> String {
+    let spec_rules = rules.iter().map(|rule| {
+        [
+            rule.conn_type.as_str(),
+            &rule.database,
+            &rule.user,
+            rule.address.as_deref().unwrap_or(""),
+            rule.method.as_str(),
+        ]
+    });
+
+    let mut buf = String::from("# Managed by compute_ctl, manual changes will be overwritten.\n");
+    for fields in PG_HBA_LOCAL_RULES.iter().copied().chain(spec_rules) {
+        let line = format!(
+            "{:<12}{:<16}{:<16}{:<24}{}",
+            fields[0], fields[1], fields[2], fields[3], fields[4]
+        );
+        buf.push_str(line.trim_end());
+        buf.push('\n');
+    }
+
+    buf
+}
+
+/// Create or completely rewrite `pg_hba.conf` specified by `path`.
+pub fn write_pg_hba(path: &Path, rules: &[HbaRule]) -> Result<()> {
+    std::fs::write(path, render_pg_hba(rules))
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+/// Write the SSL certificate and key of the spec into the data directory.
+/// Postgres refuses to use a key that is readable by others, so it's
+/// created accessible only by the owner.
+pub fn write_ssl_files(pgdata_path: &Path, ssl: &SslConfig) -> Result<()> {
+    for (file_name, material) in [(SSL_CERT_FILE, &ssl.cert), (SSL_KEY_FILE, &ssl.key)] {
+        if let Some(material) = material {
+            let path = pgdata_path.join(file_name);
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(&path)
+                .with_context(|| format!("failed to create {}", path.display()))?;
+            file.write_all(material.as_bytes())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Configuration files of the data directory as they were before a new spec
+/// is applied, to put them back if Postgres rejects the new ones.
+pub struct ConfigBackup {
+    files: Vec<SavedFile>,
+}
+
+struct SavedFile {
+    path: PathBuf,
+    /// Content and mode of the file, `None` if it didn't exist.
+    saved: Option<(Vec<u8>, u32)>,
+}
+
+impl ConfigBackup {
+    pub fn save(pgdata_path: &Path) -> Result<Self> {
+        let mut files = Vec::new();
+        for file_name in CONFIG_FILES {
+            let path = pgdata_path.join(file_name);
+            let saved = match fs::read(&path) {
+                Ok(content) => Some((content, fs::metadata(&path)?.permissions().mode())),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("failed to read {}", path.display()))
+                }
+            };
+            files.push(SavedFile { path, saved });
+        }
+
+        Ok(ConfigBackup { files })
+    }
+
+    /// Put the files back. Each one is written next to it and renamed, so
+    /// Postgres never reads a partially restored file.
+    pub fn restore(&self) -> Result<()> {
+        for SavedFile { path, saved } in &self.files {
+            match saved {
+                Some((content, mode)) => {
+                    let tmp_path = path.with_extension("restore");
+                    let mut file = OpenOptions::new()
+                        .write(true)
+                        .create(true)
+                        .truncate(true)
+                        .mode(*mode)
+                        .open(&tmp_path)
+                        .with_context(|| format!("failed to create {}", tmp_path.display()))?;
+                    file.write_all(content)?;
+                    fs::rename(&tmp_path, path)
+                        .with_context(|| format!("failed to restore {}", path.display()))?;
+                }
+                None => match fs::remove_file(path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                        return Err(e)
+                            .with_context(|| format!("failed to remove {}", path.display()));
+                    }
+                    _ => {}
+                },
+            }
+        }
+
+        Ok(())
+    }
+}
+
 // Write Postgres config block wrapped with generated comment section
 fn write_auto_managed_block(file: &mut File, buf: &str) -> Result<()> {
     writeln!(file, "# Managed by compute_ctl: begin")?;
@@ -0,0 +1,190 @@
+//!
+//! Local file cache of the `neon` extension (see `pgxn/neon/file_cache.c`),
+//! which keeps pages read from the pageserver in a file on the node's local
+//! disk. Cached pages can't be reused after a restart, but the relations that
+//! were the hottest before shutdown are remembered next to the cache file and
+//! prefetched into the cache once Postgres is started again.
+//!
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use nix::sys::statvfs::statvfs;
+use postgres::{Client, NoTls};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::metrics::FILE_CACHE_PREWARMED_PAGES;
+use crate::spec::LocalFileCache;
+
+const CACHE_FILE_NAME: &str = "file.cache";
+const HOT_RELATIONS_FILE_NAME: &str = "hot_relations.json";
+/// How many of the hottest relations are remembered at shutdown.
+const MAX_HOT_RELATIONS: i64 = 100;
+
+/// Relation fork and the number of its pages which were in the cache.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct HotRelation {
+    pub spcnode: u32,
+    pub dbnode: u32,
+    pub relnode: u32,
+    pub forknum: i32,
+    pub pages: i64,
+}
+
+impl LocalFileCache {
+    pub fn cache_file(&self) -> PathBuf {
+        Path::new(&self.path).join(CACHE_FILE_NAME)
+    }
+
+    fn hot_relations_file(&self) -> PathBuf {
+        Path::new(&self.path).join(HOT_RELATIONS_FILE_NAME)
+    }
+}
+
+/// Create the cache directory and remove the cache file left by the previous
+/// run, which Postgres can't make sense of anyway.
+pub fn prepare(cache: &LocalFileCache) -> Result<()> {
+    fs::create_dir_all(&cache.path)
+        .with_context(|| format!("failed to create file cache directory {}", cache.path))?;
+
+    let cache_file = cache.cache_file();
+    match fs::remove_file(&cache_file) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            return Err(e).with_context(|| format!("failed to remove {}", cache_file.display()));
+        }
+        _ => {}
+    }
+
+    // The cache file grows as pages are added, so running out of disk space
+    // would only show up as write errors later on.
+    let stat = statvfs(cache.path.as_str())?;
+    let available = stat.blocks_available() as u64 * stat.fragment_size() as u64;
+    if available < cache.size_mb as u64 * 1024 * 1024 {
+        warn!(
+            "only {} MB available for the {} MB file cache in {}",
+            available / 1024 / 1024,
+            cache.size_mb,
+            cache.path
+        );
+    }
+
+    Ok(())
+}
+
+/// Remember the relations with the most pages in the cache, to warm it up
+/// on the next start.
+pub fn save_hot_relations(cache: &LocalFileCache, client: &mut Client) -> Result<()> {
+    let relations: Vec<HotRelation> = client
+        .query(
+            "SELECT spcnode, dbnode, relnode, forknum, pages
+               FROM local_cache_relations()
+              ORDER BY pages DESC
+              LIMIT $1",
+            &[&MAX_HOT_RELATIONS],
+        )?
+        .iter()
+        .map(|row| HotRelation {
+            spcnode: row.get("spcnode"),
+            dbnode: row.get("dbnode"),
+            relnode: row.get("relnode"),
+            forknum: row.get("forknum"),
+            pages: row.get("pages"),
+        })
+        .collect();
+
+    // Write a new file and rename it, so that the old list is kept if we
+    // get killed in the middle.
+    let path = cache.hot_relations_file();
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, serde_json::to_vec(&relations)?)?;
+    fs::rename(&tmp_path, &path)?;
+    info!(
+        "saved {} hot relations of the file cache to {}",
+        relations.len(),
+        path.display()
+    );
+
+    Ok(())
+}
+
+/// Read the relations saved by `save_hot_relations()`. A missing or broken
+/// list only means there's nothing to warm up.
+pub fn load_hot_relations(cache: &LocalFileCache) -> Vec<HotRelation> {
+    let path = cache.hot_relations_file();
+    let content = match fs::read(&path) {
+        Ok(content) => content,
+        Err(e) => {
+            if e.kind() != ErrorKind::NotFound {
+                warn!("could not read {}: {}", path.display(), e);
+            }
+            return vec![];
+        }
+    };
+
+    match serde_json::from_slice(&content) {
+        Ok(relations) => relations,
+        Err(e) => {
+            warn!("could not parse {}: {}", path.display(), e);
+            vec![]
+        }
+    }
+}
+
+// Load the pages of `relations` from the pageserver into the cache.
+fn prewarm(connstr: &Url, relations: &[HotRelation]) -> Result<i64> {
+    let mut client = Client::connect(connstr.as_str(), NoTls)?;
+    let mut loaded = 0;
+
+    for rel in relations {
+        let result = client.query_one(
+            "SELECT local_cache_prewarm($1, $2, $3, $4, $5)",
+            &[
+                &rel.spcnode,
+                &rel.dbnode,
+                &rel.relnode,
+                &rel.forknum,
+                &rel.pages,
+            ],
+        );
+        match result {
+            Ok(row) => {
+                let pages: i64 = row.get(0);
+                loaded += pages;
+                FILE_CACHE_PREWARMED_PAGES.add(pages);
+            }
+            // Postgres has probably gone, there's no point in going on.
+            Err(e) if client.is_closed() => return Err(e.into()),
+            Err(e) => warn!(
+                "could not prewarm relation {}/{}/{}.{}: {}",
+                rel.spcnode, rel.dbnode, rel.relnode, rel.forknum, e
+            ),
+        }
+    }
+
+    Ok(loaded)
+}
+
+/// Warm the cache up in the background, so that it doesn't delay the start.
+pub fn launch_prewarm(
+    connstr: &Url,
+    relations: Vec<HotRelation>,
+) -> Result<thread::JoinHandle<()>> {
+    let connstr = connstr.clone();
+
+    Ok(thread::Builder::new()
+        .name("file-cache-prewarm".into())
+        .spawn(move || {
+            info!(
+                "warming up the file cache with {} relations",
+                relations.len()
+            );
+            match prewarm(&connstr, &relations) {
+                Ok(pages) => info!("loaded {} pages into the file cache", pages),
+                Err(e) => warn!("could not warm up the file cache: {}", e),
+            }
+        })?)
+}
@@ -2,14 +2,19 @@ use std::convert::Infallible;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 
 use anyhow::Result;
+use hyper::header::CONTENT_TYPE;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Method, Request, Response, Server, StatusCode};
 use log::{error, info};
 use serde_json;
 
-use crate::compute::{ComputeNode, ComputeStatus};
+use crate::compute::{ComputeNode, ComputeStatus, ShutdownMode};
+use crate::spec::ComputeSpec;
+
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 // Service function to handle all available routes.
 async fn routes(req: Request<Body>, compute: Arc<ComputeNode>) -> Response<Body> {
@@ -39,13 +44,22 @@ async fn routes(req: Request<Body>, compute: Arc<ComputeNode>) -> Response<Body>
             Response::new(Body::from(serde_json::to_string(&*state).unwrap()))
         }
 
-        // Startup metrics in JSON format. Keep /metrics reserved for a possible
-        // future use for Prometheus metrics format.
+        // Startup metrics in JSON format.
         (&Method::GET, "/metrics.json") => {
             info!("serving /metrics.json GET request");
             Response::new(Body::from(serde_json::to_string(&compute.metrics).unwrap()))
         }
 
+        // Prometheus metrics of compute_ctl and Postgres.
+        (&Method::GET, "/metrics") => {
+            info!("serving /metrics GET request");
+            let (content_type, buffer) = crate::metrics::render(&compute).await;
+            Response::builder()
+                .header(CONTENT_TYPE, content_type)
+                .body(Body::from(buffer))
+                .unwrap()
+        }
+
         // DEPRECATED, use POST instead
         (&Method::GET, "/check_writability") => {
             info!("serving /check_writability GET request");
@@ -65,6 +79,58 @@ async fn routes(req: Request<Body>, compute: Arc<ComputeNode>) -> Response<Body>
             }
         }
 
+        // Apply a new spec to the running compute and return the resulting state.
+        (&Method::POST, "/configure") => {
+            info!("serving /configure POST request");
+            match handle_configure_request(req, &compute).await {
+                Ok(()) => {
+                    let state = compute.state.read().unwrap();
+                    Response::new(Body::from(serde_json::to_string(&*state).unwrap()))
+                }
+                Err((msg, status)) => {
+                    error!("error handling /configure request: {}", msg);
+                    let mut resp = Response::new(Body::from(msg));
+                    *resp.status_mut() = status;
+                    resp
+                }
+            }
+        }
+
+        // Shut Postgres down, sync safekeepers and exit. Responds with the final state.
+        (&Method::POST, "/terminate") => {
+            info!("serving /terminate POST request");
+            match handle_shutdown_request(&req, &compute, false).await {
+                Ok(()) => {
+                    let state = compute.state.read().unwrap();
+                    Response::new(Body::from(serde_json::to_string(&*state).unwrap()))
+                }
+                Err((msg, status)) => {
+                    error!("error handling /terminate request: {}", msg);
+                    let mut resp = Response::new(Body::from(msg));
+                    *resp.status_mut() = status;
+                    resp
+                }
+            }
+        }
+
+        // Shut Postgres down and start it again with a new basebackup. Responds
+        // with the state once compute is running again or has failed to start.
+        (&Method::POST, "/restart") => {
+            info!("serving /restart POST request");
+            match handle_shutdown_request(&req, &compute, true).await {
+                Ok(()) => {
+                    let state = compute.state.read().unwrap();
+                    Response::new(Body::from(serde_json::to_string(&*state).unwrap()))
+                }
+                Err((msg, status)) => {
+                    error!("error handling /restart request: {}", msg);
+                    let mut resp = Response::new(Body::from(msg));
+                    *resp.status_mut() = status;
+                    resp
+                }
+            }
+        }
+
         // Return the `404 Not Found` for any other routes.
         _ => {
             let mut not_found = Response::new(Body::from("404 Not Found"));
@@ -74,6 +140,80 @@ async fn routes(req: Request<Body>, compute: Arc<ComputeNode>) -> Response<Body>
     }
 }
 
+async fn handle_configure_request(
+    req: Request<Body>,
+    compute: &Arc<ComputeNode>,
+) -> Result<(), (String, StatusCode)> {
+    let body = hyper::body::to_bytes(req.into_body())
+        .await
+        .map_err(|e| (e.to_string(), StatusCode::BAD_REQUEST))?;
+    let spec: ComputeSpec = serde_json::from_slice(&body)
+        .map_err(|e| (format!("invalid spec: {}", e), StatusCode::BAD_REQUEST))?;
+    spec.validate()
+        .map_err(|e| (format!("invalid spec: {}", e), StatusCode::BAD_REQUEST))?;
+
+    // Only a running compute can be reconfigured, `reconfigure` checks it
+    // again under the lock, but this way the caller gets a clearer response.
+    if compute.get_status() != ComputeStatus::Running {
+        return Err((
+            "compute is not running, cannot apply a new spec".to_string(),
+            StatusCode::PRECONDITION_FAILED,
+        ));
+    }
+
+    // Spec is applied with a blocking Postgres client.
+    let compute = Arc::clone(compute);
+    tokio::task::spawn_blocking(move || compute.reconfigure(spec))
+        .await
+        .map_err(|e| (e.to_string(), StatusCode::INTERNAL_SERVER_ERROR))?
+        .map_err(|e| (format!("{:?}", e), StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+async fn handle_shutdown_request(
+    req: &Request<Body>,
+    compute: &Arc<ComputeNode>,
+    restart: bool,
+) -> Result<(), (String, StatusCode)> {
+    let mut mode = ShutdownMode::Fast;
+    if let Some(query) = req.uri().query() {
+        for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            if key == "mode" {
+                mode = value
+                    .parse()
+                    .map_err(|e: anyhow::Error| (e.to_string(), StatusCode::BAD_REQUEST))?;
+            }
+        }
+    }
+
+    if compute.get_status() != ComputeStatus::Running {
+        return Err((
+            "compute is not running, cannot shut it down".to_string(),
+            StatusCode::PRECONDITION_FAILED,
+        ));
+    }
+
+    let c = Arc::clone(compute);
+    tokio::task::spawn_blocking(move || c.shutdown(mode, restart))
+        .await
+        .map_err(|e| (e.to_string(), StatusCode::INTERNAL_SERVER_ERROR))?
+        .map_err(|e| (format!("{:?}", e), StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    // Wait for Postgres to exit and safekeepers to be synced, and for it
+    // to start again in case of restart.
+    loop {
+        let done = match compute.get_status() {
+            ComputeStatus::Terminated => !restart,
+            ComputeStatus::Running => restart,
+            ComputeStatus::Failed => true,
+            _ => false,
+        };
+        if done {
+            return Ok(());
+        }
+        tokio::time::sleep(SHUTDOWN_POLL_INTERVAL).await;
+    }
+}
+
 // Main Hyper HTTP server function that runs it and blocks waiting on it forever.
 #[tokio::main]
 async fn serve(state: Arc<ComputeNode>) {
@@ -4,11 +4,14 @@
 //!
 pub mod checker;
 pub mod config;
+pub mod file_cache;
 pub mod http;
 #[macro_use]
 pub mod logger;
 pub mod compute;
+pub mod metrics;
 pub mod monitor;
 pub mod params;
 pub mod pg_helpers;
+pub mod prewarm;
 pub mod spec;
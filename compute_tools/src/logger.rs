@@ -1,12 +1,36 @@
-use std::io::Write;
+//!
+//! Logging of `compute_ctl` and of the Postgres it manages. Records are either
+//! plain text lines or JSON objects tagged with the tenant, timeline and
+//! operation of the current spec, and go to stderr, a local syslog or a file.
+//! Postgres stderr is captured and logged line by line with the `postgres`
+//! target, so that its lines are tagged the same way. Postgres filters them by
+//! its own `log_min_messages`, so `RUST_LOG` doesn't apply to them.
+//!
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use std::thread;
 
-use anyhow::Result;
-use chrono::Utc;
-use env_logger::{Builder, Env};
+use anyhow::{bail, Context, Result};
+use chrono::{SecondsFormat, Utc};
+use env_logger::{Builder, Env, Target};
+use log::{error, log, warn, Level, LevelFilter, Record};
+use once_cell::sync::Lazy;
+use serde_json::json;
+
+/// Target of the records with Postgres log lines.
+pub const POSTGRES_LOG_TARGET: &str = "postgres";
+const SYSLOG_SOCKET: &str = "/dev/log";
+/// Read errors of Postgres stderr in a row after which it's no longer logged.
+const MAX_POSTGRES_LOG_ERRORS: u32 = 10;
 
 macro_rules! info_println {
     ($($tts:tt)*) => {
-        if log_enabled!(Level::Info) {
+        if log_enabled!(Level::Info) && !$crate::logger::is_structured() {
             println!($($tts)*);
         }
     }
@@ -14,30 +38,269 @@ macro_rules! info_println {
 
 macro_rules! info_print {
     ($($tts:tt)*) => {
-        if log_enabled!(Level::Info) {
+        if log_enabled!(Level::Info) && !$crate::logger::is_structured() {
             print!($($tts)*);
         }
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Plain,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "plain" => Ok(LogFormat::Plain),
+            "json" => Ok(LogFormat::Json),
+            _ => bail!("unknown log format '{}', expected plain or json", s),
+        }
+    }
+}
+
+/// Where the log records go: `stderr`, `syslog` or an absolute path of a file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LogSink {
+    Stderr,
+    Syslog,
+    File(PathBuf),
+}
+
+impl FromStr for LogSink {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "stderr" => Ok(LogSink::Stderr),
+            "syslog" => Ok(LogSink::Syslog),
+            path if path.starts_with('/') => Ok(LogSink::File(PathBuf::from(path))),
+            _ => bail!(
+                "unknown log sink '{}', expected stderr, syslog or an absolute path",
+                s
+            ),
+        }
+    }
+}
+
+/// Fields every JSON record is tagged with, known once the spec is read.
+#[derive(Clone, Debug, Default)]
+pub struct LogContext {
+    pub tenant_id: Option<String>,
+    pub timeline_id: Option<String>,
+    pub operation_uuid: Option<String>,
+}
+
+static LOG_CONTEXT: Lazy<RwLock<LogContext>> = Lazy::new(|| RwLock::new(LogContext::default()));
+static STRUCTURED: AtomicBool = AtomicBool::new(false);
+
+/// Tag all the following records with the tenant and timeline.
+pub fn set_log_context(tenant_id: &str, timeline_id: &str) {
+    let mut context = LOG_CONTEXT.write().unwrap();
+    context.tenant_id = Some(tenant_id.to_string());
+    context.timeline_id = Some(timeline_id.to_string());
+}
+
+/// Tag all the following records with the operation of the spec being applied.
+pub fn set_operation_uuid(operation_uuid: Option<&str>) {
+    LOG_CONTEXT.write().unwrap().operation_uuid = operation_uuid.map(str::to_string);
+}
+
+/// Whether records are JSON, so nothing else should be printed in between.
+pub fn is_structured() -> bool {
+    STRUCTURED.load(Ordering::Relaxed)
+}
+
+/// Render `record` as a single line JSON object.
+pub fn format_json(record: &Record, context: &LogContext) -> String {
+    let source = if record.target() == POSTGRES_LOG_TARGET {
+        "postgres"
+    } else {
+        "compute_ctl"
+    };
+
+    json!({
+        "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        "level": record.level().as_str(),
+        "source": source,
+        "target": record.target(),
+        "thread": thread::current().name().unwrap_or("main"),
+        "message": record.args().to_string(),
+        "tenant_id": context.tenant_id,
+        "timeline_id": context.timeline_id,
+        "operation_uuid": context.operation_uuid,
+    })
+    .to_string()
+}
+
+fn format_plain(record: &Record) -> String {
+    // Postgres lines have their own timestamp and severity.
+    if record.target() == POSTGRES_LOG_TARGET {
+        return record.args().to_string();
+    }
+
+    format!(
+        "{} [{}] {}: {}",
+        Utc::now().format("%Y-%m-%d %H:%M:%S%.3f %Z"),
+        thread::current().name().unwrap_or("main"),
+        record.level(),
+        record.args()
+    )
+}
+
+// Header of a syslog message with the `user` facility.
+fn syslog_header(level: Level) -> String {
+    let severity = match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    };
+    format!("<{}>compute_ctl[{}]: ", 8 + severity, std::process::id())
+}
+
+/// Sends each record written to it as a datagram to the local syslog.
+/// `env_logger` writes a formatted record at once.
+struct SyslogWriter {
+    socket: UnixDatagram,
+}
+
+impl Write for SyslogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.send(buf.strip_suffix(b"\n").unwrap_or(buf))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 /// Initialize `env_logger` using either `default_level` or
-/// `RUST_LOG` environment variable as default log level.
-pub fn init_logger(default_level: &str) -> Result<()> {
+/// `RUST_LOG` environment variable as default log level. Postgres
+/// lines are logged whatever the level is.
+pub fn init_logger(default_level: &str, format: LogFormat, sink: &LogSink) -> Result<()> {
     let env = Env::default().filter_or("RUST_LOG", default_level);
+    let mut builder = Builder::from_env(env);
+    builder.filter_module(POSTGRES_LOG_TARGET, LevelFilter::Trace);
 
-    Builder::from_env(env)
-        .format(|buf, record| {
-            let thread_handle = std::thread::current();
-            writeln!(
-                buf,
-                "{} [{}] {}: {}",
-                Utc::now().format("%Y-%m-%d %H:%M:%S%.3f %Z"),
-                thread_handle.name().unwrap_or("main"),
-                record.level(),
-                record.args()
-            )
+    match sink {
+        LogSink::Stderr => {}
+        LogSink::Syslog => {
+            let socket = UnixDatagram::unbound()?;
+            socket
+                .connect(SYSLOG_SOCKET)
+                .with_context(|| format!("cannot connect to syslog at {}", SYSLOG_SOCKET))?;
+            builder.target(Target::Pipe(Box::new(SyslogWriter { socket })));
+        }
+        LogSink::File(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("cannot open log file {}", path.display()))?;
+            builder.target(Target::Pipe(Box::new(file)));
+        }
+    }
+
+    STRUCTURED.store(format == LogFormat::Json, Ordering::Relaxed);
+    let syslog = *sink == LogSink::Syslog;
+    builder
+        .format(move |buf, record| {
+            if syslog {
+                write!(buf, "{}", syslog_header(record.level()))?;
+            }
+            match format {
+                LogFormat::Plain => writeln!(buf, "{}", format_plain(record)),
+                LogFormat::Json => {
+                    let context = LOG_CONTEXT.read().unwrap();
+                    writeln!(buf, "{}", format_json(record, &context))
+                }
+            }
         })
         .init();
 
     Ok(())
 }
+
+/// Level of a Postgres log line by its first severity word, e.g.
+/// `2022-08-01 10:00:00.000 UTC [42] ERROR:  relation "foo" does not exist`.
+/// Lines without one, like continuation lines, are logged at info level.
+pub fn postgres_log_level(line: &str) -> Level {
+    const SEVERITIES: &[(&str, Level)] = &[
+        ("LOG:", Level::Info),
+        ("INFO:", Level::Info),
+        ("NOTICE:", Level::Info),
+        ("DETAIL:", Level::Info),
+        ("HINT:", Level::Info),
+        ("CONTEXT:", Level::Info),
+        ("STATEMENT:", Level::Info),
+        ("PANIC:", Level::Error),
+        ("FATAL:", Level::Error),
+        ("ERROR:", Level::Error),
+        ("WARNING:", Level::Warn),
+        ("DEBUG1:", Level::Debug),
+        ("DEBUG2:", Level::Debug),
+        ("DEBUG3:", Level::Debug),
+        ("DEBUG4:", Level::Debug),
+        ("DEBUG5:", Level::Debug),
+    ];
+
+    line.split_whitespace()
+        .find_map(|word| {
+            SEVERITIES
+                .iter()
+                .find(|(severity, _)| *severity == word)
+                .map(|(_, level)| *level)
+        })
+        .unwrap_or(Level::Info)
+}
+
+/// Call `f` with each line of `reader` until its end, without the line
+/// break. Bytes which aren't UTF-8 are replaced, and read errors are
+/// skipped, unless there are too many of them in a row.
+pub fn for_each_postgres_line(mut reader: impl BufRead, mut f: impl FnMut(&str)) {
+    let mut buf = Vec::new();
+    let mut errors = 0;
+    loop {
+        // After an error, the part of the line read before it is kept.
+        match reader.read_until(b'\n', &mut buf) {
+            Ok(0) if buf.is_empty() => break,
+            Ok(_) => {
+                errors = 0;
+                let line = String::from_utf8_lossy(&buf);
+                f(line.trim_end_matches('\n').trim_end_matches('\r'));
+                buf.clear();
+            }
+            Err(e) => {
+                errors += 1;
+                if errors >= MAX_POSTGRES_LOG_ERRORS {
+                    error!(
+                        "stopped reading Postgres logs after {} errors: {}",
+                        errors, e
+                    );
+                    break;
+                }
+                warn!("could not read Postgres logs: {}", e);
+            }
+        }
+    }
+}
+
+/// Log the lines Postgres writes to its stderr in a background thread,
+/// until the pipe is closed.
+pub fn launch_postgres_logger(
+    stderr: impl Read + Send + 'static,
+) -> Result<thread::JoinHandle<()>> {
+    Ok(thread::Builder::new()
+        .name("postgres-logs".into())
+        .spawn(move || {
+            for_each_postgres_line(
+                BufReader::new(stderr),
+                |line| log!(target: POSTGRES_LOG_TARGET, postgres_log_level(line), "{}", line),
+            );
+        })?)
+}
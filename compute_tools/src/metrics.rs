@@ -0,0 +1,266 @@
+//!
+//! Prometheus metrics of `compute_ctl` and of the Postgres it manages, served
+//! at `/metrics`. Most of them are updated as things happen, but `pg_stat_*`
+//! aggregates and file cache statistics are queried from Postgres on each scrape.
+//!
+use std::sync::atomic::Ordering;
+
+use anyhow::Result;
+use log::{error, warn};
+use metrics::{
+    register_gauge_vec, register_histogram_vec, register_int_counter_vec, register_int_gauge,
+    register_int_gauge_vec, Encoder, GaugeVec, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec,
+    TextEncoder,
+};
+use once_cell::sync::Lazy;
+use tokio_postgres::NoTls;
+
+use crate::compute::{ComputeNode, ComputeStatus};
+
+static STARTUP_PHASE_SECONDS: Lazy<GaugeVec> = Lazy::new(|| {
+    register_gauge_vec!(
+        "compute_ctl_startup_phase_seconds",
+        "Time spent in each phase of the compute startup",
+        &["phase"]
+    )
+    .expect("Failed to register compute_ctl_startup_phase_seconds gauge vec")
+});
+
+pub static SPEC_APPLY_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "compute_ctl_spec_apply_seconds",
+        "Time spent applying each part of the spec to Postgres, on startup and reconfiguration",
+        &["step"]
+    )
+    .expect("Failed to register compute_ctl_spec_apply_seconds histogram vec")
+});
+
+pub static ACTIVITY_CHECKS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "compute_ctl_activity_checks_total",
+        "Results of the Postgres activity checks (active|idle|error)",
+        &["result"]
+    )
+    .expect("Failed to register compute_ctl_activity_checks_total int counter vec")
+});
+
+pub static LAST_ACTIVE_TIMESTAMP: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "compute_ctl_last_active_timestamp_seconds",
+        "Unix timestamp of the last detected Postgres activity"
+    )
+    .expect("Failed to register compute_ctl_last_active_timestamp_seconds int gauge")
+});
+
+pub static POSTGRES_UP: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "compute_ctl_postgres_up",
+        "Whether Postgres accepts connections from compute_ctl"
+    )
+    .expect("Failed to register compute_ctl_postgres_up int gauge")
+});
+
+pub static PREWARMED_BUFFERS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "compute_ctl_prewarmed_buffers",
+        "Number of hot blocks read into shared buffers by the prewarm since start"
+    )
+    .expect("Failed to register compute_ctl_prewarmed_buffers int gauge")
+});
+
+static PG_STAT_DATABASE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "compute_pg_stat_database",
+        "Counters from pg_stat_database summed over all databases",
+        &["stat"]
+    )
+    .expect("Failed to register compute_pg_stat_database int gauge vec")
+});
+
+static PG_STAT_ACTIVITY: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "compute_pg_stat_activity_backends",
+        "Number of client backends by their state in pg_stat_activity",
+        &["state"]
+    )
+    .expect("Failed to register compute_pg_stat_activity_backends int gauge vec")
+});
+
+static PG_STAT_BGWRITER: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "compute_pg_stat_bgwriter",
+        "Counters from pg_stat_bgwriter",
+        &["stat"]
+    )
+    .expect("Failed to register compute_pg_stat_bgwriter int gauge vec")
+});
+
+static PG_DATABASE_SIZE: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "compute_pg_database_size_bytes",
+        "Total size of all databases"
+    )
+    .expect("Failed to register compute_pg_database_size_bytes int gauge")
+});
+
+static FILE_CACHE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "compute_file_cache",
+        "Statistics of the local file cache: hits, misses, writes, used_pages and size_pages",
+        &["stat"]
+    )
+    .expect("Failed to register compute_file_cache int gauge vec")
+});
+
+pub static FILE_CACHE_PREWARMED_PAGES: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "compute_file_cache_prewarmed_pages",
+        "Number of pages loaded into the local file cache by the warm-up since start"
+    )
+    .expect("Failed to register compute_file_cache_prewarmed_pages int gauge")
+});
+
+const PG_STAT_DATABASE_COLUMNS: &[&str] = &[
+    "numbackends",
+    "xact_commit",
+    "xact_rollback",
+    "blks_read",
+    "blks_hit",
+    "tup_returned",
+    "tup_fetched",
+    "tup_inserted",
+    "tup_updated",
+    "tup_deleted",
+    "conflicts",
+    "temp_files",
+    "temp_bytes",
+    "deadlocks",
+];
+
+const FILE_CACHE_COLUMNS: &[&str] = &["hits", "misses", "writes", "used_pages", "size_pages"];
+
+const PG_STAT_BGWRITER_COLUMNS: &[&str] = &[
+    "checkpoints_timed",
+    "checkpoints_req",
+    "buffers_checkpoint",
+    "buffers_clean",
+    "buffers_backend",
+    "buffers_alloc",
+];
+
+/// Time `f`, which applies the `step` part of the spec.
+pub fn observe_spec_apply<T>(step: &str, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    let _timer = SPEC_APPLY_SECONDS.with_label_values(&[step]).start_timer();
+    f()
+}
+
+/// Query `pg_stat_*` aggregates and file cache statistics from Postgres and
+/// update the gauges.
+async fn collect_pg_stats(compute: &ComputeNode) -> Result<()> {
+    let file_cache_enabled = compute.spec.read().unwrap().local_file_cache.is_some();
+    let (client, connection) = tokio_postgres::connect(compute.connstr.as_str(), NoTls).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            error!("connection error: {}", e);
+        }
+    });
+
+    let query = format!(
+        "SELECT {} FROM pg_catalog.pg_stat_database",
+        PG_STAT_DATABASE_COLUMNS
+            .iter()
+            .map(|c| format!("coalesce(sum({c}), 0)::bigint AS {c}"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    let row = client.query_one(query.as_str(), &[]).await?;
+    for column in PG_STAT_DATABASE_COLUMNS {
+        PG_STAT_DATABASE
+            .with_label_values(&[column])
+            .set(row.get(column));
+    }
+
+    let query = format!(
+        "SELECT {} FROM pg_catalog.pg_stat_bgwriter",
+        PG_STAT_BGWRITER_COLUMNS.join(", ")
+    );
+    let row = client.query_one(query.as_str(), &[]).await?;
+    for column in PG_STAT_BGWRITER_COLUMNS {
+        PG_STAT_BGWRITER
+            .with_label_values(&[column])
+            .set(row.get(column));
+    }
+
+    let rows = client
+        .query(
+            "SELECT coalesce(state, 'unknown') AS state, count(*) AS count
+               FROM pg_catalog.pg_stat_activity
+              WHERE backend_type = 'client backend'
+              GROUP BY 1",
+            &[],
+        )
+        .await?;
+    // Forget the states which don't have any backends now.
+    PG_STAT_ACTIVITY.reset();
+    for row in rows {
+        let state: String = row.get("state");
+        PG_STAT_ACTIVITY
+            .with_label_values(&[&state])
+            .set(row.get("count"));
+    }
+
+    let row = client
+        .query_one(
+            "SELECT coalesce(sum(pg_database_size(datname)), 0)::bigint AS size
+               FROM pg_catalog.pg_database",
+            &[],
+        )
+        .await?;
+    PG_DATABASE_SIZE.set(row.get("size"));
+
+    if file_cache_enabled {
+        let query = format!(
+            "SELECT {} FROM local_cache_stats()",
+            FILE_CACHE_COLUMNS.join(", ")
+        );
+        let row = client.query_one(query.as_str(), &[]).await?;
+        for column in FILE_CACHE_COLUMNS {
+            FILE_CACHE.with_label_values(&[column]).set(row.get(column));
+        }
+    }
+
+    Ok(())
+}
+
+/// Collect all metrics and encode them in the Prometheus text format.
+/// Returns the content type and the encoded metrics.
+pub async fn render(compute: &ComputeNode) -> (String, Vec<u8>) {
+    let phases = [
+        ("sync_safekeepers", &compute.metrics.sync_safekeepers_ms),
+        ("basebackup", &compute.metrics.basebackup_ms),
+        ("config", &compute.metrics.config_ms),
+        ("total", &compute.metrics.total_startup_ms),
+    ];
+    for (phase, ms) in phases {
+        STARTUP_PHASE_SECONDS
+            .with_label_values(&[phase])
+            .set(ms.load(Ordering::Relaxed) as f64 / 1000.0);
+    }
+
+    // There's nothing to query before Postgres is started, and nobody
+    // would care about stale stats after it has failed.
+    let status = compute.get_status();
+    if status == ComputeStatus::Running || status == ComputeStatus::Configuration {
+        if let Err(e) = collect_pg_stats(compute).await {
+            warn!("could not collect pg_stat metrics: {:?}", e);
+        }
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    encoder
+        .encode(&metrics::gather(), &mut buffer)
+        .expect("encoding metrics into a Vec should not fail");
+
+    (encoder.format_type().to_string(), buffer)
+}
@@ -3,15 +3,18 @@ use std::{thread, time};
 
 use anyhow::Result;
 use chrono::{DateTime, Utc};
-use log::{debug, info};
+use log::{debug, error, info};
 use postgres::{Client, NoTls};
 
-use crate::compute::ComputeNode;
+use crate::compute::{ComputeNode, ComputeStatus, ShutdownMode};
+use crate::metrics::{ACTIVITY_CHECKS, LAST_ACTIVE_TIMESTAMP, POSTGRES_UP};
 
 const MONITOR_CHECK_INTERVAL: u64 = 500; // milliseconds
+const NOTIFY_TIMEOUT: u64 = 10; // seconds
 
 // Spin in a loop and figure out the last activity time in the Postgres.
-// Then update it in the shared state. This function never errors out.
+// Then update it in the shared state and suspend compute if it's idle for
+// too long. This function never errors out.
 // XXX: the only expected panic is at `RwLock` unwrap().
 fn watch_compute_activity(compute: &ComputeNode) {
     // Suppose that `connstr` doesn't change
@@ -19,6 +22,8 @@ fn watch_compute_activity(compute: &ComputeNode) {
     // Define `client` outside of the loop to reuse existing connection if it's active.
     let mut client = Client::connect(connstr, NoTls);
     let timeout = time::Duration::from_millis(MONITOR_CHECK_INTERVAL);
+    // `last_active` of the idle period compute was already suspended for.
+    let mut suspended_at = None;
 
     info!("watching Postgres activity at {}", connstr);
 
@@ -30,6 +35,7 @@ fn watch_compute_activity(compute: &ComputeNode) {
             Ok(cli) => {
                 if cli.is_closed() {
                     info!("connection to postgres closed, trying to reconnect");
+                    POSTGRES_UP.set(0);
 
                     // Connection is closed, reconnect and try again.
                     client = Client::connect(connstr, NoTls);
@@ -49,7 +55,9 @@ fn watch_compute_activity(compute: &ComputeNode) {
                 let mut last_active = compute.state.read().unwrap().last_active;
 
                 if let Ok(backs) = backends {
+                    POSTGRES_UP.set(1);
                     let mut idle_backs: Vec<DateTime<Utc>> = vec![];
+                    let mut result = "idle";
 
                     for b in backs.into_iter() {
                         let state: String = b.get("state");
@@ -70,6 +78,7 @@ fn watch_compute_activity(compute: &ComputeNode) {
                             // `state_change` timestamps array as it doesn't matter now.
                             last_active = Utc::now();
                             idle_backs.clear();
+                            result = "active";
                             break;
                         }
                     }
@@ -80,17 +89,39 @@ fn watch_compute_activity(compute: &ComputeNode) {
                     if let Some(last) = idle_backs.last() {
                         last_active = *last;
                     }
+
+                    // Logical replication and background workers (e.g. pg_cron jobs)
+                    // keep compute busy without any client backends.
+                    if result == "idle" {
+                        match has_background_activity(cli) {
+                            Ok(true) => {
+                                last_active = Utc::now();
+                                result = "active";
+                            }
+                            Ok(false) => {}
+                            Err(e) => debug!("cannot check background activity: {}", e),
+                        }
+                    }
+                    ACTIVITY_CHECKS.with_label_values(&[result]).inc();
+                } else {
+                    ACTIVITY_CHECKS.with_label_values(&["error"]).inc();
                 }
 
                 // Update the last activity in the shared state if we got a more recent one.
                 let mut state = compute.state.write().unwrap();
                 if last_active > state.last_active {
                     state.last_active = last_active;
+                    LAST_ACTIVE_TIMESTAMP.set(last_active.timestamp());
                     debug!("set the last compute activity time to: {}", last_active);
                 }
+                let last_active = state.last_active;
+                drop(state);
+
+                check_auto_suspend(compute, last_active, &mut suspended_at);
             }
             Err(e) => {
                 debug!("cannot connect to postgres: {}, retrying", e);
+                POSTGRES_UP.set(0);
 
                 // Establish a new connection and try again.
                 client = Client::connect(connstr, NoTls);
@@ -99,6 +130,87 @@ fn watch_compute_activity(compute: &ComputeNode) {
     }
 }
 
+// Check whether there are logical replication walsenders or background workers
+// running a query.
+fn has_background_activity(client: &mut Client) -> Result<bool> {
+    let row = client.query_one(
+        "SELECT
+            (SELECT count(*) FROM pg_catalog.pg_replication_slots
+              WHERE active AND slot_type = 'logical') AS walsenders,
+            (SELECT count(*) FROM pg_catalog.pg_stat_activity
+              WHERE state = 'active'
+                AND backend_type NOT IN ('client backend', 'walsender', 'autovacuum worker')) AS bgworkers;",
+        &[],
+    )?;
+    let walsenders: i64 = row.get("walsenders");
+    let bgworkers: i64 = row.get("bgworkers");
+
+    Ok(walsenders > 0 || bgworkers > 0)
+}
+
+// Suspend compute if it has been idle for longer than the spec allows: either
+// notify the control plane or shut Postgres down. It's done once per idle period,
+// which is identified by its `last_active` timestamp.
+fn check_auto_suspend(
+    compute: &ComputeNode,
+    last_active: DateTime<Utc>,
+    suspended_at: &mut Option<DateTime<Utc>>,
+) {
+    let auto_suspend = match &compute.spec.read().unwrap().auto_suspend {
+        Some(auto_suspend) => auto_suspend.clone(),
+        None => return,
+    };
+
+    if compute.get_status() != ComputeStatus::Running || *suspended_at == Some(last_active) {
+        return;
+    }
+
+    // Fits, see `MAX_IDLE_TIMEOUT_SECONDS`.
+    let idle_timeout = chrono::Duration::seconds(auto_suspend.idle_timeout_seconds as i64);
+    if Utc::now().signed_duration_since(last_active) < idle_timeout {
+        return;
+    }
+
+    // Don't retry on errors, the control plane can still see that compute
+    // is idle in `/status`.
+    *suspended_at = Some(last_active);
+
+    let result = match &auto_suspend.notify_url {
+        Some(url) => {
+            info!(
+                "compute is idle since {}, notifying control plane at {}",
+                last_active, url
+            );
+            notify_idle(compute, url, last_active)
+        }
+        None => {
+            info!("compute is idle since {}, shutting it down", last_active);
+            compute.shutdown(ShutdownMode::Fast, false)
+        }
+    };
+
+    if let Err(e) = result {
+        error!("could not suspend idle compute: {:?}", e);
+    }
+}
+
+fn notify_idle(compute: &ComputeNode, url: &str, last_active: DateTime<Utc>) -> Result<()> {
+    let body = serde_json::json!({
+        "tenant_id": compute.tenant,
+        "timeline_id": compute.timeline,
+        "last_active": last_active.to_rfc3339(),
+    });
+
+    reqwest::blocking::Client::new()
+        .post(url)
+        .timeout(time::Duration::from_secs(NOTIFY_TIMEOUT))
+        .json(&body)
+        .send()?
+        .error_for_status()?;
+
+    Ok(())
+}
+
 /// Launch a separate compute monitor thread and return its `JoinHandle`.
 pub fn launch_monitor(state: &Arc<ComputeNode>) -> Result<thread::JoinHandle<()>> {
     let state = Arc::clone(state);
@@ -1,3 +1,2 @@
 pub const DEFAULT_LOG_LEVEL: &str = "info";
 pub const DEFAULT_CONNSTRING: &str = "host=localhost user=postgres";
-pub const PG_HBA_ALL_MD5: &str = "host\tall\t\tall\t\t0.0.0.0/0\t\tmd5";
@@ -30,8 +30,29 @@ pub struct Database {
     pub name: PgIdent,
     pub owner: PgIdent,
     pub options: GenericOptions,
+    /// Extensions that should be installed in this database.
+    pub extensions: Option<Vec<Extension>>,
 }
 
+/// Postgres extension which should be installed into a database. If `version`
+/// is not set, the default version of the extension is used, so it will be
+/// updated once a newer one becomes the default.
+#[derive(Clone, Deserialize)]
+pub struct Extension {
+    pub name: PgIdent,
+    pub version: Option<String>,
+}
+
+/// Extensions that only work if their library is in `shared_preload_libraries`,
+/// with the name of that library.
+const PRELOADED_EXTENSIONS: &[(&str, &str)] = &[
+    ("pg_stat_statements", "pg_stat_statements"),
+    ("pg_cron", "pg_cron"),
+    ("pg_wait_sampling", "pg_wait_sampling"),
+    ("pgaudit", "pgaudit"),
+    ("timescaledb", "timescaledb"),
+];
+
 /// Common type representing both SQL statement params with or without value,
 /// like `LOGIN` or `OWNER username` in the `CREATE/ALTER ROLE`, and config
 /// options like `wal_level = logical`.
@@ -160,6 +181,17 @@ impl Role {
     }
 }
 
+impl Extension {
+    /// Name of the library which has to be preloaded for this extension to
+    /// work, if any.
+    pub fn preload_library(&self) -> Option<&'static str> {
+        PRELOADED_EXTENSIONS
+            .iter()
+            .find(|(name, _)| *name == self.name)
+            .map(|(_, library)| *library)
+    }
+}
+
 impl Database {
     /// Serialize a list of database parameters into a Postgres-acceptable
     /// string of arguments.
@@ -196,6 +228,11 @@ impl PgQuote for PgIdent {
     }
 }
 
+/// Quote a string to be used as an SQL literal, e.g. `'it''s'`.
+pub fn escape_literal(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
 /// Build a list of existing Postgres roles
 pub fn get_existing_roles(xact: &mut Transaction<'_>) -> Result<Vec<Role>> {
     let postgres_roles = xact
@@ -224,12 +261,41 @@ pub fn get_existing_dbs(client: &mut Client) -> Result<Vec<Database>> {
             name: row.get("datname"),
             owner: row.get("owner"),
             options: None,
+            extensions: None,
         })
         .collect();
 
     Ok(postgres_dbs)
 }
 
+/// Installed extension with its version and the default version of the
+/// extension available in this Postgres.
+pub struct InstalledExtension {
+    pub name: PgIdent,
+    pub version: String,
+    pub default_version: Option<String>,
+}
+
+/// Build a list of extensions installed in the database `client` is connected to
+pub fn get_installed_extensions(client: &mut Client) -> Result<Vec<InstalledExtension>> {
+    let extensions = client
+        .query(
+            "SELECT e.extname, e.extversion, a.default_version
+               FROM pg_catalog.pg_extension e
+               LEFT JOIN pg_catalog.pg_available_extensions a ON a.name = e.extname;",
+            &[],
+        )?
+        .iter()
+        .map(|row| InstalledExtension {
+            name: row.get("extname"),
+            version: row.get("extversion"),
+            default_version: row.get("default_version"),
+        })
+        .collect();
+
+    Ok(extensions)
+}
+
 /// Wait for Postgres to become ready to accept connections:
 /// - state should be `ready` in the `pgdata/postmaster.pid`
 /// - and we should be able to connect to 127.0.0.1:5432
@@ -289,3 +355,12 @@ pub fn create_pgdata(pgdata: &str) -> Result<()> {
 
     Ok(())
 }
+
+/// Create the `neon` extension, or update it to the latest version, in the
+/// database `client` is connected to, so that its functions can be called.
+pub fn create_neon_extension(client: &mut Client) -> Result<()> {
+    client.simple_query("CREATE EXTENSION IF NOT EXISTS neon")?;
+    client.simple_query("ALTER EXTENSION neon UPDATE")?;
+
+    Ok(())
+}
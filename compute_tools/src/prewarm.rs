@@ -0,0 +1,148 @@
+//!
+//! Prewarm of the buffer cache of a freshly started compute. The pageserver
+//! remembers which blocks of the timeline were read recently, and once
+//! Postgres is running they are read into shared buffers in the background
+//! with the `prewarm_buffers()` function of the `neon` extension.
+//!
+use std::thread;
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use postgres::{Client, NoTls, SimpleQueryMessage};
+use url::Url;
+
+use crate::compute::ComputeNode;
+use crate::metrics::PREWARMED_BUFFERS;
+
+/// Consecutive blocks of a relation fork.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockRange {
+    pub spcnode: u32,
+    pub dbnode: u32,
+    pub relnode: u32,
+    pub forknum: i32,
+    pub first_block: u32,
+    pub nblocks: u32,
+}
+
+/// Merge blocks, ordered by relation and block number as the pageserver
+/// returns them, into ranges of consecutive ones.
+pub fn group_blocks(blocks: &[(u32, u32, u32, i32, u32)]) -> Vec<BlockRange> {
+    let mut ranges: Vec<BlockRange> = Vec::new();
+
+    for &(spcnode, dbnode, relnode, forknum, blkno) in blocks {
+        match ranges.last_mut() {
+            Some(last)
+                if (last.spcnode, last.dbnode, last.relnode, last.forknum)
+                    == (spcnode, dbnode, relnode, forknum)
+                    && last.first_block + last.nblocks == blkno =>
+            {
+                last.nblocks += 1;
+            }
+            _ => ranges.push(BlockRange {
+                spcnode,
+                dbnode,
+                relnode,
+                forknum,
+                first_block: blkno,
+                nblocks: 1,
+            }),
+        }
+    }
+
+    ranges
+}
+
+// Where to prewarm and where to get the hot blocks from.
+struct Target {
+    connstr: Url,
+    pageserver_connstr: String,
+    tenant: String,
+    timeline: String,
+}
+
+// Ask the pageserver for up to `limit` blocks of the timeline read recently.
+fn fetch_hot_blocks(target: &Target, limit: i64) -> Result<Vec<BlockRange>> {
+    let mut client = Client::connect(&target.pageserver_connstr, NoTls)?;
+    let query = format!("hot_blocks {} {} {}", target.tenant, target.timeline, limit);
+
+    let mut blocks = Vec::new();
+    for message in client.simple_query(&query)? {
+        if let SimpleQueryMessage::Row(row) = message {
+            let column = |name: &str| -> Result<u32> {
+                row.get(name)
+                    .with_context(|| format!("no {} in hot_blocks response", name))?
+                    .parse()
+                    .with_context(|| format!("invalid {} in hot_blocks response", name))
+            };
+            blocks.push((
+                column("spcnode")?,
+                column("dbnode")?,
+                column("relnode")?,
+                column("forknum")? as i32,
+                column("blkno")?,
+            ));
+        }
+    }
+
+    Ok(group_blocks(&blocks))
+}
+
+fn prewarm(target: &Target) -> Result<i64> {
+    let mut client = Client::connect(target.connstr.as_str(), NoTls)?;
+
+    // Leave at least half of shared buffers to what the workload reads by itself.
+    let row = client.query_one(
+        "SELECT setting::bigint / 2 FROM pg_catalog.pg_settings WHERE name = 'shared_buffers'",
+        &[],
+    )?;
+    let limit: i64 = row.get(0);
+
+    let ranges = fetch_hot_blocks(target, limit)
+        .with_context(|| "failed to get hot blocks from pageserver")?;
+    info!("prewarming {} ranges of hot blocks", ranges.len());
+
+    let mut blocks_read = 0;
+    for range in ranges {
+        let result = client.query_one(
+            "SELECT prewarm_buffers($1, $2, $3, $4, $5, $6)",
+            &[
+                &range.spcnode,
+                &range.dbnode,
+                &range.relnode,
+                &range.forknum,
+                &(range.first_block as i64),
+                &(range.nblocks as i64),
+            ],
+        );
+        match result {
+            Ok(row) => {
+                let n: i64 = row.get(0);
+                blocks_read += n;
+                PREWARMED_BUFFERS.add(n);
+            }
+            // Postgres has probably gone, there's no point in going on.
+            Err(e) if client.is_closed() => return Err(e.into()),
+            Err(e) => warn!("could not prewarm {:?}: {}", range, e),
+        }
+    }
+
+    Ok(blocks_read)
+}
+
+/// Prewarm the buffer cache in a background thread.
+pub fn launch_prewarm(compute: &ComputeNode) -> Result<thread::JoinHandle<()>> {
+    let target = Target {
+        connstr: compute.connstr.clone(),
+        pageserver_connstr: compute.pageserver_connstr.clone(),
+        tenant: compute.tenant.clone(),
+        timeline: compute.timeline.clone(),
+    };
+
+    Ok(thread::Builder::new()
+        .name("buffer-prewarm".into())
+        .spawn(move || match prewarm(&target) {
+            Ok(blocks) => info!("prewarmed {} blocks", blocks),
+            Err(e) => warn!("could not prewarm buffers: {:?}", e),
+        })?)
+}
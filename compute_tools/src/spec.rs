@@ -1,13 +1,13 @@
+use std::fmt::Write;
 use std::path::Path;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use log::{info, log_enabled, warn, Level};
 use postgres::{Client, NoTls};
 use serde::Deserialize;
+use url::Url;
 
-use crate::compute::ComputeNode;
 use crate::config;
-use crate::params::PG_HBA_ALL_MD5;
 use crate::pg_helpers::*;
 
 /// Cluster spec or configuration represented as an optional number of
@@ -20,6 +20,184 @@ pub struct ComputeSpec {
     /// Expected cluster state at the end of transition process.
     pub cluster: Cluster,
     pub delta_operations: Option<Vec<DeltaOp>>,
+    /// Suspend compute once it has been idle for a while, see `monitor`.
+    pub auto_suspend: Option<AutoSuspend>,
+    /// Cache pages read from the pageserver on the local disk, see `file_cache`.
+    pub local_file_cache: Option<LocalFileCache>,
+    /// Read the blocks of the timeline the pageserver has seen hot recently
+    /// into shared buffers after start, see `prewarm`.
+    pub prewarm_buffers: Option<bool>,
+    /// Client authentication rules, written into `pg_hba.conf` after the
+    /// trusted local ones. Without them, password auth is allowed from anywhere.
+    pub pg_hba: Option<Vec<HbaRule>>,
+    pub ssl: Option<SslConfig>,
+    pub password_encryption: Option<PasswordEncryption>,
+}
+
+/// When and how to suspend an idle compute.
+#[derive(Clone, Deserialize)]
+pub struct AutoSuspend {
+    /// Compute is considered idle after this many seconds without activity.
+    pub idle_timeout_seconds: u64,
+    /// If set, the control plane is notified at this URL when compute becomes
+    /// idle, and it decides what to do. Otherwise compute_ctl shuts Postgres
+    /// down by itself.
+    pub notify_url: Option<String>,
+}
+
+/// Where and how big the local file cache is. Both only change on restart.
+#[derive(Clone, Deserialize)]
+pub struct LocalFileCache {
+    /// Directory on the node's local disk, which outlives the compute.
+    pub path: String,
+    pub size_mb: u32,
+}
+
+/// Type of connections a `pg_hba.conf` rule applies to.
+#[derive(Clone, Copy, Deserialize, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum HbaConnectionType {
+    Local,
+    Host,
+    Hostssl,
+    Hostnossl,
+}
+
+impl HbaConnectionType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HbaConnectionType::Local => "local",
+            HbaConnectionType::Host => "host",
+            HbaConnectionType::Hostssl => "hostssl",
+            HbaConnectionType::Hostnossl => "hostnossl",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Deserialize, PartialEq, Eq, Debug)]
+pub enum HbaAuthMethod {
+    #[serde(rename = "trust")]
+    Trust,
+    #[serde(rename = "reject")]
+    Reject,
+    #[serde(rename = "md5")]
+    Md5,
+    #[serde(rename = "scram-sha-256")]
+    ScramSha256,
+    #[serde(rename = "cert")]
+    Cert,
+}
+
+impl HbaAuthMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HbaAuthMethod::Trust => "trust",
+            HbaAuthMethod::Reject => "reject",
+            HbaAuthMethod::Md5 => "md5",
+            HbaAuthMethod::ScramSha256 => "scram-sha-256",
+            HbaAuthMethod::Cert => "cert",
+        }
+    }
+}
+
+/// Single `pg_hba.conf` rule, e.g. `{"type": "hostssl", "database": "all",
+/// "user": "all", "address": "0.0.0.0/0", "method": "scram-sha-256"}`.
+#[derive(Clone, Deserialize, Debug)]
+pub struct HbaRule {
+    #[serde(rename = "type")]
+    pub conn_type: HbaConnectionType,
+    /// Database name or a keyword like `all`, as in `pg_hba.conf`.
+    pub database: String,
+    /// Role name or a keyword like `all`, as in `pg_hba.conf`.
+    pub user: String,
+    /// Client address range in CIDR notation or a keyword like `samenet`,
+    /// required for all types but `local`.
+    pub address: Option<String>,
+    pub method: HbaAuthMethod,
+}
+
+impl HbaRule {
+    /// The rule used when the spec doesn't have any.
+    pub fn default_rules() -> Vec<HbaRule> {
+        vec![HbaRule {
+            conn_type: HbaConnectionType::Host,
+            database: "all".to_string(),
+            user: "all".to_string(),
+            address: Some("0.0.0.0/0".to_string()),
+            method: HbaAuthMethod::Md5,
+        }]
+    }
+
+    fn validate(&self) -> Result<()> {
+        // Fields are written into the file as they are, so they must stay
+        // single tokens. Quoted names are not supported.
+        let is_token = |s: &str| {
+            !s.is_empty() && !s.contains(|c: char| c.is_whitespace() || "\"#,".contains(c))
+        };
+        for (field, value) in [("database", &self.database), ("user", &self.user)] {
+            if !is_token(value) {
+                bail!("invalid {} '{}' in pg_hba rule", field, value);
+            }
+        }
+        match (&self.address, self.conn_type) {
+            (Some(address), HbaConnectionType::Local) => {
+                bail!("address '{}' in a local pg_hba rule", address)
+            }
+            (None, HbaConnectionType::Local) => {}
+            (Some(address), _) if is_token(address) => {}
+            (address, conn_type) => bail!(
+                "invalid address {:?} in a {} pg_hba rule",
+                address,
+                conn_type.as_str()
+            ),
+        }
+        Ok(())
+    }
+}
+
+/// TLS certificate and key of the compute, either as PEM material which is
+/// written into the data directory, or as paths to files present on the node.
+#[derive(Clone, Deserialize)]
+pub struct SslConfig {
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    pub cert_path: Option<String>,
+    pub key_path: Option<String>,
+}
+
+impl SslConfig {
+    fn validate(&self) -> Result<()> {
+        for (name, material, path) in [
+            ("certificate", &self.cert, &self.cert_path),
+            ("key", &self.key, &self.key_path),
+        ] {
+            match (material, path) {
+                (Some(material), None) if material.contains("-----BEGIN ") => {}
+                (Some(_), None) => bail!("SSL {} is not in PEM format", name),
+                (None, Some(path)) if Path::new(path).is_absolute() => {}
+                (None, Some(path)) => bail!("SSL {} path '{}' is not absolute", name, path),
+                _ => bail!("either SSL {} or a path to it should be set", name),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Deserialize, PartialEq, Eq, Debug)]
+pub enum PasswordEncryption {
+    #[serde(rename = "md5")]
+    Md5,
+    #[serde(rename = "scram-sha-256")]
+    ScramSha256,
+}
+
+impl PasswordEncryption {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PasswordEncryption::Md5 => "md5",
+            PasswordEncryption::ScramSha256 => "scram-sha-256",
+        }
+    }
 }
 
 /// Cluster state seen from the perspective of the external tools
@@ -34,42 +212,350 @@ pub struct Cluster {
     pub settings: GenericOptions,
 }
 
+/// The newest spec format version compute_ctl understands.
+pub const MAX_FORMAT_VERSION: f32 = 1.1;
+
+/// Longer idle timeouts are surely a mistake, and the timeout has to fit
+/// into `chrono::Duration` anyway.
+pub const MAX_IDLE_TIMEOUT_SECONDS: u64 = 365 * 24 * 60 * 60;
+
 /// Single cluster state changing operation that could not be represented as
-/// a static `Cluster` structure. For example:
-/// - DROP DATABASE
-/// - DROP ROLE
-/// - ALTER ROLE name RENAME TO new_name
-/// - ALTER DATABASE name RENAME TO new_name
+/// a static `Cluster` structure. The kind of operation is set by the `action`
+/// field, e.g. `{"action": "rename_db", "name": "db", "new_name": "db2"}`.
 #[derive(Clone, Deserialize)]
-pub struct DeltaOp {
-    pub action: String,
-    pub name: PgIdent,
-    pub new_name: Option<PgIdent>,
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum DeltaOp {
+    /// DROP ROLE, after reassigning its objects to the database owners.
+    DeleteRole { name: PgIdent },
+    /// ALTER ROLE name RENAME TO new_name
+    RenameRole { name: PgIdent, new_name: PgIdent },
+    /// DROP DATABASE
+    DeleteDb { name: PgIdent },
+    /// ALTER DATABASE name RENAME TO new_name
+    RenameDb { name: PgIdent, new_name: PgIdent },
+    /// DROP EXTENSION in `database`, or in all databases if it's not set.
+    DeleteExtension {
+        name: PgIdent,
+        database: Option<PgIdent>,
+    },
+
+    // The following operations are available since format version 1.1.
+    /// ALTER ROLE role SET setting = value, or RESET setting if there's no value.
+    SetRoleSetting {
+        role: PgIdent,
+        setting: String,
+        value: Option<String>,
+    },
+    /// ALTER DATABASE name OWNER TO owner
+    SetDbOwner { name: PgIdent, owner: PgIdent },
+    /// GRANT role TO member
+    GrantRole { role: PgIdent, member: PgIdent },
+    /// REVOKE role FROM member
+    RevokeRole { role: PgIdent, member: PgIdent },
+    /// GRANT privileges ON SCHEMA schema TO role, in `database`.
+    GrantSchema {
+        database: PgIdent,
+        schema: PgIdent,
+        privileges: Vec<SchemaPrivilege>,
+        role: PgIdent,
+    },
+    /// REVOKE privileges ON SCHEMA schema FROM role, in `database`.
+    RevokeSchema {
+        database: PgIdent,
+        schema: PgIdent,
+        privileges: Vec<SchemaPrivilege>,
+        role: PgIdent,
+    },
+}
+
+#[derive(Clone, Copy, Deserialize, PartialEq, Eq, Debug)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum SchemaPrivilege {
+    Usage,
+    Create,
+}
+
+impl SchemaPrivilege {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SchemaPrivilege::Usage => "USAGE",
+            SchemaPrivilege::Create => "CREATE",
+        }
+    }
+}
+
+impl DeltaOp {
+    // Format version the operation was introduced in.
+    fn format_version(&self) -> f32 {
+        match self {
+            DeltaOp::DeleteRole { .. }
+            | DeltaOp::RenameRole { .. }
+            | DeltaOp::DeleteDb { .. }
+            | DeltaOp::RenameDb { .. }
+            | DeltaOp::DeleteExtension { .. } => 1.0,
+            _ => 1.1,
+        }
+    }
+}
+
+impl ComputeSpec {
+    /// Check that the spec is consistent, so that a bad one fails before any SQL runs.
+    pub fn validate(&self) -> Result<()> {
+        if !(1.0..=MAX_FORMAT_VERSION).contains(&self.format_version) {
+            bail!(
+                "unsupported spec format version {}, expected 1.0 to {}",
+                self.format_version,
+                MAX_FORMAT_VERSION
+            );
+        }
+
+        for rule in self.pg_hba.iter().flatten() {
+            rule.validate()?;
+        }
+        if let Some(ssl) = &self.ssl {
+            ssl.validate()?;
+        }
+        let needs_ssl = self.pg_hba.iter().flatten().any(|rule| {
+            rule.conn_type == HbaConnectionType::Hostssl || rule.method == HbaAuthMethod::Cert
+        });
+        if needs_ssl && self.ssl.is_none() {
+            bail!("pg_hba rules require SSL, but there's no ssl section in the spec");
+        }
+
+        if let Some(auto_suspend) = &self.auto_suspend {
+            if auto_suspend.idle_timeout_seconds > MAX_IDLE_TIMEOUT_SECONDS {
+                bail!(
+                    "idle timeout {} is too long, the maximum is {} seconds",
+                    auto_suspend.idle_timeout_seconds,
+                    MAX_IDLE_TIMEOUT_SECONDS
+                );
+            }
+        }
+
+        if let Some(cache) = &self.local_file_cache {
+            if !Path::new(&cache.path).is_absolute() {
+                bail!("file cache path '{}' is not absolute", cache.path);
+            }
+            if cache.size_mb == 0 {
+                bail!("file cache size should not be zero");
+            }
+        }
+
+        let ops = self.delta_operations.as_deref().unwrap_or_default();
+        let deleted_roles: Vec<&PgIdent> = ops
+            .iter()
+            .filter_map(|op| match op {
+                DeltaOp::DeleteRole { name } => Some(name),
+                _ => None,
+            })
+            .collect();
+        let check_role = |role: &PgIdent| -> Result<()> {
+            if role.is_empty() {
+                bail!("empty role name in delta operations");
+            }
+            if deleted_roles.contains(&role) {
+                bail!("role '{}' is used in delta operations, but deleted", role);
+            }
+            Ok(())
+        };
+
+        for name in &deleted_roles {
+            if self.cluster.roles.iter().any(|r| r.name == **name) {
+                bail!(
+                    "role '{}' is deleted, but present in the cluster roles",
+                    name
+                );
+            }
+        }
+
+        for op in ops {
+            if op.format_version() > self.format_version {
+                bail!(
+                    "delta operation is only supported since format version {}, spec has {}",
+                    op.format_version(),
+                    self.format_version
+                );
+            }
+
+            match op {
+                DeltaOp::DeleteRole { name }
+                | DeltaOp::DeleteDb { name }
+                | DeltaOp::DeleteExtension { name, .. } => {
+                    if name.is_empty() {
+                        bail!("empty name in delta operations");
+                    }
+                }
+                DeltaOp::RenameRole { name, new_name } | DeltaOp::RenameDb { name, new_name } => {
+                    if name.is_empty() || new_name.is_empty() {
+                        bail!("empty name in delta operations");
+                    }
+                    if name == new_name {
+                        bail!("cannot rename '{}' to itself", name);
+                    }
+                }
+                DeltaOp::SetRoleSetting { role, setting, .. } => {
+                    check_role(role)?;
+                    if !is_setting_name(setting) {
+                        bail!("invalid setting name '{}'", setting);
+                    }
+                }
+                DeltaOp::SetDbOwner { name, owner } => {
+                    check_role(owner)?;
+                    let db = self.cluster.databases.iter().find(|db| db.name == *name);
+                    if let Some(db) = db {
+                        if db.owner != *owner {
+                            bail!(
+                                "database '{}' is owned by '{}' in the cluster databases, cannot change it to '{}'",
+                                name,
+                                db.owner,
+                                owner
+                            );
+                        }
+                    }
+                }
+                DeltaOp::GrantRole { role, member } | DeltaOp::RevokeRole { role, member } => {
+                    check_role(role)?;
+                    check_role(member)?;
+                    if role == member {
+                        bail!("cannot grant role '{}' to itself", role);
+                    }
+                }
+                DeltaOp::GrantSchema {
+                    database,
+                    schema,
+                    privileges,
+                    role,
+                }
+                | DeltaOp::RevokeSchema {
+                    database,
+                    schema,
+                    privileges,
+                    role,
+                } => {
+                    check_role(role)?;
+                    if schema.is_empty() || privileges.is_empty() {
+                        bail!("schema and privileges should be set for schema operations");
+                    }
+                    if !self.cluster.databases.iter().any(|db| db.name == *database) {
+                        bail!(
+                            "schema operation refers to '{}', which is not in the cluster databases",
+                            database
+                        );
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Libraries required by the extensions of all databases, which should
+    /// be added to `shared_preload_libraries`.
+    pub fn preload_libraries(&self) -> Vec<&'static str> {
+        let mut libraries = Vec::new();
+        for db in &self.cluster.databases {
+            for ext in db.extensions.iter().flatten() {
+                if let Some(library) = ext.preload_library() {
+                    if !libraries.contains(&library) {
+                        libraries.push(library);
+                    }
+                }
+            }
+        }
+
+        libraries
+    }
+}
+
+// Postgres setting names are identifiers, optionally prefixed with an extension
+// name, e.g. `work_mem` or `neon.max_cluster_size`.
+fn is_setting_name(name: &str) -> bool {
+    let is_ident = |part: &str| {
+        let mut chars = part.chars();
+        matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+    };
+
+    match name.split_once('.') {
+        Some((prefix, name)) => is_ident(prefix) && is_ident(name),
+        None => is_ident(name),
+    }
 }
 
 /// It takes cluster specification and does the following:
 /// - Serialize cluster config and put it into `postgresql.conf` completely rewriting the file.
-/// - Update `pg_hba.conf` to allow external connections.
+/// - Write `pg_hba.conf` and SSL files to allow external connections.
 pub fn handle_configuration(spec: &ComputeSpec, pgdata_path: &Path) -> Result<()> {
     // File `postgresql.conf` is no longer included into `basebackup`, so just
     // always write all config into it creating new file.
     config::write_postgres_conf(&pgdata_path.join("postgresql.conf"), spec)?;
 
-    update_pg_hba(pgdata_path)?;
+    update_pg_hba(pgdata_path, spec)?;
+
+    Ok(())
+}
+
+/// Rewrite `pg_hba.conf` with the rules of the spec, and write the SSL
+/// certificate and key if the spec has them.
+pub fn update_pg_hba(pgdata_path: &Path, spec: &ComputeSpec) -> Result<()> {
+    let default_rules = HbaRule::default_rules();
+    let rules = spec.pg_hba.as_deref().unwrap_or(&default_rules);
+    config::write_pg_hba(&pgdata_path.join("pg_hba.conf"), rules)?;
+    info!("wrote {} rules into pg_hba.conf", rules.len());
+
+    if let Some(ssl) = &spec.ssl {
+        config::write_ssl_files(pgdata_path, ssl)?;
+    }
+
+    Ok(())
+}
+
+/// Check that `pg_hba.conf` on disk has no lines Postgres can't parse. Such
+/// a file either stops Postgres from starting or is ignored on reload.
+pub fn verify_pg_hba(client: &mut Client) -> Result<()> {
+    let errors: Vec<String> = client
+        .query(
+            "SELECT line_number, error FROM pg_catalog.pg_hba_file_rules WHERE error IS NOT NULL",
+            &[],
+        )?
+        .iter()
+        .map(|row| {
+            let line: Option<i32> = row.get("line_number");
+            let error: String = row.get("error");
+            format!("line {}: {}", line.unwrap_or_default(), error)
+        })
+        .collect();
+    if !errors.is_empty() {
+        bail!("Postgres rejected pg_hba.conf: {}", errors.join("; "));
+    }
 
     Ok(())
 }
 
-/// Check `pg_hba.conf` and update if needed to allow external connections.
-pub fn update_pg_hba(pgdata_path: &Path) -> Result<()> {
-    // XXX: consider making it a part of spec.json
-    info!("checking pg_hba.conf");
-    let pghba_path = pgdata_path.join("pg_hba.conf");
+/// Check that Postgres has accepted `pg_hba.conf` and the auth settings of
+/// the spec, so that a compute clients can't connect to isn't reported as running.
+pub fn verify_client_auth(spec: &ComputeSpec, client: &mut Client) -> Result<()> {
+    verify_pg_hba(client)?;
 
-    if config::line_in_file(&pghba_path, PG_HBA_ALL_MD5)? {
-        info!("updated pg_hba.conf to allow external connections");
-    } else {
-        info!("pg_hba.conf is up-to-date");
+    let mut expected = vec![];
+    if spec.ssl.is_some() {
+        expected.push(("ssl", "on"));
+    }
+    if let Some(encryption) = spec.password_encryption {
+        expected.push(("password_encryption", encryption.as_str()));
+    }
+    for (name, value) in expected {
+        let current: String = client
+            .query_one("SELECT current_setting($1)", &[&name])?
+            .get(0);
+        if current != value {
+            bail!(
+                "{} is '{}' in Postgres instead of '{}'",
+                name,
+                current,
+                value
+            );
+        }
     }
 
     Ok(())
@@ -100,30 +586,21 @@ pub fn handle_roles(spec: &ComputeSpec, client: &mut Client) -> Result<()> {
     if let Some(ops) = &spec.delta_operations {
         info!("processing role renames");
         for op in ops {
-            match op.action.as_ref() {
-                "delete_role" => {
-                    // no-op now, roles will be deleted at the end of configuration
-                }
-                // Renaming role drops its password, since role name is
-                // used as a salt there.  It is important that this role
-                // is recorded with a new `name` in the `roles` list.
-                // Follow up roles update will set the new password.
-                "rename_role" => {
-                    let new_name = op.new_name.as_ref().unwrap();
-
-                    // XXX: with a limited number of roles it is fine, but consider making it a HashMap
-                    if existing_roles.iter().any(|r| r.name == op.name) {
-                        let query: String = format!(
-                            "ALTER ROLE {} RENAME TO {}",
-                            op.name.quote(),
-                            new_name.quote()
-                        );
-
-                        warn!("renaming role '{}' to '{}'", op.name, new_name);
-                        xact.execute(query.as_str(), &[])?;
-                    }
+            // Deleted roles are processed at the end of configuration.
+            //
+            // Renaming role drops its password, since role name is
+            // used as a salt there.  It is important that this role
+            // is recorded with a new `name` in the `roles` list.
+            // Follow up roles update will set the new password.
+            if let DeltaOp::RenameRole { name, new_name } = op {
+                // XXX: with a limited number of roles it is fine, but consider making it a HashMap
+                if existing_roles.iter().any(|r| r.name == *name) {
+                    let query: String =
+                        format!("ALTER ROLE {} RENAME TO {}", name.quote(), new_name.quote());
+
+                    warn!("renaming role '{}' to '{}'", name, new_name);
+                    xact.execute(query.as_str(), &[])?;
                 }
-                _ => {}
             }
         }
     }
@@ -194,43 +671,49 @@ pub fn handle_roles(spec: &ComputeSpec, client: &mut Client) -> Result<()> {
 }
 
 /// Reassign all dependent objects and delete requested roles.
-pub fn handle_role_deletions(node: &ComputeNode, client: &mut Client) -> Result<()> {
-    let spec = &node.spec;
-
+pub fn handle_role_deletions(spec: &ComputeSpec, connstr: &Url, client: &mut Client) -> Result<()> {
     // First, reassign all dependent objects to db owners.
     if let Some(ops) = &spec.delta_operations {
         info!("reassigning dependent objects of to-be-deleted roles");
         for op in ops {
-            if op.action == "delete_role" {
-                reassign_owned_objects(node, &op.name)?;
+            if let DeltaOp::DeleteRole { name } = op {
+                reassign_owned_objects(spec, connstr, name)?;
             }
         }
     }
 
-    // Second, proceed with role deletions.
+    // Second, proceed with role deletions. They are committed together,
+    // so that either all of them take effect or none.
     let mut xact = client.transaction()?;
-    if let Some(ops) = &spec.delta_operations {
-        info!("processing role deletions");
-        for op in ops {
-            // We do not check either role exists or not,
-            // Postgres will take care of it for us
-            if op.action == "delete_role" {
-                let query: String = format!("DROP ROLE IF EXISTS {}", &op.name.quote());
-
-                warn!("deleting role '{}'", &op.name);
-                xact.execute(query.as_str(), &[])?;
-            }
-        }
+    info!("processing role deletions");
+    for query in role_deletion_queries(spec) {
+        warn!("deleting role: {}", query);
+        xact.execute(query.as_str(), &[])?;
     }
+    xact.commit()?;
 
     Ok(())
 }
 
+/// Statements dropping the roles of `delete_role` operations, in order.
+pub fn role_deletion_queries(spec: &ComputeSpec) -> Vec<String> {
+    // We do not check either role exists or not,
+    // Postgres will take care of it for us
+    spec.delta_operations
+        .iter()
+        .flatten()
+        .filter_map(|op| match op {
+            DeltaOp::DeleteRole { name } => Some(format!("DROP ROLE IF EXISTS {}", name.quote())),
+            _ => None,
+        })
+        .collect()
+}
+
 // Reassign all owned objects in all databases to the owner of the database.
-fn reassign_owned_objects(node: &ComputeNode, role_name: &PgIdent) -> Result<()> {
-    for db in &node.spec.cluster.databases {
+fn reassign_owned_objects(spec: &ComputeSpec, connstr: &Url, role_name: &PgIdent) -> Result<()> {
+    for db in &spec.cluster.databases {
         if db.owner != *role_name {
-            let mut connstr = node.connstr.clone();
+            let mut connstr = connstr.clone();
             // database name is always the last and the only component of the path
             connstr.set_path(&db.name);
 
@@ -275,29 +758,27 @@ pub fn handle_databases(spec: &ComputeSpec, client: &mut Client) -> Result<()> {
     if let Some(ops) = &spec.delta_operations {
         info!("processing delta operations on databases");
         for op in ops {
-            match op.action.as_ref() {
+            match op {
                 // We do not check either DB exists or not,
                 // Postgres will take care of it for us
-                "delete_db" => {
-                    let query: String = format!("DROP DATABASE IF EXISTS {}", &op.name.quote());
+                DeltaOp::DeleteDb { name } => {
+                    let query: String = format!("DROP DATABASE IF EXISTS {}", name.quote());
 
-                    warn!("deleting database '{}'", &op.name);
+                    warn!("deleting database '{}'", name);
                     client.execute(query.as_str(), &[])?;
                 }
-                "rename_db" => {
-                    let new_name = op.new_name.as_ref().unwrap();
-
-                    // XXX: with a limited number of roles it is fine, but consider making it a HashMap
-                    if existing_dbs.iter().any(|r| r.name == op.name) {
-                        let query: String = format!(
-                            "ALTER DATABASE {} RENAME TO {}",
-                            op.name.quote(),
-                            new_name.quote()
-                        );
-
-                        warn!("renaming database '{}' to '{}'", op.name, new_name);
-                        client.execute(query.as_str(), &[])?;
-                    }
+                // XXX: with a limited number of roles it is fine, but consider making it a HashMap
+                DeltaOp::RenameDb { name, new_name }
+                    if existing_dbs.iter().any(|r| r.name == *name) =>
+                {
+                    let query: String = format!(
+                        "ALTER DATABASE {} RENAME TO {}",
+                        name.quote(),
+                        new_name.quote()
+                    );
+
+                    warn!("renaming database '{}' to '{}'", name, new_name);
+                    client.execute(query.as_str(), &[])?;
                 }
                 _ => {}
             }
@@ -349,11 +830,107 @@ pub fn handle_databases(spec: &ComputeSpec, client: &mut Client) -> Result<()> {
     Ok(())
 }
 
+/// Apply delta operations which change settings of roles, role memberships,
+/// database owners and schema privileges. All of them are idempotent.
+pub fn handle_privileges(spec: &ComputeSpec, connstr: &Url, client: &mut Client) -> Result<()> {
+    let ops = match &spec.delta_operations {
+        Some(ops) => ops,
+        None => return Ok(()),
+    };
+
+    info!("processing delta operations on privileges");
+    let mut db_connstr = connstr.clone();
+    for op in ops {
+        let query = match op {
+            DeltaOp::SetRoleSetting {
+                role,
+                setting,
+                value: Some(value),
+            } => format!(
+                "ALTER ROLE {} SET {} = {}",
+                role.quote(),
+                setting,
+                escape_literal(value)
+            ),
+            DeltaOp::SetRoleSetting {
+                role,
+                setting,
+                value: None,
+            } => format!("ALTER ROLE {} RESET {}", role.quote(), setting),
+            DeltaOp::SetDbOwner { name, owner } => {
+                format!("ALTER DATABASE {} OWNER TO {}", name.quote(), owner.quote())
+            }
+            DeltaOp::GrantRole { role, member } => {
+                format!("GRANT {} TO {}", role.quote(), member.quote())
+            }
+            DeltaOp::RevokeRole { role, member } => {
+                format!("REVOKE {} FROM {}", role.quote(), member.quote())
+            }
+            DeltaOp::GrantSchema {
+                database,
+                schema,
+                privileges,
+                role,
+            }
+            | DeltaOp::RevokeSchema {
+                database,
+                schema,
+                privileges,
+                role,
+            } => {
+                let privileges = privileges
+                    .iter()
+                    .map(|p| p.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                let query = if let DeltaOp::GrantSchema { .. } = op {
+                    format!(
+                        "GRANT {} ON SCHEMA {} TO {}",
+                        privileges,
+                        schema.quote(),
+                        role.quote()
+                    )
+                } else {
+                    format!(
+                        "REVOKE {} ON SCHEMA {} FROM {}",
+                        privileges,
+                        schema.quote(),
+                        role.quote()
+                    )
+                };
+
+                // database name is always the last and the only component of the path
+                db_connstr.set_path(database);
+                let mut db_client = Client::connect(db_connstr.as_str(), NoTls)?;
+
+                // Schemas are not managed by the spec, so they may be gone by now.
+                let exists = db_client.query_opt(
+                    "SELECT 1 FROM pg_catalog.pg_namespace WHERE nspname = $1",
+                    &[schema],
+                )?;
+                if exists.is_none() {
+                    warn!("schema '{}' doesn't exist in db '{}'", schema, database);
+                    continue;
+                }
+
+                info!("schema privileges query in db '{}': {}", database, &query);
+                db_client.execute(query.as_str(), &[])?;
+                continue;
+            }
+            // The rest are handled along with roles and databases.
+            _ => continue,
+        };
+
+        info!("privileges query: {}", &query);
+        client.execute(query.as_str(), &[])?;
+    }
+
+    Ok(())
+}
+
 /// Grant CREATE ON DATABASE to the database owner and do some other alters and grants
 /// to allow users creating trusted extensions and re-creating `public` schema, for example.
-pub fn handle_grants(node: &ComputeNode, client: &mut Client) -> Result<()> {
-    let spec = &node.spec;
-
+pub fn handle_grants(spec: &ComputeSpec, connstr: &Url, client: &mut Client) -> Result<()> {
     info!("cluster spec grants:");
 
     // We now have a separate `web_access` role to connect to the database
@@ -385,8 +962,8 @@ pub fn handle_grants(node: &ComputeNode, client: &mut Client) -> Result<()> {
     // Do some per-database access adjustments. We'd better do this at db creation time,
     // but CREATE DATABASE isn't transactional. So we cannot create db + do some grants
     // atomically.
-    let mut db_connstr = node.connstr.clone();
-    for db in &node.spec.cluster.databases {
+    let mut db_connstr = connstr.clone();
+    for db in &spec.cluster.databases {
         // database name is always the last and the only component of the path
         db_connstr.set_path(&db.name);
 
@@ -426,3 +1003,77 @@ pub fn handle_grants(node: &ComputeNode, client: &mut Client) -> Result<()> {
 
     Ok(())
 }
+
+/// Create, update and drop extensions in every database according to the spec.
+/// Extensions which are installed but not mentioned in the spec are left as is,
+/// to drop an extension there should be a `delete_extension` delta operation.
+pub fn handle_extensions(spec: &ComputeSpec, connstr: &Url) -> Result<()> {
+    info!("cluster spec extensions:");
+
+    let mut db_connstr = connstr.clone();
+    for db in &spec.cluster.databases {
+        // database name is always the last and the only component of the path
+        db_connstr.set_path(&db.name);
+
+        let mut db_client = Client::connect(db_connstr.as_str(), NoTls)?;
+
+        if let Some(ops) = &spec.delta_operations {
+            for op in ops {
+                if let DeltaOp::DeleteExtension { name, database } = op {
+                    let in_db = match database {
+                        Some(database) => *database == db.name,
+                        None => true,
+                    };
+                    if in_db {
+                        let query = format!("DROP EXTENSION IF EXISTS {}", name.quote());
+
+                        warn!("deleting extension '{}' in db '{}'", name, db.name);
+                        db_client.execute(query.as_str(), &[])?;
+                    }
+                }
+            }
+        }
+
+        let installed = get_installed_extensions(&mut db_client)?;
+
+        for ext in db.extensions.iter().flatten() {
+            info_print!("{} - {}:{}", " ".repeat(27 + 5), db.name, ext.name);
+
+            // XXX: with a limited number of extensions it is fine, but consider making it a HashMap
+            let query = match installed.iter().find(|e| e.name == ext.name) {
+                Some(e) => {
+                    let wanted = ext.version.as_ref().or(e.default_version.as_ref());
+                    match wanted {
+                        Some(version) if *version != e.version => {
+                            info_print!(" -> update {} to {}", e.version, version);
+                            Some(format!(
+                                "ALTER EXTENSION {} UPDATE TO {}",
+                                ext.name.quote(),
+                                escape_literal(version)
+                            ))
+                        }
+                        _ => None,
+                    }
+                }
+                None => {
+                    info_print!(" -> create");
+                    let mut query = format!("CREATE EXTENSION IF NOT EXISTS {}", ext.name.quote());
+                    if let Some(version) = &ext.version {
+                        write!(query, " VERSION {}", escape_literal(version))
+                            .expect("String is documented to not to error during write operations");
+                    }
+                    query.push_str(" CASCADE");
+                    Some(query)
+                }
+            };
+
+            if let Some(query) = query {
+                db_client.execute(query.as_str(), &[])?;
+            }
+
+            info_print!("\n");
+        }
+    }
+
+    Ok(())
+}
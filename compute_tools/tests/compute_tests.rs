@@ -0,0 +1,49 @@
+#[cfg(test)]
+mod compute_tests {
+
+    use std::fs::File;
+    use std::sync::RwLock;
+
+    use chrono::Utc;
+    use compute_tools::compute::*;
+    use compute_tools::spec::ComputeSpec;
+
+    fn test_compute(pgdata: &str) -> ComputeNode {
+        let file = File::open("tests/cluster_spec.json").unwrap();
+        let spec: ComputeSpec = serde_json::from_reader(file).unwrap();
+
+        ComputeNode {
+            start_time: Utc::now(),
+            connstr: url::Url::parse("postgresql://cloud_admin@localhost/postgres").unwrap(),
+            pgdata: pgdata.to_string(),
+            pgbin: "postgres".to_string(),
+            spec: RwLock::new(spec),
+            tenant: "tenant".to_string(),
+            timeline: "timeline".to_string(),
+            pageserver_connstr: "postgresql://localhost:6400".to_string(),
+            metrics: ComputeMetrics::new(),
+            state: RwLock::new(ComputeState::new()),
+        }
+    }
+
+    #[test]
+    fn failed_shutdown() {
+        // There's no postmaster.pid, so Postgres can't be signalled.
+        let compute = test_compute("tests/tmp/no_such_pgdata");
+        compute.set_status(ComputeStatus::Running);
+
+        assert!(compute.shutdown(ShutdownMode::Immediate, true).is_err());
+
+        let state = compute.state.read().unwrap();
+        assert!(state.status == ComputeStatus::Running);
+        assert!(!state.shutdown_requested);
+        assert!(!state.restart_requested);
+    }
+
+    #[test]
+    fn shutdown_not_running() {
+        let compute = test_compute("tests/tmp/no_such_pgdata");
+        assert!(compute.shutdown(ShutdownMode::Fast, false).is_err());
+        assert!(compute.get_status() == ComputeStatus::Init);
+    }
+}
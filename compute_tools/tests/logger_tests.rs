@@ -0,0 +1,115 @@
+#[cfg(test)]
+mod logger_tests {
+
+    use std::io::{self, BufReader, Read};
+    use std::path::PathBuf;
+
+    use compute_tools::logger::*;
+    use log::{Level, Record};
+    use serde_json::Value;
+
+    #[test]
+    fn parse_log_options() {
+        assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert!("yaml".parse::<LogFormat>().is_err());
+
+        assert_eq!("syslog".parse::<LogSink>().unwrap(), LogSink::Syslog);
+        assert_eq!(
+            "/var/log/compute.log".parse::<LogSink>().unwrap(),
+            LogSink::File(PathBuf::from("/var/log/compute.log"))
+        );
+        assert!("compute.log".parse::<LogSink>().is_err());
+    }
+
+    #[test]
+    fn json_record() {
+        let context = LogContext {
+            tenant_id: Some("tenant".to_string()),
+            timeline_id: Some("timeline".to_string()),
+            operation_uuid: None,
+        };
+
+        let record = Record::builder()
+            .level(Level::Warn)
+            .target(POSTGRES_LOG_TARGET)
+            .args(format_args!("WARNING:  \"quoted\"\nsecond line"))
+            .build();
+        let line = format_json(&record, &context);
+        assert!(!line.contains('\n'));
+
+        let json: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["level"], "WARN");
+        assert_eq!(json["source"], "postgres");
+        assert_eq!(json["message"], "WARNING:  \"quoted\"\nsecond line");
+        assert_eq!(json["tenant_id"], "tenant");
+        assert_eq!(json["timeline_id"], "timeline");
+        assert!(json["operation_uuid"].is_null());
+
+        let record = Record::builder()
+            .level(Level::Info)
+            .target("compute_tools::compute")
+            .args(format_args!("starting compute"))
+            .build();
+        let json: Value = serde_json::from_str(&format_json(&record, &context)).unwrap();
+        assert_eq!(json["source"], "compute_ctl");
+    }
+
+    #[test]
+    fn postgres_levels() {
+        let cases = [
+            ("2022-08-01 10:00:00.000 UTC [42] LOG:  database system is ready to accept connections", Level::Info),
+            ("2022-08-01 10:00:00.000 UTC [42] ERROR:  relation \"foo\" does not exist", Level::Error),
+            ("2022-08-01 10:00:00.000 UTC [42] FATAL:  password authentication failed", Level::Error),
+            ("2022-08-01 10:00:00.000 UTC [42] WARNING:  there is no transaction in progress", Level::Warn),
+            // Only the severity of the line counts, not words in the message.
+            ("2022-08-01 10:00:00.000 UTC [42] LOG:  statement: SELECT 'ERROR: x'", Level::Info),
+            ("\tcontinuation of a statement", Level::Info),
+        ];
+
+        for (line, level) in cases {
+            assert_eq!(postgres_log_level(line), level, "{}", line);
+        }
+    }
+
+    // Returns an error once, after `data` is read up to `fail_at`.
+    struct FlakyReader {
+        data: &'static [u8],
+        pos: usize,
+        fail_at: usize,
+    }
+
+    impl Read for FlakyReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.pos == self.fail_at {
+                self.fail_at = usize::MAX;
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "flaky"));
+            }
+            let end = self.data.len().min(self.fail_at).min(self.pos + buf.len());
+            let n = end - self.pos;
+            buf[..n].copy_from_slice(&self.data[self.pos..end]);
+            self.pos = end;
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn postgres_lines() {
+        let reader = FlakyReader {
+            data: b"LOG:  first\r\nERROR:  \xff invalid\nLOG:  after error\nno newline",
+            pos: 0,
+            fail_at: 36,
+        };
+        let mut lines = vec![];
+        for_each_postgres_line(BufReader::new(reader), |line| lines.push(line.to_string()));
+
+        assert_eq!(
+            lines,
+            vec![
+                "LOG:  first",
+                "ERROR:  \u{fffd} invalid",
+                "LOG:  after error",
+                "no newline"
+            ]
+        );
+    }
+}
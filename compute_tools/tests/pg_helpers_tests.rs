@@ -4,7 +4,7 @@ mod pg_helpers_tests {
     use std::fs::File;
 
     use compute_tools::pg_helpers::*;
-    use compute_tools::spec::ComputeSpec;
+    use compute_tools::spec::{ComputeSpec, HbaAuthMethod, HbaConnectionType, PasswordEncryption};
 
     #[test]
     fn params_serialize() {
@@ -32,6 +32,73 @@ mod pg_helpers_tests {
         );
     }
 
+    #[test]
+    fn extensions() {
+        let file = File::open("tests/cluster_spec.json").unwrap();
+        let spec: ComputeSpec = serde_json::from_reader(file).unwrap();
+
+        let db = &spec.cluster.databases[1];
+        let extensions = db.extensions.as_ref().unwrap();
+        assert_eq!(extensions[0].name, "pg_stat_statements");
+        assert_eq!(extensions[0].version.as_deref(), Some("1.9"));
+        assert_eq!(extensions[1].name, "vector");
+        assert_eq!(extensions[1].version, None);
+
+        assert_eq!(extensions[0].preload_library(), Some("pg_stat_statements"));
+        assert_eq!(extensions[1].preload_library(), None);
+        assert_eq!(spec.preload_libraries(), vec!["pg_stat_statements"]);
+    }
+
+    #[test]
+    fn auto_suspend() {
+        let file = File::open("tests/cluster_spec.json").unwrap();
+        let spec: ComputeSpec = serde_json::from_reader(file).unwrap();
+
+        let auto_suspend = spec.auto_suspend.unwrap();
+        assert_eq!(auto_suspend.idle_timeout_seconds, 300);
+        assert_eq!(
+            auto_suspend.notify_url.as_deref(),
+            Some("http://localhost:3000/v1/idle")
+        );
+    }
+
+    #[test]
+    fn local_file_cache() {
+        let file = File::open("tests/cluster_spec.json").unwrap();
+        let spec: ComputeSpec = serde_json::from_reader(file).unwrap();
+
+        let cache = spec.local_file_cache.unwrap();
+        assert_eq!(cache.path, "/var/cache/neon");
+        assert_eq!(cache.size_mb, 1024);
+        assert_eq!(spec.prewarm_buffers, Some(true));
+    }
+
+    #[test]
+    fn client_auth() {
+        let file = File::open("tests/cluster_spec.json").unwrap();
+        let spec: ComputeSpec = serde_json::from_reader(file).unwrap();
+
+        let rules = spec.pg_hba.unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].conn_type, HbaConnectionType::Hostssl);
+        assert_eq!(rules[0].method, HbaAuthMethod::ScramSha256);
+        assert_eq!(rules[1].method, HbaAuthMethod::Reject);
+        assert_eq!(
+            spec.ssl.unwrap().key_path.as_deref(),
+            Some("/etc/neon/tls/server.key")
+        );
+        assert_eq!(
+            spec.password_encryption,
+            Some(PasswordEncryption::ScramSha256)
+        );
+    }
+
+    #[test]
+    fn quote_literal() {
+        assert_eq!(escape_literal("1.0"), "'1.0'");
+        assert_eq!(escape_literal("it's"), "'it''s'");
+    }
+
     #[test]
     fn quote_ident() {
         let ident: PgIdent = PgIdent::from("\"name\";\\n select 1;");
@@ -0,0 +1,36 @@
+#[cfg(test)]
+mod prewarm_tests {
+
+    use compute_tools::prewarm::*;
+
+    #[test]
+    fn group_hot_blocks() {
+        let range = |relnode, forknum, first_block, nblocks| BlockRange {
+            spcnode: 1663,
+            dbnode: 13010,
+            relnode,
+            forknum,
+            first_block,
+            nblocks,
+        };
+
+        let blocks = [
+            (1663, 13010, 16384, 0, 0),
+            (1663, 13010, 16384, 0, 1),
+            (1663, 13010, 16384, 0, 2),
+            (1663, 13010, 16384, 0, 7),
+            (1663, 13010, 16384, 1, 8),
+            (1663, 13010, 16390, 0, 9),
+        ];
+        assert_eq!(
+            group_blocks(&blocks),
+            vec![
+                range(16384, 0, 0, 3),
+                range(16384, 0, 7, 1),
+                range(16384, 1, 8, 1),
+                range(16390, 0, 9, 1),
+            ]
+        );
+        assert!(group_blocks(&[]).is_empty());
+    }
+}
@@ -0,0 +1,237 @@
+#[cfg(test)]
+mod spec_tests {
+
+    use std::fs::File;
+
+    use compute_tools::spec::*;
+    use serde_json::{json, Value};
+
+    fn test_spec() -> Value {
+        let file = File::open("tests/cluster_spec.json").unwrap();
+        serde_json::from_reader(file).unwrap()
+    }
+
+    fn with_ops(format_version: f32, ops: Value) -> Result<ComputeSpec, String> {
+        let mut spec = test_spec();
+        spec["format_version"] = json!(format_version);
+        spec["delta_operations"] = ops;
+
+        let spec: ComputeSpec = serde_json::from_value(spec).map_err(|e| e.to_string())?;
+        spec.validate().map_err(|e| e.to_string())?;
+        Ok(spec)
+    }
+
+    #[test]
+    fn parse_delta_ops() {
+        let file = File::open("tests/cluster_spec.json").unwrap();
+        let spec: ComputeSpec = serde_json::from_reader(file).unwrap();
+        spec.validate().unwrap();
+
+        let ops = spec.delta_operations.unwrap();
+        assert!(matches!(&ops[0], DeltaOp::DeleteDb { name } if name == "zenith_test"));
+        assert!(matches!(
+            &ops[1],
+            DeltaOp::RenameDb { name, new_name } if name == "DB" && new_name == "DB2"
+        ));
+        assert!(matches!(
+            &ops[4],
+            DeltaOp::DeleteExtension { name, database: Some(db) } if name == "pg_cron" && db == "zenith"
+        ));
+
+        let spec = with_ops(
+            1.1,
+            json!([
+                {"action": "set_role_setting", "role": "alexk", "setting": "work_mem", "value": "64MB"},
+                {"action": "set_role_setting", "role": "alexk", "setting": "neon.foo"},
+                {"action": "set_db_owner", "name": "other_db", "owner": "alexk"},
+                {"action": "grant_role", "role": "pg_monitor", "member": "alexk"},
+                {"action": "revoke_schema", "database": "zen", "schema": "public", "privileges": ["CREATE"], "role": "alexk"},
+            ]),
+        )
+        .unwrap();
+        let ops = spec.delta_operations.unwrap();
+        assert!(matches!(
+            &ops[1],
+            DeltaOp::SetRoleSetting { value: None, .. }
+        ));
+        assert!(matches!(
+            &ops[4],
+            DeltaOp::RevokeSchema { privileges, .. } if privileges == &[SchemaPrivilege::Create]
+        ));
+    }
+
+    #[test]
+    fn role_deletions() {
+        let spec = with_ops(
+            1.1,
+            json!([
+                {"action": "delete_role", "name": "old_role"},
+                {"action": "delete_db", "name": "old_db"},
+                {"action": "delete_role", "name": "zenith \"old\""},
+            ]),
+        )
+        .unwrap();
+        assert_eq!(
+            role_deletion_queries(&spec),
+            [
+                "DROP ROLE IF EXISTS \"old_role\"",
+                "DROP ROLE IF EXISTS \"zenith \"\"old\"\"\"",
+            ]
+        );
+
+        let spec = with_ops(1.1, json!([])).unwrap();
+        assert!(role_deletion_queries(&spec).is_empty());
+    }
+
+    #[test]
+    fn validate_delta_ops() {
+        let cases = [
+            // Unknown action
+            (1.1, json!([{"action": "drop_everything", "name": "x"}])),
+            // Missing field
+            (1.1, json!([{"action": "rename_db", "name": "x"}])),
+            // Unknown privilege
+            (
+                1.1,
+                json!([{"action": "grant_schema", "database": "zen", "schema": "public", "privileges": ["DROP"], "role": "alexk"}]),
+            ),
+            // Unsupported format versions
+            (2.0, json!([])),
+            (
+                1.0,
+                json!([{"action": "grant_role", "role": "pg_monitor", "member": "alexk"}]),
+            ),
+            // Inconsistent operations
+            (
+                1.0,
+                json!([{"action": "rename_role", "name": "alexk", "new_name": "alexk"}]),
+            ),
+            (1.0, json!([{"action": "delete_role", "name": "alexk"}])),
+            (
+                1.1,
+                json!([{"action": "grant_role", "role": "alexk", "member": "alexk"}]),
+            ),
+            (
+                1.1,
+                json!([
+                    {"action": "delete_role", "name": "old"},
+                    {"action": "grant_role", "role": "pg_monitor", "member": "old"},
+                ]),
+            ),
+            (
+                1.1,
+                json!([{"action": "set_role_setting", "role": "alexk", "setting": "work_mem; DROP", "value": "1"}]),
+            ),
+            (
+                1.1,
+                json!([{"action": "set_db_owner", "name": "zen", "owner": "alexk"}]),
+            ),
+            (
+                1.1,
+                json!([{"action": "grant_schema", "database": "missing", "schema": "public", "privileges": ["USAGE"], "role": "alexk"}]),
+            ),
+            (
+                1.1,
+                json!([{"action": "grant_schema", "database": "zen", "schema": "public", "privileges": [], "role": "alexk"}]),
+            ),
+        ];
+
+        for (format_version, ops) in cases {
+            let result = with_ops(format_version, ops.clone());
+            assert!(result.is_err(), "{} should be rejected", ops);
+        }
+    }
+
+    #[test]
+    fn validate_auto_suspend() {
+        let with_timeout = |timeout: u64| {
+            let mut spec = test_spec();
+            spec["auto_suspend"] = json!({ "idle_timeout_seconds": timeout });
+            let spec: ComputeSpec = serde_json::from_value(spec).unwrap();
+            spec.validate()
+        };
+
+        assert!(with_timeout(300).is_ok());
+        assert!(with_timeout(MAX_IDLE_TIMEOUT_SECONDS).is_ok());
+        assert!(with_timeout(MAX_IDLE_TIMEOUT_SECONDS + 1).is_err());
+        assert!(with_timeout(u64::MAX).is_err());
+    }
+
+    #[test]
+    fn validate_local_file_cache() {
+        let mut spec = test_spec();
+        spec["local_file_cache"] = json!({"path": "cache", "size_mb": 1024});
+        let spec: ComputeSpec = serde_json::from_value(spec).unwrap();
+        assert!(spec.validate().is_err());
+
+        let mut spec = test_spec();
+        spec["local_file_cache"] = json!({"path": "/var/cache/neon", "size_mb": 0});
+        let spec: ComputeSpec = serde_json::from_value(spec).unwrap();
+        assert!(spec.validate().is_err());
+    }
+
+    #[test]
+    fn validate_client_auth() {
+        let rule = |rule: Value| json!([rule]);
+        let cases = [
+            // Address in a local rule, or no address in a host one
+            (
+                "pg_hba",
+                rule(
+                    json!({"type": "local", "database": "all", "user": "all", "address": "0.0.0.0/0", "method": "trust"}),
+                ),
+            ),
+            (
+                "pg_hba",
+                rule(json!({"type": "host", "database": "all", "user": "all", "method": "md5"})),
+            ),
+            // Would break the line into more fields
+            (
+                "pg_hba",
+                rule(
+                    json!({"type": "host", "database": "all", "user": "all\nhost", "address": "0.0.0.0/0", "method": "md5"}),
+                ),
+            ),
+            (
+                "pg_hba",
+                rule(
+                    json!({"type": "host", "database": "", "user": "all", "address": "0.0.0.0/0", "method": "md5"}),
+                ),
+            ),
+            // Both material and a path, or neither
+            (
+                "ssl",
+                json!({"cert": "-----BEGIN CERTIFICATE-----", "cert_path": "/etc/server.crt", "key_path": "/etc/server.key"}),
+            ),
+            ("ssl", json!({"cert_path": "/etc/server.crt"})),
+            // Not PEM, relative path
+            (
+                "ssl",
+                json!({"cert": "MIIB", "key_path": "/etc/server.key"}),
+            ),
+            (
+                "ssl",
+                json!({"cert_path": "server.crt", "key_path": "/etc/server.key"}),
+            ),
+        ];
+        for (field, value) in cases {
+            let mut spec = test_spec();
+            spec[field] = value.clone();
+            let spec: ComputeSpec = serde_json::from_value(spec).unwrap();
+            assert!(spec.validate().is_err(), "{} should be rejected", value);
+        }
+
+        // Unknown auth method
+        let mut spec = test_spec();
+        spec["pg_hba"] = rule(
+            json!({"type": "host", "database": "all", "user": "all", "address": "0.0.0.0/0", "method": "ident"}),
+        );
+        assert!(serde_json::from_value::<ComputeSpec>(spec).is_err());
+
+        // hostssl rules need SSL to be configured
+        let mut spec = test_spec();
+        spec["ssl"] = Value::Null;
+        let spec: ComputeSpec = serde_json::from_value(spec).unwrap();
+        assert!(spec.validate().is_err());
+    }
+}
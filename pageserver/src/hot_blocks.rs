@@ -0,0 +1,163 @@
+//!
+//! Blocks that computes have read recently, tracked per timeline.
+//!
+//! They are learned from `GetPage` requests in `page_service`, and a restarted
+//! compute fetches them with the `hot_blocks` command to prewarm its buffer
+//! cache. Only the most recently read blocks are remembered, and only in
+//! memory, so the list starts empty after a pageserver restart.
+//!
+//! Recording is on the `GetPage` path, so it must not make requests wait for
+//! each other. Blocks are spread over shards with a lock each, and a read is
+//! simply not recorded if its shard is locked at the moment. The list is
+//! approximate anyway, so losing some of the reads under load is fine.
+//!
+use std::cmp::Reverse;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, TryLockError};
+
+use crate::pgdatadir_mapping::BlockNumber;
+use crate::reltag::RelTag;
+
+/// How many blocks are remembered per timeline, that's 128 MB worth of pages.
+pub const HOT_BLOCKS_CAPACITY: usize = 16 * 1024;
+
+const HOT_BLOCKS_SHARDS: usize = 16;
+
+pub struct HotBlocks {
+    capacity: usize,
+    /// Blocks each shard keeps, the capacity is split evenly between them.
+    shard_capacity: usize,
+    shards: Vec<Mutex<HotBlocksShard>>,
+    /// Number of the last recorded read, over all the shards.
+    reads: AtomicU64,
+}
+
+#[derive(Default)]
+struct HotBlocksShard {
+    /// Number of the read at which each block was last read.
+    last_read: HashMap<(RelTag, BlockNumber), u64>,
+}
+
+impl HotBlocks {
+    pub fn new(capacity: usize) -> Self {
+        Self::with_shards(capacity, HOT_BLOCKS_SHARDS)
+    }
+
+    fn with_shards(capacity: usize, shards: usize) -> Self {
+        HotBlocks {
+            capacity,
+            shard_capacity: (capacity + shards - 1) / shards,
+            shards: (0..shards).map(|_| Mutex::default()).collect(),
+            reads: AtomicU64::new(0),
+        }
+    }
+
+    /// Remember that the block has just been read, unless another read is
+    /// being recorded in the same shard.
+    pub fn record(&self, rel: RelTag, blkno: BlockNumber) {
+        let mut shard = match self.shards[self.shard_index(&rel, blkno)].try_lock() {
+            Ok(shard) => shard,
+            Err(TryLockError::WouldBlock) => return,
+            Err(TryLockError::Poisoned(e)) => panic!("hot blocks shard is poisoned: {}", e),
+        };
+        let read = self.reads.fetch_add(1, Ordering::Relaxed) + 1;
+        shard.last_read.insert((rel, blkno), read);
+
+        // Forget the blocks that haven't been read for the longest time. It's
+        // done once the shard has grown twice as large, not on every read.
+        if shard.last_read.len() >= 2 * self.shard_capacity {
+            shard.retain_latest(self.shard_capacity);
+        }
+    }
+
+    /// Up to `limit` of the most recently read blocks, ordered by relation and
+    /// block number, so that adjacent blocks can be read together.
+    pub fn list(&self, limit: usize) -> Vec<(RelTag, BlockNumber)> {
+        let mut blocks = Vec::new();
+        for shard in &self.shards {
+            let shard = shard.lock().unwrap();
+            blocks.extend(shard.last_read.iter().map(|(block, read)| (*read, *block)));
+        }
+
+        blocks.sort_unstable_by_key(|(read, _)| Reverse(*read));
+        blocks.truncate(limit.min(self.capacity));
+
+        let mut blocks: Vec<_> = blocks.into_iter().map(|(_, block)| block).collect();
+        blocks.sort_unstable();
+        blocks
+    }
+
+    fn shard_index(&self, rel: &RelTag, blkno: BlockNumber) -> usize {
+        let mut hasher = DefaultHasher::new();
+        (rel, blkno).hash(&mut hasher);
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+}
+
+impl HotBlocksShard {
+    // Keep only the `n` most recently read blocks.
+    fn retain_latest(&mut self, n: usize) {
+        if self.last_read.len() <= n {
+            return;
+        }
+
+        // Read numbers are unique, so exactly `n` of them are at or above the threshold.
+        let mut reads: Vec<u64> = self.last_read.values().copied().collect();
+        let index = reads.len() - n;
+        let (_, threshold, _) = reads.select_nth_unstable(index);
+        let threshold = *threshold;
+        self.last_read.retain(|_, read| *read >= threshold);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rel(relnode: u32) -> RelTag {
+        RelTag {
+            forknum: 0,
+            spcnode: 1663,
+            dbnode: 13010,
+            relnode,
+        }
+    }
+
+    #[test]
+    fn keeps_recently_read_blocks() {
+        let hot_blocks = HotBlocks::with_shards(4, 1);
+
+        for blkno in 0..10 {
+            hot_blocks.record(rel(1), blkno);
+        }
+        // Read again, so it's more recent than the rest.
+        hot_blocks.record(rel(1), 2);
+        hot_blocks.record(rel(0), 5);
+
+        assert_eq!(
+            hot_blocks.list(10),
+            vec![(rel(0), 5), (rel(1), 2), (rel(1), 8), (rel(1), 9)]
+        );
+        assert_eq!(hot_blocks.list(2), vec![(rel(0), 5), (rel(1), 2)]);
+    }
+
+    #[test]
+    fn sharded() {
+        let hot_blocks = HotBlocks::new(64);
+
+        for blkno in 0..1000 {
+            hot_blocks.record(rel(1), blkno);
+        }
+        hot_blocks.record(rel(0), 0);
+
+        let blocks = hot_blocks.list(100);
+        assert_eq!(blocks.len(), 64);
+        assert_eq!(blocks[0], (rel(0), 0));
+        // Each shard keeps its own most recent blocks, so the list is made of
+        // recent reads, though not exactly of the 64 latest ones.
+        assert!(blocks[1..].iter().all(|(_, blkno)| *blkno >= 1000 - 2 * 64));
+    }
+}
@@ -38,6 +38,7 @@ use crate::layered_repository::{
 };
 
 use crate::config::PageServerConf;
+use crate::hot_blocks::{HotBlocks, HOT_BLOCKS_CAPACITY};
 use crate::keyspace::{KeyPartitioning, KeySpace};
 use crate::pgdatadir_mapping::BlockNumber;
 use crate::pgdatadir_mapping::LsnForTimestamp;
@@ -425,6 +426,9 @@ pub struct Timeline {
 
     /// Relation size cache
     pub rel_size_cache: RwLock<HashMap<RelTag, (Lsn, BlockNumber)>>,
+
+    /// Blocks recently read by computes, to prewarm them on restart.
+    pub hot_blocks: HotBlocks,
 }
 
 pub struct WalReceiverInfo {
@@ -742,6 +746,7 @@ impl Timeline {
 
             last_received_wal: Mutex::new(None),
             rel_size_cache: RwLock::new(HashMap::new()),
+            hot_blocks: HotBlocks::new(HOT_BLOCKS_CAPACITY),
         };
         result.repartition_threshold = result.get_checkpoint_distance() / 10;
         result
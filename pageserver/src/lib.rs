@@ -1,5 +1,6 @@
 pub mod basebackup;
 pub mod config;
+pub mod hot_blocks;
 pub mod http;
 pub mod import_datadir;
 pub mod keyspace;
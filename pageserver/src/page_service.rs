@@ -7,6 +7,7 @@
 //     *status* -- show actual info about this pageserver,
 //     *pagestream* -- enter mode where smgr and pageserver talk with their
 //  custom protocol.
+//     *hot_blocks* -- list the blocks recently read by computes, to prewarm them.
 //
 
 use anyhow::{bail, ensure, Context, Result};
@@ -29,6 +30,7 @@ use utils::{
 
 use crate::basebackup;
 use crate::config::{PageServerConf, ProfilingConfig};
+use crate::hot_blocks::HOT_BLOCKS_CAPACITY;
 use crate::import_datadir::{import_basebackup_from_tar, import_wal_from_tar};
 use crate::layered_repository::Timeline;
 use crate::pgdatadir_mapping::LsnForTimestamp;
@@ -81,8 +83,14 @@ struct PagestreamGetPageRequest {
     lsn: Lsn,
     rel: RelTag,
     blkno: u32,
+    /// Read by the compute to warm its caches up, not by a query.
+    prewarm: bool,
 }
 
+// Flags of GetPage requests, see PAGESTREAM_GETPAGE_PREWARM in pagestore_client.h.
+// Older computes don't send them.
+const GETPAGE_PREWARM: u8 = 0x01;
+
 #[derive(Debug)]
 struct PagestreamDbSizeRequest {
     latest: bool,
@@ -155,6 +163,7 @@ impl PagestreamFeMessage {
                     forknum: body.get_u8(),
                 },
                 blkno: body.get_u32(),
+                prewarm: body.has_remaining() && body.get_u8() & GETPAGE_PREWARM != 0,
             })),
             3 => Ok(PagestreamFeMessage::DbSize(PagestreamDbSizeRequest {
                 latest: body.get_u8() != 0,
@@ -752,6 +761,9 @@ impl PageServerHandler {
         }
         */
         let page = timeline.get_rel_page_at_lsn(req.rel, req.blkno, lsn)?;
+        if !req.prewarm {
+            timeline.hot_blocks.record(req.rel, req.blkno);
+        }
 
         Ok(PagestreamBeMessage::GetPage(PagestreamGetPageResponse {
             page,
@@ -924,6 +936,50 @@ impl postgres_backend::Handler for PageServerHandler {
             ]))?
             .write_message(&BeMessage::CommandComplete(b"SELECT 1"))?;
         }
+        // return blocks recently read from the timeline, to prewarm a restarted compute
+        else if query_string.starts_with("hot_blocks ") {
+            let (_, params_raw) = query_string.split_at("hot_blocks ".len());
+            let params = params_raw.split_whitespace().collect::<Vec<_>>();
+
+            ensure!(
+                params.len() == 2 || params.len() == 3,
+                "invalid param number for hot_blocks command"
+            );
+
+            let tenantid = ZTenantId::from_str(params[0])?;
+            let timelineid = ZTimelineId::from_str(params[1])?;
+            let limit = match params.get(2) {
+                Some(limit) => limit
+                    .parse()
+                    .context("invalid limit for hot_blocks command")?,
+                None => HOT_BLOCKS_CAPACITY,
+            };
+
+            self.check_permission(Some(tenantid))?;
+            let timeline = tenant_mgr::get_local_timeline_with_load(tenantid, timelineid)
+                .context("Cannot load local timeline")?;
+
+            pgb.write_message_noflush(&BeMessage::RowDescription(&[
+                RowDescriptor::int8_col(b"spcnode"),
+                RowDescriptor::int8_col(b"dbnode"),
+                RowDescriptor::int8_col(b"relnode"),
+                RowDescriptor::int8_col(b"forknum"),
+                RowDescriptor::int8_col(b"blkno"),
+            ]))?;
+            let blocks = timeline.hot_blocks.list(limit);
+            for (rel, blkno) in &blocks {
+                pgb.write_message_noflush(&BeMessage::DataRow(&[
+                    Some(rel.spcnode.to_string().as_bytes()),
+                    Some(rel.dbnode.to_string().as_bytes()),
+                    Some(rel.relnode.to_string().as_bytes()),
+                    Some(rel.forknum.to_string().as_bytes()),
+                    Some(blkno.to_string().as_bytes()),
+                ]))?;
+            }
+            pgb.write_message(&BeMessage::CommandComplete(
+                format!("SELECT {}", blocks.len()).as_bytes(),
+            ))?;
+        }
         // same as basebackup, but result includes relational data as well
         else if query_string.starts_with("fullbackup ") {
             let (_, params_raw) = query_string.split_at("fullbackup ".len());
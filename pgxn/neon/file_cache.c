@@ -0,0 +1,696 @@
+/*-------------------------------------------------------------------------
+ *
+ * file_cache.c
+ *      Local file cache of the pages read from the page server.
+ *
+ * The cache is a single file on the compute node's local disk, divided into
+ * BLCKSZ slots. Which page is in which slot is tracked in a shared memory
+ * hash, so the content of the file is meaningless after a restart and the
+ * file is simply overwritten. Unpinned pages are kept in an LRU list, and
+ * the least recently used one is evicted when the cache is full. Pages of
+ * each relation fork are also linked from a second hash keyed by the fork,
+ * so that it can be invalidated without scanning the whole cache.
+ *
+ * A page is written into its slot outside of the lock, so it can only be read
+ * once the write has completed and the entry is marked valid. A slot which
+ * is being read is never overwritten: the newer version is dropped instead.
+ *
+ * Pages get into the cache when they're read from the page server, or
+ * written out or extended by Postgres, so the cache always holds the latest
+ * version of a page. Truncation and unlinking of a relation remove its pages.
+ *
+ * Portions Copyright (c) 1996-2021, PostgreSQL Global Development Group
+ * Portions Copyright (c) 1994, Regents of the University of California
+ *
+ *
+ * IDENTIFICATION
+ *	  contrib/neon/file_cache.c
+ *
+ *-------------------------------------------------------------------------
+ */
+#include "postgres.h"
+
+#include <fcntl.h>
+#include <unistd.h>
+
+#include "pagestore_client.h"
+#include "access/htup_details.h"
+#include "catalog/pg_class.h"
+#include "catalog/pg_type.h"
+#include "fmgr.h"
+#include "funcapi.h"
+#include "lib/ilist.h"
+#include "miscadmin.h"
+#include "storage/buf_internals.h"
+#include "storage/bufmgr.h"
+#include "storage/fd.h"
+#include "storage/ipc.h"
+#include "storage/lwlock.h"
+#include "storage/shmem.h"
+#include "storage/smgr.h"
+#include "utils/builtins.h"
+#include "utils/dynahash.h"
+#include "utils/guc.h"
+#include "utils/tuplestore.h"
+
+#define PAGES_PER_MB (1024 * 1024 / BLCKSZ)
+
+typedef struct FileCacheEntry
+{
+	BufferTag	key;
+	uint32		offset;			/* slot of the page in the cache file */
+	uint32		access_count;	/* backends currently reading or writing the slot */
+	bool		valid;			/* the page has been completely written */
+	bool		invalid;		/* removed while accessed, free it once released */
+	dlist_node	lru_node;		/* in the LRU list while not accessed */
+	dlist_node	rel_node;		/* in the pages list of the relation fork */
+} FileCacheEntry;
+
+typedef struct FileCacheControl
+{
+	uint32		size;			/* capacity of the cache in pages */
+	uint32		used;			/* slots ever handed out, from the start of the file */
+	uint32		n_free;			/* number of slots in free_slots */
+	uint64		hits;
+	uint64		misses;
+	uint64		writes;
+	dlist_head	lru;			/* least recently used entries first */
+	uint32		free_slots[FLEXIBLE_ARRAY_MEMBER];	/* slots released by truncation */
+} FileCacheControl;
+
+typedef struct
+{
+	RelFileNode rnode;
+	ForkNumber	forknum;
+} RelTag;
+
+typedef struct
+{
+	RelTag		tag;
+	dlist_head	pages;			/* cached pages of the relation fork */
+} FileCacheRelation;
+
+static HTAB *lfc_hash;
+static HTAB *lfc_rel_hash;
+static LWLockId lfc_lock;
+static FileCacheControl *lfc_ctl;
+static shmem_startup_hook_type prev_shmem_startup_hook = NULL;
+
+static int	lfc_max_size;
+static char *lfc_path;
+
+/* Per-backend state */
+static int	lfc_desc = -1;
+static bool lfc_disabled = false;
+static bool lfc_prewarming = false;
+
+static uint32
+lfc_size_pages(void)
+{
+	return (uint32) lfc_max_size * PAGES_PER_MB;
+}
+
+static Size
+lfc_control_size(void)
+{
+	return add_size(offsetof(FileCacheControl, free_slots),
+					mul_size(lfc_size_pages(), sizeof(uint32)));
+}
+
+static void
+lfc_shmem_startup(void)
+{
+	static HASHCTL info;
+	bool		found;
+
+	if (prev_shmem_startup_hook)
+		prev_shmem_startup_hook();
+
+	LWLockAcquire(AddinShmemInitLock, LW_EXCLUSIVE);
+	lfc_lock = (LWLockId) GetNamedLWLockTranche("neon_file_cache");
+	lfc_ctl = ShmemInitStruct("neon_file_cache_control", lfc_control_size(), &found);
+	if (!found)
+	{
+		lfc_ctl->size = lfc_size_pages();
+		lfc_ctl->used = 0;
+		lfc_ctl->n_free = 0;
+		lfc_ctl->hits = 0;
+		lfc_ctl->misses = 0;
+		lfc_ctl->writes = 0;
+		dlist_init(&lfc_ctl->lru);
+	}
+	info.keysize = sizeof(BufferTag);
+	info.entrysize = sizeof(FileCacheEntry);
+	lfc_hash = ShmemInitHash("neon_file_cache",
+							 lfc_size_pages(), lfc_size_pages(),
+							 &info,
+							 HASH_ELEM | HASH_BLOBS);
+	/* Each cached page may belong to a different relation fork */
+	info.keysize = sizeof(RelTag);
+	info.entrysize = sizeof(FileCacheRelation);
+	lfc_rel_hash = ShmemInitHash("neon_file_cache_relations",
+								 lfc_size_pages(), lfc_size_pages(),
+								 &info,
+								 HASH_ELEM | HASH_BLOBS);
+	LWLockRelease(AddinShmemInitLock);
+}
+
+static bool
+lfc_enabled(void)
+{
+	return lfc_max_size > 0 && !lfc_disabled;
+}
+
+/*
+ * Open the cache file in this backend, if it's not open yet. If that fails,
+ * the backend goes on without the cache.
+ */
+static bool
+lfc_ensure_opened(void)
+{
+	if (!lfc_enabled())
+		return false;
+
+	if (lfc_desc < 0)
+	{
+		lfc_desc = BasicOpenFile(lfc_path, O_RDWR | O_CREAT | PG_BINARY);
+		if (lfc_desc < 0)
+		{
+			ereport(WARNING,
+					(errcode_for_file_access(),
+					 errmsg("could not open local file cache \"%s\": %m, disabling it",
+							lfc_path)));
+			lfc_disabled = true;
+			return false;
+		}
+	}
+	return true;
+}
+
+/*
+ * Add an entry for the page in `offset` slot, pinned and not valid yet, and
+ * link it to its relation fork. Called under lfc_lock.
+ */
+static FileCacheEntry *
+lfc_add_entry(BufferTag *key, uint32 offset)
+{
+	FileCacheEntry *entry;
+	FileCacheRelation *rel;
+	RelTag		rel_tag;
+	bool		found;
+
+	entry = hash_search(lfc_hash, key, HASH_ENTER, NULL);
+	entry->offset = offset;
+	entry->access_count = 1;
+	entry->valid = false;
+	entry->invalid = false;
+
+	rel_tag.rnode = key->rnode;
+	rel_tag.forknum = key->forkNum;
+	rel = hash_search(lfc_rel_hash, &rel_tag, HASH_ENTER, &found);
+	if (!found)
+		dlist_init(&rel->pages);
+	dlist_push_tail(&rel->pages, &entry->rel_node);
+
+	return entry;
+}
+
+/*
+ * Remove the entry from the hashes, and its relation fork too, if it was the
+ * last cached page of it. The slot is not freed. Called under lfc_lock.
+ */
+static void
+lfc_delete_entry(FileCacheEntry *entry)
+{
+	FileCacheRelation *rel;
+	RelTag		rel_tag;
+
+	rel_tag.rnode = entry->key.rnode;
+	rel_tag.forknum = entry->key.forkNum;
+	rel = hash_search(lfc_rel_hash, &rel_tag, HASH_FIND, NULL);
+	Assert(rel != NULL);
+
+	dlist_delete(&entry->rel_node);
+	if (dlist_is_empty(&rel->pages))
+		hash_search(lfc_rel_hash, &rel_tag, HASH_REMOVE, NULL);
+	hash_search(lfc_hash, &entry->key, HASH_REMOVE, NULL);
+}
+
+/* Mark the entry as accessed, so that it's not evicted. Called under lfc_lock. */
+static void
+lfc_pin(FileCacheEntry *entry)
+{
+	if (entry->access_count++ == 0)
+		dlist_delete(&entry->lru_node);
+}
+
+/*
+ * Release the entry. An unused one goes to the end of the LRU list, or, if it
+ * was invalidated meanwhile, is removed and its slot freed. So all entries in
+ * the LRU list are valid. Called under lfc_lock.
+ */
+static void
+lfc_unpin(FileCacheEntry *entry)
+{
+	Assert(entry->access_count > 0);
+	if (--entry->access_count > 0)
+		return;
+
+	if (entry->invalid)
+	{
+		lfc_ctl->free_slots[lfc_ctl->n_free++] = entry->offset;
+		lfc_delete_entry(entry);
+	}
+	else
+		dlist_push_tail(&lfc_ctl->lru, &entry->lru_node);
+}
+
+/*
+ * Find a slot for a new page: an unused one, a freed one, or that of the
+ * least recently used page, which is evicted. Called under lfc_lock.
+ */
+static bool
+lfc_alloc_slot(uint32 *offset)
+{
+	FileCacheEntry *victim;
+
+	if (lfc_ctl->used < lfc_ctl->size)
+	{
+		*offset = lfc_ctl->used++;
+		return true;
+	}
+	if (lfc_ctl->n_free > 0)
+	{
+		*offset = lfc_ctl->free_slots[--lfc_ctl->n_free];
+		return true;
+	}
+	if (dlist_is_empty(&lfc_ctl->lru))
+		return false;			/* all pages are being accessed */
+
+	victim = dlist_container(FileCacheEntry, lru_node,
+							 dlist_pop_head_node(&lfc_ctl->lru));
+	*offset = victim->offset;
+	lfc_delete_entry(victim);
+	return true;
+}
+
+/* Remove the entry, or mark it invalid if it's being accessed. Called under lfc_lock. */
+static void
+lfc_remove(FileCacheEntry *entry)
+{
+	if (entry->access_count > 0)
+	{
+		entry->invalid = true;
+		return;
+	}
+	dlist_delete(&entry->lru_node);
+	lfc_ctl->free_slots[lfc_ctl->n_free++] = entry->offset;
+	lfc_delete_entry(entry);
+}
+
+/*
+ * Read the page from the cache into `buffer`. Returns false if it isn't cached.
+ */
+bool
+lfc_read(RelFileNode rnode, ForkNumber forknum, BlockNumber blkno, char *buffer)
+{
+	BufferTag	tag;
+	FileCacheEntry *entry;
+	ssize_t		rc;
+
+	if (!lfc_ensure_opened())
+		return false;
+
+	INIT_BUFFERTAG(tag, rnode, forknum, blkno);
+	LWLockAcquire(lfc_lock, LW_EXCLUSIVE);
+	entry = hash_search(lfc_hash, &tag, HASH_FIND, NULL);
+	if (entry == NULL || !entry->valid || entry->invalid)
+	{
+		if (!lfc_prewarming)
+			lfc_ctl->misses += 1;
+		LWLockRelease(lfc_lock);
+		return false;
+	}
+	lfc_pin(entry);
+	if (!lfc_prewarming)
+		lfc_ctl->hits += 1;
+	LWLockRelease(lfc_lock);
+
+	rc = pg_pread(lfc_desc, buffer, BLCKSZ, ((off_t) entry->offset) * BLCKSZ);
+
+	LWLockAcquire(lfc_lock, LW_EXCLUSIVE);
+	if (rc != BLCKSZ)
+		entry->invalid = true;
+	lfc_unpin(entry);
+	LWLockRelease(lfc_lock);
+
+	if (rc != BLCKSZ)
+	{
+		ereport(WARNING,
+				(errcode_for_file_access(),
+				 errmsg("could not read block %u in rel %u/%u/%u.%u from local file cache: %m",
+						blkno, rnode.spcNode, rnode.dbNode, rnode.relNode, forknum)));
+		return false;
+	}
+	return true;
+}
+
+/*
+ * Put the latest version of the page into the cache, replacing the cached one.
+ */
+void
+lfc_write(RelFileNode rnode, ForkNumber forknum, BlockNumber blkno, char *buffer)
+{
+	BufferTag	tag;
+	FileCacheEntry *entry;
+	uint32		offset;
+	ssize_t		rc;
+
+	if (!lfc_ensure_opened())
+		return;
+
+	INIT_BUFFERTAG(tag, rnode, forknum, blkno);
+	LWLockAcquire(lfc_lock, LW_EXCLUSIVE);
+	entry = hash_search(lfc_hash, &tag, HASH_FIND, NULL);
+	if (entry == NULL)
+	{
+		if (!lfc_alloc_slot(&offset))
+		{
+			LWLockRelease(lfc_lock);
+			return;
+		}
+		entry = lfc_add_entry(&tag, offset);
+	}
+	else if (entry->access_count > 0)
+	{
+		/*
+		 * Another backend is reading or writing the slot, so it can't be
+		 * overwritten. The cached version is stale anyway, drop it.
+		 */
+		entry->invalid = true;
+		LWLockRelease(lfc_lock);
+		return;
+	}
+	else
+	{
+		lfc_pin(entry);
+		entry->valid = false;
+	}
+	lfc_ctl->writes += 1;
+	LWLockRelease(lfc_lock);
+
+	rc = pg_pwrite(lfc_desc, buffer, BLCKSZ, ((off_t) entry->offset) * BLCKSZ);
+
+	LWLockAcquire(lfc_lock, LW_EXCLUSIVE);
+	if (rc != BLCKSZ)
+		entry->invalid = true;
+	else
+		entry->valid = true;
+	lfc_unpin(entry);
+	LWLockRelease(lfc_lock);
+
+	if (rc != BLCKSZ)
+		ereport(WARNING,
+				(errcode_for_file_access(),
+				 errmsg("could not write block %u in rel %u/%u/%u.%u to local file cache: %m",
+						blkno, rnode.spcNode, rnode.dbNode, rnode.relNode, forknum)));
+}
+
+/*
+ * Forget cached pages of the relation fork starting from `nblocks`, e.g. after
+ * truncation. InvalidForkNumber stands for all forks.
+ */
+void
+lfc_invalidate(RelFileNode rnode, ForkNumber forknum, BlockNumber nblocks)
+{
+	ForkNumber	fork;
+
+	if (lfc_max_size == 0)
+		return;
+
+	LWLockAcquire(lfc_lock, LW_EXCLUSIVE);
+	for (fork = 0; fork <= MAX_FORKNUM; fork++)
+	{
+		FileCacheRelation *rel;
+		RelTag		rel_tag;
+		dlist_mutable_iter iter;
+
+		if (forknum != InvalidForkNumber && fork != forknum)
+			continue;
+
+		rel_tag.rnode = rnode;
+		rel_tag.forknum = fork;
+		rel = hash_search(lfc_rel_hash, &rel_tag, HASH_FIND, NULL);
+		if (rel == NULL)
+			continue;
+
+		dlist_foreach_modify(iter, &rel->pages)
+		{
+			FileCacheEntry *entry = dlist_container(FileCacheEntry, rel_node, iter.cur);
+			bool		last = !dlist_has_next(&rel->pages, iter.cur);
+
+			if (entry->key.blockNum >= nblocks)
+				lfc_remove(entry);
+			/* Removal of the last page removes the relation as well */
+			if (last)
+				break;
+		}
+	}
+	LWLockRelease(lfc_lock);
+}
+
+static bool
+lfc_contains(RelFileNode rnode, ForkNumber forknum, BlockNumber blkno)
+{
+	BufferTag	tag;
+	FileCacheEntry *entry;
+	bool		found;
+
+	INIT_BUFFERTAG(tag, rnode, forknum, blkno);
+	LWLockAcquire(lfc_lock, LW_SHARED);
+	entry = hash_search(lfc_hash, &tag, HASH_FIND, NULL);
+	found = entry != NULL && entry->valid && !entry->invalid;
+	LWLockRelease(lfc_lock);
+
+	return found;
+}
+
+void
+lfc_init(void)
+{
+	DefineCustomIntVariable("neon.max_file_cache_size",
+							"Sets the size of the local file cache, 0 disables it",
+							NULL,
+							&lfc_max_size,
+							0,
+							0,
+							INT_MAX / PAGES_PER_MB,
+							PGC_POSTMASTER,
+							GUC_UNIT_MB,
+							NULL, NULL, NULL);
+
+	DefineCustomStringVariable("neon.file_cache_path",
+							   "Path to the local file cache",
+							   NULL,
+							   &lfc_path,
+							   "file.cache",
+							   PGC_POSTMASTER,
+							   0,
+							   NULL, NULL, NULL);
+
+	if (lfc_max_size > 0)
+	{
+		RequestAddinShmemSpace(lfc_control_size());
+		RequestAddinShmemSpace(hash_estimate_size(lfc_size_pages(), sizeof(FileCacheEntry)));
+		RequestAddinShmemSpace(hash_estimate_size(lfc_size_pages(), sizeof(FileCacheRelation)));
+		RequestNamedLWLockTranche("neon_file_cache", 1);
+
+		prev_shmem_startup_hook = shmem_startup_hook;
+		shmem_startup_hook = lfc_shmem_startup;
+	}
+}
+
+PG_FUNCTION_INFO_V1(local_cache_stats);
+PG_FUNCTION_INFO_V1(local_cache_relations);
+PG_FUNCTION_INFO_V1(local_cache_prewarm);
+
+Datum
+local_cache_stats(PG_FUNCTION_ARGS)
+{
+	uint64		hits = 0;
+	uint64		misses = 0;
+	uint64		writes = 0;
+	uint64		used = 0;
+	uint64		size = 0;
+	Datum		values[5];
+	bool		nulls[5];
+	TupleDesc	tupdesc;
+
+	if (get_call_result_type(fcinfo, NULL, &tupdesc) != TYPEFUNC_COMPOSITE)
+		elog(ERROR, "return type must be a row type");
+
+	if (lfc_max_size > 0)
+	{
+		LWLockAcquire(lfc_lock, LW_SHARED);
+		hits = lfc_ctl->hits;
+		misses = lfc_ctl->misses;
+		writes = lfc_ctl->writes;
+		used = lfc_ctl->used - lfc_ctl->n_free;
+		size = lfc_ctl->size;
+		LWLockRelease(lfc_lock);
+	}
+
+	MemSet(nulls, 0, sizeof(nulls));
+	values[0] = Int64GetDatum(hits);
+	values[1] = Int64GetDatum(misses);
+	values[2] = Int64GetDatum(writes);
+	values[3] = Int64GetDatum(used);
+	values[4] = Int64GetDatum(size);
+
+	PG_RETURN_DATUM(HeapTupleGetDatum(heap_form_tuple(tupdesc, values, nulls)));
+}
+
+/*
+ * Number of cached pages of each relation fork.
+ */
+Datum
+local_cache_relations(PG_FUNCTION_ARGS)
+{
+	ReturnSetInfo *rsinfo = (ReturnSetInfo *) fcinfo->resultinfo;
+	TupleDesc	tupdesc;
+	Tuplestorestate *tupstore;
+	MemoryContext oldcontext;
+	HASH_SEQ_STATUS status;
+	FileCacheRelation *rel;
+	RelTag	   *tags;
+	int64	   *pages;
+	long		max_relations;
+	long		n_relations = 0;
+	long		i;
+
+	if (rsinfo == NULL || !IsA(rsinfo, ReturnSetInfo))
+		ereport(ERROR,
+				(errcode(ERRCODE_FEATURE_NOT_SUPPORTED),
+				 errmsg("set-valued function called in context that cannot accept a set")));
+	if (!(rsinfo->allowedModes & SFRM_Materialize))
+		ereport(ERROR,
+				(errcode(ERRCODE_FEATURE_NOT_SUPPORTED),
+				 errmsg("materialize mode required, but it is not allowed in this context")));
+	if (get_call_result_type(fcinfo, NULL, &tupdesc) != TYPEFUNC_COMPOSITE)
+		elog(ERROR, "return type must be a row type");
+
+	oldcontext = MemoryContextSwitchTo(rsinfo->econtext->ecxt_per_query_memory);
+	tupstore = tuplestore_begin_heap(true, false, work_mem);
+	rsinfo->returnMode = SFRM_Materialize;
+	rsinfo->setResult = tupstore;
+	rsinfo->setDesc = tupdesc;
+	MemoryContextSwitchTo(oldcontext);
+
+	if (lfc_max_size == 0)
+		return (Datum) 0;
+
+	/* Count the pages under the lock, but don't hold it while storing tuples */
+	LWLockAcquire(lfc_lock, LW_SHARED);
+	max_relations = hash_get_num_entries(lfc_rel_hash);
+	tags = palloc(sizeof(RelTag) * max_relations);
+	pages = palloc(sizeof(int64) * max_relations);
+	hash_seq_init(&status, lfc_rel_hash);
+	while ((rel = hash_seq_search(&status)) != NULL)
+	{
+		dlist_iter	iter;
+		int64		n_pages = 0;
+
+		dlist_foreach(iter, &rel->pages)
+		{
+			FileCacheEntry *entry = dlist_container(FileCacheEntry, rel_node, iter.cur);
+
+			if (entry->valid && !entry->invalid)
+				n_pages += 1;
+		}
+		if (n_pages == 0)
+			continue;
+		tags[n_relations] = rel->tag;
+		pages[n_relations] = n_pages;
+		n_relations++;
+	}
+	LWLockRelease(lfc_lock);
+
+	for (i = 0; i < n_relations; i++)
+	{
+		Datum		values[5];
+		bool		nulls[5];
+
+		MemSet(nulls, 0, sizeof(nulls));
+		values[0] = ObjectIdGetDatum(tags[i].rnode.spcNode);
+		values[1] = ObjectIdGetDatum(tags[i].rnode.dbNode);
+		values[2] = ObjectIdGetDatum(tags[i].rnode.relNode);
+		values[3] = Int32GetDatum(tags[i].forknum);
+		values[4] = Int64GetDatum(pages[i]);
+		tuplestore_putvalues(tupstore, tupdesc, values, nulls);
+	}
+
+	return (Datum) 0;
+}
+
+/*
+ * Load up to `max_pages` pages of the relation fork from the page server into
+ * the cache. Pages are read through a small ring of shared buffers, so that
+ * a page can't be loaded while a newer version of it is written out by another
+ * backend. Returns the number of loaded pages.
+ */
+Datum
+local_cache_prewarm(PG_FUNCTION_ARGS)
+{
+	RelFileNode rnode;
+	ForkNumber	forknum = PG_GETARG_INT32(3);
+	int64		max_pages = PG_GETARG_INT64(4);
+	int64		loaded = 0;
+	SMgrRelation reln;
+	BlockNumber nblocks;
+	BlockNumber blkno;
+	BufferAccessStrategy strategy;
+
+	rnode.spcNode = PG_GETARG_OID(0);
+	rnode.dbNode = PG_GETARG_OID(1);
+	rnode.relNode = PG_GETARG_OID(2);
+	if (forknum < 0 || forknum > MAX_FORKNUM)
+		ereport(ERROR,
+				(errcode(ERRCODE_INVALID_PARAMETER_VALUE),
+				 errmsg("invalid fork number %d", forknum)));
+
+	if (!lfc_ensure_opened())
+		PG_RETURN_INT64(0);
+
+	reln = smgropen(rnode, InvalidBackendId, RELPERSISTENCE_PERMANENT);
+	if (!smgrexists(reln, forknum))
+		PG_RETURN_INT64(0);
+	nblocks = smgrnblocks(reln, forknum);
+	strategy = GetAccessStrategy(BAS_BULKREAD);
+
+	/* Warm-up reads are not what hit ratio or hot blocks should account for */
+	lfc_prewarming = true;
+	zenith_prewarming = true;
+	PG_TRY();
+	{
+		for (blkno = 0; blkno < nblocks && loaded < max_pages; blkno++)
+		{
+			CHECK_FOR_INTERRUPTS();
+
+			if (lfc_contains(rnode, forknum, blkno))
+				continue;
+			/* zenith_read() puts the page into the cache, unless it's in a buffer */
+			ReleaseBuffer(ReadBufferWithoutRelcache(rnode, forknum, blkno,
+													RBM_NORMAL, strategy));
+			loaded++;
+		}
+	}
+	PG_FINALLY();
+	{
+		lfc_prewarming = false;
+		zenith_prewarming = false;
+	}
+	PG_END_TRY();
+	FreeAccessStrategy(strategy);
+
+	PG_RETURN_INT64(loaded);
+}
@@ -402,6 +402,7 @@ pg_init_libpagestore(void)
 							NULL, NULL, NULL);
 
 	relsize_hash_init();
+	lfc_init();
 
 	if (page_server != NULL)
 		neon_log(ERROR, "libpagestore already loaded");
@@ -0,0 +1,39 @@
+\echo Use "ALTER EXTENSION neon UPDATE TO '1.1'" to load this file. \quit
+
+CREATE FUNCTION local_cache_stats(
+    OUT hits bigint,
+    OUT misses bigint,
+    OUT writes bigint,
+    OUT used_pages bigint,
+    OUT size_pages bigint
+)
+RETURNS record
+AS 'MODULE_PATHNAME', 'local_cache_stats'
+LANGUAGE C STRICT
+PARALLEL UNSAFE;
+
+CREATE FUNCTION local_cache_relations(
+    OUT spcnode oid,
+    OUT dbnode oid,
+    OUT relnode oid,
+    OUT forknum integer,
+    OUT pages bigint
+)
+RETURNS SETOF record
+AS 'MODULE_PATHNAME', 'local_cache_relations'
+LANGUAGE C STRICT
+PARALLEL UNSAFE;
+
+CREATE FUNCTION local_cache_prewarm(
+    spcnode oid,
+    dbnode oid,
+    relnode oid,
+    forknum integer,
+    max_pages bigint
+)
+RETURNS bigint
+AS 'MODULE_PATHNAME', 'local_cache_prewarm'
+LANGUAGE C STRICT
+PARALLEL UNSAFE;
+
+REVOKE ALL ON FUNCTION local_cache_prewarm(oid, oid, oid, integer, bigint) FROM PUBLIC;
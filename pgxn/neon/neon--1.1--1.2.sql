@@ -0,0 +1,16 @@
+\echo Use "ALTER EXTENSION neon UPDATE TO '1.2'" to load this file. \quit
+
+CREATE FUNCTION prewarm_buffers(
+    spcnode oid,
+    dbnode oid,
+    relnode oid,
+    forknum integer,
+    first_block bigint,
+    nblocks bigint
+)
+RETURNS bigint
+AS 'MODULE_PATHNAME', 'prewarm_buffers'
+LANGUAGE C STRICT
+PARALLEL UNSAFE;
+
+REVOKE ALL ON FUNCTION prewarm_buffers(oid, oid, oid, integer, bigint, bigint) FROM PUBLIC;
@@ -15,14 +15,18 @@
 #include "access/xlog.h"
 #include "storage/buf_internals.h"
 #include "storage/bufmgr.h"
+#include "catalog/pg_class.h"
 #include "catalog/pg_type.h"
 #include "replication/walsender.h"
 #include "funcapi.h"
+#include "miscadmin.h"
+#include "storage/smgr.h"
 #include "access/htup_details.h"
 #include "utils/pg_lsn.h"
 #include "utils/guc.h"
 
 #include "neon.h"
+#include "pagestore_client.h"
 #include "walproposer.h"
 
 PG_MODULE_MAGIC;
@@ -40,6 +44,7 @@ void		_PG_init(void)
 
 PG_FUNCTION_INFO_V1(pg_cluster_size);
 PG_FUNCTION_INFO_V1(backpressure_lsns);
+PG_FUNCTION_INFO_V1(prewarm_buffers);
 
 Datum
 pg_cluster_size(PG_FUNCTION_ARGS)
@@ -80,3 +85,63 @@ backpressure_lsns(PG_FUNCTION_ARGS)
 
 	PG_RETURN_DATUM(HeapTupleGetDatum(heap_form_tuple(tupdesc, values, nulls)));
 }
+
+/*
+ * Read blocks [first_block, first_block + nblocks) of the relation fork into
+ * shared buffers, like pg_prewarm does, but by relfilenode, so that relations
+ * of any database can be prewarmed. Blocks beyond the end of the relation are
+ * skipped. Returns the number of blocks read.
+ */
+Datum
+prewarm_buffers(PG_FUNCTION_ARGS)
+{
+	RelFileNode rnode;
+	ForkNumber	forknum = PG_GETARG_INT32(3);
+	int64		first_block = PG_GETARG_INT64(4);
+	int64		nblocks = PG_GETARG_INT64(5);
+	int64		blocks_read = 0;
+	int64		blkno;
+	SMgrRelation reln;
+	BlockNumber rel_nblocks;
+
+	rnode.spcNode = PG_GETARG_OID(0);
+	rnode.dbNode = PG_GETARG_OID(1);
+	rnode.relNode = PG_GETARG_OID(2);
+	if (forknum < 0 || forknum > MAX_FORKNUM)
+		ereport(ERROR,
+				(errcode(ERRCODE_INVALID_PARAMETER_VALUE),
+				 errmsg("invalid fork number %d", forknum)));
+	if (first_block < 0 || nblocks < 0)
+		ereport(ERROR,
+				(errcode(ERRCODE_INVALID_PARAMETER_VALUE),
+				 errmsg("invalid block range starting at %lld of %lld blocks",
+						(long long) first_block, (long long) nblocks)));
+
+	/* The relation might have been dropped or truncated since it was hot */
+	reln = smgropen(rnode, InvalidBackendId, RELPERSISTENCE_PERMANENT);
+	if (!smgrexists(reln, forknum))
+		PG_RETURN_INT64(0);
+	rel_nblocks = smgrnblocks(reln, forknum);
+
+	/* Don't let the page server take the blocks for hot ones once again */
+	zenith_prewarming = true;
+	PG_TRY();
+	{
+		for (blkno = first_block; blkno < first_block + nblocks && blkno < rel_nblocks; blkno++)
+		{
+			Buffer		buf;
+
+			CHECK_FOR_INTERRUPTS();
+			buf = ReadBufferWithoutRelcache(rnode, forknum, (BlockNumber) blkno, RBM_NORMAL, NULL);
+			ReleaseBuffer(buf);
+			blocks_read++;
+		}
+	}
+	PG_FINALLY();
+	{
+		zenith_prewarming = false;
+	}
+	PG_END_TRY();
+
+	PG_RETURN_INT64(blocks_read);
+}
@@ -41,6 +41,9 @@ typedef enum
 	T_ZenithDbSizeResponse,
 } ZenithMessageTag;
 
+/* Flags of ZenithGetPageRequest, sent after the block number */
+#define PAGESTREAM_GETPAGE_PREWARM	0x01
+
 
 
 /* base struct for c-style inheritance */
@@ -93,6 +96,7 @@ typedef struct
 	RelFileNode rnode;
 	ForkNumber	forknum;
 	BlockNumber blkno;
+	bool		prewarm;		/* read to warm caches up, not by a query */
 } ZenithGetPageRequest;
 
 /* supertype of all the Zenith*Response structs below */
@@ -151,6 +155,7 @@ extern char *zenith_timeline;
 extern char *zenith_tenant;
 extern bool wal_redo;
 extern int32 max_cluster_size;
+extern bool zenith_prewarming;
 
 extern const f_smgr *smgr_zenith(BackendId backend, RelFileNode rnode);
 extern void smgr_init_zenith(void);
@@ -218,4 +223,10 @@ extern void set_cached_relsize(RelFileNode rnode, ForkNumber forknum, BlockNumbe
 extern void update_cached_relsize(RelFileNode rnode, ForkNumber forknum, BlockNumber size);
 extern void forget_cached_relsize(RelFileNode rnode, ForkNumber forknum);
 
+/* local file cache */
+extern void lfc_init(void);
+extern bool lfc_read(RelFileNode rnode, ForkNumber forknum, BlockNumber blkno, char *buffer);
+extern void lfc_write(RelFileNode rnode, ForkNumber forknum, BlockNumber blkno, char *buffer);
+extern void lfc_invalidate(RelFileNode rnode, ForkNumber forknum, BlockNumber nblocks);
+
 #endif
@@ -93,6 +93,12 @@ char	   *zenith_tenant;
 bool		wal_redo = false;
 int32		max_cluster_size;
 
+/*
+ * Set while the backend prewarms caches. Its pages are requested with the
+ * prewarm flag, so that the page server doesn't take them for hot blocks.
+ */
+bool		zenith_prewarming = false;
+
 /* unlogged relation build states */
 typedef enum
 {
@@ -163,6 +169,8 @@ zm_pack_request(ZenithRequest *msg)
 				pq_sendint32(&s, msg_req->rnode.relNode);
 				pq_sendbyte(&s, msg_req->forknum);
 				pq_sendint32(&s, msg_req->blkno);
+				/* flags, older page servers ignore them */
+				pq_sendbyte(&s, msg_req->prewarm ? PAGESTREAM_GETPAGE_PREWARM : 0);
 
 				break;
 			}
@@ -329,6 +337,7 @@ zm_to_string(ZenithMessage *msg)
 				appendStringInfo(&s, ", \"blkno\": %u", msg_req->blkno);
 				appendStringInfo(&s, ", \"lsn\": \"%X/%X\"", LSN_FORMAT_ARGS(msg_req->req.lsn));
 				appendStringInfo(&s, ", \"latest\": %d", msg_req->req.latest);
+				appendStringInfo(&s, ", \"prewarm\": %d", msg_req->prewarm);
 				appendStringInfoChar(&s, '}');
 				break;
 			}
@@ -834,6 +843,7 @@ zenith_unlink(RelFileNodeBackend rnode, ForkNumber forkNum, bool isRedo)
 	mdunlink(rnode, forkNum, isRedo);
 	if (!RelFileNodeBackendIsTemp(rnode)) {
 		forget_cached_relsize(rnode.node, forkNum);
+		lfc_invalidate(rnode.node, forkNum, 0);
 	}
 }
 
@@ -891,6 +901,7 @@ zenith_extend(SMgrRelation reln, ForkNumber forkNum, BlockNumber blkno,
 
 	zenith_wallog_page(reln, forkNum, blkno, buffer);
 	set_cached_relsize(reln->smgr_rnode.node, forkNum, blkno + 1);
+	lfc_write(reln->smgr_rnode.node, forkNum, blkno, buffer);
 
 	lsn = PageGetLSN(buffer);
 	elog(SmgrTrace, "smgrextend called for %u/%u/%u.%u blk %u, page LSN: %X/%08X",
@@ -1019,7 +1030,8 @@ void zenith_read_at_lsn(RelFileNode rnode, ForkNumber forkNum, BlockNumber blkno
 			.req.lsn = request_lsn,
 			.rnode = rnode,
 			.forknum = forkNum,
-			.blkno = blkno
+			.blkno = blkno,
+			.prewarm = zenith_prewarming
 		};
 
 		resp = page_server->request((ZenithRequest *) &request);
@@ -1079,8 +1091,13 @@ zenith_read(SMgrRelation reln, ForkNumber forkNum, BlockNumber blkno,
 			elog(ERROR, "unknown relpersistence '%c'", reln->smgr_relpersistence);
 	}
 
+	/* Try the local file cache first */
+	if (lfc_read(reln->smgr_rnode.node, forkNum, blkno, buffer))
+		return;
+
 	request_lsn = zenith_get_request_lsn(&latest);
 	zenith_read_at_lsn(reln->smgr_rnode.node, forkNum, blkno, request_lsn, latest, buffer);
+	lfc_write(reln->smgr_rnode.node, forkNum, blkno, buffer);
 
 #ifdef DEBUG_COMPARE_LOCAL
 	if (forkNum == MAIN_FORKNUM && IS_LOCAL_REL(reln))
@@ -1231,6 +1248,7 @@ zenith_write(SMgrRelation reln, ForkNumber forknum, BlockNumber blocknum,
 	}
 
 	zenith_wallog_page(reln, forknum, blocknum, buffer);
+	lfc_write(reln->smgr_rnode.node, forknum, blocknum, buffer);
 
 	lsn = PageGetLSN(buffer);
 	elog(SmgrTrace, "smgrwrite called for %u/%u/%u.%u blk %u, page LSN: %X/%08X",
@@ -1412,6 +1430,7 @@ zenith_truncate(SMgrRelation reln, ForkNumber forknum, BlockNumber nblocks)
 	}
 
 	set_cached_relsize(reln->smgr_rnode.node, forknum, nblocks);
+	lfc_invalidate(reln->smgr_rnode.node, forknum, nblocks);
 
 	/*
 	 * Truncating a relation drops all its buffers from the buffer cache
@@ -4,7 +4,9 @@ pub mod backend;
 pub use backend::{BackendType, DatabaseInfo};
 
 mod credentials;
-pub use credentials::ClientCredentials;
+pub use credentials::{ClientCredentials, ClientCredsParseError};
+
+mod ip_allowlist;
 
 mod password_hack;
 use password_hack::PasswordHackPayload;
@@ -53,6 +55,12 @@ pub enum AuthErrorImpl {
     )]
     MissingProjectName,
 
+    #[error("Connections from this IP address are not allowed for the project")]
+    IpAddressNotAllowed,
+
+    #[error(transparent)]
+    RateLimit(#[from] crate::rate_limit::RateLimitError),
+
     /// Errors produced by e.g. [`crate::stream::PqStream`].
     #[error(transparent)]
     Io(#[from] io::Error),
@@ -66,6 +74,14 @@ impl AuthError {
     pub fn bad_auth_method(name: impl Into<Box<str>>) -> Self {
         AuthErrorImpl::BadAuthMethod(name.into()).into()
     }
+
+    /// Whether the client has presented wrong credentials.
+    pub fn is_authentication_failure(&self) -> bool {
+        matches!(
+            self.0.as_ref(),
+            AuthErrorImpl::Sasl(crate::sasl::Error::AuthenticationFailed(_))
+        )
+    }
 }
 
 impl<E: Into<AuthErrorImpl>> From<E> for AuthError {
@@ -86,6 +102,8 @@ impl UserFacingError for AuthError {
             BadAuthMethod(_) => self.to_string(),
             MalformedPassword(_) => self.to_string(),
             MissingProjectName => self.to_string(),
+            IpAddressNotAllowed => self.to_string(),
+            RateLimit(e) => e.to_string_client(),
             _ => "Internal error".to_string(),
         }
     }
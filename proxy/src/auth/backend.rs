@@ -1,23 +1,29 @@
 mod postgres;
 
+mod static_config;
+pub use static_config::{configure_static_backend, reload_on_sighup};
+
 mod link;
 pub use link::LinkAuthError;
 
 mod console;
-pub use console::{GetAuthInfoError, WakeComputeError};
+pub use console::{
+    configure_caches, invalidate_compute_address, GetAuthInfoError, WakeComputeError,
+};
 
 mod legacy_console;
 pub use legacy_console::LegacyAuthError;
 
 use crate::{
-    auth::{self, AuthFlow, ClientCredentials},
+    auth::{self, ip_allowlist, AuthFlow, ClientCredentials},
     compute, config, mgmt,
-    stream::PqStream,
+    stream::{PqStream, Stream},
     waiters::{self, Waiter, Waiters},
 };
 
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
 use tokio::io::{AsyncRead, AsyncWrite};
 
 static CPLANE_WAITERS: Lazy<Waiters<mgmt::ComputeReady>> = Lazy::new(Default::default);
@@ -94,6 +100,8 @@ pub enum BackendType<T> {
     Console(T),
     /// Local mock of Cloud API (V2).
     Postgres(T),
+    /// Projects and roles listed in a local file.
+    Static(T),
     /// Authentication via a web browser.
     Link,
 }
@@ -108,6 +116,7 @@ impl<T> BackendType<T> {
             LegacyConsole(x) => LegacyConsole(f(x)),
             Console(x) => Console(f(x)),
             Postgres(x) => Postgres(f(x)),
+            Static(x) => Static(f(x)),
             Link => Link,
         }
     }
@@ -122,21 +131,37 @@ impl<T, E> BackendType<Result<T, E>> {
             LegacyConsole(x) => x.map(LegacyConsole),
             Console(x) => x.map(Console),
             Postgres(x) => x.map(Postgres),
+            Static(x) => x.map(Static),
             Link => Ok(Link),
         }
     }
 }
 
 impl BackendType<ClientCredentials> {
+    /// Get the project name if the client has already told us.
+    pub fn project(&self) -> Option<&str> {
+        use BackendType::*;
+        match self {
+            LegacyConsole(creds) | Console(creds) | Postgres(creds) | Static(creds) => {
+                creds.project()
+            }
+            Link => None,
+        }
+    }
+
     /// Authenticate the client via the requested backend, possibly using credentials.
+    /// If the project name comes in the password, it's passed to `check_project`
+    /// before any requests are made on behalf of the project.
     pub async fn authenticate(
         mut self,
         urls: &config::AuthUrls,
-        client: &mut PqStream<impl AsyncRead + AsyncWrite + Unpin + Send>,
+        client: &mut PqStream<Stream<impl AsyncRead + AsyncWrite + Unpin + Send>>,
+        peer_addr: IpAddr,
+        check_project: impl FnOnce(&str) -> super::Result<()>,
     ) -> super::Result<compute::NodeInfo> {
         use BackendType::*;
 
-        if let Console(creds) | Postgres(creds) = &mut self {
+        if let Console(creds) | Postgres(creds) | Static(creds) = &mut self {
             // If there's no project so far, that entails that client doesn't
             // support SNI or other means of passing the project name.
             // We now expect to see a very specific payload in the place of password.
@@ -147,23 +172,17 @@ impl BackendType<ClientCredentials> {
                     .authenticate()
                     .await?;
 
+                check_project(&payload.project)?;
+
                 // Finally we may finish the initialization of `creds`.
                 // TODO: add missing type safety to ClientCredentials.
                 creds.project = Some(payload.project);
+                self.check_peer_addr(urls, peer_addr).await?;
 
-                let mut config = match &self {
-                    Console(creds) => {
-                        console::Api::new(&urls.auth_endpoint, creds)
-                            .wake_compute()
-                            .await?
-                    }
-                    Postgres(creds) => {
-                        postgres::Api::new(&urls.auth_endpoint, creds)
-                            .wake_compute()
-                            .await?
-                    }
-                    _ => unreachable!("see the patterns above"),
-                };
+                let mut config = self
+                    .wake_compute(urls)
+                    .await?
+                    .expect("see the patterns above");
 
                 // We should use a password from payload as well.
                 config.password(payload.password);
@@ -171,10 +190,13 @@ impl BackendType<ClientCredentials> {
                 return Ok(compute::NodeInfo {
                     reported_auth_ok: false,
                     config,
+                    pool_key: None,
                 });
             }
         }
 
+        self.check_peer_addr(urls, peer_addr).await?;
+
         match self {
             LegacyConsole(creds) => {
                 legacy_console::handle_user(
@@ -195,10 +217,103 @@ impl BackendType<ClientCredentials> {
                     .handle_user(client)
                     .await
             }
+            Static(creds) => static_config::Api::new(&creds).handle_user(client).await,
             // NOTE: this auth backend doesn't use client credentials.
             Link => link::handle_user(&urls.auth_link_uri, client).await,
         }
     }
+
+    /// Authenticate the client using a cleartext password, which will be checked
+    /// by the compute node itself. This is meant for clients which don't speak
+    /// the postgres protocol with us, e.g. [`crate::serverless`] http requests.
+    pub async fn authenticate_with_password(
+        &self,
+        urls: &config::AuthUrls,
+        password: &[u8],
+        peer_addr: IpAddr,
+    ) -> super::Result<compute::NodeInfo> {
+        use BackendType::*;
+
+        // There's no way to ask the client for a project name here.
+        if let Console(creds) | Postgres(creds) | Static(creds) = self {
+            if creds.project().is_none() {
+                return Err(auth::AuthErrorImpl::MissingProjectName.into());
+            }
+        }
+
+        self.check_peer_addr(urls, peer_addr).await?;
+
+        let mut config = self
+            .wake_compute(urls)
+            .await?
+            .ok_or_else(|| auth::AuthError::bad_auth_method("cleartext password"))?;
+
+        config.password(password);
+
+        Ok(compute::NodeInfo {
+            reported_auth_ok: false,
+            config,
+            pool_key: None,
+        })
+    }
+
+    /// Reject the client if its address is not in the project's allowlist.
+    /// The project name should be known at this point.
+    async fn check_peer_addr(
+        &self,
+        urls: &config::AuthUrls,
+        peer_addr: IpAddr,
+    ) -> super::Result<()> {
+        use BackendType::*;
+
+        let allowed_ips = match self {
+            Console(creds) => {
+                console::Api::new(&urls.auth_endpoint, creds)
+                    .get_allowed_ips()
+                    .await?
+            }
+            Postgres(creds) => {
+                postgres::Api::new(&urls.auth_endpoint, creds)
+                    .get_allowed_ips()
+                    .await?
+            }
+            Static(creds) => static_config::Api::new(creds).get_allowed_ips().await?,
+            LegacyConsole(_) | Link => None,
+        };
+
+        match allowed_ips {
+            Some(allowlist) if !ip_allowlist::is_allowed(&allowlist, peer_addr) => {
+                Err(auth::AuthErrorImpl::IpAddressNotAllowed.into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Wake up the compute node without authenticating the client.
+    /// Returns `None` if the backend doesn't support this.
+    async fn wake_compute(
+        &self,
+        urls: &config::AuthUrls,
+    ) -> super::Result<Option<compute::ComputeConnCfg>> {
+        use BackendType::*;
+
+        let config = match self {
+            Console(creds) => {
+                console::Api::new(&urls.auth_endpoint, creds)
+                    .wake_compute()
+                    .await?
+            }
+            Postgres(creds) => {
+                postgres::Api::new(&urls.auth_endpoint, creds)
+                    .wake_compute()
+                    .await?
+            }
+            Static(creds) => static_config::Api::new(creds).wake_compute().await?,
+            LegacyConsole(_) | Link => return Ok(None),
+        };
+
+        Ok(Some(config))
+    }
 }
 
 #[cfg(test)]
@@ -211,6 +326,7 @@ mod tests {
             BackendType::LegacyConsole(0),
             BackendType::Console(0),
             BackendType::Postgres(0),
+            BackendType::Static(0),
             BackendType::Link,
         ];
 
@@ -225,6 +341,7 @@ mod tests {
             BackendType::LegacyConsole(Ok::<_, ()>(0)),
             BackendType::Console(Ok(0)),
             BackendType::Postgres(Ok(0)),
+            BackendType::Static(Ok(0)),
             BackendType::Link,
         ];
 
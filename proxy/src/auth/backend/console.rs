@@ -1,13 +1,22 @@
 //! Cloud API V2.
 
 use crate::{
-    auth::{self, AuthFlow, ClientCredentials},
+    auth::{
+        self,
+        ip_allowlist::{parse_allowlist, IpPattern, IpPatternParseError},
+        AuthFlow, ClientCredentials,
+    },
+    cache::{CacheOptions, TimedCache},
     compute::{self, ComputeConnCfg},
     error::{io_error, UserFacingError},
+    pool::PoolKey,
     scram,
-    stream::PqStream,
+    stream::{PqStream, Stream},
     url::ApiUrl,
 };
+use anyhow::anyhow;
+use once_cell::sync::OnceCell;
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
 use std::future::Future;
 use thiserror::Error;
@@ -15,6 +24,101 @@ use tokio::io::{AsyncRead, AsyncWrite};
 
 const REQUEST_FAILED: &str = "Console request failed";
 
+/// Console responses shared by all client connections.
+/// Errors are represented by the HTTP status returned by the console.
+struct ApiCaches {
+    options: CacheOptions,
+    /// Role secrets (along with project settings) by (project, role).
+    role_secrets: TimedCache<(String, String), Result<RoleInfo, StatusCode>>,
+    /// Compute node addresses by project.
+    compute_addresses: TimedCache<String, Result<(String, u16), StatusCode>>,
+}
+
+static CACHES: OnceCell<ApiCaches> = OnceCell::new();
+
+/// Enable caching of console responses. Caching is disabled unless this is called.
+pub fn configure_caches(options: CacheOptions) -> anyhow::Result<()> {
+    let caches = ApiCaches {
+        options,
+        role_secrets: TimedCache::new("role_secrets", options.capacity),
+        compute_addresses: TimedCache::new("compute_addresses", options.capacity),
+    };
+
+    CACHES
+        .set(caches)
+        .map_err(|_| anyhow!("console caches are already configured"))
+}
+
+/// Forget the cached compute address if we've failed to connect to it,
+/// since the compute node might have been moved or suspended.
+pub fn invalidate_compute_address(host: &str, port: u16) {
+    if let Some(caches) = CACHES.get() {
+        caches
+            .compute_addresses
+            .invalidate_if(|_, address| matches!(address, Ok((h, p)) if h == host && *p == port));
+    }
+}
+
+/// Forget the cached role secret if the client has failed to authenticate
+/// with it, since the password might have been changed.
+fn invalidate_role_secret(creds: &ClientCredentials) {
+    if let (Some(caches), Some(project)) = (CACHES.get(), creds.project()) {
+        caches
+            .role_secrets
+            .invalidate(&(project.to_owned(), creds.user.clone()));
+    }
+}
+
+/// Errors which may be cached for a while, since they aren't transient.
+trait CacheableError: From<TransportError> {
+    fn transport(&self) -> Option<&TransportError>;
+
+    /// Status of a console response which is worth caching, i.e. unknown
+    /// project or role. Other errors, e.g. the console throttling us with
+    /// `429 Too Many Requests`, may go away on the next request.
+    fn cacheable_status(&self) -> Option<StatusCode> {
+        match self.transport()? {
+            TransportError::HttpStatus(status) if *status == StatusCode::NOT_FOUND => Some(*status),
+            _ => None,
+        }
+    }
+}
+
+/// Look the response up in the cache, or make a request and cache its result.
+/// Cache is bypassed if it hasn't been configured.
+async fn cached<K, V, E>(
+    cache: impl FnOnce(&ApiCaches) -> &TimedCache<K, Result<V, StatusCode>>,
+    key: K,
+    request: impl Future<Output = Result<V, E>>,
+) -> Result<V, E>
+where
+    K: std::hash::Hash + Eq + Clone,
+    V: Clone,
+    E: CacheableError,
+{
+    let caches = match CACHES.get() {
+        Some(caches) => caches,
+        None => return request.await,
+    };
+
+    let cache = cache(caches);
+    if let Some(cached) = cache.get(&key) {
+        return cached.map_err(|status| TransportError::HttpStatus(status).into());
+    }
+
+    let result = request.await;
+    match &result {
+        Ok(value) => cache.insert(key, Ok(value.clone()), caches.options.ttl),
+        Err(e) => {
+            if let Some(status) = e.cacheable_status() {
+                cache.insert(key, Err(status), caches.options.negative_ttl);
+            }
+        }
+    }
+
+    result
+}
+
 #[derive(Debug, Error)]
 pub enum TransportError {
     #[error("Console responded with a malformed JSON: {0}")]
@@ -51,6 +155,9 @@ pub enum GetAuthInfoError {
     #[error("Console responded with a malformed auth secret")]
     BadSecret,
 
+    #[error("Console responded with a malformed IP allowlist: {0}")]
+    BadAllowlist(IpPatternParseError),
+
     #[error(transparent)]
     Transport(TransportError),
 }
@@ -59,7 +166,7 @@ impl UserFacingError for GetAuthInfoError {
     fn to_string_client(&self) -> String {
         use GetAuthInfoError::*;
         match self {
-            BadSecret => REQUEST_FAILED.to_owned(),
+            BadSecret | BadAllowlist(_) => REQUEST_FAILED.to_owned(),
             Transport(e) => e.to_string_client(),
         }
     }
@@ -71,6 +178,15 @@ impl<E: Into<TransportError>> From<E> for GetAuthInfoError {
     }
 }
 
+impl CacheableError for GetAuthInfoError {
+    fn transport(&self) -> Option<&TransportError> {
+        match self {
+            Self::Transport(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum WakeComputeError {
     // We shouldn't show users the address even if it's broken.
@@ -97,10 +213,22 @@ impl<E: Into<TransportError>> From<E> for WakeComputeError {
     }
 }
 
+impl CacheableError for WakeComputeError {
+    fn transport(&self) -> Option<&TransportError> {
+        match self {
+            Self::Transport(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
 // TODO: convert into an enum with "error"
 #[derive(Serialize, Deserialize, Debug)]
 struct GetRoleSecretResponse {
     role_secret: String,
+    /// Addresses which may connect to the project; anyone may if it's missing.
+    #[serde(default)]
+    allowed_ips: Option<Vec<String>>,
 }
 
 // TODO: convert into an enum with "error"
@@ -110,6 +238,7 @@ struct GetWakeComputeResponse {
 }
 
 /// Auth secret which is managed by the cloud.
+#[derive(Clone)]
 pub enum AuthInfo {
     /// Md5 hash of user's password.
     Md5([u8; 16]),
@@ -118,6 +247,14 @@ pub enum AuthInfo {
     Scram(scram::ServerSecret),
 }
 
+/// Role secret along with the project settings returned by the same request.
+#[derive(Clone)]
+pub struct RoleInfo {
+    pub auth_info: AuthInfo,
+    /// `None` means that there are no restrictions.
+    pub allowed_ips: Option<Vec<IpPattern>>,
+}
+
 #[must_use]
 pub(super) struct Api<'a> {
     endpoint: &'a ApiUrl,
@@ -133,12 +270,41 @@ impl<'a> Api<'a> {
     /// Authenticate the existing user or throw an error.
     pub(super) async fn handle_user(
         self,
-        client: &mut PqStream<impl AsyncRead + AsyncWrite + Unpin + Send>,
+        client: &mut PqStream<Stream<impl AsyncRead + AsyncWrite + Unpin + Send>>,
     ) -> auth::Result<compute::NodeInfo> {
-        handle_user(client, &self, Self::get_auth_info, Self::wake_compute).await
+        let result = handle_user(
+            client,
+            self.creds,
+            &self,
+            Self::get_auth_info,
+            Self::wake_compute,
+        )
+        .await;
+
+        if matches!(&result, Err(e) if e.is_authentication_failure()) {
+            invalidate_role_secret(self.creds);
+        }
+
+        result
     }
 
     async fn get_auth_info(&self) -> Result<AuthInfo, GetAuthInfoError> {
+        Ok(self.get_role_info().await?.auth_info)
+    }
+
+    /// Get the project's IP allowlist, if any.
+    pub(super) async fn get_allowed_ips(&self) -> Result<Option<Vec<IpPattern>>, GetAuthInfoError> {
+        // The allowlist comes with the role secret, which we're going to need anyway.
+        Ok(self.get_role_info().await?.allowed_ips)
+    }
+
+    async fn get_role_info(&self) -> Result<RoleInfo, GetAuthInfoError> {
+        let project = self.creds.project().expect("impossible");
+        let key = (project.to_owned(), self.creds.user.clone());
+        cached(|c| &c.role_secrets, key, self.fetch_role_info()).await
+    }
+
+    async fn fetch_role_info(&self) -> Result<RoleInfo, GetAuthInfoError> {
         let mut url = self.endpoint.clone();
         url.path_segments_mut().push("proxy_get_role_secret");
         url.query_pairs_mut()
@@ -155,13 +321,40 @@ impl<'a> Api<'a> {
 
         let response: GetRoleSecretResponse = serde_json::from_str(&resp.text().await?)?;
 
-        scram::ServerSecret::parse(&response.role_secret)
+        let auth_info = scram::ServerSecret::parse(&response.role_secret)
             .map(AuthInfo::Scram)
-            .ok_or(GetAuthInfoError::BadSecret)
+            .ok_or(GetAuthInfoError::BadSecret)?;
+
+        let allowed_ips = response
+            .allowed_ips
+            .map(parse_allowlist)
+            .transpose()
+            .map_err(GetAuthInfoError::BadAllowlist)?;
+
+        Ok(RoleInfo {
+            auth_info,
+            allowed_ips,
+        })
     }
 
     /// Wake up the compute node and return the corresponding connection info.
     pub(super) async fn wake_compute(&self) -> Result<ComputeConnCfg, WakeComputeError> {
+        let project = self.creds.project().expect("impossible");
+        let key = project.to_owned();
+        let (host, port) =
+            cached(|c| &c.compute_addresses, key, self.fetch_compute_address()).await?;
+
+        let mut config = ComputeConnCfg::new();
+        config
+            .host(&host)
+            .port(port)
+            .dbname(&self.creds.dbname)
+            .user(&self.creds.user);
+
+        Ok(config)
+    }
+
+    async fn fetch_compute_address(&self) -> Result<(String, u16), WakeComputeError> {
         let mut url = self.endpoint.clone();
         url.path_segments_mut().push("proxy_wake_compute");
         url.query_pairs_mut()
@@ -178,26 +371,18 @@ impl<'a> Api<'a> {
         let response: GetWakeComputeResponse = serde_json::from_str(&resp.text().await?)?;
 
         // Unfortunately, ownership won't let us use `Option::ok_or` here.
-        let (host, port) = match parse_host_port(&response.address) {
-            None => return Err(WakeComputeError::BadComputeAddress(response.address)),
-            Some(x) => x,
-        };
-
-        let mut config = ComputeConnCfg::new();
-        config
-            .host(host)
-            .port(port)
-            .dbname(&self.creds.dbname)
-            .user(&self.creds.user);
-
-        Ok(config)
+        match parse_host_port(&response.address) {
+            None => Err(WakeComputeError::BadComputeAddress(response.address)),
+            Some((host, port)) => Ok((host.to_owned(), port)),
+        }
     }
 }
 
 /// Common logic for user handling in API V2.
 /// We reuse this for a mock API implementation in [`super::postgres`].
 pub(super) async fn handle_user<'a, Endpoint, GetAuthInfo, WakeCompute>(
-    client: &mut PqStream<impl AsyncRead + AsyncWrite + Unpin>,
+    client: &mut PqStream<Stream<impl AsyncRead + AsyncWrite + Unpin>>,
+    creds: &ClientCredentials,
     endpoint: &'a Endpoint,
     get_auth_info: impl FnOnce(&'a Endpoint) -> GetAuthInfo,
     wake_compute: impl FnOnce(&'a Endpoint) -> WakeCompute,
@@ -231,6 +416,8 @@ where
     Ok(compute::NodeInfo {
         reported_auth_ok: false,
         config,
+        // The client has proved its identity to us, so it's safe to share connections.
+        pool_key: PoolKey::new(creds),
     })
 }
 
@@ -238,3 +425,28 @@ fn parse_host_port(input: &str) -> Option<(&str, u16)> {
     let (host, port) = input.split_once(':')?;
     Some((host, port.parse().ok()?))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cacheable_errors() {
+        let error = |status| GetAuthInfoError::from(TransportError::HttpStatus(status));
+
+        assert_eq!(
+            error(StatusCode::NOT_FOUND).cacheable_status(),
+            Some(StatusCode::NOT_FOUND)
+        );
+        assert_eq!(
+            error(StatusCode::TOO_MANY_REQUESTS).cacheable_status(),
+            None
+        );
+        assert_eq!(error(StatusCode::BAD_REQUEST).cacheable_status(), None);
+        assert_eq!(
+            error(StatusCode::SERVICE_UNAVAILABLE).cacheable_status(),
+            None
+        );
+        assert_eq!(GetAuthInfoError::BadSecret.cacheable_status(), None);
+    }
+}
@@ -130,6 +130,7 @@ async fn handle_existing_user(
     Ok(compute::NodeInfo {
         reported_auth_ok: false,
         config: db_info.into(),
+        pool_key: None,
     })
 }
 
@@ -70,5 +70,6 @@ pub async fn handle_user(
     Ok(compute::NodeInfo {
         reported_auth_ok: true,
         config: db_info.into(),
+        pool_key: None,
     })
 }
@@ -4,12 +4,13 @@ use crate::{
     auth::{
         self,
         backend::console::{self, AuthInfo, GetAuthInfoError, TransportError, WakeComputeError},
+        ip_allowlist::{parse_allowlist, IpPattern},
         ClientCredentials,
     },
     compute::{self, ComputeConnCfg},
     error::io_error,
     scram,
-    stream::PqStream,
+    stream::{PqStream, Stream},
     url::ApiUrl,
 };
 use tokio::io::{AsyncRead, AsyncWrite};
@@ -36,14 +37,20 @@ impl<'a> Api<'a> {
     /// Authenticate the existing user or throw an error.
     pub(super) async fn handle_user(
         self,
-        client: &mut PqStream<impl AsyncRead + AsyncWrite + Unpin + Send>,
+        client: &mut PqStream<Stream<impl AsyncRead + AsyncWrite + Unpin + Send>>,
     ) -> auth::Result<compute::NodeInfo> {
         // We reuse user handling logic from a production module.
-        console::handle_user(client, &self, Self::get_auth_info, Self::wake_compute).await
+        console::handle_user(
+            client,
+            self.creds,
+            &self,
+            Self::get_auth_info,
+            Self::wake_compute,
+        )
+        .await
     }
 
-    /// This implementation fetches the auth info from a local postgres instance.
-    async fn get_auth_info(&self) -> Result<AuthInfo, GetAuthInfoError> {
+    async fn connect(&self) -> Result<tokio_postgres::Client, TransportError> {
         // Perhaps we could persist this connection, but then we'd have to
         // write more code for reopening it if it got closed, which doesn't
         // seem worth it.
@@ -51,6 +58,38 @@ impl<'a> Api<'a> {
             tokio_postgres::connect(self.endpoint.as_str(), tokio_postgres::NoTls).await?;
 
         tokio::spawn(connection);
+        Ok(client)
+    }
+
+    /// Mimic the project's IP allowlist with a custom role setting, e.g.
+    /// `ALTER ROLE john SET neon.allowed_ips = '127.0.0.1, 10.0.0.0/8'`.
+    pub(super) async fn get_allowed_ips(&self) -> Result<Option<Vec<IpPattern>>, GetAuthInfoError> {
+        let client = self.connect().await?;
+        let query = "select rolconfig from pg_catalog.pg_roles where rolname = $1";
+        let rows = client.query(query, &[&self.creds.user]).await?;
+
+        let settings: Option<Vec<String>> = match &rows[..] {
+            [row, ..] => row
+                .try_get("rolconfig")
+                .map_err(|e| io_error(format!("failed to read role settings: {e}")))?,
+            // Unknown user will be reported by `get_auth_info`.
+            [] => None,
+        };
+
+        let allowlist = settings
+            .iter()
+            .flatten()
+            .find_map(|setting| setting.strip_prefix("neon.allowed_ips="));
+
+        allowlist
+            .map(|list| parse_allowlist(list.split(',')))
+            .transpose()
+            .map_err(GetAuthInfoError::BadAllowlist)
+    }
+
+    /// This implementation fetches the auth info from a local postgres instance.
+    async fn get_auth_info(&self) -> Result<AuthInfo, GetAuthInfoError> {
+        let client = self.connect().await?;
         let query = "select rolpassword from pg_catalog.pg_authid where rolname = $1";
         let rows = client.query(query, &[&self.creds.user]).await?;
 
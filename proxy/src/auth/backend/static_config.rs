@@ -0,0 +1,363 @@
+//! Auth backend which doesn't need any external service: projects, roles
+//! and compute addresses are read from a local file, which is reloaded on SIGHUP.
+//!
+//! TOML example (JSON with the same structure is accepted as well,
+//! provided that the file name ends with `.json`):
+//! ```toml
+//! [[projects]]
+//! name = "my-project"
+//! address = "127.0.0.1:5432"
+//! # Optional; anyone may connect if it's missing.
+//! allowed_ips = ["10.0.0.0/8", "192.168.1.1"]
+//!
+//! [[projects.roles]]
+//! name = "john_doe"
+//! secret = "SCRAM-SHA-256$4096:<salt>$<stored key>:<server key>"
+//! ```
+
+use crate::{
+    auth::{
+        self,
+        backend::console::{self, AuthInfo, GetAuthInfoError, WakeComputeError},
+        ip_allowlist::{parse_allowlist, IpPattern},
+        ClientCredentials,
+    },
+    compute::{self, ComputeConnCfg},
+    error::io_error,
+    scram,
+    stream::{PqStream, Stream},
+};
+use anyhow::{anyhow, bail, Context};
+use hashbrown::HashMap;
+use once_cell::sync::OnceCell;
+use parking_lot::RwLock;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::signal::unix::{signal, SignalKind};
+
+#[derive(Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    projects: Vec<ProjectEntry>,
+}
+
+#[derive(Deserialize)]
+struct ProjectEntry {
+    name: String,
+    /// Compute node address, `host:port`; IPv6 addresses go in brackets.
+    address: String,
+    #[serde(default)]
+    allowed_ips: Option<Vec<String>>,
+    #[serde(default)]
+    roles: Vec<RoleEntry>,
+}
+
+#[derive(Deserialize)]
+struct RoleEntry {
+    name: String,
+    /// SCRAM secret in the `pg_authid.rolpassword` format.
+    secret: String,
+}
+
+struct Project {
+    host: String,
+    port: u16,
+    allowed_ips: Option<Vec<IpPattern>>,
+    roles: HashMap<String, scram::ServerSecret>,
+}
+
+/// Parsed contents of the config file.
+pub struct StaticConfig {
+    projects: HashMap<String, Project>,
+}
+
+impl StaticConfig {
+    /// Read the file; its format is determined by the extension.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read auth config at '{}'", path.display()))?;
+
+        let file: ConfigFile = if path.extension().map_or(false, |ext| ext == "json") {
+            serde_json::from_str(&text)?
+        } else {
+            toml_edit::easy::from_str(&text)?
+        };
+
+        Self::from_file(file).with_context(|| format!("Bad auth config at '{}'", path.display()))
+    }
+
+    fn from_file(file: ConfigFile) -> anyhow::Result<Self> {
+        let mut projects = HashMap::new();
+        for entry in file.projects {
+            let (host, port) = parse_address(&entry.address)
+                .ok_or_else(|| anyhow!("bad compute address of project '{}'", entry.name))?;
+
+            let allowed_ips = entry
+                .allowed_ips
+                .map(parse_allowlist)
+                .transpose()
+                .with_context(|| format!("bad allowlist of project '{}'", entry.name))?;
+
+            let mut roles = HashMap::new();
+            for role in entry.roles {
+                // Putting the secret into this message is a security hazard!
+                let secret = scram::ServerSecret::parse(&role.secret).ok_or_else(|| {
+                    anyhow!("bad secret of role '{}' in '{}'", role.name, entry.name)
+                })?;
+
+                if roles.insert(role.name, secret).is_some() {
+                    bail!("duplicate role in project '{}'", entry.name);
+                }
+            }
+
+            let project = Project {
+                host,
+                port,
+                allowed_ips,
+                roles,
+            };
+            if projects.insert(entry.name.clone(), project).is_some() {
+                bail!("duplicate project '{}'", entry.name);
+            }
+        }
+
+        Ok(Self { projects })
+    }
+
+    fn project(&self, name: &str) -> std::io::Result<&Project> {
+        self.projects
+            .get(name)
+            .ok_or_else(|| io_error(format!("unknown project '{name}'")))
+    }
+}
+
+struct ConfigHolder {
+    path: PathBuf,
+    current: RwLock<Arc<StaticConfig>>,
+}
+
+static CONFIG: OnceCell<ConfigHolder> = OnceCell::new();
+
+/// Load the config file, which is required by [`super::BackendType::Static`].
+pub fn configure_static_backend(path: PathBuf) -> anyhow::Result<()> {
+    let config = StaticConfig::load(&path)?;
+    println!(
+        "Loaded {} project(s) from {}",
+        config.projects.len(),
+        path.display()
+    );
+
+    let holder = ConfigHolder {
+        path,
+        current: RwLock::new(Arc::new(config)),
+    };
+
+    CONFIG
+        .set(holder)
+        .map_err(|_| anyhow!("static auth backend is already configured"))
+}
+
+/// Re-read the config file on each SIGHUP. If the file turns out
+/// to be broken, we keep using the previous version.
+pub async fn reload_on_sighup() -> anyhow::Result<()> {
+    let holder = CONFIG
+        .get()
+        .context("static auth backend is not configured")?;
+
+    let mut hangups = signal(SignalKind::hangup())?;
+    while hangups.recv().await.is_some() {
+        match StaticConfig::load(&holder.path) {
+            Ok(config) => {
+                println!(
+                    "Reloaded {} project(s) from {}",
+                    config.projects.len(),
+                    holder.path.display()
+                );
+                *holder.current.write() = Arc::new(config);
+            }
+            Err(e) => println!("Failed to reload auth config, keeping the old one: {e:#}"),
+        }
+    }
+
+    Ok(())
+}
+
+fn current_config() -> std::io::Result<Arc<StaticConfig>> {
+    let holder = CONFIG
+        .get()
+        .ok_or_else(|| io_error("static auth backend is not configured"))?;
+
+    Ok(Arc::clone(&holder.current.read()))
+}
+
+#[must_use]
+pub(super) struct Api<'a> {
+    creds: &'a ClientCredentials,
+}
+
+impl<'a> Api<'a> {
+    /// Construct an API object containing the auth parameters.
+    pub(super) fn new(creds: &'a ClientCredentials) -> Self {
+        Self { creds }
+    }
+
+    /// Authenticate the existing user or throw an error.
+    pub(super) async fn handle_user(
+        self,
+        client: &mut PqStream<Stream<impl AsyncRead + AsyncWrite + Unpin + Send>>,
+    ) -> auth::Result<compute::NodeInfo> {
+        // We reuse user handling logic from a production module.
+        console::handle_user(
+            client,
+            self.creds,
+            &self,
+            Self::get_auth_info,
+            Self::wake_compute,
+        )
+        .await
+    }
+
+    pub(super) async fn get_allowed_ips(&self) -> Result<Option<Vec<IpPattern>>, GetAuthInfoError> {
+        let projects = current_config()?;
+        let project = projects.project(self.creds.project().expect("impossible"))?;
+        Ok(project.allowed_ips.clone())
+    }
+
+    async fn get_auth_info(&self) -> Result<AuthInfo, GetAuthInfoError> {
+        let projects = current_config()?;
+        let project = projects.project(self.creds.project().expect("impossible"))?;
+        let secret = project
+            .roles
+            .get(&self.creds.user)
+            .ok_or_else(|| io_error(format!("unknown role '{}'", self.creds.user)))?;
+
+        Ok(AuthInfo::Scram(secret.clone()))
+    }
+
+    /// Computes are supposed to be running already, so we just return the connection info.
+    pub(super) async fn wake_compute(&self) -> Result<ComputeConnCfg, WakeComputeError> {
+        let projects = current_config()?;
+        let project = projects.project(self.creds.project().expect("impossible"))?;
+
+        let mut config = ComputeConnCfg::new();
+        config
+            .host(&project.host)
+            .port(project.port)
+            .dbname(&self.creds.dbname)
+            .user(&self.creds.user);
+
+        Ok(config)
+    }
+}
+
+/// Split `host:port` or `[ipv6]:port` into the host (without brackets) and port.
+fn parse_address(address: &str) -> Option<(String, u16)> {
+    let (host, port) = address.rsplit_once(':')?;
+    let host = match host.strip_prefix('[') {
+        Some(host) => host.strip_suffix(']')?,
+        // Without brackets, we can't tell where an IPv6 address ends.
+        None if host.contains(':') => return None,
+        None => host,
+    };
+    Some((host.to_owned(), port.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "SCRAM-SHA-256$4096:XiWDgg1XmYoCYJSLQ+B1Rw==$\
+        L0wRiTAQ4yJSgafHE0KHGMB7BvM/0Uf2nGeM+sGSqsk=:\
+        WPSqJQWHrJXfy5HgSM0TGzwNtIOaW0MxyDaglTtdPNw=";
+
+    #[test]
+    fn parse_toml() -> anyhow::Result<()> {
+        let text = format!(
+            r#"
+            [[projects]]
+            name = "foo"
+            address = "localhost:5432"
+
+            [[projects.roles]]
+            name = "john_doe"
+            secret = "{SECRET}"
+
+            [[projects]]
+            name = "bar"
+            address = "127.0.0.1:6543"
+            allowed_ips = ["10.0.0.0/8"]
+
+            [[projects]]
+            name = "baz"
+            address = "[::1]:5432"
+            "#
+        );
+
+        let config = StaticConfig::from_file(toml_edit::easy::from_str(&text)?)?;
+        let foo = config.project("foo")?;
+        assert_eq!((foo.host.as_str(), foo.port), ("localhost", 5432));
+        assert!(foo.roles.contains_key("john_doe"));
+        assert!(foo.allowed_ips.is_none());
+
+        let bar = config.project("bar")?;
+        assert_eq!((bar.host.as_str(), bar.port), ("127.0.0.1", 6543));
+        assert!(bar.roles.is_empty());
+        assert_eq!(bar.allowed_ips.as_ref().map(Vec::len), Some(1));
+
+        let baz = config.project("baz")?;
+        assert_eq!((baz.host.as_str(), baz.port), ("::1", 5432));
+
+        assert!(config.project("qux").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn reject_bad_entries() -> anyhow::Result<()> {
+        let parse = |json: serde_json::Value| -> anyhow::Result<StaticConfig> {
+            StaticConfig::from_file(serde_json::from_value(json)?)
+        };
+
+        let role = serde_json::json!({ "name": "john_doe", "secret": SECRET });
+        assert!(parse(serde_json::json!({
+            "projects": [{ "name": "foo", "address": "localhost:5432", "roles": [role] }]
+        }))
+        .is_ok());
+
+        // No port.
+        assert!(parse(serde_json::json!({
+            "projects": [{ "name": "foo", "address": "localhost" }]
+        }))
+        .is_err());
+
+        // IPv6 address without brackets.
+        assert!(parse(serde_json::json!({
+            "projects": [{ "name": "foo", "address": "::1:5432" }]
+        }))
+        .is_err());
+
+        // Not a SCRAM secret.
+        assert!(parse(serde_json::json!({
+            "projects": [{
+                "name": "foo",
+                "address": "localhost:5432",
+                "roles": [{ "name": "john_doe", "secret": "md5deadbeef" }],
+            }]
+        }))
+        .is_err());
+
+        // Malformed allowlist.
+        assert!(parse(serde_json::json!({
+            "projects": [{ "name": "foo", "address": "localhost:5432", "allowed_ips": ["nope"] }]
+        }))
+        .is_err());
+
+        // Duplicate project.
+        let project = serde_json::json!({ "name": "foo", "address": "localhost:5432" });
+        assert!(parse(serde_json::json!({ "projects": [project, project] })).is_err());
+
+        Ok(())
+    }
+}
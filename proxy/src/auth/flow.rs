@@ -1,8 +1,12 @@
 //! Main authentication flow.
 
 use super::{AuthErrorImpl, PasswordHackPayload};
-use crate::{sasl, scram, stream::PqStream};
+use crate::{
+    sasl, scram,
+    stream::{PqStream, Stream},
+};
 use std::io;
+use std::sync::Arc;
 use tokio::io::{AsyncRead, AsyncWrite};
 use utils::pq_proto::{BeAuthenticationSaslMessage, BeMessage, BeMessage as Be};
 
@@ -10,7 +14,8 @@ use utils::pq_proto::{BeAuthenticationSaslMessage, BeMessage, BeMessage as Be};
 pub trait AuthMethod {
     /// Any authentication selector should provide initial backend message
     /// containing auth method name and parameters, e.g. md5 salt.
-    fn first_message(&self) -> BeMessage<'_>;
+    /// `channel_binding` tells if the connection supports it (i.e. TLS is on).
+    fn first_message(&self, channel_binding: bool) -> BeMessage<'_>;
 }
 
 /// Initial state of [`AuthFlow`].
@@ -21,8 +26,13 @@ pub struct Scram<'a>(pub &'a scram::ServerSecret);
 
 impl AuthMethod for Scram<'_> {
     #[inline(always)]
-    fn first_message(&self) -> BeMessage<'_> {
-        Be::AuthenticationSasl(BeAuthenticationSaslMessage::Methods(scram::METHODS))
+    fn first_message(&self, channel_binding: bool) -> BeMessage<'_> {
+        let methods = if channel_binding {
+            scram::METHODS
+        } else {
+            scram::METHODS_WITHOUT_PLUS
+        };
+        Be::AuthenticationSasl(BeAuthenticationSaslMessage::Methods(methods))
     }
 }
 
@@ -32,37 +42,47 @@ pub struct PasswordHack;
 
 impl AuthMethod for PasswordHack {
     #[inline(always)]
-    fn first_message(&self) -> BeMessage<'_> {
+    fn first_message(&self, _channel_binding: bool) -> BeMessage<'_> {
         Be::AuthenticationCleartextPassword
     }
 }
 
 /// This wrapper for [`PqStream`] performs client authentication.
 #[must_use]
-pub struct AuthFlow<'a, Stream, State> {
+pub struct AuthFlow<'a, S, State> {
     /// The underlying stream which implements libpq's protocol.
-    stream: &'a mut PqStream<Stream>,
+    stream: &'a mut PqStream<S>,
     /// State might contain ancillary data (see [`Self::begin`]).
     state: State,
+    /// Channel binding data of the TLS connection, if any.
+    cert_digest: Option<Arc<[u8]>>,
 }
 
 /// Initial state of the stream wrapper.
-impl<'a, S: AsyncWrite + Unpin> AuthFlow<'a, S, Begin> {
+impl<'a, S> AuthFlow<'a, Stream<S>, Begin> {
     /// Create a new wrapper for client authentication.
-    pub fn new(stream: &'a mut PqStream<S>) -> Self {
+    pub fn new(stream: &'a mut PqStream<Stream<S>>) -> Self {
+        let cert_digest = stream.get_ref().cert_digest().cloned();
         Self {
             stream,
             state: Begin,
+            cert_digest,
         }
     }
+}
 
+impl<'a, S: AsyncWrite + Unpin> AuthFlow<'a, S, Begin> {
     /// Move to the next step by sending auth method's name & params to client.
     pub async fn begin<M: AuthMethod>(self, method: M) -> io::Result<AuthFlow<'a, S, M>> {
-        self.stream.write_message(&method.first_message()).await?;
+        let channel_binding = self.cert_digest.is_some();
+        self.stream
+            .write_message(&method.first_message(channel_binding))
+            .await?;
 
         Ok(AuthFlow {
             stream: self.stream,
             state: method,
+            cert_digest: self.cert_digest,
         })
     }
 }
@@ -95,14 +115,19 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AuthFlow<'_, S, Scram<'_>> {
         let sasl = sasl::FirstMessage::parse(&msg)
             .ok_or(AuthErrorImpl::MalformedPassword("bad sasl message"))?;
 
-        // Currently, the only supported SASL method is SCRAM.
-        if !scram::METHODS.contains(&sasl.method) {
-            return Err(super::AuthError::bad_auth_method(sasl.method));
-        }
+        // Currently, the only supported SASL method is SCRAM. We mustn't
+        // accept `SCRAM-SHA-256-PLUS` unless we've offered it, though.
+        use scram::ChannelBindingPolicy::*;
+        let channel_binding = match (sasl.method, self.cert_digest.as_deref()) {
+            (scram::SCRAM_SHA_256_PLUS, Some(cert_digest)) => Required(cert_digest),
+            (scram::SCRAM_SHA_256, Some(_)) => Declined,
+            (scram::SCRAM_SHA_256, None) => NotOffered,
+            _ => return Err(super::AuthError::bad_auth_method(sasl.method)),
+        };
 
         let secret = self.state.0;
         let key = sasl::SaslStream::new(self.stream, sasl.message)
-            .authenticate(scram::Exchange::new(secret, rand::random, None))
+            .authenticate(scram::Exchange::new(secret, rand::random, channel_binding))
             .await?;
 
         Ok(key)
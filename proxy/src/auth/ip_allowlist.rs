@@ -0,0 +1,132 @@
+//! Per-project lists of client addresses which may connect to the project.
+
+use std::net::IpAddr;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("Malformed IP allowlist entry: '{0}'")]
+pub struct IpPatternParseError(String);
+
+/// Either a single address (`192.168.1.1`) or a CIDR range (`10.0.0.0/8`, `2001:db8::/32`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpPattern {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpPattern {
+    pub fn matches(&self, addr: IpAddr) -> bool {
+        match (self.addr, normalize(addr)) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => prefix_matches(
+                u32::from(net).into(),
+                u32::from(addr).into(),
+                32,
+                self.prefix_len,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                prefix_matches(net.into(), addr.into(), 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Treat IPv4-mapped IPv6 addresses (which we get on dual-stack sockets) as IPv4.
+fn normalize(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, _, _] => {
+                let [.., a, b, c, d] = v6.octets();
+                IpAddr::from([a, b, c, d])
+            }
+            _ => addr,
+        },
+        IpAddr::V4(_) => addr,
+    }
+}
+
+/// Compare the `prefix_len` leading bits of `bits`-wide numbers.
+fn prefix_matches(net: u128, addr: u128, bits: u8, prefix_len: u8) -> bool {
+    let shift = u32::from(bits - prefix_len);
+    // `checked_shr` handles the zero-length prefix, which matches everything.
+    net.checked_shr(shift).unwrap_or(0) == addr.checked_shr(shift).unwrap_or(0)
+}
+
+impl FromStr for IpPattern {
+    type Err = IpPatternParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || IpPatternParseError(s.to_owned());
+        let (addr, prefix_len) = match s.trim().split_once('/') {
+            Some((addr, len)) => (addr, Some(len.parse::<u8>().map_err(|_| err())?)),
+            None => (s.trim(), None),
+        };
+
+        let addr = normalize(addr.parse().map_err(|_| err())?);
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = prefix_len.unwrap_or(max_len);
+        if prefix_len > max_len {
+            return Err(err());
+        }
+
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+/// Parse allowlist entries as received from the control plane.
+pub fn parse_allowlist(
+    entries: impl IntoIterator<Item = impl AsRef<str>>,
+) -> Result<Vec<IpPattern>, IpPatternParseError> {
+    entries.into_iter().map(|e| e.as_ref().parse()).collect()
+}
+
+/// Check the address against the allowlist. An empty list allows nobody.
+pub fn is_allowed(allowlist: &[IpPattern], addr: IpAddr) -> bool {
+    allowlist.iter().any(|pattern| pattern.matches(addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn match_patterns() -> anyhow::Result<()> {
+        let allowlist = parse_allowlist(["10.0.0.0/8", "192.168.1.1", "2001:db8::/32"])?;
+
+        assert!(is_allowed(&allowlist, ip("10.1.2.3")));
+        assert!(is_allowed(&allowlist, ip("192.168.1.1")));
+        assert!(is_allowed(&allowlist, ip("2001:db8:1::1")));
+        // IPv4-mapped addresses of dual-stack sockets.
+        assert!(is_allowed(&allowlist, ip("::ffff:10.0.0.1")));
+
+        assert!(!is_allowed(&allowlist, ip("11.0.0.1")));
+        assert!(!is_allowed(&allowlist, ip("192.168.1.2")));
+        assert!(!is_allowed(&allowlist, ip("2001:db9::1")));
+        assert!(!is_allowed(&[], ip("10.0.0.1")));
+
+        let everyone = parse_allowlist(["0.0.0.0/0"])?;
+        assert!(is_allowed(&everyone, ip("1.2.3.4")));
+        assert!(!is_allowed(&everyone, ip("::1")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn reject_malformed() {
+        for entry in [
+            "",
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0/8",
+            "10.0.0.0/x",
+            "localhost",
+        ] {
+            assert!(entry.parse::<IpPattern>().is_err(), "{entry}");
+        }
+    }
+}
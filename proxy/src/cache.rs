@@ -0,0 +1,150 @@
+//! Small in-memory caches for control plane responses.
+
+use hashbrown::HashMap;
+use metrics::{register_int_counter_vec, IntCounterVec};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+static CACHE_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proxy_cache_requests_total",
+        "Number of cache lookups, by cache name and outcome.",
+        &["cache", "outcome"]
+    )
+    .unwrap()
+});
+
+/// Cache settings, see `--cache-*` CLI options.
+#[derive(Debug, Clone, Copy)]
+pub struct CacheOptions {
+    /// Maximal number of entries in each cache.
+    pub capacity: usize,
+    /// How long to keep successful responses.
+    pub ttl: Duration,
+    /// How long to keep errors which are not expected to go away
+    /// on their own, e.g. unknown role or project.
+    pub negative_ttl: Duration,
+}
+
+struct Entry<V> {
+    value: V,
+    expires_at: Instant,
+}
+
+/// A map with per-entry expiration time and limited capacity.
+/// When full, the entry which is the closest to its expiration is evicted.
+pub struct TimedCache<K, V> {
+    /// Used in metrics.
+    name: &'static str,
+    capacity: usize,
+    entries: Mutex<HashMap<K, Entry<V>>>,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> TimedCache<K, V> {
+    pub fn new(name: &'static str, capacity: usize) -> Self {
+        Self {
+            name,
+            capacity,
+            entries: Default::default(),
+        }
+    }
+
+    /// Get a copy of the value unless it has expired.
+    pub fn get(&self, key: &K) -> Option<V> {
+        let mut entries = self.entries.lock();
+        let value = match entries.get(key) {
+            Some(entry) if entry.expires_at > Instant::now() => Some(entry.value.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        };
+
+        let outcome = if value.is_some() { "hit" } else { "miss" };
+        CACHE_REQUESTS
+            .with_label_values(&[self.name, outcome])
+            .inc();
+
+        value
+    }
+
+    /// Insert or replace the value, which will expire after `ttl`.
+    pub fn insert(&self, key: K, value: V, ttl: Duration) {
+        if ttl.is_zero() || self.capacity == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut entries = self.entries.lock();
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            entries.retain(|_, entry| entry.expires_at > now);
+        }
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires_at)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+
+        let expires_at = now + ttl;
+        entries.insert(key, Entry { value, expires_at });
+    }
+
+    /// Remove the entry, e.g. if it turned out to be stale.
+    pub fn invalidate(&self, key: &K) {
+        self.entries.lock().remove(key);
+    }
+
+    /// Remove all entries matching the predicate.
+    pub fn invalidate_if(&self, mut predicate: impl FnMut(&K, &V) -> bool) {
+        self.entries
+            .lock()
+            .retain(|key, entry| !predicate(key, &entry.value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expiration_and_invalidation() {
+        let cache = TimedCache::new("test", 10);
+        cache.insert("a", 1, Duration::from_secs(60));
+        cache.insert("b", 2, Duration::from_millis(1));
+        cache.insert("c", 3, Duration::ZERO);
+        std::thread::sleep(Duration::from_millis(10));
+
+        assert_eq!(cache.get(&"a"), Some(1));
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.get(&"c"), None);
+
+        cache.insert("d", 4, Duration::from_secs(60));
+        cache.invalidate(&"a");
+        cache.invalidate_if(|_, value| *value == 4);
+        assert_eq!(cache.get(&"a"), None);
+        assert_eq!(cache.get(&"d"), None);
+    }
+
+    #[test]
+    fn capacity_limit() {
+        let cache = TimedCache::new("test", 2);
+        cache.insert("a", 1, Duration::from_secs(10));
+        cache.insert("b", 2, Duration::from_secs(60));
+        // Replacing an existing entry doesn't evict anything.
+        cache.insert("b", 3, Duration::from_secs(60));
+        assert_eq!(cache.get(&"a"), Some(1));
+
+        // The entry closest to expiration goes first.
+        cache.insert("c", 4, Duration::from_secs(60));
+        assert_eq!(cache.get(&"a"), None);
+        assert_eq!(cache.get(&"b"), Some(3));
+        assert_eq!(cache.get(&"c"), Some(4));
+    }
+}
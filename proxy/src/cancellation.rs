@@ -1,20 +1,53 @@
-use anyhow::{anyhow, Context};
+use crate::url::ApiUrl;
+use anyhow::{anyhow, bail, Context};
 use hashbrown::HashMap;
 use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
+use std::time::Duration;
 use tokio::net::TcpStream;
 use tokio_postgres::{CancelToken, NoTls};
 use utils::pq_proto::CancelKeyData;
 
+/// With [`CancelPeers`], the upper bits of `backend_pid` in each cancel key
+/// hold the id of the proxy instance which owns the session.
+const INSTANCE_ID_SHIFT: u32 = 16;
+
 /// Enables serving `CancelRequest`s.
 #[derive(Default)]
-pub struct CancelMap(Mutex<HashMap<CancelKeyData, Option<CancelClosure>>>);
+pub struct CancelMap {
+    sessions: Mutex<HashMap<CancelKeyData, Option<CancelClosure>>>,
+    /// Set if there are other proxy instances which may receive our cancel requests.
+    peers: Option<CancelPeers>,
+}
 
 impl CancelMap {
-    /// Cancel a running query for the corresponding connection.
+    /// Sessions' cancel keys will point to this instance among the `peers`.
+    pub fn with_peers(peers: CancelPeers) -> Self {
+        Self {
+            sessions: Default::default(),
+            peers: Some(peers),
+        }
+    }
+
+    /// Cancel a running query for the corresponding connection,
+    /// which might belong to another proxy instance.
     pub async fn cancel_session(&self, key: CancelKeyData) -> anyhow::Result<()> {
+        if let Some(peers) = &self.peers {
+            let instance_id = instance_id(key);
+            if instance_id != peers.instance_id {
+                return peers.forward(instance_id, key).await;
+            }
+        }
+
+        self.cancel_local_session(key).await
+    }
+
+    /// Cancel a running query for the connection served by this instance.
+    /// This is what we do for requests forwarded by other instances.
+    pub async fn cancel_local_session(&self, key: CancelKeyData) -> anyhow::Result<()> {
         let cancel_closure = self
-            .0
+            .sessions
             .lock()
             .get(&key)
             .and_then(|x| x.clone())
@@ -34,11 +67,11 @@ impl CancelMap {
         // for it. The client will be able to notice that this is not the
         // actual backend_pid, but backend_pid is not used for anything
         // so it doesn't matter.
-        let key = rand::random();
+        let key = self.new_key();
 
         // Random key collisions are unlikely to happen here, but they're still possible,
         // which is why we have to take care not to rewrite an existing key.
-        self.0
+        self.sessions
             .lock()
             .try_insert(key, None)
             .map_err(|_| anyhow!("session already exists: {:?}", key))?;
@@ -46,12 +79,127 @@ impl CancelMap {
         // This will guarantee that the session gets dropped
         // as soon as the future is finished.
         scopeguard::defer! {
-            self.0.lock().remove(&key);
+            self.sessions.lock().remove(&key);
         }
 
         let session = Session::new(key, self);
         f(session).await
     }
+
+    /// Generate a random key, which also identifies this instance if needed.
+    fn new_key(&self) -> CancelKeyData {
+        let mut key: CancelKeyData = rand::random();
+        if let Some(peers) = &self.peers {
+            let random_bits = key.backend_pid as u32 & ((1 << INSTANCE_ID_SHIFT) - 1);
+            let instance_bits = u32::from(peers.instance_id) << INSTANCE_ID_SHIFT;
+            key.backend_pid = (instance_bits | random_bits) as i32;
+        }
+        key
+    }
+}
+
+/// Extract the id of the instance which has generated the key.
+fn instance_id(key: CancelKeyData) -> u16 {
+    (key.backend_pid as u32 >> INSTANCE_ID_SHIFT) as u16
+}
+
+/// Body of `POST /v1/cancel_session` served by the http endpoint.
+#[derive(Serialize, Deserialize)]
+pub struct CancelSessionRequest {
+    pub backend_pid: i32,
+    pub cancel_key: i32,
+}
+
+impl From<CancelKeyData> for CancelSessionRequest {
+    fn from(key: CancelKeyData) -> Self {
+        Self {
+            backend_pid: key.backend_pid,
+            cancel_key: key.cancel_key,
+        }
+    }
+}
+
+impl From<CancelSessionRequest> for CancelKeyData {
+    fn from(req: CancelSessionRequest) -> Self {
+        Self {
+            backend_pid: req.backend_pid,
+            cancel_key: req.cancel_key,
+        }
+    }
+}
+
+/// Limits of a request to another proxy instance, which may be down or unreachable.
+const PEER_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const PEER_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Proxy instances behind the same load balancer, which forward
+/// cancel requests to each other (see `--instance-id`, `--cancel-peers`).
+pub struct CancelPeers {
+    /// Id of this instance.
+    instance_id: u16,
+    /// Http endpoints of the other instances.
+    peers: HashMap<u16, ApiUrl>,
+    client: reqwest::Client,
+}
+
+impl CancelPeers {
+    pub fn new(instance_id: u16, peers: HashMap<u16, ApiUrl>) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .connect_timeout(PEER_CONNECT_TIMEOUT)
+            .timeout(PEER_REQUEST_TIMEOUT)
+            .build()
+            .context("failed to build http client for cancel peers")?;
+
+        Ok(Self {
+            instance_id,
+            peers,
+            client,
+        })
+    }
+
+    /// Parse a comma-separated list of `<instance id>=<http endpoint url>`.
+    pub fn parse_peers(s: &str) -> anyhow::Result<HashMap<u16, ApiUrl>> {
+        let mut peers = HashMap::new();
+        for peer in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (id, url) = peer
+                .split_once('=')
+                .with_context(|| format!("bad cancel peer '{peer}', expected <id>=<url>"))?;
+            let id: u16 = id.parse().context("bad cancel peer id")?;
+            if peers.insert(id, url.parse()?).is_some() {
+                bail!("duplicate cancel peer id {id}");
+            }
+        }
+
+        Ok(peers)
+    }
+
+    /// Ask the instance which owns the session to cancel it.
+    async fn forward(&self, instance_id: u16, key: CancelKeyData) -> anyhow::Result<()> {
+        let mut url = self
+            .peers
+            .get(&instance_id)
+            .with_context(|| format!("unknown proxy instance {instance_id} for {key:?}"))?
+            .clone();
+        url.path_segments_mut()
+            .pop_if_empty()
+            .extend(["v1", "cancel_session"]);
+
+        let resp = self
+            .client
+            .post(url.into_inner())
+            .json(&CancelSessionRequest::from(key))
+            .send()
+            .await?;
+
+        if !resp.status().is_success() {
+            bail!(
+                "proxy instance {instance_id} failed to cancel {key:?}: {}",
+                resp.status()
+            );
+        }
+
+        Ok(())
+    }
 }
 
 /// This should've been a [`std::future::Future`], but
@@ -95,12 +243,46 @@ impl<'a> Session<'a> {
 
     /// Store the cancel token for the given session.
     /// This enables query cancellation in [`crate::proxy::handshake`].
-    pub fn enable_cancellation(self, cancel_closure: CancelClosure) -> CancelKeyData {
+    /// Calling this again replaces the token, e.g. if the session
+    /// has switched to another compute connection.
+    pub fn enable_cancellation(&self, cancel_closure: CancelClosure) -> CancelKeyData {
         self.cancel_map
-            .0
+            .sessions
             .lock()
             .insert(self.key, Some(cancel_closure));
 
         self.key
     }
+
+    /// Forget the cancel token, e.g. when the compute connection
+    /// is no longer used by this session.
+    pub fn disable_cancellation(&self) {
+        self.cancel_map.sessions.lock().insert(self.key, None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instance_keys() {
+        let cancel_map = CancelMap::with_peers(CancelPeers::new(0xabcd, HashMap::new()).unwrap());
+        for _ in 0..100 {
+            assert_eq!(instance_id(cancel_map.new_key()), 0xabcd);
+        }
+    }
+
+    #[test]
+    fn parse_peers() -> anyhow::Result<()> {
+        let peers = CancelPeers::parse_peers("1=http://proxy-1:7001, 2=http://proxy-2:7001/")?;
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[&1].as_str(), "http://proxy-1:7001/");
+
+        assert!(CancelPeers::parse_peers("http://proxy-1:7001").is_err());
+        assert!(CancelPeers::parse_peers("x=http://proxy-1:7001").is_err());
+        assert!(CancelPeers::parse_peers("1=http://a:7001,1=http://b:7001").is_err());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,430 @@
+//! Server certificates for client connections. There may be several of them,
+//! in which case the one to present is selected by SNI. Certificates are
+//! re-read from disk periodically, so that they may be rotated without a restart.
+
+use anyhow::{bail, ensure, Context};
+use parking_lot::{Mutex, RwLock};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use sha2::{Digest, Sha224, Sha256, Sha384, Sha512};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Names of the files in each subdirectory of `--certs-dir`.
+/// These match the layout of kubernetes TLS secrets.
+const KEY_FILE_NAME: &str = "tls.key";
+const CERT_FILE_NAME: &str = "tls.crt";
+
+/// Where to find a private key and the corresponding certificate chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertPaths {
+    pub key: PathBuf,
+    pub cert: PathBuf,
+}
+
+/// Raw contents of the files, which let us tell whether anything has changed.
+#[derive(PartialEq, Eq)]
+struct CertFiles {
+    paths: CertPaths,
+    key: Vec<u8>,
+    cert: Vec<u8>,
+}
+
+impl CertFiles {
+    fn read(paths: CertPaths) -> anyhow::Result<Self> {
+        let key = std::fs::read(&paths.key)
+            .with_context(|| format!("Failed to read TLS key at '{}'", paths.key.display()))?;
+        let cert = std::fs::read(&paths.cert)
+            .with_context(|| format!("Failed to read TLS cert at '{}'", paths.cert.display()))?;
+        Ok(Self { paths, key, cert })
+    }
+
+    fn parse(&self) -> anyhow::Result<CertEntry> {
+        let key = {
+            let mut keys =
+                rustls_pemfile::pkcs8_private_keys(&mut &self.key[..]).with_context(|| {
+                    format!("Failed to read TLS keys at '{}'", self.paths.key.display())
+                })?;
+
+            ensure!(keys.len() == 1, "keys.len() = {} (should be 1)", keys.len());
+            keys.pop().map(rustls::PrivateKey).unwrap()
+        };
+
+        let cert_chain = rustls_pemfile::certs(&mut &self.cert[..])
+            .with_context(|| {
+                format!(
+                    "Failed to read TLS certificate chain at '{}'",
+                    self.paths.cert.display()
+                )
+            })?
+            .into_iter()
+            .map(rustls::Certificate)
+            .collect();
+
+        CertEntry::new(cert_chain, &key)
+            .with_context(|| format!("Bad TLS certificate at '{}'", self.paths.cert.display()))
+    }
+}
+
+/// A certificate along with the data we derive from it.
+pub struct CertEntry {
+    certified_key: Arc<CertifiedKey>,
+    /// The domain of a wildcard certificate (`CN=*.<domain>`), whose
+    /// subdomains are project names (see [`crate::auth::ClientCredentials`]).
+    common_name: Option<String>,
+    /// Channel binding data, see [`tls_server_end_point`].
+    cert_digest: Option<Arc<[u8]>>,
+}
+
+impl CertEntry {
+    pub fn new(
+        cert_chain: Vec<rustls::Certificate>,
+        key: &rustls::PrivateKey,
+    ) -> anyhow::Result<Self> {
+        // The first certificate in the chain is the server's own one.
+        let cert = cert_chain.first().context("No certificates found")?;
+
+        let common_name = {
+            let (_, parsed) = x509_parser::parse_x509_certificate(&cert.0)
+                .context("Failed to parse TLS certificate")?;
+            let common_name = parsed.subject().to_string();
+            common_name.strip_prefix("CN=*.").map(|s| s.to_string())
+        };
+        let cert_digest = tls_server_end_point(cert)?;
+
+        let key = rustls::sign::any_supported_type(key).context("Unsupported TLS key")?;
+
+        Ok(Self {
+            certified_key: Arc::new(CertifiedKey::new(cert_chain, key)),
+            common_name,
+            cert_digest,
+        })
+    }
+
+    pub fn common_name(&self) -> Option<&str> {
+        self.common_name.as_deref()
+    }
+
+    pub fn cert_digest(&self) -> Option<&Arc<[u8]>> {
+        self.cert_digest.as_ref()
+    }
+
+    /// Check whether this certificate is meant for the hostname.
+    fn serves(&self, hostname: &str) -> bool {
+        self.common_name.as_deref().map_or(false, |cn| {
+            hostname == cn
+                || hostname
+                    .strip_suffix(cn)
+                    .map_or(false, |sub| sub.ends_with('.'))
+        })
+    }
+}
+
+/// All certificates we currently have, the default one first.
+struct CertSet {
+    entries: Vec<Arc<CertEntry>>,
+    /// The files we've loaded the certificates from.
+    files: Vec<CertFiles>,
+}
+
+impl CertSet {
+    fn parse(files: Vec<CertFiles>) -> anyhow::Result<Self> {
+        ensure!(!files.is_empty(), "No TLS certificates found");
+        let entries = files
+            .iter()
+            .map(|f| f.parse().map(Arc::new))
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self { entries, files })
+    }
+
+    /// Pick the certificate with the most specific domain matching the hostname.
+    /// If there's none (or no hostname at all), use the default one.
+    fn find(&self, hostname: Option<&str>) -> &Arc<CertEntry> {
+        hostname
+            .and_then(|hostname| {
+                self.entries
+                    .iter()
+                    .filter(|entry| entry.serves(hostname))
+                    .max_by_key(|entry| entry.common_name.as_ref().map(String::len))
+            })
+            .unwrap_or(&self.entries[0])
+    }
+}
+
+/// Provides rustls with the certificate requested by client.
+pub struct CertResolver {
+    /// Certificate which is used if nothing else matches SNI.
+    default: Option<CertPaths>,
+    /// Directory with a subdirectory per certificate.
+    certs_dir: Option<PathBuf>,
+    current: RwLock<Arc<CertSet>>,
+}
+
+impl CertResolver {
+    /// Load certificates from the files. Either one may be missing, but not both.
+    pub fn load(default: Option<CertPaths>, certs_dir: Option<PathBuf>) -> anyhow::Result<Self> {
+        let files = read_files(default.as_ref(), certs_dir.as_deref())?;
+        let certs = CertSet::parse(files)?;
+
+        for file in &certs.files {
+            println!("Loaded TLS certificate from {}", file.paths.cert.display());
+        }
+
+        Ok(Self {
+            default,
+            certs_dir,
+            current: RwLock::new(Arc::new(certs)),
+        })
+    }
+
+    /// Build a resolver for certificates which aren't backed by files.
+    #[cfg(test)]
+    pub fn from_entries(entries: Vec<CertEntry>) -> Self {
+        Self {
+            default: None,
+            certs_dir: None,
+            current: RwLock::new(Arc::new(CertSet {
+                entries: entries.into_iter().map(Arc::new).collect(),
+                files: Vec::new(),
+            })),
+        }
+    }
+
+    /// Find the certificate for the hostname (e.g. SNI), see [`CertSet::find`].
+    pub fn find(&self, hostname: Option<&str>) -> Arc<CertEntry> {
+        Arc::clone(self.current.read().find(hostname))
+    }
+
+    /// Re-read the files and replace the certificates if anything has changed.
+    /// If the new files turn out to be broken, we keep using the old ones.
+    fn reload(&self) -> anyhow::Result<()> {
+        let files = read_files(self.default.as_ref(), self.certs_dir.as_deref())?;
+        if files == self.current.read().files {
+            return Ok(());
+        }
+
+        let certs = CertSet::parse(files)?;
+        println!("Reloaded {} TLS certificate(s)", certs.entries.len());
+        *self.current.write() = Arc::new(certs);
+
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let entry = self.find(client_hello.server_name());
+        Some(Arc::clone(&entry.certified_key))
+    }
+}
+
+/// Resolver of a single connection, which remembers the certificate it has
+/// presented. The certificates may be reloaded once the handshake is over,
+/// so looking the certificate up again might give a different one.
+pub struct ConnCertResolver {
+    certs: Arc<CertResolver>,
+    chosen: Mutex<Option<Arc<CertEntry>>>,
+}
+
+impl ConnCertResolver {
+    pub fn new(certs: Arc<CertResolver>) -> Self {
+        Self {
+            certs,
+            chosen: Mutex::new(None),
+        }
+    }
+
+    /// The certificate presented to the client, unless the session has been
+    /// resumed, in which case there was no need to present one.
+    pub fn chosen(&self) -> Option<Arc<CertEntry>> {
+        self.chosen.lock().clone()
+    }
+}
+
+impl ResolvesServerCert for ConnCertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let entry = self.certs.find(client_hello.server_name());
+        let certified_key = Arc::clone(&entry.certified_key);
+        *self.chosen.lock() = Some(entry);
+        Some(certified_key)
+    }
+}
+
+/// List certificate files: the default ones, then those from the directory.
+fn read_files(
+    default: Option<&CertPaths>,
+    certs_dir: Option<&Path>,
+) -> anyhow::Result<Vec<CertFiles>> {
+    let mut paths: Vec<CertPaths> = default.into_iter().cloned().collect();
+
+    if let Some(certs_dir) = certs_dir {
+        let mut dirs = Vec::new();
+        let entries = std::fs::read_dir(certs_dir)
+            .with_context(|| format!("Failed to read certs dir '{}'", certs_dir.display()))?;
+        for entry in entries {
+            let entry = entry?;
+            // Skip hidden entries, e.g. `..data` of kubernetes volumes.
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            if entry.path().is_dir() {
+                dirs.push(entry.path());
+            }
+        }
+
+        // Keep the order stable, otherwise we'd think that the files have changed.
+        dirs.sort();
+        paths.extend(dirs.into_iter().map(|dir| CertPaths {
+            key: dir.join(KEY_FILE_NAME),
+            cert: dir.join(CERT_FILE_NAME),
+        }));
+    }
+
+    paths.into_iter().map(CertFiles::read).collect()
+}
+
+/// Check the files for changes every `interval`.
+pub async fn reload_periodically(
+    resolver: Arc<CertResolver>,
+    interval: Duration,
+) -> anyhow::Result<()> {
+    if resolver.default.is_none() && resolver.certs_dir.is_none() {
+        bail!("TLS certificates aren't backed by files");
+    }
+
+    let mut ticks = tokio::time::interval(interval);
+    // The first tick completes immediately, but we've just loaded everything.
+    ticks.tick().await;
+
+    loop {
+        ticks.tick().await;
+        if let Err(e) = resolver.reload() {
+            println!("Failed to reload TLS certificates, keeping the old ones: {e:#}");
+        }
+    }
+}
+
+/// Compute `tls-server-end-point` channel binding data, i.e. the hash of the
+/// server certificate (RFC 5929). The hash function is the one used in the
+/// certificate's signature, except that MD5 and SHA-1 are replaced with SHA-256.
+/// Returns `None` if the signature algorithm doesn't specify a hash function
+/// (e.g. Ed25519), in which case channel binding is not supported.
+pub fn tls_server_end_point(cert: &rustls::Certificate) -> anyhow::Result<Option<Arc<[u8]>>> {
+    let (_, parsed) =
+        x509_parser::parse_x509_certificate(&cert.0).context("Failed to parse TLS certificate")?;
+
+    let der = cert.0.as_slice();
+    let digest = match parsed.signature_algorithm.algorithm.to_id_string().as_str() {
+        // sha224WithRSAEncryption, ecdsa-with-SHA224
+        "1.2.840.113549.1.1.14" | "1.2.840.10045.4.3.1" => Sha224::digest(der).to_vec(),
+        // sha384WithRSAEncryption, ecdsa-with-SHA384
+        "1.2.840.113549.1.1.12" | "1.2.840.10045.4.3.3" => Sha384::digest(der).to_vec(),
+        // sha512WithRSAEncryption, ecdsa-with-SHA512
+        "1.2.840.113549.1.1.13" | "1.2.840.10045.4.3.4" => Sha512::digest(der).to_vec(),
+        // Ed25519, Ed448
+        "1.3.101.112" | "1.3.101.113" => return Ok(None),
+        // Everything else is either SHA-256 or something weaker.
+        _ => Sha256::digest(der).to_vec(),
+    };
+
+    Ok(Some(digest.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generate_cert(common_name: &str) -> anyhow::Result<rcgen::Certificate> {
+        let cert = rcgen::Certificate::from_params({
+            let mut params = rcgen::CertificateParams::new(vec![format!("*.{common_name}")]);
+            params
+                .distinguished_name
+                .push(rcgen::DnType::CommonName, format!("*.{common_name}"));
+            params
+        })?;
+        Ok(cert)
+    }
+
+    fn cert_entry(common_name: &str) -> anyhow::Result<CertEntry> {
+        let cert = generate_cert(common_name)?;
+        CertEntry::new(
+            vec![rustls::Certificate(cert.serialize_der()?)],
+            &rustls::PrivateKey(cert.serialize_private_key_der()),
+        )
+    }
+
+    fn write_cert(dir: &Path, common_name: &str) -> anyhow::Result<()> {
+        let cert = generate_cert(common_name)?;
+        std::fs::create_dir_all(dir)?;
+        std::fs::write(dir.join(KEY_FILE_NAME), cert.serialize_private_key_pem())?;
+        std::fs::write(dir.join(CERT_FILE_NAME), cert.serialize_pem()?)?;
+        Ok(())
+    }
+
+    fn common_name(resolver: &CertResolver, hostname: Option<&str>) -> Option<String> {
+        resolver.find(hostname).common_name().map(str::to_owned)
+    }
+
+    #[test]
+    fn select_by_sni() -> anyhow::Result<()> {
+        let resolver = CertResolver::from_entries(vec![
+            cert_entry("default.com")?,
+            cert_entry("foo.com")?,
+            cert_entry("eu.foo.com")?,
+        ]);
+        let cn = |hostname| common_name(&resolver, hostname);
+
+        assert_eq!(cn(Some("project.foo.com")).as_deref(), Some("foo.com"));
+        assert_eq!(cn(Some("foo.com")).as_deref(), Some("foo.com"));
+        // The most specific domain wins.
+        assert_eq!(
+            cn(Some("project.eu.foo.com")).as_deref(),
+            Some("eu.foo.com")
+        );
+
+        // Otherwise, we fall back to the default certificate.
+        assert_eq!(
+            cn(Some("project.notfoo.com")).as_deref(),
+            Some("default.com")
+        );
+        assert_eq!(cn(Some("localhost")).as_deref(), Some("default.com"));
+        assert_eq!(cn(None).as_deref(), Some("default.com"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn reload_certs_dir() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("proxy-certs-{}", rand::random::<u64>()));
+        scopeguard::defer! {
+            let _ = std::fs::remove_dir_all(&dir);
+        }
+
+        write_cert(&dir.join("foo"), "foo.com")?;
+        // Hidden entries, e.g. kubernetes' `..data`, are ignored.
+        std::fs::create_dir_all(dir.join("..data"))?;
+
+        let resolver = CertResolver::load(None, Some(dir.clone()))?;
+        let cn = |hostname| common_name(&resolver, Some(hostname));
+        assert_eq!(cn("project.foo.com").as_deref(), Some("foo.com"));
+        assert_eq!(cn("project.bar.com").as_deref(), Some("foo.com"));
+
+        // Nothing has changed.
+        resolver.reload()?;
+
+        write_cert(&dir.join("bar"), "bar.com")?;
+        resolver.reload()?;
+        assert_eq!(cn("project.bar.com").as_deref(), Some("bar.com"));
+        // The certificates are sorted by directory name, so `bar` is the default now.
+        assert_eq!(cn("project.baz.com").as_deref(), Some("bar.com"));
+
+        // A broken certificate doesn't replace the old ones.
+        std::fs::write(dir.join("bar").join(CERT_FILE_NAME), "garbage")?;
+        assert!(resolver.reload().is_err());
+        assert_eq!(cn("project.bar.com").as_deref(), Some("bar.com"));
+
+        Ok(())
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{cancellation::CancelClosure, error::UserFacingError};
+use crate::{auth, cancellation::CancelClosure, error::UserFacingError};
 use futures::TryFutureExt;
 use std::{io, net::SocketAddr};
 use thiserror::Error;
@@ -45,6 +45,9 @@ pub struct NodeInfo {
     pub reported_auth_ok: bool,
     /// Compute node connection params.
     pub config: tokio_postgres::Config,
+    /// Set only if the proxy has verified the client itself,
+    /// so that it may share compute connections with other clients.
+    pub pool_key: Option<crate::pool::PoolKey>,
 }
 
 impl NodeInfo {
@@ -87,6 +90,7 @@ impl NodeInfo {
                 Err(err) => {
                     // We can't throw an error here, as there might be more hosts to try.
                     println!("failed to connect to compute `{host}:{port}`: {err}");
+                    auth::backend::invalidate_compute_address(host, *port);
                     connection_error = Some(err);
                 }
             }
@@ -129,4 +133,26 @@ impl NodeInfo {
 
         Ok((db, cancel_closure))
     }
+
+    /// Connect to a corresponding compute node and return a client for running
+    /// queries on the proxy's behalf (as opposed to passing the raw stream around).
+    pub async fn connect_client(
+        &self,
+    ) -> Result<(tokio_postgres::Client, CancelClosure), ConnectionError> {
+        let (socket_addr, stream) = self
+            .connect_raw()
+            .await
+            .map_err(|_| ConnectionError::FailedToConnectToCompute)?;
+
+        let (client, conn) = self.config.connect_raw(stream, NoTls).await?;
+        tokio::spawn(async move {
+            if let Err(err) = conn.await {
+                println!("compute connection error: {err}");
+            }
+        });
+
+        let cancel_closure = CancelClosure::new(socket_addr, client.cancel_token());
+
+        Ok((client, cancel_closure))
+    }
 }
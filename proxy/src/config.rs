@@ -1,6 +1,12 @@
-use crate::{auth, url::ApiUrl};
-use anyhow::{bail, ensure, Context};
-use std::{str::FromStr, sync::Arc};
+use crate::{
+    auth,
+    certs::{CertPaths, CertResolver, ConnCertResolver},
+    pool::ConnPool,
+    rate_limit::RateLimiter,
+    url::ApiUrl,
+};
+use anyhow::bail;
+use std::{path::PathBuf, str::FromStr, sync::Arc};
 
 impl FromStr for auth::BackendType<()> {
     type Err = anyhow::Error;
@@ -11,6 +17,7 @@ impl FromStr for auth::BackendType<()> {
             "legacy" => LegacyConsole(()),
             "console" => Console(()),
             "postgres" => Postgres(()),
+            "static" => Static(()),
             "link" => Link,
             _ => bail!("Invalid option `{s}` for auth method"),
         })
@@ -21,6 +28,11 @@ pub struct ProxyConfig {
     pub tls_config: Option<TlsConfig>,
     pub auth_backend: auth::BackendType<()>,
     pub auth_urls: AuthUrls,
+    /// Set if compute connections may be shared by clients, see [`crate::pool`].
+    pub conn_pool: Option<ConnPool>,
+    pub rate_limiter: RateLimiter,
+    /// Expect a PROXY protocol header on each client connection.
+    pub proxy_protocol: bool,
 }
 
 pub struct AuthUrls {
@@ -30,61 +42,45 @@ pub struct AuthUrls {
 
 pub struct TlsConfig {
     pub config: Arc<rustls::ServerConfig>,
-    pub common_name: Option<String>,
+    pub certs: Arc<CertResolver>,
 }
 
 impl TlsConfig {
-    pub fn to_server_config(&self) -> Arc<rustls::ServerConfig> {
-        self.config.clone()
-    }
-}
-
-/// Configure TLS for the main endpoint.
-pub fn configure_tls(key_path: &str, cert_path: &str) -> anyhow::Result<TlsConfig> {
-    let key = {
-        let key_bytes = std::fs::read(key_path).context("TLS key file")?;
-        let mut keys = rustls_pemfile::pkcs8_private_keys(&mut &key_bytes[..])
-            .context(format!("Failed to read TLS keys at '{key_path}'"))?;
-
-        ensure!(keys.len() == 1, "keys.len() = {} (should be 1)", keys.len());
-        keys.pop().map(rustls::PrivateKey).unwrap()
-    };
+    pub fn new(certs: Arc<CertResolver>) -> anyhow::Result<Self> {
+        let config = rustls::ServerConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            // allow TLS 1.2 to be compatible with older client libraries
+            .with_protocol_versions(&[&rustls::version::TLS13, &rustls::version::TLS12])?
+            .with_no_client_auth()
+            .with_cert_resolver(certs.clone())
+            .into();
 
-    let cert_chain_bytes = std::fs::read(cert_path)
-        .context(format!("Failed to read TLS cert file at '{cert_path}.'"))?;
-    let cert_chain = {
-        rustls_pemfile::certs(&mut &cert_chain_bytes[..])
-            .context(format!(
-                "Failed to read TLS certificate chain from bytes from file at '{cert_path}'."
-            ))?
-            .into_iter()
-            .map(rustls::Certificate)
-            .collect()
-    };
+        Ok(Self { config, certs })
+    }
 
-    let config = rustls::ServerConfig::builder()
-        .with_safe_default_cipher_suites()
-        .with_safe_default_kx_groups()
-        // allow TLS 1.2 to be compatible with older client libraries
-        .with_protocol_versions(&[&rustls::version::TLS13, &rustls::version::TLS12])?
-        .with_no_client_auth()
-        .with_single_cert(cert_chain, key)?
-        .into();
+    /// Config for a single connection, along with its resolver, which tells
+    /// the certificate presented in the handshake.
+    pub fn to_conn_server_config(&self) -> (Arc<rustls::ServerConfig>, Arc<ConnCertResolver>) {
+        let resolver = Arc::new(ConnCertResolver::new(self.certs.clone()));
+        let mut config = (*self.config).clone();
+        config.cert_resolver = resolver.clone();
+        (Arc::new(config), resolver)
+    }
 
-    // determine common name from tls-cert (-c server.crt param).
-    // used in asserting project name formatting invariant.
-    let common_name = {
-        let pem = x509_parser::pem::parse_x509_pem(&cert_chain_bytes)
-            .context(format!(
-                "Failed to parse PEM object from bytes from file at '{cert_path}'."
-            ))?
-            .1;
-        let common_name = pem.parse_x509()?.subject().to_string();
-        common_name.strip_prefix("CN=*.").map(|s| s.to_string())
-    };
+    /// Domain of the certificate selected by the hostname (e.g. SNI).
+    /// Used in asserting project name formatting invariant.
+    pub fn common_name(&self, hostname: Option<&str>) -> Option<String> {
+        self.certs.find(hostname).common_name().map(str::to_owned)
+    }
+}
 
-    Ok(TlsConfig {
-        config,
-        common_name,
-    })
+/// Configure TLS for the main endpoint: `default` is the certificate to use
+/// if no other one matches SNI, `certs_dir` has a subdirectory per certificate.
+pub fn configure_tls(
+    default: Option<CertPaths>,
+    certs_dir: Option<PathBuf>,
+) -> anyhow::Result<TlsConfig> {
+    let certs = CertResolver::load(default, certs_dir)?;
+    TlsConfig::new(Arc::new(certs))
 }
@@ -1,23 +1,53 @@
+use crate::cancellation::{CancelMap, CancelSessionRequest};
 use anyhow::anyhow;
 use hyper::{Body, Request, Response, StatusCode};
+use routerify::ext::RequestExt;
 use std::net::TcpListener;
-use utils::http::{endpoint, error::ApiError, json::json_response, RouterBuilder, RouterService};
+use std::sync::Arc;
+use utils::http::{
+    endpoint,
+    error::ApiError,
+    json::{json_request, json_response},
+    RouterBuilder, RouterService,
+};
 
 async fn status_handler(_: Request<Body>) -> Result<Response<Body>, ApiError> {
     json_response(StatusCode::OK, "")
 }
 
-fn make_router() -> RouterBuilder<hyper::Body, ApiError> {
+/// Cancel a session of this instance on behalf of another proxy instance,
+/// which has received the client's `CancelRequest`.
+async fn cancel_session_handler(mut request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let key: CancelSessionRequest = json_request(&mut request).await?;
+    let cancel_map = request
+        .data::<Arc<CancelMap>>()
+        .expect("unknown state type");
+
+    cancel_map
+        .cancel_local_session(key.into())
+        .await
+        .map_err(ApiError::from_err)?;
+
+    json_response(StatusCode::OK, "")
+}
+
+fn make_router(cancel_map: Arc<CancelMap>) -> RouterBuilder<hyper::Body, ApiError> {
     let router = endpoint::make_router();
-    router.get("/v1/status", status_handler)
+    router
+        .data(cancel_map)
+        .get("/v1/status", status_handler)
+        .post("/v1/cancel_session", cancel_session_handler)
 }
 
-pub async fn thread_main(http_listener: TcpListener) -> anyhow::Result<()> {
+pub async fn thread_main(
+    http_listener: TcpListener,
+    cancel_map: Arc<CancelMap>,
+) -> anyhow::Result<()> {
     scopeguard::defer! {
         println!("http has shut down");
     }
 
-    let service = || RouterService::new(make_router().build()?);
+    let service = || RouterService::new(make_router(cancel_map).build()?);
 
     hyper::Server::from_tcp(http_listener)?
         .serve(service().map_err(|e| anyhow!(e))?)
@@ -5,25 +5,32 @@
 //! in somewhat transparent manner (again via communication with control plane API).
 
 mod auth;
+mod cache;
 mod cancellation;
+mod certs;
 mod compute;
 mod config;
 mod error;
 mod http;
 mod mgmt;
 mod parse;
+mod pool;
 mod proxy;
+mod proxy_protocol;
+mod rate_limit;
 mod sasl;
 mod scram;
+mod serverless;
 mod stream;
 mod url;
 mod waiters;
 
 use anyhow::{bail, Context};
+use cancellation::{CancelMap, CancelPeers};
 use clap::{App, Arg};
 use config::ProxyConfig;
 use futures::FutureExt;
-use std::{future::Future, net::SocketAddr};
+use std::{future::Future, net::SocketAddr, path::PathBuf, sync::Arc};
 use tokio::{net::TcpListener, task::JoinError};
 use utils::project_git_version;
 
@@ -52,7 +59,7 @@ async fn main() -> anyhow::Result<()> {
             Arg::new("auth-backend")
                 .long("auth-backend")
                 .takes_value(true)
-                .help("Possible values: legacy | console | postgres | link")
+                .help("Possible values: legacy | console | postgres | static | link")
                 .default_value("legacy"),
         )
         .arg(
@@ -71,6 +78,12 @@ async fn main() -> anyhow::Result<()> {
                 .help("listen for incoming http connections (metrics, etc) on ip:port")
                 .default_value("127.0.0.1:7001"),
         )
+        .arg(
+            Arg::new("wss")
+                .long("wss")
+                .takes_value(true)
+                .help("listen for incoming websocket and sql over http connections on ip:port"),
+        )
         .arg(
             Arg::new("uri")
                 .short('u')
@@ -87,6 +100,114 @@ async fn main() -> anyhow::Result<()> {
                 .help("cloud API endpoint for authenticating users")
                 .default_value("http://localhost:3000/authenticate_proxy_request/"),
         )
+        .arg(
+            Arg::new("auth-config")
+                .long("auth-config")
+                .takes_value(true)
+                .help("path to TOML or JSON file with projects and roles for static auth backend, reloaded on SIGHUP"),
+        )
+        .arg(
+            Arg::new("cache-capacity")
+                .long("cache-capacity")
+                .takes_value(true)
+                .help("maximal number of entries in each cache of console responses")
+                .default_value("10000"),
+        )
+        .arg(
+            Arg::new("cache-ttl")
+                .long("cache-ttl")
+                .takes_value(true)
+                .help("how long to cache role secrets and compute addresses, 0 disables caching")
+                .default_value("1m"),
+        )
+        .arg(
+            Arg::new("negative-cache-ttl")
+                .long("negative-cache-ttl")
+                .takes_value(true)
+                .help("how long to cache not found console responses, i.e. unknown role or project")
+                .default_value("10s"),
+        )
+        .arg(
+            Arg::new("transaction-pool")
+                .long("transaction-pool")
+                .takes_value(false)
+                .help("share compute connections between clients at transaction boundaries"),
+        )
+        .arg(
+            Arg::new("pool-max-size")
+                .long("pool-max-size")
+                .takes_value(true)
+                .help("maximal number of pooled compute connections per project, database and role")
+                .default_value("20"),
+        )
+        .arg(
+            Arg::new("pool-idle-timeout")
+                .long("pool-idle-timeout")
+                .takes_value(true)
+                .help("close pooled compute connections which have been idle for that long")
+                .default_value("5m"),
+        )
+        .arg(
+            Arg::new("pool-acquire-timeout")
+                .long("pool-acquire-timeout")
+                .takes_value(true)
+                .help("how long a client may wait for a pooled compute connection")
+                .default_value("10s"),
+        )
+        .arg(
+            Arg::new("instance-id")
+                .long("instance-id")
+                .takes_value(true)
+                .help("id of this proxy instance (0-65535) to be encoded into cancel keys, required by --cancel-peers"),
+        )
+        .arg(
+            Arg::new("cancel-peers")
+                .long("cancel-peers")
+                .takes_value(true)
+                .requires("instance-id")
+                .help("comma-separated <instance id>=<http url> of other proxy instances, which cancel requests for their sessions are forwarded to"),
+        )
+        .arg(
+            Arg::new("proxy-protocol")
+                .long("proxy-protocol")
+                .takes_value(false)
+                .help("expect PROXY protocol (v1 or v2) header on client connections, e.g. behind a load balancer"),
+        )
+        .arg(
+            Arg::new("project-rate-limit")
+                .long("project-rate-limit")
+                .takes_value(true)
+                .help("new connections per second allowed for each project, 0 disables the limit")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("project-rate-burst")
+                .long("project-rate-burst")
+                .takes_value(true)
+                .help("how many connections to a project may be opened at once before the rate limit kicks in")
+                .default_value("100"),
+        )
+        .arg(
+            Arg::new("ip-rate-limit")
+                .long("ip-rate-limit")
+                .takes_value(true)
+                .help("new connections per second allowed from each client address, 0 disables the limit")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("ip-rate-burst")
+                .long("ip-rate-burst")
+                .takes_value(true)
+                .help("how many connections a client address may open at once before the rate limit kicks in")
+                .default_value("100"),
+        )
+        .arg(
+            Arg::new("max-project-connections")
+                .long("max-project-connections")
+                .takes_value(true)
+                .help("maximal number of concurrent client connections per project, 0 disables the limit")
+                .default_value("0"),
+        )
         .arg(
             Arg::new("tls-key")
                 .short('k')
@@ -103,30 +224,113 @@ async fn main() -> anyhow::Result<()> {
                 .takes_value(true)
                 .help("path to TLS cert for client postgres connections"),
         )
+        .arg(
+            Arg::new("certs-dir")
+                .long("certs-dir")
+                .takes_value(true)
+                .help("path to a directory with more TLS certs selected by SNI, one per subdirectory (with tls.key and tls.crt files)"),
+        )
+        .arg(
+            Arg::new("tls-reload-interval")
+                .long("tls-reload-interval")
+                .takes_value(true)
+                .help("how often to check TLS key and cert files for changes")
+                .default_value("1m"),
+        )
         .get_matches();
 
-    let tls_config = match (
+    let default_cert = match (
         arg_matches.value_of("tls-key"),
         arg_matches.value_of("tls-cert"),
     ) {
-        (Some(key_path), Some(cert_path)) => Some(config::configure_tls(key_path, cert_path)?),
+        (Some(key_path), Some(cert_path)) => Some(certs::CertPaths {
+            key: key_path.into(),
+            cert: cert_path.into(),
+        }),
         (None, None) => None,
         _ => bail!("either both or neither tls-key and tls-cert must be specified"),
     };
+    let certs_dir = arg_matches.value_of("certs-dir").map(PathBuf::from);
+    let tls_config = if default_cert.is_some() || certs_dir.is_some() {
+        Some(config::configure_tls(default_cert, certs_dir)?)
+    } else {
+        None
+    };
+    let tls_reload_interval =
+        humantime::parse_duration(arg_matches.value_of("tls-reload-interval").unwrap())?;
 
     let proxy_address: SocketAddr = arg_matches.value_of("proxy").unwrap().parse()?;
     let mgmt_address: SocketAddr = arg_matches.value_of("mgmt").unwrap().parse()?;
     let http_address: SocketAddr = arg_matches.value_of("http").unwrap().parse()?;
+    let wss_address: Option<SocketAddr> = arg_matches
+        .value_of("wss")
+        .map(|addr| addr.parse())
+        .transpose()?;
+    // Passwords are sent in plain text over this listener, see `serverless`.
+    if wss_address.is_some() && tls_config.is_none() {
+        bail!("--wss requires TLS, specify --tls-key and --tls-cert or --certs-dir");
+    }
 
     let auth_urls = config::AuthUrls {
         auth_endpoint: arg_matches.value_of("auth-endpoint").unwrap().parse()?,
         auth_link_uri: arg_matches.value_of("uri").unwrap().parse()?,
     };
 
+    auth::backend::configure_caches(cache::CacheOptions {
+        capacity: arg_matches.value_of("cache-capacity").unwrap().parse()?,
+        ttl: humantime::parse_duration(arg_matches.value_of("cache-ttl").unwrap())?,
+        negative_ttl: humantime::parse_duration(
+            arg_matches.value_of("negative-cache-ttl").unwrap(),
+        )?,
+    })?;
+
+    let conn_pool = if arg_matches.is_present("transaction-pool") {
+        Some(pool::ConnPool::new(pool::PoolOptions {
+            max_size: arg_matches.value_of("pool-max-size").unwrap().parse()?,
+            idle_timeout: humantime::parse_duration(
+                arg_matches.value_of("pool-idle-timeout").unwrap(),
+            )?,
+            acquire_timeout: humantime::parse_duration(
+                arg_matches.value_of("pool-acquire-timeout").unwrap(),
+            )?,
+        }))
+    } else {
+        None
+    };
+
+    let auth_backend: auth::BackendType<()> =
+        arg_matches.value_of("auth-backend").unwrap().parse()?;
+    if let auth::BackendType::Static(()) = auth_backend {
+        let path = arg_matches
+            .value_of("auth-config")
+            .context("static auth backend requires --auth-config")?;
+        auth::backend::configure_static_backend(path.into())?;
+    }
+
+    let rate_limiter = rate_limit::RateLimiter::new(rate_limit::RateLimitOptions {
+        project_rate: arg_matches
+            .value_of("project-rate-limit")
+            .unwrap()
+            .parse()?,
+        project_burst: arg_matches
+            .value_of("project-rate-burst")
+            .unwrap()
+            .parse()?,
+        ip_rate: arg_matches.value_of("ip-rate-limit").unwrap().parse()?,
+        ip_burst: arg_matches.value_of("ip-rate-burst").unwrap().parse()?,
+        max_project_connections: arg_matches
+            .value_of("max-project-connections")
+            .unwrap()
+            .parse()?,
+    });
+
     let config: &ProxyConfig = Box::leak(Box::new(ProxyConfig {
         tls_config,
-        auth_backend: arg_matches.value_of("auth-backend").unwrap().parse()?,
+        auth_backend,
         auth_urls,
+        conn_pool,
+        rate_limiter,
+        proxy_protocol: arg_matches.is_present("proxy-protocol"),
     }));
 
     println!("Version: {GIT_VERSION}");
@@ -142,12 +346,58 @@ async fn main() -> anyhow::Result<()> {
     println!("Starting proxy on {}", proxy_address);
     let proxy_listener = TcpListener::bind(proxy_address).await?;
 
-    let tasks = [
-        tokio::spawn(http::thread_main(http_listener)),
-        tokio::spawn(proxy::thread_main(config, proxy_listener)),
+    let wss_listener = match wss_address {
+        Some(wss_address) => {
+            println!("Starting wss on {}", wss_address);
+            Some(TcpListener::bind(wss_address).await?)
+        }
+        None => None,
+    };
+
+    // Cancellation requests may come via any of the client-facing listeners,
+    // or from other proxy instances via the http endpoint.
+    let cancel_map = Arc::new(match arg_matches.value_of("instance-id") {
+        Some(instance_id) => {
+            let peers = match arg_matches.value_of("cancel-peers") {
+                Some(peers) => CancelPeers::parse_peers(peers)?,
+                None => Default::default(),
+            };
+            println!("Instance id: {instance_id}, cancel peers: {}", peers.len());
+            CancelMap::with_peers(CancelPeers::new(instance_id.parse()?, peers)?)
+        }
+        None => CancelMap::default(),
+    });
+
+    let mut tasks = vec![
+        tokio::spawn(http::thread_main(http_listener, Arc::clone(&cancel_map))),
+        tokio::spawn(proxy::thread_main(
+            config,
+            Arc::clone(&cancel_map),
+            proxy_listener,
+        )),
         tokio::task::spawn_blocking(move || mgmt::thread_main(mgmt_listener)),
-    ]
-    .map(flatten_err);
+    ];
+
+    if let Some(tls) = &config.tls_config {
+        tasks.push(tokio::spawn(certs::reload_periodically(
+            Arc::clone(&tls.certs),
+            tls_reload_interval,
+        )));
+    }
+
+    if let auth::BackendType::Static(()) = config.auth_backend {
+        tasks.push(tokio::spawn(auth::backend::reload_on_sighup()));
+    }
+
+    if let Some(wss_listener) = wss_listener {
+        tasks.push(tokio::spawn(serverless::thread_main(
+            config,
+            cancel_map,
+            wss_listener,
+        )));
+    }
+
+    let tasks = tasks.into_iter().map(flatten_err);
 
     // This will block until all tasks have completed.
     // Furthermore, the first one to fail will cancel the rest.
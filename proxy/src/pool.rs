@@ -0,0 +1,189 @@
+//! Optional transaction-level pooling of compute connections.
+//!
+//! Clients which have proved their identity to the proxy itself may share
+//! compute connections with other clients of the same (project, dbname, user).
+//! A connection is assigned to a client for the duration of a transaction
+//! and goes back to the pool as soon as compute reports idle state.
+//!
+//! Session state doesn't survive such reassignment, so a client which creates
+//! any (`SET`, `PREPARE`, `LISTEN`, temporary tables, named prepared statements
+//! and so on) gets pinned to its current connection till the end of session.
+//! Such connections are reset with `DISCARD ALL` before they're reused, while
+//! connections abandoned in the middle of a transaction are closed.
+
+mod transaction;
+pub use transaction::proxy_pass;
+
+use crate::auth::ClientCredentials;
+use crate::cancellation::CancelClosure;
+use crate::compute::{self, NodeInfo};
+use crate::error::UserFacingError;
+use hashbrown::HashMap;
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::net::TcpStream;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Connections may only be shared by clients with the same key.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PoolKey {
+    pub project: String,
+    pub dbname: String,
+    pub user: String,
+}
+
+impl PoolKey {
+    /// Returns `None` if we don't know the project yet.
+    pub fn new(creds: &ClientCredentials) -> Option<Self> {
+        Some(Self {
+            project: creds.project()?.to_owned(),
+            dbname: creds.dbname.clone(),
+            user: creds.user.clone(),
+        })
+    }
+}
+
+/// Pool settings, see `--pool-*` CLI options.
+#[derive(Debug, Clone, Copy)]
+pub struct PoolOptions {
+    /// Maximal number of compute connections per key.
+    pub max_size: usize,
+    /// Idle connections are closed after this timeout.
+    pub idle_timeout: Duration,
+    /// How long a client may wait for a connection if the pool is exhausted.
+    pub acquire_timeout: Duration,
+}
+
+#[derive(Debug, Error)]
+pub enum PoolError {
+    #[error("Timed out waiting for a pooled connection, too many clients")]
+    Timeout,
+
+    #[error(transparent)]
+    Connect(#[from] compute::ConnectionError),
+}
+
+impl UserFacingError for PoolError {
+    fn to_string_client(&self) -> String {
+        use PoolError::*;
+        match self {
+            Timeout => self.to_string(),
+            Connect(e) => e.to_string_client(),
+        }
+    }
+}
+
+/// Authenticated compute connection which may be shared by clients.
+pub struct PooledConn {
+    pub stream: TcpStream,
+    /// PostgreSQL version of this instance.
+    pub version: String,
+    pub cancel_closure: CancelClosure,
+    last_used: Instant,
+}
+
+impl PooledConn {
+    /// Check that the idle connection hasn't been closed by compute.
+    /// There should be nothing to read from it, so we don't lose anything.
+    fn is_alive(&self) -> bool {
+        let mut buf = [0u8; 1];
+        matches!(self.stream.try_read(&mut buf), Err(e) if e.kind() == std::io::ErrorKind::WouldBlock)
+    }
+}
+
+/// Connections of a single key.
+struct Pool {
+    idle: Mutex<Vec<PooledConn>>,
+    /// Limits the number of connections in use; idle connections are
+    /// only created by the clients which hold a permit, so they're
+    /// limited as well.
+    slots: Arc<Semaphore>,
+}
+
+/// A slot for a connection taken from the pool. The connection is closed
+/// unless it's returned via [`Self::release`].
+pub struct PoolGuard {
+    pool: Arc<Pool>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl PoolGuard {
+    /// Return the connection to the pool. It should be idle and free of session state.
+    pub fn release(self, mut conn: PooledConn) {
+        conn.last_used = Instant::now();
+        self.pool.idle.lock().push(conn);
+    }
+}
+
+pub struct ConnPool {
+    options: PoolOptions,
+    pools: Mutex<HashMap<PoolKey, Arc<Pool>>>,
+}
+
+impl ConnPool {
+    pub fn new(options: PoolOptions) -> Self {
+        Self {
+            options,
+            pools: Default::default(),
+        }
+    }
+
+    fn get_pool(&self, key: &PoolKey) -> Arc<Pool> {
+        let mut pools = self.pools.lock();
+        if let Some(pool) = pools.get(key) {
+            return Arc::clone(pool);
+        }
+
+        let pool = Arc::new(Pool {
+            idle: Default::default(),
+            slots: Arc::new(Semaphore::new(self.options.max_size)),
+        });
+        pools.insert(key.clone(), Arc::clone(&pool));
+        pool
+    }
+
+    /// Take an idle connection or open a new one using the client's `node` info.
+    pub async fn acquire(
+        &self,
+        key: &PoolKey,
+        node: &NodeInfo,
+    ) -> Result<(PooledConn, PoolGuard), PoolError> {
+        let pool = self.get_pool(key);
+        let permit = tokio::time::timeout(
+            self.options.acquire_timeout,
+            Arc::clone(&pool.slots).acquire_owned(),
+        )
+        .await
+        .map_err(|_| PoolError::Timeout)?
+        .expect("pool semaphore is never closed");
+
+        let idle_conn = {
+            let mut idle = pool.idle.lock();
+            let idle_timeout = self.options.idle_timeout;
+            idle.retain(|conn| conn.last_used.elapsed() < idle_timeout && conn.is_alive());
+            idle.pop()
+        };
+
+        let conn = match idle_conn {
+            Some(conn) => conn,
+            None => {
+                let (db, cancel_closure) = node.connect().await?;
+                PooledConn {
+                    stream: db.stream,
+                    version: db.version,
+                    cancel_closure,
+                    last_used: Instant::now(),
+                }
+            }
+        };
+
+        let guard = PoolGuard {
+            pool,
+            _permit: permit,
+        };
+
+        Ok((conn, guard))
+    }
+}
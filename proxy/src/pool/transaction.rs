@@ -0,0 +1,408 @@
+//! Proxying of a client session over pooled compute connections.
+//!
+//! We don't need to decode most of the messages, but we have to follow
+//! their framing to notice when compute becomes idle and to spot the
+//! statements which create session state.
+
+use super::{ConnPool, PoolGuard, PoolKey, PooledConn};
+use crate::cancellation::{self, CancelClosure};
+use crate::compute::NodeInfo;
+use crate::error::UserFacingError;
+use crate::parse::split_cstr;
+use anyhow::bail;
+use bytes::{BufMut, Bytes, BytesMut};
+use std::io;
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use utils::pq_proto::BeMessage;
+
+/// Messages can't be larger than that in postgres anyway.
+const MAX_MESSAGE_SIZE: usize = 1 << 30;
+
+/// A protocol message (except for the startup ones) including its header.
+struct Message {
+    bytes: Bytes,
+}
+
+impl Message {
+    fn tag(&self) -> u8 {
+        self.bytes[0]
+    }
+
+    fn body(&self) -> &[u8] {
+        &self.bytes[5..]
+    }
+}
+
+/// Splits the stream into messages. Reading is cancellation safe.
+struct MessageStream<S> {
+    stream: S,
+    buffer: BytesMut,
+}
+
+impl<S: AsyncRead + Unpin> MessageStream<S> {
+    fn new(stream: S) -> Self {
+        Self {
+            stream,
+            buffer: BytesMut::new(),
+        }
+    }
+
+    /// Take the next message if it has been received completely.
+    fn next_buffered(&mut self) -> io::Result<Option<Message>> {
+        if self.buffer.len() < 5 {
+            return Ok(None);
+        }
+
+        let len = u32::from_be_bytes(self.buffer[1..5].try_into().unwrap()) as usize;
+        if !(4..=MAX_MESSAGE_SIZE).contains(&len) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid message length {len}"),
+            ));
+        }
+
+        if self.buffer.len() < len + 1 {
+            self.buffer.reserve(len + 1 - self.buffer.len());
+            return Ok(None);
+        }
+
+        let bytes = self.buffer.split_to(len + 1).freeze();
+        Ok(Some(Message { bytes }))
+    }
+
+    /// Receive the next message; `None` means the peer has closed the stream.
+    async fn read(&mut self) -> io::Result<Option<Message>> {
+        loop {
+            if let Some(msg) = self.next_buffered()? {
+                return Ok(Some(msg));
+            }
+
+            if self.stream.read_buf(&mut self.buffer).await? == 0 {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+}
+
+/// Compute connection assigned to the client.
+struct Backend {
+    stream: MessageStream<TcpStream>,
+    version: String,
+    cancel_closure: CancelClosure,
+    guard: PoolGuard,
+    /// Number of queries and syncs which haven't been answered by ReadyForQuery yet.
+    pending: usize,
+    /// Extended query messages have been sent, but not followed by Sync yet.
+    in_extended_query: bool,
+    /// Transaction status from the last ReadyForQuery.
+    status: u8,
+}
+
+impl Backend {
+    fn new(conn: PooledConn, guard: PoolGuard) -> Self {
+        Self {
+            stream: MessageStream::new(conn.stream),
+            version: conn.version,
+            cancel_closure: conn.cancel_closure,
+            guard,
+            pending: 0,
+            in_extended_query: false,
+            status: b'I',
+        }
+    }
+
+    /// Track the messages sent by client to know when compute is done with them.
+    fn on_client_message(&mut self, msg: &Message) {
+        match msg.tag() {
+            // Query & FunctionCall.
+            b'Q' | b'F' => self.pending += 1,
+            // Sync.
+            b'S' => {
+                self.pending += 1;
+                self.in_extended_query = false;
+            }
+            // Parse, Bind, Describe, Execute, Close & Flush.
+            b'P' | b'B' | b'D' | b'E' | b'C' | b'H' => self.in_extended_query = true,
+            // E.g. CopyData, which is a part of a query.
+            _ => {}
+        }
+    }
+
+    fn on_compute_message(&mut self, msg: &Message) {
+        // ReadyForQuery.
+        if msg.tag() == b'Z' {
+            self.pending = self.pending.saturating_sub(1);
+            self.status = msg.body().first().copied().unwrap_or(b'E');
+        }
+    }
+
+    /// Compute has answered everything we've sent and is not in a transaction.
+    fn is_idle(&self) -> bool {
+        self.status == b'I' && self.pending == 0 && !self.in_extended_query
+    }
+
+    /// Get rid of any session state. Returns `false` if compute is not idle afterwards.
+    async fn reset(&mut self) -> io::Result<bool> {
+        let query = b"DISCARD ALL\0";
+        let mut msg = BytesMut::with_capacity(5 + query.len());
+        msg.put_u8(b'Q');
+        msg.put_u32(4 + query.len() as u32);
+        msg.put_slice(query);
+        self.stream.stream.write_all(&msg).await?;
+
+        let mut success = true;
+        while let Some(msg) = self.stream.read().await? {
+            match msg.tag() {
+                // ErrorResponse.
+                b'E' => success = false,
+                b'Z' => return Ok(success && msg.body() == b"I"),
+                _ => {}
+            }
+        }
+
+        Ok(false)
+    }
+
+    fn release(self) {
+        let conn = PooledConn {
+            stream: self.stream.stream,
+            version: self.version,
+            cancel_closure: self.cancel_closure,
+            last_used: Instant::now(),
+        };
+        self.guard.release(conn);
+    }
+}
+
+/// Wait for the next compute message, if there's a connection.
+async fn read_compute(backend: &mut Option<Backend>) -> io::Result<Option<Message>> {
+    match backend {
+        Some(backend) => backend.stream.read().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Proxy the client's traffic, assigning a pooled connection to it for each
+/// transaction. `conn` is the connection we've used to greet the client.
+pub async fn proxy_pass(
+    client: impl AsyncRead + AsyncWrite + Unpin,
+    pool: &ConnPool,
+    key: &PoolKey,
+    node: &NodeInfo,
+    session: &cancellation::Session<'_>,
+    conn: PooledConn,
+    guard: PoolGuard,
+) -> anyhow::Result<()> {
+    let mut client = MessageStream::new(client);
+    let mut backend = Some(Backend::new(conn, guard));
+    let mut pinned = false;
+    let mut output = BytesMut::new();
+
+    let result: anyhow::Result<()> = async {
+        loop {
+            tokio::select! {
+                msg = client.read() => {
+                    let msg = match msg? {
+                        Some(msg) => msg,
+                        None => return Ok(()),
+                    };
+
+                    // Terminate.
+                    if msg.tag() == b'X' {
+                        return Ok(());
+                    }
+
+                    if backend.is_none() {
+                        let (conn, guard) = match pool.acquire(key, node).await {
+                            Ok(x) => x,
+                            Err(e) => {
+                                let msg = e.to_string_client();
+                                output.clear();
+                                BeMessage::write(&mut output, &BeMessage::ErrorResponse(&msg))?;
+                                client.stream.write_all(&output).await?;
+                                client.stream.flush().await?;
+                                bail!(e);
+                            }
+                        };
+                        session.enable_cancellation(conn.cancel_closure.clone());
+                        backend = Some(Backend::new(conn, guard));
+                    }
+                    let compute = backend.as_mut().expect("assigned above");
+
+                    // Forward everything we've received so far at once.
+                    output.clear();
+                    let mut next = Some(msg);
+                    while let Some(msg) = next {
+                        pinned |= creates_session_state(&msg);
+                        compute.on_client_message(&msg);
+                        output.extend_from_slice(&msg.bytes);
+                        next = client.next_buffered()?;
+                    }
+                    compute.stream.stream.write_all(&output).await?;
+                }
+                msg = read_compute(&mut backend) => {
+                    let compute = backend.as_mut().expect("messages come from compute");
+                    let msg = match msg? {
+                        Some(msg) => msg,
+                        None => bail!("compute has closed the connection"),
+                    };
+
+                    output.clear();
+                    let mut next = Some(msg);
+                    while let Some(msg) = next {
+                        compute.on_compute_message(&msg);
+                        output.extend_from_slice(&msg.bytes);
+                        next = compute.stream.next_buffered()?;
+                    }
+                    client.stream.write_all(&output).await?;
+                    client.stream.flush().await?;
+
+                    if !pinned && compute.is_idle() {
+                        session.disable_cancellation();
+                        backend.take().expect("checked above").release();
+                    }
+                }
+            }
+        }
+    }
+    .await;
+
+    // Connection is only worth keeping if the client has left it in a clean state.
+    if let Some(mut compute) = backend.take() {
+        session.disable_cancellation();
+        if compute.is_idle() && (!pinned || compute.reset().await.unwrap_or(false)) {
+            compute.release();
+        }
+    }
+
+    result
+}
+
+/// Check whether the message may create session state, which
+/// would prevent us from passing the connection to other clients.
+fn creates_session_state(msg: &Message) -> bool {
+    let query_creates_session_state = |bytes: &[u8]| match split_cstr(bytes) {
+        Some((query, _)) => query_creates_session_state(&query.to_string_lossy()),
+        None => false,
+    };
+
+    match msg.tag() {
+        // Query.
+        b'Q' => query_creates_session_state(msg.body()),
+        // Parse.
+        b'P' => match split_cstr(msg.body()) {
+            // Named prepared statements outlive transactions.
+            Some((name, _)) if !name.to_bytes().is_empty() => true,
+            Some((_, rest)) => query_creates_session_state(rest),
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+/// This is a rough check which may give false positives, e.g. if a
+/// string literal contains a semicolon. That's fine, since we'll just
+/// keep the connection to ourselves.
+fn query_creates_session_state(query: &str) -> bool {
+    let query = query.to_ascii_lowercase();
+
+    // These functions may be called by any statement. Session level advisory
+    // locks are taken by `pg_[try_]advisory_lock[_shared]`, the name of the
+    // transaction level ones has `xact` in between.
+    if ["advisory_lock", "set_config"]
+        .iter()
+        .any(|f| query.contains(f))
+    {
+        return true;
+    }
+
+    query.split(';').any(|statement| {
+        let mut words = skip_comments(statement).split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("set"), Some("local" | "transaction")) => false,
+            (Some("set" | "reset" | "prepare" | "listen" | "declare" | "load"), _) => true,
+            (Some("create"), Some("temp" | "temporary" | "local" | "global")) => true,
+            _ => false,
+        }
+    })
+}
+
+/// Skip the leading whitespace and comments.
+fn skip_comments(mut statement: &str) -> &str {
+    loop {
+        statement = statement.trim_start();
+        if let Some(rest) = statement.strip_prefix("--") {
+            statement = rest.split_once('\n').map_or("", |(_, rest)| rest);
+        } else if let Some(rest) = statement.strip_prefix("/*") {
+            statement = rest.split_once("*/").map_or("", |(_, rest)| rest);
+        } else {
+            return statement;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_session_state() {
+        for query in [
+            "SET search_path = foo",
+            "select 1; set statement_timeout to 0",
+            "-- comment\n/* another one */ PREPARE q AS SELECT 1",
+            "LISTEN channel",
+            "create temp table t(x int)",
+            "select pg_advisory_lock(1)",
+            "select pg_try_advisory_lock(1)",
+            "select pg_try_advisory_lock_shared(1)",
+        ] {
+            assert!(query_creates_session_state(query), "{query}");
+        }
+
+        for query in [
+            "select 1",
+            "SET LOCAL search_path = foo",
+            "begin; set transaction isolation level serializable; commit",
+            "create table settings(x int)",
+            "select pg_advisory_xact_lock(1)",
+            "select pg_try_advisory_xact_lock(1)",
+        ] {
+            assert!(!query_creates_session_state(query), "{query}");
+        }
+    }
+
+    #[tokio::test]
+    async fn message_stream_framing() -> anyhow::Result<()> {
+        let (mut tx, rx) = tokio::io::duplex(1024);
+        let mut stream = MessageStream::new(rx);
+
+        // Query message split into several writes.
+        let query = b"Q\0\0\0\x0dselect 1\0";
+        tokio::spawn(async move {
+            for part in [&query[..3], &query[3..9], &query[9..]] {
+                tx.write_all(part).await?;
+                tx.flush().await?;
+            }
+            tx.write_all(b"S\0\0\0\x04").await?;
+            anyhow::Ok(())
+        });
+
+        let msg = stream.read().await?.expect("query message");
+        assert_eq!(msg.tag(), b'Q');
+        assert_eq!(msg.body(), b"select 1\0");
+
+        let msg = stream.read().await?.expect("sync message");
+        assert_eq!(msg.tag(), b'S');
+        assert!(msg.body().is_empty());
+
+        assert!(stream.read().await?.is_none());
+
+        Ok(())
+    }
+}
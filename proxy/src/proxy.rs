@@ -1,11 +1,12 @@
-use crate::auth;
 use crate::cancellation::{self, CancelMap};
 use crate::config::{ProxyConfig, TlsConfig};
 use crate::stream::{MetricsStream, PqStream, Stream};
+use crate::{auth, compute, pool, proxy_protocol};
 use anyhow::{bail, Context};
 use futures::TryFutureExt;
 use metrics::{register_int_counter, IntCounter};
 use once_cell::sync::Lazy;
+use std::net::IpAddr;
 use std::sync::Arc;
 use tokio::io::{AsyncRead, AsyncWrite};
 use utils::pq_proto::{BeMessage as Be, *};
@@ -38,7 +39,7 @@ static NUM_BYTES_PROXIED_COUNTER: Lazy<IntCounter> = Lazy::new(|| {
 });
 
 /// A small combinator for pluggable error logging.
-async fn log_error<R, F>(future: F) -> F::Output
+pub async fn log_error<R, F>(future: F) -> F::Output
 where
     F: std::future::Future<Output = anyhow::Result<R>>,
 {
@@ -50,6 +51,7 @@ where
 
 pub async fn thread_main(
     config: &'static ProxyConfig,
+    cancel_map: Arc<CancelMap>,
     listener: tokio::net::TcpListener,
 ) -> anyhow::Result<()> {
     scopeguard::defer! {
@@ -60,9 +62,8 @@ pub async fn thread_main(
     // will be inherited by all accepted client sockets.
     socket2::SockRef::from(&listener).set_keepalive(true)?;
 
-    let cancel_map = Arc::new(CancelMap::default());
     loop {
-        let (socket, peer_addr) = listener.accept().await?;
+        let (mut socket, mut peer_addr) = listener.accept().await?;
         println!("accepted connection from {}", peer_addr);
 
         let cancel_map = Arc::clone(&cancel_map);
@@ -71,7 +72,15 @@ pub async fn thread_main(
                 .set_nodelay(true)
                 .context("failed to set socket option")?;
 
-            handle_client(config, &cancel_map, socket).await
+            // Behind a load balancer, the real client address comes in a header.
+            if config.proxy_protocol {
+                if let Some(addr) = proxy_protocol::read_header(&mut socket).await? {
+                    println!("connection from {peer_addr} is proxied for {addr}");
+                    peer_addr = addr;
+                }
+            }
+
+            handle_client(config, &cancel_map, socket, peer_addr.ip()).await
         }));
     }
 }
@@ -80,6 +89,7 @@ async fn handle_client(
     config: &ProxyConfig,
     cancel_map: &CancelMap,
     stream: impl AsyncRead + AsyncWrite + Unpin + Send,
+    peer_addr: IpAddr,
 ) -> anyhow::Result<()> {
     // The `closed` counter will increase when this future is destroyed.
     NUM_CONNECTIONS_ACCEPTED_COUNTER.inc();
@@ -88,25 +98,79 @@ async fn handle_client(
     }
 
     let tls = config.tls_config.as_ref();
-    let (mut stream, params) = match handshake(stream, tls, cancel_map).await? {
+    let (stream, params) = match handshake(stream, tls, cancel_map).await? {
         Some(x) => x,
         None => return Ok(()), // it's a cancellation request
     };
 
+    let sni = stream.get_ref().sni_hostname().map(str::to_owned);
+    authenticate_and_proxy(
+        config,
+        cancel_map,
+        stream,
+        params,
+        sni.as_deref(),
+        peer_addr,
+    )
+    .await
+}
+
+/// Serve a client which tunnels the postgres protocol through a WebSocket.
+/// TLS (if any) has already been terminated by the http server, which
+/// also gives us the hostname requested by client to be used in place of SNI.
+pub async fn handle_ws_client(
+    config: &ProxyConfig,
+    cancel_map: &CancelMap,
+    stream: impl AsyncRead + AsyncWrite + Unpin + Send,
+    hostname: Option<&str>,
+    peer_addr: IpAddr,
+) -> anyhow::Result<()> {
+    NUM_CONNECTIONS_ACCEPTED_COUNTER.inc();
+    scopeguard::defer! {
+        NUM_CONNECTIONS_CLOSED_COUNTER.inc();
+    }
+
+    // The tunnel is already secure, so we don't ask for another TLS upgrade.
+    let (stream, params) = match handshake(stream, None, cancel_map).await? {
+        Some(x) => x,
+        None => return Ok(()), // it's a cancellation request
+    };
+
+    authenticate_and_proxy(config, cancel_map, stream, params, hostname, peer_addr).await
+}
+
+/// Parse client credentials, let the client authenticate and start proxying.
+async fn authenticate_and_proxy(
+    config: &ProxyConfig,
+    cancel_map: &CancelMap,
+    mut stream: PqStream<Stream<impl AsyncRead + AsyncWrite + Unpin + Send>>,
+    params: StartupMessageParams,
+    sni: Option<&str>,
+    peer_addr: IpAddr,
+) -> anyhow::Result<()> {
     let creds = {
-        let sni = stream.get_ref().sni_hostname();
-        let common_name = tls.and_then(|tls| tls.common_name.as_deref());
+        let common_name = config
+            .tls_config
+            .as_ref()
+            .and_then(|tls| tls.common_name(sni));
         let result = config
             .auth_backend
-            .map(|_| auth::ClientCredentials::parse(params, sni, common_name))
+            .map(|_| auth::ClientCredentials::parse(params, sni, common_name.as_deref()))
             .transpose();
 
         async { result }.or_else(|e| stream.throw_error(e)).await?
     };
 
+    // Check the limits before we bother the control plane or compute.
+    // The permit keeps this connection accounted for until it's closed.
+    let _permit = match config.rate_limiter.check(peer_addr, creds.project()) {
+        Ok(permit) => permit,
+        Err(e) => return stream.throw_error(e).await,
+    };
+
     let client = Client::new(stream, creds);
     cancel_map
-        .with_session(|session| client.connect_to_db(config, session))
+        .with_session(|session| client.connect_to_db(config, session, peer_addr))
         .await
 }
 
@@ -139,9 +203,7 @@ async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
                     if let Some(tls) = tls.take() {
                         // Upgrade raw stream into a secure TLS-backed stream.
                         // NOTE: We've consumed `tls`; this fact will be used later.
-                        stream = PqStream::new(
-                            stream.into_inner().upgrade(tls.to_server_config()).await?,
-                        );
+                        stream = PqStream::new(stream.into_inner().upgrade(tls).await?);
                     }
                 }
                 _ => bail!(ERR_PROTO_VIOLATION),
@@ -176,14 +238,14 @@ async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
 /// Thin connection context.
 struct Client<S> {
     /// The underlying libpq protocol stream.
-    stream: PqStream<S>,
+    stream: PqStream<Stream<S>>,
     /// Client credentials that we care about.
     creds: auth::BackendType<auth::ClientCredentials>,
 }
 
 impl<S> Client<S> {
     /// Construct a new connection context.
-    fn new(stream: PqStream<S>, creds: auth::BackendType<auth::ClientCredentials>) -> Self {
+    fn new(stream: PqStream<Stream<S>>, creds: auth::BackendType<auth::ClientCredentials>) -> Self {
         Self { stream, creds }
     }
 }
@@ -194,31 +256,21 @@ impl<S: AsyncRead + AsyncWrite + Unpin + Send> Client<S> {
         self,
         config: &ProxyConfig,
         session: cancellation::Session<'_>,
+        peer_addr: IpAddr,
     ) -> anyhow::Result<()> {
         let Self { mut stream, creds } = self;
 
-        // Authenticate and connect to a compute node.
-        let auth = creds.authenticate(&config.auth_urls, &mut stream).await;
+        // Authenticate and connect to a compute node. If the project wasn't
+        // known when the connection was let in, its limits are checked now.
+        let mut _project_permit = None;
+        let auth = creds
+            .authenticate(&config.auth_urls, &mut stream, peer_addr, |project| {
+                _project_permit = Some(config.rate_limiter.check_project(project)?);
+                Ok(())
+            })
+            .await;
         let node = async { auth }.or_else(|e| stream.throw_error(e)).await?;
 
-        let (db, cancel_closure) = node.connect().or_else(|e| stream.throw_error(e)).await?;
-        let cancel_key_data = session.enable_cancellation(cancel_closure);
-
-        // Report authentication success if we haven't done this already.
-        if !node.reported_auth_ok {
-            stream
-                .write_message_noflush(&Be::AuthenticationOk)?
-                .write_message_noflush(&BeParameterStatusMessage::encoding())?;
-        }
-
-        stream
-            .write_message_noflush(&BeMessage::ParameterStatus(
-                BeParameterStatusMessage::ServerVersion(&db.version),
-            ))?
-            .write_message_noflush(&Be::BackendKeyData(cancel_key_data))?
-            .write_message(&BeMessage::ReadyForQuery)
-            .await?;
-
         /// This function will be called for writes to either direction.
         fn inc_proxied(cnt: usize) {
             // Consider inventing something more sophisticated
@@ -226,6 +278,23 @@ impl<S: AsyncRead + AsyncWrite + Unpin + Send> Client<S> {
             NUM_BYTES_PROXIED_COUNTER.inc_by(cnt as u64);
         }
 
+        if let (Some(pool), Some(key)) = (&config.conn_pool, &node.pool_key) {
+            let (conn, guard) = pool
+                .acquire(key, &node)
+                .or_else(|e| stream.throw_error(e))
+                .await?;
+            let cancel_key_data = session.enable_cancellation(conn.cancel_closure.clone());
+            greet_client(&mut stream, &node, &conn.version, cancel_key_data).await?;
+
+            // Compute connections will be assigned to the client per transaction.
+            let client = MetricsStream::new(stream.into_inner(), inc_proxied);
+            return pool::proxy_pass(client, pool, key, &node, &session, conn, guard).await;
+        }
+
+        let (db, cancel_closure) = node.connect().or_else(|e| stream.throw_error(e)).await?;
+        let cancel_key_data = session.enable_cancellation(cancel_closure);
+        greet_client(&mut stream, &node, &db.version, cancel_key_data).await?;
+
         // Starting from here we only proxy the client's traffic.
         let mut db = MetricsStream::new(db.stream, inc_proxied);
         let mut client = MetricsStream::new(stream.into_inner(), inc_proxied);
@@ -235,9 +304,35 @@ impl<S: AsyncRead + AsyncWrite + Unpin + Send> Client<S> {
     }
 }
 
+/// Tell the client that it may start sending queries.
+async fn greet_client(
+    stream: &mut PqStream<impl AsyncRead + AsyncWrite + Unpin>,
+    node: &compute::NodeInfo,
+    version: &str,
+    cancel_key_data: CancelKeyData,
+) -> anyhow::Result<()> {
+    // Report authentication success if we haven't done this already.
+    if !node.reported_auth_ok {
+        stream
+            .write_message_noflush(&Be::AuthenticationOk)?
+            .write_message_noflush(&BeParameterStatusMessage::encoding())?;
+    }
+
+    stream
+        .write_message_noflush(&BeMessage::ParameterStatus(
+            BeParameterStatusMessage::ServerVersion(version),
+        ))?
+        .write_message_noflush(&Be::BackendKeyData(cancel_key_data))?
+        .write_message(&BeMessage::ReadyForQuery)
+        .await?;
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::certs::{CertEntry, CertResolver};
     use crate::{auth, scram};
     use async_trait::async_trait;
     use rstest::rstest;
@@ -248,6 +343,7 @@ mod tests {
     /// Generate a set of TLS certificates: CA + server.
     fn generate_certs(
         hostname: &str,
+        common_name: &str,
     ) -> anyhow::Result<(rustls::Certificate, rustls::Certificate, rustls::PrivateKey)> {
         let ca = rcgen::Certificate::from_params({
             let mut params = rcgen::CertificateParams::default();
@@ -255,7 +351,14 @@ mod tests {
             params
         })?;
 
-        let cert = rcgen::generate_simple_self_signed(vec![hostname.into()])?;
+        let cert = rcgen::Certificate::from_params({
+            let mut params = rcgen::CertificateParams::new(vec![hostname.into()]);
+            params
+                .distinguished_name
+                .push(rcgen::DnType::CommonName, format!("*.{common_name}"));
+            params
+        })?;
+
         Ok((
             rustls::Certificate(ca.serialize_der()?),
             rustls::Certificate(cert.serialize_der_with_signer(&ca)?),
@@ -283,19 +386,11 @@ mod tests {
         hostname: &'a str,
         common_name: &'a str,
     ) -> anyhow::Result<(ClientConfig<'a>, TlsConfig)> {
-        let (ca, cert, key) = generate_certs(hostname)?;
+        let (ca, cert, key) = generate_certs(hostname, common_name)?;
 
         let tls_config = {
-            let config = rustls::ServerConfig::builder()
-                .with_safe_defaults()
-                .with_no_client_auth()
-                .with_single_cert(vec![cert], key)?
-                .into();
-
-            TlsConfig {
-                config,
-                common_name: Some(common_name.to_string()),
-            }
+            let entry = CertEntry::new(vec![cert], &key)?;
+            TlsConfig::new(Arc::new(CertResolver::from_entries(vec![entry])))?
         };
 
         let client_config = {
@@ -0,0 +1,240 @@
+//! [PROXY protocol](https://www.haproxy.org/download/2.6/doc/proxy-protocol.txt)
+//! header sent by TCP load balancers to tell us the real client address.
+//! Both the human-readable v1 and the binary v2 formats are supported.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V1_SIGNATURE: &[u8; 5] = b"PROXY";
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Including the signature and the trailing CRLF, as defined by the spec.
+const V1_MAX_LENGTH: usize = 107;
+
+/// We don't care about the TLVs, but let's not read garbage forever.
+const V2_MAX_LENGTH: usize = 4096;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("PROXY protocol: {msg}"))
+}
+
+/// Read the header, which should precede anything else sent by the load balancer.
+/// Returns `None` if the connection wasn't proxied (e.g. it's a health check),
+/// in which case the peer address of the socket should be used.
+///
+/// We read exactly the header, so the stream can be used as usual afterwards.
+pub async fn read_header(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Option<SocketAddr>> {
+    // Both signatures are longer than that, and the shortest v1 header is 15 bytes.
+    let mut signature = [0u8; 5];
+    stream.read_exact(&mut signature).await?;
+
+    if &signature == V1_SIGNATURE {
+        read_v1(stream).await
+    } else if signature == V2_SIGNATURE[..5] {
+        read_v2(stream).await
+    } else {
+        Err(invalid("bad signature"))
+    }
+}
+
+/// Parse the rest of `PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n`.
+async fn read_v1(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Option<SocketAddr>> {
+    // Since we mustn't consume anything past the header, we have to read
+    // it byte by byte. That's fine, since it's short and sent just once.
+    let mut line = Vec::with_capacity(V1_MAX_LENGTH);
+    line.extend_from_slice(V1_SIGNATURE);
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(invalid("header is too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[V1_SIGNATURE.len()..line.len() - 2])
+        .map_err(|_| invalid("header is not valid ASCII"))?;
+    parse_v1_addresses(line)
+}
+
+fn parse_v1_addresses(line: &str) -> io::Result<Option<SocketAddr>> {
+    let bad_address = || invalid("malformed address");
+    let mut fields = line.strip_prefix(' ').ok_or_else(bad_address)?.split(' ');
+
+    match fields.next() {
+        Some("TCP4" | "TCP6") => {}
+        // The rest of the line should be ignored, per spec.
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(invalid("unsupported protocol")),
+    }
+
+    let src_ip: IpAddr = fields
+        .next()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(bad_address)?;
+    let _dst_ip = fields.next().ok_or_else(bad_address)?;
+    let src_port: u16 = fields
+        .next()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(bad_address)?;
+
+    Ok(Some(SocketAddr::new(src_ip, src_port)))
+}
+
+async fn read_v2(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Option<SocketAddr>> {
+    let mut header = [0u8; 16];
+    header[..5].copy_from_slice(&V2_SIGNATURE[..5]);
+    stream.read_exact(&mut header[5..]).await?;
+    if &header[..12] != V2_SIGNATURE {
+        return Err(invalid("bad signature"));
+    }
+
+    let (version, command) = (header[12] >> 4, header[12] & 0x0f);
+    if version != 2 {
+        return Err(invalid("unsupported version"));
+    }
+
+    let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+    if len > V2_MAX_LENGTH {
+        return Err(invalid("header is too long"));
+    }
+
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload).await?;
+
+    match command {
+        // LOCAL: the connection was made by the load balancer itself.
+        0x0 => Ok(None),
+        // PROXY: the addresses follow.
+        0x1 => parse_v2_addresses(header[13], &payload),
+        _ => Err(invalid("unsupported command")),
+    }
+}
+
+/// Extract the source address; the destination and TLVs are ignored.
+fn parse_v2_addresses(family: u8, payload: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let short = || invalid("address block is too short");
+    let port = |bytes: &[u8]| u16::from_be_bytes([bytes[0], bytes[1]]);
+
+    // The high nibble is the address family, the low one is the transport.
+    let addr = match family >> 4 {
+        // AF_INET: src addr (4), dst addr (4), src port (2), dst port (2).
+        0x1 => {
+            let block = payload.get(..12).ok_or_else(short)?;
+            let ip: [u8; 4] = block[..4].try_into().unwrap();
+            SocketAddr::new(IpAddr::from(ip), port(&block[8..10]))
+        }
+        // AF_INET6: src addr (16), dst addr (16), src port (2), dst port (2).
+        0x2 => {
+            let block = payload.get(..36).ok_or_else(short)?;
+            let ip: [u8; 16] = block[..16].try_into().unwrap();
+            SocketAddr::new(IpAddr::from(ip), port(&block[32..34]))
+        }
+        // AF_UNSPEC, AF_UNIX: there's no address we could use.
+        _ => return Ok(None),
+    };
+
+    Ok(Some(addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    fn v2_header(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[tokio::test]
+    async fn parse_v2() -> anyhow::Result<()> {
+        let (mut tx, mut rx) = tokio::io::duplex(1024);
+
+        // TCP over IPv4: 10.0.0.1:5678 -> 10.0.0.2:5432, followed by the startup packet.
+        let addresses = [10, 0, 0, 1, 10, 0, 0, 2, 0x16, 0x2e, 0x15, 0x38];
+        tx.write_all(&v2_header(0x1, 0x11, &addresses)).await?;
+        tx.write_all(b"startup").await?;
+
+        let addr = read_header(&mut rx).await?;
+        assert_eq!(addr, Some("10.0.0.1:5678".parse()?));
+
+        // Nothing past the header has been consumed.
+        let mut rest = [0u8; 7];
+        rx.read_exact(&mut rest).await?;
+        assert_eq!(&rest, b"startup");
+
+        // TCP over IPv6 with a trailing TLV.
+        let mut addresses = Vec::new();
+        addresses.extend_from_slice(&"2001:db8::1".parse::<std::net::Ipv6Addr>()?.octets());
+        addresses.extend_from_slice(&[0; 16]);
+        addresses.extend_from_slice(&[0x16, 0x2e, 0x15, 0x38]);
+        addresses.extend_from_slice(&[0x04, 0x00, 0x01, 0xff]);
+        tx.write_all(&v2_header(0x1, 0x21, &addresses)).await?;
+        let addr = read_header(&mut rx).await?;
+        assert_eq!(addr, Some("[2001:db8::1]:5678".parse()?));
+
+        // LOCAL command, e.g. a health check.
+        tx.write_all(&v2_header(0x0, 0x00, &[])).await?;
+        assert_eq!(read_header(&mut rx).await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn parse_v1() -> anyhow::Result<()> {
+        let (mut tx, mut rx) = tokio::io::duplex(1024);
+
+        tx.write_all(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 5432\r\nstartup")
+            .await?;
+        let addr = read_header(&mut rx).await?;
+        assert_eq!(addr, Some("192.168.0.1:56324".parse()?));
+
+        // Nothing past the header has been consumed.
+        let mut rest = [0u8; 7];
+        rx.read_exact(&mut rest).await?;
+        assert_eq!(&rest, b"startup");
+
+        tx.write_all(b"PROXY TCP6 2001:db8::1 2001:db8::2 4567 5432\r\n")
+            .await?;
+        let addr = read_header(&mut rx).await?;
+        assert_eq!(addr, Some("[2001:db8::1]:4567".parse()?));
+
+        tx.write_all(b"PROXY UNKNOWN whatever\r\n").await?;
+        assert_eq!(read_header(&mut rx).await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reject_malformed_v1() -> anyhow::Result<()> {
+        for header in [
+            &b"PROXY TCP4 192.168.0.1\r\n"[..],
+            b"PROXY UDP4 192.168.0.1 192.168.0.11 56324 5432\r\n",
+            b"PROXY TCP4 localhost 192.168.0.11 56324 5432\r\n",
+            &[b'P'; 200],
+        ] {
+            let (mut tx, mut rx) = tokio::io::duplex(1024);
+            tx.write_all(header).await?;
+            assert!(read_header(&mut rx).await.is_err());
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reject_malformed_v2() -> anyhow::Result<()> {
+        let (mut tx, mut rx) = tokio::io::duplex(1024);
+        tx.write_all(b"\0\0\0\x08\x04\xd2\x16\x2fgarbage!").await?;
+        assert!(read_header(&mut rx).await.is_err());
+
+        let (mut tx, mut rx) = tokio::io::duplex(1024);
+        tx.write_all(&v2_header(0x1, 0x11, &[10, 0, 0, 1])).await?;
+        assert!(read_header(&mut rx).await.is_err());
+
+        Ok(())
+    }
+}
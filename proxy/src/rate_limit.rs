@@ -0,0 +1,328 @@
+//! Limits on new and concurrent client connections, which protect compute
+//! nodes and the control plane from misbehaving clients.
+
+use crate::error::UserFacingError;
+use hashbrown::HashMap;
+use metrics::{register_int_counter_vec, IntCounterVec};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::time::Instant;
+use thiserror::Error;
+
+static RATE_LIMITED_CONNECTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proxy_rate_limited_connections_total",
+        "Number of client connections rejected by rate limits, by reason.",
+        &["reason"]
+    )
+    .unwrap()
+});
+
+/// Buckets which have been refilled completely carry no information,
+/// so we forget them once there are too many buckets around.
+const MAX_BUCKETS_BEFORE_CLEANUP: usize = 10_000;
+
+/// Rate limiter settings, see `--*-rate-limit` CLI options.
+/// Zero rate or connection count disables the corresponding limit.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RateLimitOptions {
+    /// New connections per second per project.
+    pub project_rate: f64,
+    pub project_burst: f64,
+    /// New connections per second per client IP address.
+    pub ip_rate: f64,
+    pub ip_burst: f64,
+    /// Maximal number of concurrent connections per project.
+    pub max_project_connections: usize,
+}
+
+#[derive(Debug, Error)]
+pub enum RateLimitError {
+    #[error("Too many connection attempts from this address, please try again later")]
+    Ip,
+
+    #[error("Too many connection attempts to this project, please try again later")]
+    Project,
+
+    #[error("Too many concurrent connections to this project")]
+    Concurrency,
+}
+
+impl UserFacingError for RateLimitError {}
+
+impl RateLimitError {
+    fn reason(&self) -> &'static str {
+        use RateLimitError::*;
+        match self {
+            Ip => "ip",
+            Project => "project",
+            Concurrency => "concurrency",
+        }
+    }
+
+    fn report(self) -> Self {
+        RATE_LIMITED_CONNECTIONS
+            .with_label_values(&[self.reason()])
+            .inc();
+        self
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    /// Refill the bucket according to the time passed since the last update.
+    fn refill(&mut self, now: Instant, rate: f64, burst: f64) {
+        let elapsed = now.saturating_duration_since(self.updated_at);
+        self.tokens = f64::min(burst, self.tokens + elapsed.as_secs_f64() * rate);
+        self.updated_at = now;
+    }
+}
+
+/// Token buckets for a set of keys (e.g. projects or IP addresses).
+struct Buckets<K> {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<K, TokenBucket>>,
+}
+
+impl<K: Hash + Eq> Buckets<K> {
+    fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            // A bucket should hold at least one token, or nothing will ever pass.
+            burst: f64::max(burst, 1.0),
+            buckets: Default::default(),
+        }
+    }
+
+    /// Take a token from the key's bucket. Returns `false` if it's empty.
+    fn try_acquire(&self, key: K, now: Instant) -> bool {
+        if self.rate <= 0.0 {
+            return true;
+        }
+
+        let mut buckets = self.buckets.lock();
+        if buckets.len() >= MAX_BUCKETS_BEFORE_CLEANUP {
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.retain(|_, bucket| {
+                bucket.refill(now, rate, burst);
+                bucket.tokens < burst
+            });
+        }
+
+        let bucket = buckets.entry(key).or_insert(TokenBucket {
+            tokens: self.burst,
+            updated_at: now,
+        });
+
+        bucket.refill(now, self.rate, self.burst);
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+pub struct RateLimiter {
+    projects: Buckets<String>,
+    ips: Buckets<IpAddr>,
+    max_project_connections: usize,
+    /// Number of live connections per project.
+    project_connections: Mutex<HashMap<String, usize>>,
+}
+
+/// Keeps the connection accounted for in the concurrency limit.
+#[must_use]
+pub struct ConnectionPermit<'a> {
+    limiter: &'a RateLimiter,
+    project: Option<String>,
+}
+
+impl Drop for ConnectionPermit<'_> {
+    fn drop(&mut self) {
+        if let Some(project) = self.project.take() {
+            let mut connections = self.limiter.project_connections.lock();
+            if let Some(count) = connections.get_mut(&project) {
+                *count -= 1;
+                if *count == 0 {
+                    connections.remove(&project);
+                }
+            }
+        }
+    }
+}
+
+impl RateLimiter {
+    pub fn new(options: RateLimitOptions) -> Self {
+        Self {
+            projects: Buckets::new(options.project_rate, options.project_burst),
+            ips: Buckets::new(options.ip_rate, options.ip_burst),
+            max_project_connections: options.max_project_connections,
+            project_connections: Default::default(),
+        }
+    }
+
+    /// Check all limits for a new connection. `project` may be unknown
+    /// at this point, in which case only the address is limited.
+    /// The returned permit should be kept while the connection is alive.
+    pub fn check(
+        &self,
+        addr: IpAddr,
+        project: Option<&str>,
+    ) -> Result<ConnectionPermit<'_>, RateLimitError> {
+        if !self.ips.try_acquire(addr, Instant::now()) {
+            return Err(RateLimitError::Ip.report());
+        }
+
+        match project {
+            Some(project) => self.check_project(project),
+            None => Ok(ConnectionPermit {
+                limiter: self,
+                project: None,
+            }),
+        }
+    }
+
+    /// Check the project limits for a connection which has been let in by
+    /// [`Self::check`] before its project was known, e.g. because the client
+    /// has passed the project name in the password.
+    pub fn check_project(&self, project: &str) -> Result<ConnectionPermit<'_>, RateLimitError> {
+        let now = Instant::now();
+        if !self.projects.try_acquire(project.to_owned(), now) {
+            return Err(RateLimitError::Project.report());
+        }
+
+        if self.max_project_connections == 0 {
+            return Ok(ConnectionPermit {
+                limiter: self,
+                project: None,
+            });
+        }
+
+        let mut connections = self.project_connections.lock();
+        let count = connections.entry(project.to_owned()).or_default();
+        if *count >= self.max_project_connections {
+            return Err(RateLimitError::Concurrency.report());
+        }
+        *count += 1;
+
+        Ok(ConnectionPermit {
+            limiter: self,
+            project: Some(project.to_owned()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn token_bucket_refill() {
+        let buckets = Buckets::new(10.0, 2.0);
+        let start = Instant::now();
+
+        assert!(buckets.try_acquire("foo", start));
+        assert!(buckets.try_acquire("foo", start));
+        assert!(!buckets.try_acquire("foo", start));
+        // Other keys have their own buckets.
+        assert!(buckets.try_acquire("bar", start));
+
+        // 10 tokens per second => one token per 100ms.
+        assert!(!buckets.try_acquire("foo", start + Duration::from_millis(50)));
+        assert!(buckets.try_acquire("foo", start + Duration::from_millis(150)));
+
+        // Tokens don't accumulate beyond the burst size.
+        let later = start + Duration::from_secs(60);
+        assert!(buckets.try_acquire("foo", later));
+        assert!(buckets.try_acquire("foo", later));
+        assert!(!buckets.try_acquire("foo", later));
+    }
+
+    #[test]
+    fn disabled_limits() {
+        let limiter = RateLimiter::new(RateLimitOptions::default());
+        let addr = IpAddr::from([127, 0, 0, 1]);
+
+        let permits: Vec<_> = (0..1000)
+            .map(|_| limiter.check(addr, Some("foo")))
+            .collect::<Result<_, _>>()
+            .expect("nothing should be limited");
+        assert_eq!(permits.len(), 1000);
+    }
+
+    #[test]
+    fn concurrent_connections() {
+        let limiter = RateLimiter::new(RateLimitOptions {
+            max_project_connections: 2,
+            ..Default::default()
+        });
+        let addr = IpAddr::from([127, 0, 0, 1]);
+
+        let first = limiter.check(addr, Some("foo")).unwrap();
+        let _second = limiter.check(addr, Some("foo")).unwrap();
+        assert!(matches!(
+            limiter.check(addr, Some("foo")),
+            Err(RateLimitError::Concurrency)
+        ));
+
+        // Other projects and clients without a known project aren't affected.
+        let _bar = limiter.check(addr, Some("bar")).unwrap();
+        let _unknown = limiter.check(addr, None).unwrap();
+
+        drop(first);
+        let _third = limiter.check(addr, Some("foo")).unwrap();
+    }
+
+    #[test]
+    fn ip_limit() {
+        let limiter = RateLimiter::new(RateLimitOptions {
+            ip_rate: 1.0,
+            ip_burst: 1.0,
+            ..Default::default()
+        });
+
+        let _permit = limiter.check(IpAddr::from([10, 0, 0, 1]), None).unwrap();
+        assert!(matches!(
+            limiter.check(IpAddr::from([10, 0, 0, 1]), Some("foo")),
+            Err(RateLimitError::Ip)
+        ));
+        let _permit = limiter.check(IpAddr::from([10, 0, 0, 2]), None).unwrap();
+    }
+
+    #[test]
+    fn project_resolved_later() {
+        let limiter = RateLimiter::new(RateLimitOptions {
+            project_rate: 1.0,
+            project_burst: 1.0,
+            max_project_connections: 1,
+            ..Default::default()
+        });
+        let addr = IpAddr::from([127, 0, 0, 1]);
+
+        let _permit = limiter.check(addr, None).unwrap();
+        let project_permit = limiter.check_project("foo").unwrap();
+        assert!(matches!(
+            limiter.check_project("foo"),
+            Err(RateLimitError::Project)
+        ));
+
+        // The connection is accounted for once its project is known.
+        limiter.projects.buckets.lock().remove("foo");
+        assert!(matches!(
+            limiter.check_project("foo"),
+            Err(RateLimitError::Concurrency)
+        ));
+        drop(project_permit);
+        let _permit = limiter.check_project("foo").unwrap();
+    }
+}
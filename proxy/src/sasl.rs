@@ -43,8 +43,7 @@ impl UserFacingError for Error {
         match self {
             // This constructor contains the reason why auth has failed.
             AuthenticationFailed(s) => s.to_string(),
-            // TODO: add support for channel binding
-            ChannelBindingFailed(_) => "channel binding is not supported yet".to_string(),
+            ChannelBindingFailed(s) => format!("channel binding failed: {s}"),
             ChannelBindingBadMethod(m) => format!("unsupported channel binding method {m}"),
             _ => "authentication protocol violation".to_string(),
         }
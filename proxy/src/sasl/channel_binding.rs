@@ -36,9 +36,9 @@ impl<'a> ChannelBinding<&'a str> {
 
 impl<T: std::fmt::Display> ChannelBinding<T> {
     /// Encode channel binding data as base64 for subsequent checks.
-    pub fn encode<E>(
+    pub fn encode<D: AsRef<[u8]>, E>(
         &self,
-        get_cbind_data: impl FnOnce(&T) -> Result<String, E>,
+        get_cbind_data: impl FnOnce(&T) -> Result<D, E>,
     ) -> Result<std::borrow::Cow<'static, str>, E> {
         use ChannelBinding::*;
         Ok(match self {
@@ -51,11 +51,9 @@ impl<T: std::fmt::Display> ChannelBinding<T> {
                 "eSws".into()
             }
             Required(mode) => {
-                let msg = format!(
-                    "p={mode},,{data}",
-                    mode = mode,
-                    data = get_cbind_data(mode)?
-                );
+                // Channel binding data is binary, e.g. a certificate hash.
+                let mut msg = format!("p={mode},,").into_bytes();
+                msg.extend_from_slice(get_cbind_data(mode)?.as_ref());
                 base64::encode(msg).into()
             }
         })
@@ -73,11 +71,11 @@ mod tests {
         let cases = [
             (NotSupportedClient, base64::encode("n,,")),
             (NotSupportedServer, base64::encode("y,,")),
-            (Required("foo"), base64::encode("p=foo,,bar")),
+            (Required("foo"), base64::encode(b"p=foo,,\xde\xad")),
         ];
 
         for (cb, input) in cases {
-            assert_eq!(cb.encode(|_| anyhow::Ok("bar".to_owned()))?, input);
+            assert_eq!(cb.encode(|_| anyhow::Ok([0xde_u8, 0xad]))?, input);
         }
 
         Ok(())
@@ -15,7 +15,7 @@ mod signature;
 #[cfg(test)]
 mod password;
 
-pub use exchange::Exchange;
+pub use exchange::{ChannelBindingPolicy, Exchange};
 pub use key::ScramKey;
 pub use secret::ServerSecret;
 pub use secret::*;
@@ -23,9 +23,14 @@ pub use secret::*;
 use hmac::{Hmac, Mac};
 use sha2::{Digest, Sha256};
 
-// TODO: add SCRAM-SHA-256-PLUS
-/// A list of supported SCRAM methods.
-pub const METHODS: &[&str] = &["SCRAM-SHA-256"];
+pub const SCRAM_SHA_256: &str = "SCRAM-SHA-256";
+pub const SCRAM_SHA_256_PLUS: &str = "SCRAM-SHA-256-PLUS";
+
+/// A list of supported SCRAM methods, most preferred first.
+pub const METHODS: &[&str] = &[SCRAM_SHA_256_PLUS, SCRAM_SHA_256];
+
+/// Channel binding requires TLS, so that's all we can offer without it.
+pub const METHODS_WITHOUT_PLUS: &[&str] = &[SCRAM_SHA_256];
 
 /// Decode base64 into array without any heap allocations
 fn base64_decode_array<const N: usize>(input: impl AsRef<[u8]>) -> Option<[u8; N]> {
@@ -27,6 +27,43 @@ impl std::str::FromStr for TlsServerEndPoint {
     }
 }
 
+/// What we expect from the client regarding channel binding,
+/// given the SASL method it has chosen.
+#[derive(Debug, Clone, Copy)]
+pub enum ChannelBindingPolicy<'a> {
+    /// We haven't offered `SCRAM-SHA-256-PLUS`, so the client can't use channel binding.
+    NotOffered,
+    /// We've offered `SCRAM-SHA-256-PLUS`, but the client has chosen plain `SCRAM-SHA-256`.
+    Declined,
+    /// The client has chosen `SCRAM-SHA-256-PLUS`,
+    /// so it must prove that it sees the same certificate (with this digest).
+    Required(&'a [u8]),
+}
+
+impl ChannelBindingPolicy<'_> {
+    /// Check the client's channel binding flag, which must be consistent with the chosen method.
+    fn check<T>(&self, cbind_flag: &ChannelBinding<T>) -> sasl::Result<()> {
+        use ChannelBinding as Flag;
+        use ChannelBindingPolicy::*;
+        match (self, cbind_flag) {
+            (NotOffered, Flag::NotSupportedClient | Flag::NotSupportedServer) => Ok(()),
+            (Declined, Flag::NotSupportedClient) => Ok(()),
+            (Required(_), Flag::Required(_)) => Ok(()),
+            // The client supports channel binding, yet it hasn't seen our offer.
+            // Someone might have stripped `SCRAM-SHA-256-PLUS` from our message.
+            (Declined, Flag::NotSupportedServer) => Err(SaslError::ChannelBindingFailed(
+                "client believes that the server doesn't support it",
+            )),
+            (NotOffered | Declined, Flag::Required(_)) => Err(SaslError::ChannelBindingFailed(
+                "it may only be used with SCRAM-SHA-256-PLUS",
+            )),
+            (Required(_), Flag::NotSupportedClient | Flag::NotSupportedServer) => Err(
+                SaslError::ChannelBindingFailed("SCRAM-SHA-256-PLUS requires channel binding"),
+            ),
+        }
+    }
+}
+
 enum ExchangeState {
     /// Waiting for [`ClientFirstMessage`].
     Initial,
@@ -43,20 +80,20 @@ pub struct Exchange<'a> {
     state: ExchangeState,
     secret: &'a ServerSecret,
     nonce: fn() -> [u8; SCRAM_RAW_NONCE_LEN],
-    cert_digest: Option<&'a [u8]>,
+    channel_binding: ChannelBindingPolicy<'a>,
 }
 
 impl<'a> Exchange<'a> {
     pub fn new(
         secret: &'a ServerSecret,
         nonce: fn() -> [u8; SCRAM_RAW_NONCE_LEN],
-        cert_digest: Option<&'a [u8]>,
+        channel_binding: ChannelBindingPolicy<'a>,
     ) -> Self {
         Self {
             state: ExchangeState::Initial,
             secret,
             nonce,
-            cert_digest,
+            channel_binding,
         }
     }
 }
@@ -78,8 +115,11 @@ impl sasl::Mechanism for Exchange<'_> {
                 );
                 let msg = server_first_message.as_str().to_owned();
 
+                let cbind_flag = client_first_message.cbind_flag.and_then(str::parse)?;
+                self.channel_binding.check(&cbind_flag)?;
+
                 self.state = SaltSent {
-                    cbind_flag: client_first_message.cbind_flag.and_then(str::parse)?,
+                    cbind_flag,
                     client_first_message_bare: client_first_message.bare.to_owned(),
                     server_first_message,
                 };
@@ -94,10 +134,9 @@ impl sasl::Mechanism for Exchange<'_> {
                 let client_final_message =
                     ClientFinalMessage::parse(input).ok_or(SaslError::BadClientMessage)?;
 
-                let channel_binding = cbind_flag.encode(|_| {
-                    self.cert_digest
-                        .map(base64::encode)
-                        .ok_or(SaslError::ChannelBindingFailed("no cert digest provided"))
+                let channel_binding = cbind_flag.encode(|_| match self.channel_binding {
+                    ChannelBindingPolicy::Required(cert_digest) => Ok(cert_digest),
+                    _ => Err(SaslError::ChannelBindingFailed("no cert digest provided")),
                 })?;
 
                 // This might've been caused by a MITM attack
@@ -133,3 +172,78 @@ impl sasl::Mechanism for Exchange<'_> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sasl::{Mechanism, Step};
+
+    const CERT_DIGEST: &[u8] = b"digest";
+
+    fn exchange(channel_binding: ChannelBindingPolicy<'_>, cbind_flag: &str) -> sasl::Result<()> {
+        let secret = ServerSecret::mock("user", &[0; 32]);
+        let exchange = Exchange::new(&secret, || [0; SCRAM_RAW_NONCE_LEN], channel_binding);
+
+        let client_first_message = format!("{cbind_flag},,n=,r=clientnonce");
+        let (step, server_first_message) = exchange.exchange(&client_first_message)?;
+        let exchange = match step {
+            Step::Continue(exchange) => exchange,
+            Step::Authenticated(_) => panic!("exchange has finished too early"),
+        };
+
+        // We don't bother computing the proof, since it's checked
+        // after the channel binding data; wrong proof means success.
+        let nonce = server_first_message
+            .split(',')
+            .find_map(|s| s.strip_prefix("r="))
+            .expect("no nonce");
+        let cbind_data = ChannelBinding::parse(cbind_flag)
+            .expect("bad flag")
+            .encode(|_| sasl::Result::Ok(CERT_DIGEST))?;
+        let proof = base64::encode([0; 32]);
+        let client_final_message = format!("c={cbind_data},r={nonce},p={proof}");
+
+        match exchange.exchange(&client_final_message) {
+            Err(SaslError::AuthenticationFailed(_)) => Ok(()),
+            Err(e) => Err(e),
+            Ok(_) => panic!("the proof should not match"),
+        }
+    }
+
+    #[test]
+    fn channel_binding_negotiation() {
+        use ChannelBindingPolicy::*;
+
+        let good = [
+            (NotOffered, "n"),
+            (NotOffered, "y"),
+            (Declined, "n"),
+            (Required(CERT_DIGEST), "p=tls-server-end-point"),
+        ];
+        for (policy, flag) in good {
+            exchange(policy, flag).unwrap_or_else(|e| panic!("{policy:?}, {flag}: {e}"));
+        }
+
+        let bad = [
+            // Someone has stripped SCRAM-SHA-256-PLUS from the list of methods.
+            (Declined, "y"),
+            (NotOffered, "p=tls-server-end-point"),
+            (Declined, "p=tls-server-end-point"),
+            (Required(CERT_DIGEST), "n"),
+            (Required(CERT_DIGEST), "y"),
+            // The client has seen a different certificate.
+            (Required(b"other digest"), "p=tls-server-end-point"),
+            (Required(CERT_DIGEST), "p=tls-unique"),
+        ];
+        for (policy, flag) in bad {
+            let res = exchange(policy, flag);
+            assert!(
+                matches!(
+                    res,
+                    Err(SaslError::ChannelBindingFailed(_) | SaslError::ChannelBindingBadMethod(_))
+                ),
+                "{policy:?}, {flag}"
+            );
+        }
+    }
+}
@@ -0,0 +1,123 @@
+//! Database access for clients which can't open raw TCP connections,
+//! e.g. serverless and edge functions. Such clients are served on a separate
+//! listener in two flavors:
+//!
+//! * WebSocket connections tunneling the postgres protocol, see [`websocket`];
+//! * "POST a query, get JSON rows" http requests, see [`sql_over_http`].
+//!
+//! Passwords come in plain text with both of them, so the listener always
+//! requires TLS.
+
+mod sql_over_http;
+mod websocket;
+
+use crate::cancellation::CancelMap;
+use crate::config::ProxyConfig;
+use crate::proxy::log_error;
+use crate::proxy_protocol;
+use crate::stream::Stream;
+use anyhow::Context;
+use hyper::{header, service::service_fn, Body, Method, Request, Response, StatusCode};
+use std::{convert::Infallible, net::IpAddr, sync::Arc};
+use tokio::net::TcpListener;
+
+pub async fn thread_main(
+    config: &'static ProxyConfig,
+    cancel_map: Arc<CancelMap>,
+    listener: TcpListener,
+) -> anyhow::Result<()> {
+    scopeguard::defer! {
+        println!("wss has shut down");
+    }
+
+    // When set for the server socket, the keepalive setting
+    // will be inherited by all accepted client sockets.
+    socket2::SockRef::from(&listener).set_keepalive(true)?;
+
+    loop {
+        let (mut socket, mut peer_addr) = listener.accept().await?;
+        println!("accepted wss connection from {}", peer_addr);
+
+        let cancel_map = Arc::clone(&cancel_map);
+        tokio::spawn(log_error(async move {
+            socket
+                .set_nodelay(true)
+                .context("failed to set socket option")?;
+
+            // The header precedes the TLS handshake, just like on the postgres listener.
+            if config.proxy_protocol {
+                if let Some(addr) = proxy_protocol::read_header(&mut socket).await? {
+                    println!("wss connection from {peer_addr} is proxied for {addr}");
+                    peer_addr = addr;
+                }
+            }
+
+            let tls = config
+                .tls_config
+                .as_ref()
+                .context("wss listener requires TLS")?;
+            let stream = Stream::from_raw(socket).upgrade(tls).await?;
+
+            let service = service_fn(move |req| {
+                let cancel_map = Arc::clone(&cancel_map);
+                async move {
+                    let response = handle_request(config, cancel_map, req, peer_addr.ip()).await;
+                    Ok::<_, Infallible>(response)
+                }
+            });
+
+            hyper::server::conn::Http::new()
+                .serve_connection(stream, service)
+                .with_upgrades()
+                .await?;
+
+            Ok(())
+        }));
+    }
+}
+
+async fn handle_request(
+    config: &'static ProxyConfig,
+    cancel_map: Arc<CancelMap>,
+    req: Request<Body>,
+    peer_addr: IpAddr,
+) -> Response<Body> {
+    if websocket::is_upgrade_request(&req) {
+        // The hostname serves the same purpose as SNI in the postgres protocol.
+        let hostname = request_hostname(&req);
+        return websocket::upgrade(config, cancel_map, req, hostname, peer_addr);
+    }
+
+    match (req.method(), req.uri().path()) {
+        (&Method::POST, "/sql") => sql_over_http::handle(config, req, peer_addr).await,
+        _ => json_error(StatusCode::NOT_FOUND, "Not found"),
+    }
+}
+
+/// Extract the hostname requested by the client, without port.
+fn request_hostname(req: &Request<Body>) -> Option<String> {
+    let host = match req.uri().host() {
+        Some(host) => host,
+        None => req.headers().get(header::HOST)?.to_str().ok()?,
+    };
+
+    let host = match host.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => host,
+        _ => host,
+    };
+
+    Some(host.to_owned())
+}
+
+fn json_response(status: StatusCode, value: &serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(value.to_string()))
+        .expect("response parts should be valid")
+}
+
+/// Make a response carrying an error message which is safe to show to the client.
+fn json_error(status: StatusCode, message: &str) -> Response<Body> {
+    json_response(status, &serde_json::json!({ "message": message }))
+}
@@ -0,0 +1,205 @@
+//! Postgres protocol tunneled through WebSocket binary messages.
+//! Once the http connection is upgraded, the client is served by
+//! [`crate::proxy::handle_ws_client`] just like a regular one.
+
+use super::json_error;
+use crate::{cancellation::CancelMap, config::ProxyConfig, error::io_error, proxy};
+use anyhow::Context;
+use bytes::{Buf, Bytes};
+use futures::{ready, Sink, Stream};
+use hyper::{header, Body, Request, Response, StatusCode};
+use pin_project_lite::pin_project;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::{io, task};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::{handshake::derive_accept_key, protocol::Role, Message};
+use tokio_tungstenite::WebSocketStream;
+
+/// Check whether the client asks us to switch to the WebSocket protocol.
+pub fn is_upgrade_request(req: &Request<Body>) -> bool {
+    let header_contains = |name, token: &str| {
+        req.headers()
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    };
+
+    header_contains(header::CONNECTION, "upgrade") && header_contains(header::UPGRADE, "websocket")
+}
+
+/// Accept the WebSocket handshake and spawn a task which will serve the
+/// client once hyper is done with the http part of the connection.
+pub fn upgrade(
+    config: &'static ProxyConfig,
+    cancel_map: Arc<CancelMap>,
+    mut req: Request<Body>,
+    hostname: Option<String>,
+    peer_addr: IpAddr,
+) -> Response<Body> {
+    let key = match req.headers().get(header::SEC_WEBSOCKET_KEY) {
+        Some(key) => key,
+        None => return json_error(StatusCode::BAD_REQUEST, "Missing Sec-WebSocket-Key"),
+    };
+
+    if req
+        .headers()
+        .get(header::SEC_WEBSOCKET_VERSION)
+        .map(|v| v.as_bytes())
+        != Some(b"13")
+    {
+        return json_error(StatusCode::BAD_REQUEST, "Unsupported WebSocket version");
+    }
+
+    let accept_key = derive_accept_key(key.as_bytes());
+    let on_upgrade = hyper::upgrade::on(&mut req);
+
+    tokio::spawn(proxy::log_error(async move {
+        let upgraded = on_upgrade.await.context("WebSocket upgrade failed")?;
+        let ws = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+
+        proxy::handle_ws_client(
+            config,
+            &cancel_map,
+            WebSocketRw::new(ws),
+            hostname.as_deref(),
+            peer_addr,
+        )
+        .await
+    }));
+
+    Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::CONNECTION, "upgrade")
+        .header(header::UPGRADE, "websocket")
+        .header(header::SEC_WEBSOCKET_ACCEPT, accept_key)
+        .body(Body::empty())
+        .expect("response parts should be valid")
+}
+
+pin_project! {
+    /// Exposes a WebSocket as a plain byte stream.
+    /// Each write is sent as a separate binary message,
+    /// while reads don't care about message boundaries.
+    pub struct WebSocketRw<S> {
+        #[pin]
+        stream: WebSocketStream<S>,
+        // Unread part of the last received message.
+        chunk: Bytes,
+    }
+}
+
+impl<S> WebSocketRw<S> {
+    pub fn new(stream: WebSocketStream<S>) -> Self {
+        Self {
+            stream,
+            chunk: Bytes::new(),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocketRw<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        context: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> task::Poll<io::Result<()>> {
+        let mut this = self.project();
+        loop {
+            if !this.chunk.is_empty() {
+                let len = std::cmp::min(this.chunk.len(), buf.remaining());
+                buf.put_slice(&this.chunk[..len]);
+                this.chunk.advance(len);
+                return task::Poll::Ready(Ok(()));
+            }
+
+            let res = match ready!(this.stream.as_mut().poll_next(context)) {
+                Some(Ok(Message::Binary(data))) => {
+                    *this.chunk = Bytes::from(data);
+                    continue;
+                }
+                // Pings are answered by tungstenite itself.
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => continue,
+                Some(Ok(Message::Text(_))) => Err(io_error("unexpected text message")),
+                // Report EOF.
+                Some(Ok(Message::Close(_))) | None => Ok(()),
+                Some(Err(e)) => Err(io_error(e)),
+            };
+
+            return task::Poll::Ready(res);
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocketRw<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        context: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> task::Poll<io::Result<usize>> {
+        let mut this = self.project();
+        ready!(this.stream.as_mut().poll_ready(context)).map_err(io_error)?;
+        this.stream
+            .start_send(Message::Binary(buf.to_vec()))
+            .map_err(io_error)?;
+
+        task::Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        context: &mut task::Context<'_>,
+    ) -> task::Poll<io::Result<()>> {
+        self.project().stream.poll_flush(context).map_err(io_error)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        context: &mut task::Context<'_>,
+    ) -> task::Poll<io::Result<()>> {
+        self.project().stream.poll_close(context).map_err(io_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn websocket_rw_roundtrip() -> anyhow::Result<()> {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+        let mut server =
+            WebSocketRw::new(WebSocketStream::from_raw_socket(server, Role::Server, None).await);
+
+        // Message boundaries and pings should be invisible to the reader.
+        client.send(Message::Binary(b"hello ".to_vec())).await?;
+        client.send(Message::Ping(b"ping".to_vec())).await?;
+        client.send(Message::Binary(b"world".to_vec())).await?;
+
+        let mut buf = [0u8; 11];
+        server.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello world");
+
+        server.write_all(b"reply").await?;
+        server.flush().await?;
+        loop {
+            match client.next().await.context("unexpected end of stream")?? {
+                Message::Pong(_) => continue,
+                Message::Binary(data) => break assert_eq!(data, b"reply"),
+                other => anyhow::bail!("unexpected message: {other:?}"),
+            }
+        }
+
+        // Close message is reported as EOF.
+        client.close(None).await?;
+        assert_eq!(server.read(&mut buf).await?, 0);
+
+        Ok(())
+    }
+}
@@ -1,8 +1,8 @@
+use crate::config::TlsConfig;
 use crate::error::UserFacingError;
 use anyhow::bail;
 use bytes::BytesMut;
 use pin_project_lite::pin_project;
-use rustls::ServerConfig;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::{io, task};
@@ -136,7 +136,8 @@ pin_project! {
         /// which may then be upgraded into a secure stream.
         Raw { #[pin] raw: S },
         /// We box [`TlsStream`] since it can be quite large.
-        Tls { #[pin] tls: Box<TlsStream<S>> },
+        /// We also keep the channel binding data of the certificate we've presented.
+        Tls { #[pin] tls: Box<TlsStream<S>>, cert_digest: Option<Arc<[u8]>> },
     }
 }
 
@@ -150,7 +151,15 @@ impl<S> Stream<S> {
     pub fn sni_hostname(&self) -> Option<&str> {
         match self {
             Stream::Raw { .. } => None,
-            Stream::Tls { tls } => tls.get_ref().1.sni_hostname(),
+            Stream::Tls { tls, .. } => tls.get_ref().1.sni_hostname(),
+        }
+    }
+
+    /// Return `tls-server-end-point` channel binding data if TLS is active.
+    pub fn cert_digest(&self) -> Option<&Arc<[u8]>> {
+        match self {
+            Stream::Raw { .. } => None,
+            Stream::Tls { cert_digest, .. } => cert_digest.as_ref(),
         }
     }
 }
@@ -167,11 +176,21 @@ pub enum StreamUpgradeError {
 
 impl<S: AsyncRead + AsyncWrite + Unpin> Stream<S> {
     /// If possible, upgrade raw stream into a secure TLS-based stream.
-    pub async fn upgrade(self, cfg: Arc<ServerConfig>) -> Result<Self, StreamUpgradeError> {
+    pub async fn upgrade(self, cfg: &TlsConfig) -> Result<Self, StreamUpgradeError> {
         match self {
             Stream::Raw { raw } => {
-                let tls = Box::new(tokio_rustls::TlsAcceptor::from(cfg).accept(raw).await?);
-                Ok(Stream::Tls { tls })
+                let (config, resolver) = cfg.to_conn_server_config();
+                let acceptor = tokio_rustls::TlsAcceptor::from(config);
+                let tls = Box::new(acceptor.accept(raw).await?);
+
+                // A resumed session doesn't present a certificate, so the best we
+                // can do is to take the one the client would get in a full handshake.
+                let entry = resolver.chosen().unwrap_or_else(|| {
+                    let sni = tls.get_ref().1.sni_hostname();
+                    cfg.certs.find(sni)
+                });
+                let cert_digest = entry.cert_digest().cloned();
+                Ok(Stream::Tls { tls, cert_digest })
             }
             Stream::Tls { .. } => Err(StreamUpgradeError::AlreadyTls),
         }
@@ -187,7 +206,7 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for Stream<S> {
         use StreamProj::*;
         match self.project() {
             Raw { raw } => raw.poll_read(context, buf),
-            Tls { tls } => tls.poll_read(context, buf),
+            Tls { tls, .. } => tls.poll_read(context, buf),
         }
     }
 }
@@ -201,7 +220,7 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Stream<S> {
         use StreamProj::*;
         match self.project() {
             Raw { raw } => raw.poll_write(context, buf),
-            Tls { tls } => tls.poll_write(context, buf),
+            Tls { tls, .. } => tls.poll_write(context, buf),
         }
     }
 
@@ -212,7 +231,7 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Stream<S> {
         use StreamProj::*;
         match self.project() {
             Raw { raw } => raw.poll_flush(context),
-            Tls { tls } => tls.poll_flush(context),
+            Tls { tls, .. } => tls.poll_flush(context),
         }
     }
 
@@ -223,7 +242,7 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Stream<S> {
         use StreamProj::*;
         match self.project() {
             Raw { raw } => raw.poll_shutdown(context),
-            Tls { tls } => tls.poll_shutdown(context),
+            Tls { tls, .. } => tls.poll_shutdown(context),
         }
     }
 }
@@ -134,6 +134,22 @@ fn main() -> anyhow::Result<()> {
                 .default_missing_value("true")
                 .help("Enable/disable WAL backup to s3. When disabled, safekeeper removes WAL ignoring WAL backup horizon."),
         )
+        .arg(
+            Arg::new("wal-backup-compression")
+                .long("wal-backup-compression")
+                .takes_value(true)
+                .default_value("false")
+                .default_missing_value("true")
+                .help("Enable/disable compression of WAL segments uploaded to s3. Compressed segments get .zst suffix."),
+        )
+        .arg(
+            Arg::new("local-wal-compression")
+                .long("local-wal-compression")
+                .takes_value(true)
+                .default_value("false")
+                .default_missing_value("true")
+                .help("Enable/disable compression of local WAL segments which are behind commit_lsn."),
+        )
         .arg(
             Arg::new("auth-validation-public-key-path")
                 .long("auth-validation-public-key-path")
@@ -211,6 +227,16 @@ fn main() -> anyhow::Result<()> {
         .unwrap()
         .parse()
         .context("failed to parse bool enable-s3-offload bool")?;
+    conf.wal_backup_compression = arg_matches
+        .value_of("wal-backup-compression")
+        .unwrap()
+        .parse()
+        .context("failed to parse bool wal-backup-compression")?;
+    conf.local_wal_compression = arg_matches
+        .value_of("local-wal-compression")
+        .unwrap()
+        .parse()
+        .context("failed to parse bool local-wal-compression")?;
 
     conf.auth_validation_public_key_path = arg_matches
         .value_of("auth-validation-public-key-path")
@@ -245,6 +245,34 @@ impl Storage for FileStorage {
     }
 }
 
+/// InMemoryStorage keeps the state in memory only, every persist is
+/// immediately durable. Used to test consensus logic without touching disk.
+#[derive(Debug, Clone)]
+pub struct InMemoryStorage {
+    state: SafeKeeperState,
+}
+
+impl InMemoryStorage {
+    pub fn new(state: SafeKeeperState) -> InMemoryStorage {
+        InMemoryStorage { state }
+    }
+}
+
+impl Deref for InMemoryStorage {
+    type Target = SafeKeeperState;
+
+    fn deref(&self) -> &Self::Target {
+        &self.state
+    }
+}
+
+impl Storage for InMemoryStorage {
+    fn persist(&mut self, s: &SafeKeeperState) -> Result<()> {
+        self.state = s.clone();
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::FileStorage;
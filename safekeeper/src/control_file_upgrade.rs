@@ -2,6 +2,7 @@
 use crate::safekeeper::{
     AcceptorState, Peers, PgUuid, SafeKeeperState, ServerInfo, Term, TermHistory, TermSwitchEntry,
 };
+use crate::wal_retention::WalRetentionPolicy;
 use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
 use tracing::*;
@@ -140,6 +141,122 @@ pub struct SafeKeeperStateV4 {
     pub peers: Peers,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SafeKeeperStateV6 {
+    #[serde(with = "hex")]
+    pub tenant_id: ZTenantId,
+    /// Zenith timelineid
+    #[serde(with = "hex")]
+    pub timeline_id: ZTimelineId,
+    /// persistent acceptor state
+    pub acceptor_state: AcceptorState,
+    /// information about server
+    pub server: ServerInfo,
+    /// Unique id of the last *elected* proposer we dealt with. Not needed
+    /// for correctness, exists for monitoring purposes.
+    #[serde(with = "hex")]
+    pub proposer_uuid: PgUuid,
+    /// Since which LSN this timeline generally starts. Safekeeper might have
+    /// joined later.
+    pub timeline_start_lsn: Lsn,
+    /// Since which LSN safekeeper has (had) WAL for this timeline.
+    pub local_start_lsn: Lsn,
+    /// Part of WAL acknowledged by quorum and available locally. Always points
+    /// to record boundary.
+    pub commit_lsn: Lsn,
+    /// LSN that points to the end of the last backed up segment.
+    pub backup_lsn: Lsn,
+    /// Minimal LSN which may be needed for recovery of some safekeeper.
+    pub peer_horizon_lsn: Lsn,
+    /// LSN of the oldest known checkpoint made by pageserver and successfully
+    /// pushed to s3.
+    pub remote_consistent_lsn: Lsn,
+    // Peers and their state as we remember it.
+    pub peers: Peers,
+}
+
+impl From<SafeKeeperStateV6> for SafeKeeperState {
+    fn from(oldstate: SafeKeeperStateV6) -> Self {
+        SafeKeeperState {
+            tenant_id: oldstate.tenant_id,
+            timeline_id: oldstate.timeline_id,
+            acceptor_state: oldstate.acceptor_state,
+            server: oldstate.server,
+            proposer_uuid: oldstate.proposer_uuid,
+            timeline_start_lsn: oldstate.timeline_start_lsn,
+            local_start_lsn: oldstate.local_start_lsn,
+            commit_lsn: oldstate.commit_lsn,
+            backup_lsn: oldstate.backup_lsn,
+            peer_horizon_lsn: oldstate.peer_horizon_lsn,
+            remote_consistent_lsn: oldstate.remote_consistent_lsn,
+            peers: oldstate.peers,
+            wal_retention: WalRetentionPolicy::default(),
+            remote_removed_lsn: Lsn(0),
+            slots: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SafeKeeperStateV7 {
+    #[serde(with = "hex")]
+    pub tenant_id: ZTenantId,
+    /// Zenith timelineid
+    #[serde(with = "hex")]
+    pub timeline_id: ZTimelineId,
+    /// persistent acceptor state
+    pub acceptor_state: AcceptorState,
+    /// information about server
+    pub server: ServerInfo,
+    /// Unique id of the last *elected* proposer we dealt with. Not needed
+    /// for correctness, exists for monitoring purposes.
+    #[serde(with = "hex")]
+    pub proposer_uuid: PgUuid,
+    /// Since which LSN this timeline generally starts. Safekeeper might have
+    /// joined later.
+    pub timeline_start_lsn: Lsn,
+    /// Since which LSN safekeeper has (had) WAL for this timeline.
+    pub local_start_lsn: Lsn,
+    /// Part of WAL acknowledged by quorum and available locally. Always points
+    /// to record boundary.
+    pub commit_lsn: Lsn,
+    /// LSN that points to the end of the last backed up segment.
+    pub backup_lsn: Lsn,
+    /// Minimal LSN which may be needed for recovery of some safekeeper.
+    pub peer_horizon_lsn: Lsn,
+    /// LSN of the oldest known checkpoint made by pageserver and successfully
+    /// pushed to s3.
+    pub remote_consistent_lsn: Lsn,
+    // Peers and their state as we remember it.
+    pub peers: Peers,
+    /// Operator defined WAL retention settings of the timeline.
+    pub wal_retention: WalRetentionPolicy,
+    /// LSN up to which offloaded WAL was removed from remote storage.
+    pub remote_removed_lsn: Lsn,
+}
+
+impl From<SafeKeeperStateV7> for SafeKeeperState {
+    fn from(oldstate: SafeKeeperStateV7) -> Self {
+        SafeKeeperState {
+            tenant_id: oldstate.tenant_id,
+            timeline_id: oldstate.timeline_id,
+            acceptor_state: oldstate.acceptor_state,
+            server: oldstate.server,
+            proposer_uuid: oldstate.proposer_uuid,
+            timeline_start_lsn: oldstate.timeline_start_lsn,
+            local_start_lsn: oldstate.local_start_lsn,
+            commit_lsn: oldstate.commit_lsn,
+            backup_lsn: oldstate.backup_lsn,
+            peer_horizon_lsn: oldstate.peer_horizon_lsn,
+            remote_consistent_lsn: oldstate.remote_consistent_lsn,
+            peers: oldstate.peers,
+            wal_retention: oldstate.wal_retention,
+            remote_removed_lsn: oldstate.remote_removed_lsn,
+            slots: Vec::new(),
+        }
+    }
+}
+
 pub fn upgrade_control_file(buf: &[u8], version: u32) -> Result<SafeKeeperState> {
     // migrate to storing full term history
     if version == 1 {
@@ -169,6 +286,9 @@ pub fn upgrade_control_file(buf: &[u8], version: u32) -> Result<SafeKeeperState>
             peer_horizon_lsn: oldstate.truncate_lsn,
             remote_consistent_lsn: Lsn(0),
             peers: Peers(vec![]),
+            wal_retention: WalRetentionPolicy::default(),
+            remote_removed_lsn: Lsn(0),
+            slots: Vec::new(),
         });
     // migrate to hexing some zids
     } else if version == 2 {
@@ -192,6 +312,9 @@ pub fn upgrade_control_file(buf: &[u8], version: u32) -> Result<SafeKeeperState>
             peer_horizon_lsn: oldstate.truncate_lsn,
             remote_consistent_lsn: Lsn(0),
             peers: Peers(vec![]),
+            wal_retention: WalRetentionPolicy::default(),
+            remote_removed_lsn: Lsn(0),
+            slots: Vec::new(),
         });
     // migrate to moving ztenantid/ztli to the top and adding some lsns
     } else if version == 3 {
@@ -215,6 +338,9 @@ pub fn upgrade_control_file(buf: &[u8], version: u32) -> Result<SafeKeeperState>
             peer_horizon_lsn: oldstate.truncate_lsn,
             remote_consistent_lsn: Lsn(0),
             peers: Peers(vec![]),
+            wal_retention: WalRetentionPolicy::default(),
+            remote_removed_lsn: Lsn(0),
+            slots: Vec::new(),
         });
     // migrate to having timeline_start_lsn
     } else if version == 4 {
@@ -238,12 +364,15 @@ pub fn upgrade_control_file(buf: &[u8], version: u32) -> Result<SafeKeeperState>
             peer_horizon_lsn: oldstate.peer_horizon_lsn,
             remote_consistent_lsn: Lsn(0),
             peers: Peers(vec![]),
+            wal_retention: WalRetentionPolicy::default(),
+            remote_removed_lsn: Lsn(0),
+            slots: Vec::new(),
         });
     } else if version == 5 {
         info!("reading safekeeper control file version {}", version);
-        let mut oldstate = SafeKeeperState::des(&buf[..buf.len()])?;
+        let mut oldstate = SafeKeeperStateV6::des(&buf[..buf.len()])?;
         if oldstate.timeline_start_lsn != Lsn(0) {
-            return Ok(oldstate);
+            return Ok(oldstate.into());
         }
 
         // set special timeline_start_lsn because we don't know the real one
@@ -251,7 +380,17 @@ pub fn upgrade_control_file(buf: &[u8], version: u32) -> Result<SafeKeeperState>
         oldstate.timeline_start_lsn = Lsn(1);
         oldstate.local_start_lsn = Lsn(1);
 
-        return Ok(oldstate);
+        return Ok(oldstate.into());
+    // migrate to having WAL retention policy
+    } else if version == 6 {
+        info!("reading safekeeper control file version {}", version);
+        let oldstate = SafeKeeperStateV6::des(&buf[..buf.len()])?;
+        return Ok(oldstate.into());
+    // migrate to having logical replication slots
+    } else if version == 7 {
+        info!("reading safekeeper control file version {}", version);
+        let oldstate = SafeKeeperStateV7::des(&buf[..buf.len()])?;
+        return Ok(oldstate.into());
     }
     bail!("unsupported safekeeper control file version {}", version)
 }
@@ -35,6 +35,9 @@ pub struct SafekeeperPostgresHandler {
 enum SafekeeperPostgresCommand {
     StartWalPush,
     StartReplication { start_lsn: Lsn },
+    StartLogicalReplication { slot_name: String, start_lsn: Lsn },
+    CreateLogicalSlot { slot_name: String, plugin: String },
+    DropSlot { slot_name: String },
     IdentifySystem,
     JSONCtrl { cmd: AppendLogicalMessage },
 }
@@ -42,6 +45,20 @@ enum SafekeeperPostgresCommand {
 fn parse_cmd(cmd: &str) -> Result<SafekeeperPostgresCommand> {
     if cmd.starts_with("START_WAL_PUSH") {
         Ok(SafekeeperPostgresCommand::StartWalPush)
+    } else if cmd.starts_with("START_REPLICATION SLOT") {
+        // Options for the output plugin, if any, are ignored: WAL is streamed
+        // as is and decoding is up to the consumer, see RAW_WAL_PLUGIN.
+        let re = Regex::new(
+            r#"START_REPLICATION SLOT "?([[:word:]]+)"? LOGICAL ([[:xdigit:]]+/[[:xdigit:]]+)"#,
+        )
+        .unwrap();
+        let caps = re.captures(cmd).context(
+            "failed to parse START_REPLICATION SLOT command, only LOGICAL slots are supported",
+        )?;
+        Ok(SafekeeperPostgresCommand::StartLogicalReplication {
+            slot_name: caps[1].to_owned(),
+            start_lsn: caps[2].parse::<Lsn>()?,
+        })
     } else if cmd.starts_with("START_REPLICATION") {
         let re =
             Regex::new(r"START_REPLICATION(?: PHYSICAL)? ([[:xdigit:]]+/[[:xdigit:]]+)").unwrap();
@@ -51,6 +68,31 @@ fn parse_cmd(cmd: &str) -> Result<SafekeeperPostgresCommand> {
             .map(|cap| cap[1].parse::<Lsn>())
             .context("failed to parse start LSN from START_REPLICATION command")??;
         Ok(SafekeeperPostgresCommand::StartReplication { start_lsn })
+    } else if cmd.starts_with("CREATE_REPLICATION_SLOT") {
+        let re = Regex::new(
+            r#"CREATE_REPLICATION_SLOT "?([[:word:]]+)"?( TEMPORARY)? LOGICAL "?([[:word:]]+)"?"#,
+        )
+        .unwrap();
+        let caps = re.captures(cmd).context(
+            "failed to parse CREATE_REPLICATION_SLOT command, only LOGICAL slots are supported",
+        )?;
+        // Slots are persisted in the control file, nothing would drop a
+        // temporary one and it would hold WAL back forever.
+        if caps.get(2).is_some() {
+            bail!("temporary replication slots are not supported");
+        }
+        Ok(SafekeeperPostgresCommand::CreateLogicalSlot {
+            slot_name: caps[1].to_owned(),
+            plugin: caps[3].to_owned(),
+        })
+    } else if cmd.starts_with("DROP_REPLICATION_SLOT") {
+        let re = Regex::new(r#"DROP_REPLICATION_SLOT "?([[:word:]]+)"?"#).unwrap();
+        let caps = re
+            .captures(cmd)
+            .context("failed to parse DROP_REPLICATION_SLOT command")?;
+        Ok(SafekeeperPostgresCommand::DropSlot {
+            slot_name: caps[1].to_owned(),
+        })
     } else if cmd.starts_with("IDENTIFY_SYSTEM") {
         Ok(SafekeeperPostgresCommand::IdentifySystem)
     } else if cmd.starts_with("JSON_CTRL") {
@@ -95,8 +137,10 @@ impl postgres_backend::Handler for SafekeeperPostgresHandler {
             query_string, self.ztimelineid
         );
 
-        let create = !(matches!(cmd, SafekeeperPostgresCommand::StartReplication { .. })
-            || matches!(cmd, SafekeeperPostgresCommand::IdentifySystem));
+        let create = matches!(
+            cmd,
+            SafekeeperPostgresCommand::StartWalPush | SafekeeperPostgresCommand::JSONCtrl { .. }
+        );
 
         let tenantid = self.ztenantid.context("tenantid is required")?;
         let timelineid = self.ztimelineid.context("timelineid is required")?;
@@ -113,8 +157,22 @@ impl postgres_backend::Handler for SafekeeperPostgresHandler {
                 .run(self)
                 .context("failed to run ReceiveWalConn"),
             SafekeeperPostgresCommand::StartReplication { start_lsn } => ReplicationConn::new(pgb)
-                .run(self, pgb, start_lsn)
+                .run(self, pgb, start_lsn, None)
+                .context("failed to run ReplicationConn"),
+            SafekeeperPostgresCommand::StartLogicalReplication {
+                slot_name,
+                start_lsn,
+            } => ReplicationConn::new(pgb)
+                .run(self, pgb, start_lsn, Some(slot_name))
                 .context("failed to run ReplicationConn"),
+            SafekeeperPostgresCommand::CreateLogicalSlot { slot_name, plugin } => {
+                self.handle_create_slot(pgb, &slot_name, &plugin)
+            }
+            SafekeeperPostgresCommand::DropSlot { slot_name } => {
+                self.timeline.get().drop_slot(&slot_name)?;
+                pgb.write_message(&BeMessage::CommandComplete(b"DROP_REPLICATION_SLOT"))?;
+                Ok(())
+            }
             SafekeeperPostgresCommand::IdentifySystem => self.handle_identify_system(pgb),
             SafekeeperPostgresCommand::JSONCtrl { ref cmd } => handle_json_ctrl(self, pgb, cmd),
         }
@@ -208,6 +266,54 @@ impl SafekeeperPostgresHandler {
         Ok(())
     }
 
+    ///
+    /// Handle CREATE_REPLICATION_SLOT replication command
+    ///
+    fn handle_create_slot(
+        &mut self,
+        pgb: &mut PostgresBackend,
+        slot_name: &str,
+        plugin: &str,
+    ) -> Result<()> {
+        let slot = self.timeline.get().create_slot(slot_name, plugin)?;
+        let consistent_point = slot.restart_lsn.to_string();
+
+        pgb.write_message_noflush(&BeMessage::RowDescription(&[
+            RowDescriptor {
+                name: b"slot_name",
+                typoid: TEXT_OID,
+                typlen: -1,
+                ..Default::default()
+            },
+            RowDescriptor {
+                name: b"consistent_point",
+                typoid: TEXT_OID,
+                typlen: -1,
+                ..Default::default()
+            },
+            RowDescriptor {
+                name: b"snapshot_name",
+                typoid: TEXT_OID,
+                typlen: -1,
+                ..Default::default()
+            },
+            RowDescriptor {
+                name: b"output_plugin",
+                typoid: TEXT_OID,
+                typlen: -1,
+                ..Default::default()
+            },
+        ]))?
+        .write_message_noflush(&BeMessage::DataRow(&[
+            Some(slot.name.as_bytes()),
+            Some(consistent_point.as_bytes()),
+            None,
+            Some(slot.plugin.as_bytes()),
+        ]))?
+        .write_message(&BeMessage::CommandComplete(b"CREATE_REPLICATION_SLOT"))?;
+        Ok(())
+    }
+
     /// Returns true if current connection is a replication connection, originating
     /// from a walproposer recovery function. This connection gets a special handling:
     /// safekeeper must stream all local WAL till the flush_lsn, whether committed or not.
@@ -215,3 +321,29 @@ impl SafekeeperPostgresHandler {
         self.appname == Some("wal_proposer_recovery".to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_slot_commands() {
+        let cmd = parse_cmd(r#"CREATE_REPLICATION_SLOT "cdc" LOGICAL "neon_raw_wal""#).unwrap();
+        assert!(matches!(
+            cmd,
+            SafekeeperPostgresCommand::CreateLogicalSlot { slot_name, plugin }
+                if slot_name == "cdc" && plugin == "neon_raw_wal"
+        ));
+
+        // Nothing would drop it
+        assert!(parse_cmd("CREATE_REPLICATION_SLOT cdc TEMPORARY LOGICAL neon_raw_wal").is_err());
+        assert!(parse_cmd("CREATE_REPLICATION_SLOT cdc PHYSICAL").is_err());
+
+        let cmd = parse_cmd("START_REPLICATION SLOT cdc LOGICAL 0/16B3748").unwrap();
+        assert!(matches!(
+            cmd,
+            SafekeeperPostgresCommand::StartLogicalReplication { slot_name, start_lsn }
+                if slot_name == "cdc" && start_lsn == Lsn(0x16B3748)
+        ));
+    }
+}
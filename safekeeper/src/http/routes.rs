@@ -3,13 +3,17 @@ use hyper::{Body, Request, Response, StatusCode, Uri};
 use once_cell::sync::Lazy;
 use serde::Serialize;
 use serde::Serializer;
+use std::cmp::min;
 use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
+use std::str::FromStr;
 use std::sync::Arc;
 
 use crate::safekeeper::Term;
 use crate::safekeeper::TermHistory;
 use crate::timeline::{GlobalTimelines, TimelineDeleteForceResult};
+use crate::wal_inspect;
+use crate::wal_retention::WalRetentionPolicy;
 use crate::SafeKeeperConf;
 use etcd_broker::subscription_value::SkTimelineInfo;
 use utils::{
@@ -27,6 +31,10 @@ use utils::{
 
 use super::models::TimelineCreateRequest;
 
+/// Number of WAL records decoded by default in a single request.
+const DEFAULT_WAL_RECORDS_LIMIT: usize = 1000;
+const MAX_WAL_RECORDS_LIMIT: usize = 10000;
+
 #[derive(Debug, Serialize)]
 struct SafekeeperStatus {
     id: NodeId,
@@ -47,6 +55,27 @@ fn get_conf(request: &Request<Body>) -> &SafeKeeperConf {
         .as_ref()
 }
 
+/// Parse optional query param of the request.
+fn parse_query_param<T: FromStr>(
+    request: &Request<Body>,
+    param_name: &str,
+) -> Result<Option<T>, ApiError> {
+    request
+        .uri()
+        .query()
+        .and_then(|query| {
+            url::form_urlencoded::parse(query.as_bytes())
+                .into_owned()
+                .find(|(name, _)| name == param_name)
+        })
+        .map(|(_, value)| {
+            value
+                .parse()
+                .map_err(|_| ApiError::BadRequest(format!("failed to parse {}", param_name)))
+        })
+        .transpose()
+}
+
 /// Serialize through Display trait.
 fn display_serialize<S, F>(z: &F, s: S) -> Result<S::Ok, S::Error>
 where
@@ -86,6 +115,9 @@ struct TimelineStatus {
     peer_horizon_lsn: Lsn,
     #[serde(serialize_with = "display_serialize")]
     remote_consistent_lsn: Lsn,
+    wal_retention: WalRetentionPolicy,
+    #[serde(serialize_with = "display_serialize")]
+    remote_removed_lsn: Lsn,
 }
 
 /// Report info about timeline.
@@ -118,10 +150,123 @@ async fn timeline_status_handler(request: Request<Body>) -> Result<Response<Body
         backup_lsn: inmem.backup_lsn,
         peer_horizon_lsn: inmem.peer_horizon_lsn,
         remote_consistent_lsn: inmem.remote_consistent_lsn,
+        wal_retention: state.wal_retention,
+        remote_removed_lsn: state.remote_removed_lsn,
     };
     json_response(StatusCode::OK, status)
 }
 
+/// Report term and term history of the timeline.
+async fn timeline_acceptor_state_handler(
+    request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    let zttid = ZTenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    check_permission(&request, Some(zttid.tenant_id))?;
+
+    let tli = GlobalTimelines::get(get_conf(&request), zttid, false).map_err(ApiError::from_err)?;
+    let (_, state) = tli.get_state();
+    let flush_lsn = tli.get_end_of_wal();
+
+    let acc_state = AcceptorStateStatus {
+        term: state.acceptor_state.term,
+        epoch: state.acceptor_state.get_epoch(flush_lsn),
+        term_history: state.acceptor_state.term_history,
+    };
+    json_response(StatusCode::OK, acc_state)
+}
+
+/// Report persisted term history of the timeline. Unlike the one reported
+/// in acceptor state, it is not cut at flush_lsn.
+async fn timeline_term_history_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let zttid = ZTenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    check_permission(&request, Some(zttid.tenant_id))?;
+
+    let tli = GlobalTimelines::get(get_conf(&request), zttid, false).map_err(ApiError::from_err)?;
+    let (_, state) = tli.get_state();
+    json_response(StatusCode::OK, state.acceptor_state.term_history)
+}
+
+/// List WAL segment files of the timeline.
+async fn timeline_wal_segments_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let zttid = ZTenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    check_permission(&request, Some(zttid.tenant_id))?;
+
+    let conf = get_conf(&request);
+    // make sure the timeline exists
+    GlobalTimelines::get(conf, zttid, false).map_err(ApiError::from_err)?;
+    let segments =
+        wal_inspect::list_segments(&conf.timeline_dir(&zttid)).map_err(ApiError::from_err)?;
+    json_response(StatusCode::OK, segments)
+}
+
+/// Decode headers of WAL records starting at `start_lsn` query param, which
+/// must point to the beginning of a record, up to `end_lsn` (end of WAL by
+/// default), at most `limit` of them.
+async fn timeline_wal_records_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let zttid = ZTenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    check_permission(&request, Some(zttid.tenant_id))?;
+    let start_lsn: Lsn = parse_query_param(&request, "start_lsn")?
+        .ok_or_else(|| ApiError::BadRequest("no start_lsn specified".to_string()))?;
+    let limit = min(
+        parse_query_param(&request, "limit")?.unwrap_or(DEFAULT_WAL_RECORDS_LIMIT),
+        MAX_WAL_RECORDS_LIMIT,
+    );
+
+    let conf = get_conf(&request);
+    let tli = GlobalTimelines::get(conf, zttid, false).map_err(ApiError::from_err)?;
+    let (_, state) = tli.get_state();
+    let flush_lsn = tli.get_end_of_wal();
+    let end_lsn = parse_query_param(&request, "end_lsn")?.unwrap_or(flush_lsn);
+
+    let timeline_dir = conf.timeline_dir(&zttid);
+    let enable_remote_read = conf.wal_backup_enabled;
+    let records = tokio::task::spawn_blocking(move || {
+        wal_inspect::decode_records(
+            timeline_dir,
+            &state,
+            start_lsn,
+            end_lsn,
+            flush_lsn,
+            limit,
+            enable_remote_read,
+        )
+    })
+    .await
+    .map_err(ApiError::from_err)?
+    .map_err(ApiError::from_err)?;
+    json_response(StatusCode::OK, records)
+}
+
+/// Set WAL retention policy of the timeline.
+async fn timeline_wal_retention_handler(
+    mut request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    let zttid = ZTenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    check_permission(&request, Some(zttid.tenant_id))?;
+    let wal_retention: WalRetentionPolicy = json_request(&mut request).await?;
+
+    let tli = GlobalTimelines::get(get_conf(&request), zttid, false).map_err(ApiError::from_err)?;
+    tli.set_wal_retention(wal_retention.clone())
+        .map_err(ApiError::from_err)?;
+
+    json_response(StatusCode::OK, wal_retention)
+}
+
 async fn timeline_create_handler(mut request: Request<Body>) -> Result<Response<Body>, ApiError> {
     let request_data: TimelineCreateRequest = json_request(&mut request).await?;
 
@@ -231,6 +376,26 @@ pub fn make_router(
             "/v1/tenant/:tenant_id/timeline/:timeline_id",
             timeline_delete_force_handler,
         )
+        .put(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/wal_retention",
+            timeline_wal_retention_handler,
+        )
+        .get(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/acceptor_state",
+            timeline_acceptor_state_handler,
+        )
+        .get(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/term_history",
+            timeline_term_history_handler,
+        )
+        .get(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/wal_segments",
+            timeline_wal_segments_handler,
+        )
+        .get(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/wal_records",
+            timeline_wal_records_handler,
+        )
         .delete("/v1/tenant/:tenant_id", tenant_delete_force_handler)
         // for tests
         .post(
@@ -18,8 +18,13 @@ pub mod receive_wal;
 pub mod remove_wal;
 pub mod safekeeper;
 pub mod send_wal;
+#[cfg(test)]
+mod simulation;
 pub mod timeline;
 pub mod wal_backup;
+pub mod wal_compression;
+pub mod wal_inspect;
+pub mod wal_retention;
 pub mod wal_service;
 pub mod wal_storage;
 
@@ -54,6 +59,10 @@ pub struct SafeKeeperConf {
     pub remote_storage: Option<RemoteStorageConfig>,
     pub backup_runtime_threads: usize,
     pub wal_backup_enabled: bool,
+    /// Upload completed segments compressed.
+    pub wal_backup_compression: bool,
+    /// Compress completed local segments once they are behind commit_lsn.
+    pub local_wal_compression: bool,
     pub my_id: NodeId,
     pub broker_endpoints: Vec<Url>,
     pub broker_etcd_prefix: String,
@@ -89,6 +98,8 @@ impl Default for SafeKeeperConf {
             broker_etcd_prefix: etcd_broker::DEFAULT_NEON_BROKER_ETCD_PREFIX.to_string(),
             backup_runtime_threads: DEFAULT_WAL_BACKUP_RUNTIME_THREADS,
             wal_backup_enabled: true,
+            wal_backup_compression: false,
+            local_wal_compression: false,
             auth_validation_public_key_path: None,
         }
     }
@@ -1,4 +1,4 @@
-//! Thread removing old WAL.
+//! Thread removing old WAL and compressing WAL behind commit_lsn, if configured.
 
 use std::{thread, time::Duration};
 
@@ -18,6 +18,14 @@ pub fn thread_main(conf: SafeKeeperConf) {
                         tli.zttid.tenant_id, tli.zttid.timeline_id, e
                     );
                 }
+                if conf.local_wal_compression {
+                    if let Err(e) = tli.compress_old_wal() {
+                        warn!(
+                            "failed to compress WAL for tenant {} timeline {}: {}",
+                            tli.zttid.tenant_id, tli.zttid.timeline_id, e
+                        );
+                    }
+                }
             }
         }
         thread::sleep(wal_removal_interval)
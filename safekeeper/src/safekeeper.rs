@@ -16,6 +16,7 @@ use tracing::*;
 use crate::control_file;
 use crate::send_wal::HotStandbyFeedback;
 
+use crate::wal_retention::WalRetentionPolicy;
 use crate::wal_storage;
 use utils::{
     bin_ser::LeSer,
@@ -25,8 +26,8 @@ use utils::{
 };
 
 pub const SK_MAGIC: u32 = 0xcafeceefu32;
-pub const SK_FORMAT_VERSION: u32 = 6;
-const SK_PROTOCOL_VERSION: u32 = 2;
+pub const SK_FORMAT_VERSION: u32 = 8;
+pub const SK_PROTOCOL_VERSION: u32 = 2;
 const UNKNOWN_SERVER_VERSION: u32 = 0;
 
 /// Consensus logical timestamp.
@@ -156,6 +157,30 @@ impl PeerInfo {
     }
 }
 
+/// The only output plugin of safekeeper slots. Safekeeper doesn't decode WAL,
+/// so consumers get raw WAL in XLogData messages, as with physical
+/// replication, and must decode it themselves. Consumers expecting
+/// `pgoutput`, `wal2json` etc. would misparse it, so those are refused.
+pub const RAW_WAL_PLUGIN: &str = "neon_raw_wal";
+
+/// Logical replication slot kept by safekeeper for a decoding consumer (e.g.
+/// CDC tool). Safekeeper doesn't decode WAL itself, it only streams it and
+/// holds it back until the consumer confirms it is processed.
+///
+/// Slots live on the safekeeper they were created on: they are not
+/// replicated to its peers, so the consumer must always connect to the same
+/// safekeeper, and its WAL retention doesn't protect the WAL on others.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplicationSlot {
+    pub name: String,
+    /// Output plugin, always `RAW_WAL_PLUGIN` for slots created now.
+    pub plugin: String,
+    /// WAL since this LSN is kept for the consumer.
+    pub restart_lsn: Lsn,
+    /// The consumer confirmed it has processed WAL up to this LSN.
+    pub confirmed_flush_lsn: Lsn,
+}
+
 // vector-based node id -> peer state map with very limited functionality we
 // need/
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -205,6 +230,13 @@ pub struct SafeKeeperState {
     // obviously can be stale. (Currently not saved at all, but let's provision
     // place to have less file version upgrades).
     pub peers: Peers,
+    /// Operator defined WAL retention settings of the timeline.
+    pub wal_retention: WalRetentionPolicy,
+    /// LSN up to which offloaded WAL was removed from remote storage
+    /// according to `wal_retention`.
+    pub remote_removed_lsn: Lsn,
+    /// Logical replication slots of the timeline.
+    pub slots: Vec<ReplicationSlot>,
 }
 
 #[derive(Debug, Clone)]
@@ -216,6 +248,7 @@ pub struct SafekeeperMemState {
     pub peer_horizon_lsn: Lsn,
     pub remote_consistent_lsn: Lsn,
     pub proposer_uuid: PgUuid,
+    pub slots: Vec<ReplicationSlot>,
 }
 
 impl SafeKeeperState {
@@ -240,6 +273,9 @@ impl SafeKeeperState {
             peer_horizon_lsn: Lsn(0),
             remote_consistent_lsn: Lsn(0),
             peers: Peers(peers.iter().map(|p| (*p, PeerInfo::new())).collect()),
+            wal_retention: WalRetentionPolicy::default(),
+            remote_removed_lsn: Lsn(0),
+            slots: Vec::new(),
         }
     }
 
@@ -271,27 +307,27 @@ pub struct ProposerGreeting {
 /// (acceptor voted for).
 #[derive(Debug, Serialize)]
 pub struct AcceptorGreeting {
-    term: u64,
-    node_id: NodeId,
+    pub term: u64,
+    pub node_id: NodeId,
 }
 
 /// Vote request sent from proposer to safekeepers
 #[derive(Debug, Deserialize)]
 pub struct VoteRequest {
-    term: Term,
+    pub term: Term,
 }
 
 /// Vote itself, sent from safekeeper to proposer
 #[derive(Debug, Serialize)]
 pub struct VoteResponse {
-    term: Term, // safekeeper's current term; if it is higher than proposer's, the compute is out of date.
-    vote_given: u64, // fixme u64 due to padding
+    pub term: Term, // safekeeper's current term; if it is higher than proposer's, the compute is out of date.
+    pub vote_given: u64, // fixme u64 due to padding
     // Safekeeper flush_lsn (end of WAL) + history of term switches allow
     // proposer to choose the most advanced one.
-    flush_lsn: Lsn,
-    truncate_lsn: Lsn,
-    term_history: TermHistory,
-    timeline_start_lsn: Lsn,
+    pub flush_lsn: Lsn,
+    pub truncate_lsn: Lsn,
+    pub term_history: TermHistory,
+    pub timeline_start_lsn: Lsn,
 }
 
 /*
@@ -528,6 +564,7 @@ where
                 peer_horizon_lsn: state.peer_horizon_lsn,
                 remote_consistent_lsn: state.remote_consistent_lsn,
                 proposer_uuid: state.proposer_uuid,
+                slots: state.slots.clone(),
             },
             state,
             wal_store,
@@ -777,6 +814,7 @@ where
         state.peer_horizon_lsn = self.inmem.peer_horizon_lsn;
         state.remote_consistent_lsn = self.inmem.remote_consistent_lsn;
         state.proposer_uuid = self.inmem.proposer_uuid;
+        state.slots = self.inmem.slots.clone();
         self.state.persist(&state)
     }
 
@@ -895,93 +933,150 @@ where
                 < new_peer_horizon_lsn;
             self.inmem.peer_horizon_lsn = new_peer_horizon_lsn;
         }
+        let mut state = self.state.clone();
+        if let Some(remote_removed_lsn) = sk_info.remote_removed_lsn {
+            // Unlike the above, it is persisted right away: it changes rarely,
+            // and only the node which removed WAL knows it otherwise.
+            if remote_removed_lsn > state.remote_removed_lsn {
+                state.remote_removed_lsn = remote_removed_lsn;
+                sync_control_file = true;
+            }
+        }
         if sync_control_file {
-            self.persist_control_file(self.state.clone())?;
+            self.persist_control_file(state)?;
         }
         Ok(())
     }
 
-    /// Get oldest segno we still need to keep. We hold WAL till it is consumed
-    /// by all of 1) pageserver (remote_consistent_lsn) 2) peers 3) s3
-    /// offloading.
-    /// While it is safe to use inmem values for determining horizon,
-    /// we use persistent to make possible normal states less surprising.
-    pub fn get_horizon_segno(&self, wal_backup_enabled: bool) -> XLogSegNo {
-        let mut horizon_lsn = min(
-            self.state.remote_consistent_lsn,
-            self.state.peer_horizon_lsn,
-        );
-        if wal_backup_enabled {
-            horizon_lsn = min(horizon_lsn, self.state.backup_lsn);
-        }
-        horizon_lsn.segment_number(self.state.server.wal_seg_size as usize)
+    /// Set new WAL retention policy of the timeline and persist it.
+    pub fn set_wal_retention(&mut self, wal_retention: WalRetentionPolicy) -> Result<()> {
+        let mut state = self.state.clone();
+        state.wal_retention = wal_retention;
+        self.persist_control_file(state)
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::wal_storage::Storage;
-    use std::ops::Deref;
 
-    // fake storage for tests
-    struct InMemoryState {
-        persisted_state: SafeKeeperState,
+    /// Remember how far offloaded WAL was removed from remote storage.
+    pub fn set_remote_removed_lsn(&mut self, remote_removed_lsn: Lsn) -> Result<()> {
+        let mut state = self.state.clone();
+        state.remote_removed_lsn = max(state.remote_removed_lsn, remote_removed_lsn);
+        self.persist_control_file(state)
     }
 
-    impl control_file::Storage for InMemoryState {
-        fn persist(&mut self, s: &SafeKeeperState) -> Result<()> {
-            self.persisted_state = s.clone();
-            Ok(())
+    /// Create new logical replication slot. Its consistent point is the
+    /// current commit_lsn.
+    pub fn create_slot(&mut self, name: &str, plugin: &str) -> Result<ReplicationSlot> {
+        if plugin != RAW_WAL_PLUGIN {
+            bail!(
+                "output plugin \"{}\" is not supported, safekeeper streams raw WAL with \"{}\"",
+                plugin,
+                RAW_WAL_PLUGIN
+            );
+        }
+        if self.inmem.slots.iter().any(|s| s.name == name) {
+            bail!("replication slot \"{}\" already exists", name);
         }
+        let slot = ReplicationSlot {
+            name: name.to_owned(),
+            plugin: plugin.to_owned(),
+            restart_lsn: self.inmem.commit_lsn,
+            confirmed_flush_lsn: self.inmem.commit_lsn,
+        };
+        self.inmem.slots.push(slot.clone());
+        self.persist_control_file(self.state.clone())?;
+        info!("created replication slot {:?}", slot);
+        Ok(slot)
     }
 
-    impl Deref for InMemoryState {
-        type Target = SafeKeeperState;
-
-        fn deref(&self) -> &Self::Target {
-            &self.persisted_state
+    /// Drop logical replication slot, releasing WAL it held.
+    pub fn drop_slot(&mut self, name: &str) -> Result<()> {
+        let n_slots = self.inmem.slots.len();
+        self.inmem.slots.retain(|s| s.name != name);
+        if self.inmem.slots.len() == n_slots {
+            bail!("replication slot \"{}\" does not exist", name);
         }
+        self.persist_control_file(self.state.clone())?;
+        info!("dropped replication slot {}", name);
+        Ok(())
     }
 
-    struct DummyWalStore {
-        lsn: Lsn,
+    pub fn get_slot(&self, name: &str) -> Option<&ReplicationSlot> {
+        self.inmem.slots.iter().find(|s| s.name == name)
     }
 
-    impl wal_storage::Storage for DummyWalStore {
-        fn flush_lsn(&self) -> Lsn {
-            self.lsn
-        }
-
-        fn init_storage(&mut self, _state: &SafeKeeperState) -> Result<()> {
-            Ok(())
+    /// Advance slot position according to the consumer feedback. Like other
+    /// LSNs, it is persisted only when it moves by more than a segment.
+    pub fn advance_slot(&mut self, name: &str, confirmed_flush_lsn: Lsn) -> Result<()> {
+        let commit_lsn = self.inmem.commit_lsn;
+        let slot = self
+            .inmem
+            .slots
+            .iter_mut()
+            .find(|s| s.name == name)
+            .with_context(|| format!("replication slot \"{}\" does not exist", name))?;
+        // Consumer can't confirm WAL we haven't sent it.
+        let confirmed_flush_lsn = min(confirmed_flush_lsn, commit_lsn);
+        if confirmed_flush_lsn <= slot.confirmed_flush_lsn {
+            return Ok(());
         }
-
-        fn write_wal(&mut self, startpos: Lsn, buf: &[u8]) -> Result<()> {
-            self.lsn = startpos + buf.len() as u64;
-            Ok(())
+        slot.confirmed_flush_lsn = confirmed_flush_lsn;
+        slot.restart_lsn = confirmed_flush_lsn;
+
+        let persisted_restart_lsn = self
+            .state
+            .slots
+            .iter()
+            .find(|s| s.name == name)
+            .map(|s| s.restart_lsn)
+            .unwrap_or(Lsn(0));
+        if persisted_restart_lsn + (self.state.server.wal_seg_size as u64) < confirmed_flush_lsn {
+            self.persist_control_file(self.state.clone())?;
         }
+        Ok(())
+    }
 
-        fn truncate_wal(&mut self, end_pos: Lsn) -> Result<()> {
-            self.lsn = end_pos;
-            Ok(())
+    /// Get oldest LSN we still need to keep for consumers of WAL: 1)
+    /// pageserver (remote_consistent_lsn) 2) peers 3) s3 offloading 4)
+    /// logical replication slots.
+    /// While it is safe to use inmem values for determining horizon,
+    /// we use persistent to make possible normal states less surprising.
+    pub fn get_consumers_horizon_lsn(&self, wal_backup_enabled: bool) -> Lsn {
+        let mut horizon_lsn = min(
+            self.state.remote_consistent_lsn,
+            self.state.peer_horizon_lsn,
+        );
+        if wal_backup_enabled {
+            horizon_lsn = min(horizon_lsn, self.state.backup_lsn);
         }
-
-        fn flush_wal(&mut self) -> Result<()> {
-            Ok(())
+        if let Some(slots_horizon_lsn) = self.state.slots.iter().map(|s| s.restart_lsn).min() {
+            horizon_lsn = min(horizon_lsn, slots_horizon_lsn);
         }
+        horizon_lsn
+    }
 
-        fn remove_up_to(&self) -> Box<dyn Fn(XLogSegNo) -> Result<()>> {
-            Box::new(move |_segno_up_to: XLogSegNo| Ok(()))
+    /// Get oldest segno we still need to keep. We hold WAL till it is consumed
+    /// by everyone, see `get_consumers_horizon_lsn`, and additionally keep
+    /// `min_local_bytes` of the retention policy.
+    pub fn get_horizon_segno(&self, wal_backup_enabled: bool) -> XLogSegNo {
+        let mut horizon_lsn = self.get_consumers_horizon_lsn(wal_backup_enabled);
+        let min_local_bytes = self.state.wal_retention.min_local_bytes;
+        if min_local_bytes > 0 {
+            let retained_from = Lsn(self.flush_lsn().0.saturating_sub(min_local_bytes));
+            horizon_lsn = min(horizon_lsn, retained_from);
         }
+        horizon_lsn.segment_number(self.state.server.wal_seg_size as usize)
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control_file::InMemoryStorage;
+    use crate::wal_storage::Storage;
 
     #[test]
     fn test_voting() {
-        let storage = InMemoryState {
-            persisted_state: SafeKeeperState::empty(),
-        };
-        let wal_store = DummyWalStore { lsn: Lsn(0) };
+        let storage = InMemoryStorage::new(SafeKeeperState::empty());
+        let wal_store = wal_storage::InMemoryStorage::new();
         let ztli = ZTimelineId::from([0u8; 16]);
 
         let mut sk = SafeKeeper::new(ztli, storage, wal_store, NodeId(0)).unwrap();
@@ -995,10 +1090,7 @@ mod tests {
         }
 
         // reboot...
-        let state = sk.state.persisted_state.clone();
-        let storage = InMemoryState {
-            persisted_state: state,
-        };
+        let storage = sk.state.clone();
 
         sk = SafeKeeper::new(ztli, storage, sk.wal_store, NodeId(0)).unwrap();
 
@@ -1011,11 +1103,76 @@ mod tests {
     }
 
     #[test]
-    fn test_epoch_switch() {
-        let storage = InMemoryState {
-            persisted_state: SafeKeeperState::empty(),
+    fn test_replication_slots() {
+        let mut state = SafeKeeperState::empty();
+        state.server.wal_seg_size = 16;
+        state.commit_lsn = Lsn(100);
+        state.remote_consistent_lsn = Lsn(1000);
+        state.peer_horizon_lsn = Lsn(1000);
+        let storage = InMemoryStorage::new(state);
+        let wal_store = wal_storage::InMemoryStorage::new();
+        let ztli = ZTimelineId::from([0u8; 16]);
+
+        let mut sk = SafeKeeper::new(ztli, storage, wal_store, NodeId(0)).unwrap();
+
+        // WAL is not decoded, so decoding plugins are refused
+        assert!(sk.create_slot("cdc", "wal2json").is_err());
+        let slot = sk.create_slot("cdc", RAW_WAL_PLUGIN).unwrap();
+        assert_eq!(slot.restart_lsn, Lsn(100));
+        assert!(sk.create_slot("cdc", RAW_WAL_PLUGIN).is_err());
+        // slot is persisted and holds WAL back
+        assert_eq!(sk.get_horizon_segno(false), 100 / 16);
+
+        // can't confirm more than committed
+        sk.advance_slot("cdc", Lsn(500)).unwrap();
+        assert_eq!(sk.get_slot("cdc").unwrap().confirmed_flush_lsn, Lsn(100));
+
+        sk.inmem.commit_lsn = Lsn(500);
+        sk.advance_slot("cdc", Lsn(400)).unwrap();
+        assert_eq!(sk.get_slot("cdc").unwrap().restart_lsn, Lsn(400));
+        assert_eq!(sk.get_horizon_segno(false), 400 / 16);
+
+        sk.drop_slot("cdc").unwrap();
+        assert!(sk.drop_slot("cdc").is_err());
+        assert_eq!(sk.get_horizon_segno(false), 1000 / 16);
+    }
+
+    #[test]
+    fn test_remote_removed_lsn_from_peers() {
+        let storage = InMemoryStorage::new(SafeKeeperState::empty());
+        let wal_store = wal_storage::InMemoryStorage::new();
+        let ztli = ZTimelineId::from([0u8; 16]);
+
+        let mut sk = SafeKeeper::new(ztli, storage, wal_store, NodeId(0)).unwrap();
+        let peer_info = |remote_removed_lsn| SkTimelineInfo {
+            last_log_term: None,
+            flush_lsn: None,
+            commit_lsn: None,
+            backup_lsn: None,
+            remote_consistent_lsn: None,
+            peer_horizon_lsn: None,
+            remote_removed_lsn: Some(remote_removed_lsn),
+            safekeeper_connstr: None,
         };
-        let wal_store = DummyWalStore { lsn: Lsn(0) };
+
+        // Removal done by the previous backup leader is persisted, so removal
+        // here continues from it even after restart.
+        sk.record_safekeeper_info(&peer_info(Lsn(0x3000000)))
+            .unwrap();
+        assert_eq!(sk.state.remote_removed_lsn, Lsn(0x3000000));
+        let mut sk = SafeKeeper::new(ztli, sk.state.clone(), sk.wal_store, NodeId(0)).unwrap();
+        assert_eq!(sk.state.remote_removed_lsn, Lsn(0x3000000));
+
+        // and it never moves back
+        sk.record_safekeeper_info(&peer_info(Lsn(0x1000000)))
+            .unwrap();
+        assert_eq!(sk.state.remote_removed_lsn, Lsn(0x3000000));
+    }
+
+    #[test]
+    fn test_epoch_switch() {
+        let storage = InMemoryStorage::new(SafeKeeperState::empty());
+        let wal_store = wal_storage::InMemoryStorage::new();
         let ztli = ZTimelineId::from([0u8; 16]);
 
         let mut sk = SafeKeeper::new(ztli, storage, wal_store, NodeId(0)).unwrap();
@@ -2,6 +2,7 @@
 //! with the "START_REPLICATION" message.
 
 use crate::handler::SafekeeperPostgresHandler;
+use crate::safekeeper::RAW_WAL_PLUGIN;
 use crate::timeline::{ReplicaState, Timeline, TimelineTools};
 use crate::wal_storage::WalReader;
 use anyhow::{bail, Context, Result};
@@ -10,7 +11,7 @@ use postgres_ffi::v14::xlog_utils::{get_current_timestamp, TimestampTz, MAX_SEND
 
 use bytes::Bytes;
 use serde::{Deserialize, Serialize};
-use std::cmp::min;
+use std::cmp::{max, min};
 use std::net::Shutdown;
 use std::sync::Arc;
 use std::time::Duration;
@@ -95,6 +96,7 @@ impl ReplicationConn {
     fn background_thread(
         mut stream_in: ReadStream,
         replica_guard: Arc<ReplicationConnGuard>,
+        slot_name: Option<String>,
     ) -> Result<()> {
         let replica_id = replica_guard.replica;
         let timeline = &replica_guard.timeline;
@@ -115,14 +117,19 @@ impl ReplicationConn {
                             timeline.update_replica_state(replica_id, state);
                         }
                         Some(STANDBY_STATUS_UPDATE_TAG_BYTE) => {
-                            let _reply = StandbyReply::des(&m[1..])
+                            let reply = StandbyReply::des(&m[1..])
                                 .context("failed to deserialize StandbyReply")?;
-                            // This must be a regular postgres replica,
-                            // because pageserver doesn't send this type of messages to safekeeper.
-                            // Currently this is not implemented, so this message is ignored.
-
-                            warn!("unexpected StandbyReply. Read-only postgres replicas are not supported in safekeepers yet.");
-                            // timeline.update_replica_state(replica_id, Some(state));
+                            if let Some(slot_name) = &slot_name {
+                                // Logical consumer confirms processed WAL.
+                                timeline.advance_slot(slot_name, reply.flush_lsn)?;
+                            } else {
+                                // This must be a regular postgres replica,
+                                // because pageserver doesn't send this type of messages to safekeeper.
+                                // Currently this is not implemented, so this message is ignored.
+
+                                warn!("unexpected StandbyReply. Read-only postgres replicas are not supported in safekeepers yet.");
+                                // timeline.update_replica_state(replica_id, Some(state));
+                            }
                         }
                         Some(NEON_STATUS_UPDATE_TAG_BYTE) => {
                             // Note: deserializing is on m[9..] because we skip the tag byte and len bytes.
@@ -157,16 +164,36 @@ impl ReplicationConn {
     }
 
     ///
-    /// Handle START_REPLICATION replication command
+    /// Handle START_REPLICATION replication command. If `slot_name` is given,
+    /// WAL is streamed to a logical consumer, starting no earlier than the
+    /// slot's restart_lsn, and the consumer's feedback advances the slot.
     ///
     pub fn run(
         &mut self,
         spg: &mut SafekeeperPostgresHandler,
         pgb: &mut PostgresBackend,
         mut start_pos: Lsn,
+        slot_name: Option<String>,
     ) -> Result<()> {
         let _enter = info_span!("WAL sender", timeline = %spg.ztimelineid.unwrap()).entered();
 
+        if let Some(slot_name) = &slot_name {
+            let slot =
+                spg.timeline.get().get_slot(slot_name).with_context(|| {
+                    format!("replication slot \"{}\" does not exist", slot_name)
+                })?;
+            // Slots created before the plugin was checked might expect decoded changes.
+            if slot.plugin != RAW_WAL_PLUGIN {
+                bail!(
+                    "replication slot \"{}\" uses output plugin \"{}\", but only \"{}\" is supported",
+                    slot_name,
+                    slot.plugin,
+                    RAW_WAL_PLUGIN
+                );
+            }
+            start_pos = max(start_pos, slot.restart_lsn);
+        }
+
         // spawn the background thread which receives HotStandbyFeedback messages.
         let bg_timeline = Arc::clone(spg.timeline.get());
         let bg_stream_in = self.stream_in.take().unwrap();
@@ -191,7 +218,8 @@ impl ReplicationConn {
             .spawn(move || {
                 let _enter =
                     info_span!("HotStandbyFeedback thread", timeline = %bg_timeline_id).entered();
-                if let Err(err) = Self::background_thread(bg_stream_in, bg_replica_guard) {
+                if let Err(err) = Self::background_thread(bg_stream_in, bg_replica_guard, slot_name)
+                {
                     error!("Replication background thread failed: {}", err);
                 }
             })?;
@@ -0,0 +1,627 @@
+//! Deterministic simulation of proposer-acceptor consensus.
+//!
+//! Several `SafeKeeper`s with in-memory storage are driven by simplified
+//! proposers (modelled after walproposer) through a network which delivers
+//! messages of different connections in random order, breaks connections and
+//! crashes nodes. After every step we check safety properties:
+//! - at most one proposer is elected in each term;
+//! - WAL once committed (by an acceptor or proposer) never changes.
+//!
+//! All randomness comes from the seed, so a failing seed can be replayed.
+
+use bytes::Bytes;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::{max, min};
+use std::collections::{HashMap, VecDeque};
+use tracing::*;
+use utils::{
+    lsn::Lsn,
+    zid::{NodeId, ZTenantId, ZTenantTimelineId, ZTimelineId},
+};
+
+use crate::control_file;
+use crate::safekeeper::{
+    AcceptorProposerMessage, AppendRequest, AppendRequestHeader, AppendResponse, PgUuid,
+    ProposerAcceptorMessage, ProposerElected, ProposerGreeting, SafeKeeper, SafeKeeperState, Term,
+    TermHistory, TermSwitchEntry, VoteRequest, VoteResponse, SK_PROTOCOL_VERSION,
+};
+use crate::wal_storage;
+
+const WAL_SEG_SIZE: u32 = 16 * 1024 * 1024;
+/// LSN at which the simulated timeline starts.
+const TIMELINE_START_LSN: Lsn = Lsn(0x100);
+const MAX_RECORD_LEN: usize = 32;
+
+type Acceptor = SafeKeeper<control_file::InMemoryStorage, wal_storage::InMemoryStorage>;
+
+struct Node {
+    id: NodeId,
+    sk: Acceptor,
+}
+
+/// Connection between proposer and acceptor. Messages inside a connection are
+/// delivered in order, like in TCP.
+struct Conn {
+    connected: bool,
+    to_acceptor: VecDeque<ProposerAcceptorMessage>,
+    to_proposer: VecDeque<AcceptorProposerMessage>,
+    /// Term reported by acceptor in greeting.
+    greeting_term: Option<Term>,
+    /// Vote given to us.
+    vote: Option<VoteResponse>,
+    /// Whether ProposerElected has been sent and WAL is streamed.
+    streaming: bool,
+    /// Next LSN to send.
+    next_lsn: Lsn,
+    /// commit_lsn sent last time.
+    sent_commit_lsn: Lsn,
+    /// flush_lsn acknowledged by acceptor in our term.
+    flush_lsn: Lsn,
+}
+
+impl Conn {
+    fn new() -> Self {
+        Conn {
+            connected: false,
+            to_acceptor: VecDeque::new(),
+            to_proposer: VecDeque::new(),
+            greeting_term: None,
+            vote: None,
+            streaming: false,
+            next_lsn: Lsn(0),
+            sent_commit_lsn: Lsn(0),
+            flush_lsn: Lsn(0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Greeting,
+    Voting,
+    Elected,
+}
+
+struct Proposer {
+    uuid: PgUuid,
+    phase: Phase,
+    term: Term,
+    conns: Vec<Conn>,
+    // Fields below are set on election.
+    term_history: TermHistory,
+    timeline_start_lsn: Lsn,
+    epoch_start_lsn: Lsn,
+    /// WAL since timeline_start_lsn.
+    wal: Vec<u8>,
+    commit_lsn: Lsn,
+}
+
+impl Proposer {
+    fn new(uuid: PgUuid, n_nodes: usize) -> Self {
+        Proposer {
+            uuid,
+            phase: Phase::Greeting,
+            term: 0,
+            conns: (0..n_nodes).map(|_| Conn::new()).collect(),
+            term_history: TermHistory::empty(),
+            timeline_start_lsn: Lsn(0),
+            epoch_start_lsn: Lsn(0),
+            wal: Vec::new(),
+            commit_lsn: Lsn(0),
+        }
+    }
+
+    fn end_lsn(&self) -> Lsn {
+        self.timeline_start_lsn + self.wal.len() as u64
+    }
+}
+
+/// Find the point since which acceptor's WAL must be overwritten, it is the
+/// end of the last term common in both histories.
+fn start_streaming_at(prop_th: &TermHistory, sk_th: &TermHistory, sk_flush_lsn: Lsn) -> Lsn {
+    let common = prop_th
+        .0
+        .iter()
+        .zip(sk_th.0.iter())
+        .take_while(|(p, s)| p.term == s.term)
+        .count();
+    if common == 0 {
+        return prop_th.0[0].lsn;
+    }
+    let prop_end = prop_th.0.get(common).map_or(Lsn::MAX, |e| e.lsn);
+    let sk_end = sk_th.0.get(common).map_or(sk_flush_lsn, |e| e.lsn);
+    min(prop_end, sk_end)
+}
+
+struct Simulation {
+    rng: StdRng,
+    zttid: ZTenantTimelineId,
+    nodes: Vec<Node>,
+    proposers: Vec<Proposer>,
+    /// Enables message loss and crashes.
+    faults: bool,
+    /// Proposer elected in each term.
+    elected: HashMap<Term, PgUuid>,
+    /// WAL known to be committed, since TIMELINE_START_LSN.
+    committed: Vec<u8>,
+}
+
+impl Simulation {
+    fn new(seed: u64, n_nodes: usize, n_proposers: usize) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let zttid =
+            ZTenantTimelineId::new(ZTenantId::from([1u8; 16]), ZTimelineId::from([2u8; 16]));
+        let nodes = (0..n_nodes)
+            .map(|i| {
+                let state = SafeKeeperState::new(&zttid, vec![]);
+                let id = NodeId(i as u64);
+                let sk = SafeKeeper::new(
+                    zttid.timeline_id,
+                    control_file::InMemoryStorage::new(state),
+                    wal_storage::InMemoryStorage::new(),
+                    id,
+                )
+                .unwrap();
+                Node { id, sk }
+            })
+            .collect();
+        let proposers = (0..n_proposers)
+            .map(|_| Proposer::new(rng.gen(), n_nodes))
+            .collect();
+        Simulation {
+            rng,
+            zttid,
+            nodes,
+            proposers,
+            faults: true,
+            elected: HashMap::new(),
+            committed: Vec::new(),
+        }
+    }
+
+    fn quorum(&self) -> usize {
+        self.nodes.len() / 2 + 1
+    }
+
+    fn step(&mut self) {
+        let dice: f64 = self.rng.gen();
+        if dice < 0.6 {
+            self.deliver_random_message();
+        } else if dice < 0.9 {
+            let p = self.rng.gen_range(0..self.proposers.len());
+            self.proposer_step(p);
+        } else if self.faults {
+            if dice < 0.96 {
+                let p = self.rng.gen_range(0..self.proposers.len());
+                let a = self.rng.gen_range(0..self.nodes.len());
+                self.proposers[p].conns[a] = Conn::new();
+            } else if dice < 0.985 {
+                let a = self.rng.gen_range(0..self.nodes.len());
+                self.crash_acceptor(a);
+            } else {
+                let p = self.rng.gen_range(0..self.proposers.len());
+                self.restart_proposer(p);
+            }
+        }
+    }
+
+    /// Pick message from random connection, so messages of different
+    /// connections are reordered.
+    fn deliver_random_message(&mut self) {
+        let mut queues = Vec::new();
+        for (p, proposer) in self.proposers.iter().enumerate() {
+            for (a, conn) in proposer.conns.iter().enumerate() {
+                if !conn.to_acceptor.is_empty() {
+                    queues.push((p, a, true));
+                }
+                if !conn.to_proposer.is_empty() {
+                    queues.push((p, a, false));
+                }
+            }
+        }
+        if queues.is_empty() {
+            return;
+        }
+        let (p, a, to_acceptor) = queues[self.rng.gen_range(0..queues.len())];
+        if to_acceptor {
+            self.deliver_to_acceptor(p, a);
+        } else {
+            self.deliver_to_proposer(p, a);
+        }
+    }
+
+    fn deliver_to_acceptor(&mut self, p: usize, a: usize) {
+        let conn = &mut self.proposers[p].conns[a];
+        let msg = conn.to_acceptor.pop_front().unwrap();
+        match self.nodes[a].sk.process_msg(&msg) {
+            Ok(Some(reply)) => conn.to_proposer.push_back(reply),
+            Ok(None) => {}
+            Err(e) => {
+                // as in real life, error closes the connection
+                info!("acceptor {} failed to process message: {}", a, e);
+                *conn = Conn::new();
+            }
+        }
+        self.check_acceptor(a);
+    }
+
+    fn deliver_to_proposer(&mut self, p: usize, a: usize) {
+        let msg = self.proposers[p].conns[a].to_proposer.pop_front().unwrap();
+        match msg {
+            AcceptorProposerMessage::Greeting(g) => self.handle_greeting(p, a, g.term),
+            AcceptorProposerMessage::VoteResponse(v) => self.handle_vote_response(p, a, v),
+            AcceptorProposerMessage::AppendResponse(r) => self.handle_append_response(p, a, r),
+        }
+    }
+
+    /// Connect to acceptors, generate and stream WAL.
+    fn proposer_step(&mut self, p: usize) {
+        for a in 0..self.nodes.len() {
+            if !self.proposers[p].conns[a].connected && self.rng.gen_bool(0.5) {
+                self.connect(p, a);
+            }
+        }
+        if self.proposers[p].phase != Phase::Elected {
+            return;
+        }
+        if self.rng.gen_bool(0.5) {
+            let len = self.rng.gen_range(1..=MAX_RECORD_LEN);
+            let record: Vec<u8> = (0..len).map(|_| self.rng.gen()).collect();
+            self.proposers[p].wal.extend_from_slice(&record);
+        }
+        for a in 0..self.nodes.len() {
+            let no_flush = self.rng.gen_bool(0.3);
+            let proposer = &mut self.proposers[p];
+            let end_lsn = proposer.end_lsn();
+            let truncate_lsn = proposer
+                .conns
+                .iter()
+                .map(|c| c.flush_lsn)
+                .min()
+                .unwrap_or(Lsn(0));
+            let conn = &mut proposer.conns[a];
+            if !conn.streaming
+                || (conn.next_lsn == end_lsn && conn.sent_commit_lsn == proposer.commit_lsn)
+            {
+                continue;
+            }
+            let start = (conn.next_lsn.0 - proposer.timeline_start_lsn.0) as usize;
+            let msg = AppendRequest {
+                h: AppendRequestHeader {
+                    term: proposer.term,
+                    epoch_start_lsn: proposer.epoch_start_lsn,
+                    begin_lsn: conn.next_lsn,
+                    end_lsn,
+                    commit_lsn: proposer.commit_lsn,
+                    truncate_lsn,
+                    proposer_uuid: proposer.uuid,
+                },
+                wal_data: Bytes::copy_from_slice(&proposer.wal[start..]),
+            };
+            conn.next_lsn = end_lsn;
+            conn.sent_commit_lsn = proposer.commit_lsn;
+            if no_flush {
+                conn.to_acceptor
+                    .push_back(ProposerAcceptorMessage::NoFlushAppendRequest(msg));
+                conn.to_acceptor
+                    .push_back(ProposerAcceptorMessage::FlushWAL);
+            } else {
+                conn.to_acceptor
+                    .push_back(ProposerAcceptorMessage::AppendRequest(msg));
+            }
+        }
+    }
+
+    fn connect(&mut self, p: usize, a: usize) {
+        let proposer = &mut self.proposers[p];
+        let conn = &mut proposer.conns[a];
+        *conn = Conn::new();
+        conn.connected = true;
+        conn.to_acceptor
+            .push_back(ProposerAcceptorMessage::Greeting(ProposerGreeting {
+                protocol_version: SK_PROTOCOL_VERSION,
+                pg_version: 140000,
+                proposer_id: proposer.uuid,
+                system_id: 0,
+                ztli: self.zttid.timeline_id,
+                tenant_id: self.zttid.tenant_id,
+                tli: 1,
+                wal_seg_size: WAL_SEG_SIZE,
+            }));
+    }
+
+    fn handle_greeting(&mut self, p: usize, a: usize, term: Term) {
+        let quorum = self.quorum();
+        let proposer = &mut self.proposers[p];
+        if proposer.phase != Phase::Greeting {
+            if term > proposer.term {
+                self.restart_proposer(p);
+                return;
+            }
+            let msg = VoteRequest {
+                term: proposer.term,
+            };
+            proposer.conns[a]
+                .to_acceptor
+                .push_back(ProposerAcceptorMessage::VoteRequest(msg));
+            return;
+        }
+
+        proposer.conns[a].greeting_term = Some(term);
+        let terms: Vec<Term> = proposer
+            .conns
+            .iter()
+            .filter_map(|c| c.greeting_term)
+            .collect();
+        if terms.len() < quorum {
+            return;
+        }
+        proposer.term = terms.into_iter().max().unwrap() + 1;
+        proposer.phase = Phase::Voting;
+        for conn in proposer.conns.iter_mut() {
+            if conn.greeting_term.is_some() {
+                let msg = VoteRequest {
+                    term: proposer.term,
+                };
+                conn.to_acceptor
+                    .push_back(ProposerAcceptorMessage::VoteRequest(msg));
+            }
+        }
+    }
+
+    fn handle_vote_response(&mut self, p: usize, a: usize, vote: VoteResponse) {
+        let proposer = &mut self.proposers[p];
+        if vote.term > proposer.term {
+            self.restart_proposer(p);
+            return;
+        }
+        match proposer.phase {
+            Phase::Greeting => unreachable!("got vote before asking for it"),
+            Phase::Voting => {
+                if vote.vote_given == 0 {
+                    // the term is taken by another proposer
+                    self.restart_proposer(p);
+                    return;
+                }
+                proposer.conns[a].vote = Some(vote);
+                let n_votes = proposer.conns.iter().filter(|c| c.vote.is_some()).count();
+                if n_votes >= self.quorum() {
+                    self.become_elected(p);
+                }
+            }
+            Phase::Elected => {
+                // acceptor joined after election
+                self.start_streaming(p, a, &vote);
+            }
+        }
+    }
+
+    fn become_elected(&mut self, p: usize) {
+        let proposer = &mut self.proposers[p];
+        let (donor, donor_vote) = proposer
+            .conns
+            .iter()
+            .enumerate()
+            .filter_map(|(a, c)| c.vote.as_ref().map(|v| (a, v)))
+            .max_by_key(|(_, v)| (v.term_history.0.last().map_or(0, |e| e.term), v.flush_lsn))
+            .unwrap();
+        let timeline_start_lsn = proposer
+            .conns
+            .iter()
+            .filter_map(|c| c.vote.as_ref())
+            .map(|v| v.timeline_start_lsn)
+            .find(|lsn| *lsn != Lsn(0))
+            .unwrap_or(TIMELINE_START_LSN);
+        let donor_flush_lsn = max(donor_vote.flush_lsn, timeline_start_lsn);
+        let mut term_history = donor_vote.term_history.clone();
+
+        // Fetch WAL from donor. It could have been truncated by a newer
+        // proposer in the meanwhile, then we lost.
+        let donor_sk = &self.nodes[donor].sk;
+        if donor_sk.state.acceptor_state.term != proposer.term {
+            self.restart_proposer(p);
+            return;
+        }
+        let wal = if donor_flush_lsn == timeline_start_lsn {
+            Vec::new()
+        } else {
+            donor_sk
+                .wal_store
+                .read(timeline_start_lsn, donor_flush_lsn)
+                .expect("donor lost WAL it has voted with")
+                .to_vec()
+        };
+
+        term_history.0.push(TermSwitchEntry {
+            term: proposer.term,
+            lsn: donor_flush_lsn,
+        });
+        info!(
+            "proposer {} elected in term {}, donor {}, epoch_start_lsn {}",
+            p, proposer.term, donor, donor_flush_lsn
+        );
+        if let Some(other) = self.elected.insert(proposer.term, proposer.uuid) {
+            panic!(
+                "two proposers {:?} and {:?} elected in term {}",
+                other, proposer.uuid, proposer.term
+            );
+        }
+        proposer.phase = Phase::Elected;
+        proposer.term_history = term_history;
+        proposer.timeline_start_lsn = timeline_start_lsn;
+        proposer.epoch_start_lsn = donor_flush_lsn;
+        proposer.wal = wal;
+        proposer.commit_lsn = Lsn(0);
+
+        for a in 0..self.nodes.len() {
+            if let Some(vote) = self.proposers[p].conns[a].vote.take() {
+                self.start_streaming(p, a, &vote);
+            }
+        }
+    }
+
+    fn start_streaming(&mut self, p: usize, a: usize, vote: &VoteResponse) {
+        let proposer = &mut self.proposers[p];
+        let start_lsn =
+            start_streaming_at(&proposer.term_history, &vote.term_history, vote.flush_lsn);
+        let conn = &mut proposer.conns[a];
+        conn.to_acceptor
+            .push_back(ProposerAcceptorMessage::Elected(ProposerElected {
+                term: proposer.term,
+                start_streaming_at: start_lsn,
+                term_history: proposer.term_history.clone(),
+                timeline_start_lsn: proposer.timeline_start_lsn,
+            }));
+        conn.streaming = true;
+        conn.next_lsn = start_lsn;
+        conn.sent_commit_lsn = Lsn(0);
+        conn.flush_lsn = Lsn(0);
+    }
+
+    fn handle_append_response(&mut self, p: usize, a: usize, resp: AppendResponse) {
+        let quorum = self.quorum();
+        let proposer = &mut self.proposers[p];
+        if resp.term > proposer.term {
+            self.restart_proposer(p);
+            return;
+        }
+        proposer.conns[a].flush_lsn = resp.flush_lsn;
+
+        // Like in Raft, entries of previous terms can't be committed by
+        // counting replicas, so ignore acknowledgements below epoch_start_lsn.
+        let mut acked: Vec<Lsn> = proposer
+            .conns
+            .iter()
+            .map(|c| c.flush_lsn)
+            .filter(|lsn| *lsn >= proposer.epoch_start_lsn)
+            .collect();
+        if acked.len() < quorum {
+            return;
+        }
+        acked.sort_unstable_by(|a, b| b.cmp(a));
+        let commit_lsn = acked[quorum - 1];
+        if commit_lsn > proposer.commit_lsn {
+            proposer.commit_lsn = commit_lsn;
+            let timeline_start_lsn = proposer.timeline_start_lsn;
+            let len = (commit_lsn.0 - timeline_start_lsn.0) as usize;
+            let wal = proposer.wal[..len].to_vec();
+            self.check_committed(timeline_start_lsn, &wal);
+        }
+    }
+
+    fn restart_proposer(&mut self, p: usize) {
+        info!("restarting proposer {}", p);
+        self.proposers[p] = Proposer::new(self.rng.gen(), self.nodes.len());
+    }
+
+    /// Restart acceptor, losing everything not flushed to disk.
+    fn crash_acceptor(&mut self, a: usize) {
+        info!("crashing acceptor {}", a);
+        for proposer in self.proposers.iter_mut() {
+            proposer.conns[a] = Conn::new();
+        }
+        let node = &mut self.nodes[a];
+        let state = node.sk.state.clone();
+        let mut wal_store = std::mem::take(&mut node.sk.wal_store);
+        wal_store.lose_unflushed();
+        node.sk = SafeKeeper::new(self.zttid.timeline_id, state, wal_store, node.id).unwrap();
+        self.check_acceptor(a);
+    }
+
+    fn check_acceptor(&mut self, a: usize) {
+        let sk = &self.nodes[a].sk;
+        let timeline_start_lsn = sk.state.timeline_start_lsn;
+        if timeline_start_lsn == Lsn(0) {
+            return;
+        }
+        assert_eq!(timeline_start_lsn, TIMELINE_START_LSN);
+        assert!(sk.state.commit_lsn <= sk.inmem.commit_lsn);
+        let wal = sk
+            .wal_store
+            .read(timeline_start_lsn, sk.inmem.commit_lsn)
+            .expect("acceptor lost committed WAL")
+            .to_vec();
+        self.check_committed(timeline_start_lsn, &wal);
+    }
+
+    /// Check that the given committed WAL doesn't contradict what we have
+    /// seen committed before, and remember it.
+    fn check_committed(&mut self, timeline_start_lsn: Lsn, wal: &[u8]) {
+        assert_eq!(timeline_start_lsn, TIMELINE_START_LSN);
+        let common = min(wal.len(), self.committed.len());
+        assert!(
+            wal[..common] == self.committed[..common],
+            "committed WAL diverged"
+        );
+        if wal.len() > common {
+            self.committed.extend_from_slice(&wal[common..]);
+        }
+    }
+
+    /// Leave only the first proposer, stop injecting faults and wait until
+    /// more WAL is committed.
+    fn check_progress(&mut self, max_steps: usize) {
+        self.proposers.truncate(1);
+        self.faults = false;
+        let committed = self.committed.len();
+        for _ in 0..max_steps {
+            self.step();
+            if self.committed.len() > committed {
+                return;
+            }
+        }
+        panic!("no progress without faults");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_start_streaming_at() {
+        let th = |entries: &[(Term, u64)]| {
+            TermHistory(
+                entries
+                    .iter()
+                    .map(|&(term, lsn)| TermSwitchEntry {
+                        term,
+                        lsn: Lsn(lsn),
+                    })
+                    .collect(),
+            )
+        };
+        let prop_th = th(&[(1, 100), (3, 200), (5, 300)]);
+        // empty acceptor
+        assert_eq!(start_streaming_at(&prop_th, &th(&[]), Lsn(0)), Lsn(100));
+        // acceptor is behind in common term
+        assert_eq!(
+            start_streaming_at(&prop_th, &th(&[(1, 100), (3, 200)]), Lsn(250)),
+            Lsn(250)
+        );
+        // acceptor has WAL of term which wasn't committed
+        assert_eq!(
+            start_streaming_at(&prop_th, &th(&[(1, 100), (2, 150)]), Lsn(180)),
+            Lsn(150)
+        );
+        // acceptor is ahead in term the proposer has overwritten
+        assert_eq!(
+            start_streaming_at(&prop_th, &th(&[(1, 100), (3, 200)]), Lsn(350)),
+            Lsn(300)
+        );
+        // acceptor already streamed in proposer's term
+        assert_eq!(start_streaming_at(&prop_th, &prop_th, Lsn(400)), Lsn(400));
+    }
+
+    #[test]
+    fn test_random_schedules() {
+        for seed in 0..100 {
+            let mut sim = Simulation::new(seed, 3, 2);
+            for _ in 0..2000 {
+                sim.step();
+            }
+            sim.check_progress(10000);
+        }
+    }
+}
@@ -16,6 +16,7 @@ use std::collections::{HashMap, HashSet};
 use std::fs::{self};
 
 use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
 
 use tokio::sync::mpsc::Sender;
 use tracing::*;
@@ -28,12 +29,13 @@ use utils::{
 
 use crate::control_file;
 use crate::safekeeper::{
-    AcceptorProposerMessage, ProposerAcceptorMessage, SafeKeeper, SafeKeeperState,
+    AcceptorProposerMessage, ProposerAcceptorMessage, ReplicationSlot, SafeKeeper, SafeKeeperState,
     SafekeeperMemState,
 };
 use crate::send_wal::HotStandbyFeedback;
 
 use crate::metrics::FullTimelineInfo;
+use crate::wal_retention::{LsnHistory, WalRetentionPolicy};
 use crate::wal_storage;
 use crate::wal_storage::Storage as wal_storage_iface;
 use crate::SafeKeeperConf;
@@ -92,6 +94,9 @@ struct SharedState {
     active: bool,
     num_computes: u32,
     last_removed_segno: XLogSegNo,
+    last_compressed_segno: XLogSegNo,
+    /// When local WAL was written, for time based retention.
+    flush_lsn_history: LsnHistory,
 }
 
 impl SharedState {
@@ -114,6 +119,8 @@ impl SharedState {
             active: false,
             num_computes: 0,
             last_removed_segno: 0,
+            last_compressed_segno: 0,
+            flush_lsn_history: LsnHistory::new(),
         })
     }
 
@@ -132,6 +139,8 @@ impl SharedState {
             active: false,
             num_computes: 0,
             last_removed_segno: 0,
+            last_compressed_segno: 0,
+            flush_lsn_history: LsnHistory::new(),
         })
     }
     fn is_active(&self) -> bool {
@@ -459,6 +468,7 @@ impl Timeline {
                 shared_state.sk.inmem.remote_consistent_lsn,
             )),
             peer_horizon_lsn: Some(shared_state.sk.inmem.peer_horizon_lsn),
+            remote_removed_lsn: Some(shared_state.sk.state.remote_removed_lsn),
             safekeeper_connstr: Some(conf.listen_pg_addr.clone()),
             backup_lsn: Some(shared_state.sk.inmem.backup_lsn),
         }
@@ -512,16 +522,31 @@ impl Timeline {
         shared_state.sk.wal_store.flush_lsn()
     }
 
+    /// Remove WAL not needed by anyone, keeping what retention policy asks for.
+    /// Expected to be called periodically, as it also keeps track of WAL age.
     pub fn remove_old_wal(&self, wal_backup_enabled: bool) -> Result<()> {
-        let horizon_segno: XLogSegNo;
+        let mut horizon_segno: XLogSegNo;
         let remover: Box<dyn Fn(u64) -> Result<(), anyhow::Error>>;
         {
-            let shared_state = self.mutex.lock().unwrap();
+            let mut shared_state = self.mutex.lock().unwrap();
             // WAL seg size not initialized yet, no WAL exists.
             if shared_state.get_wal_seg_size() == 0 {
                 return Ok(());
             }
             horizon_segno = shared_state.sk.get_horizon_segno(wal_backup_enabled);
+
+            let now = SystemTime::now();
+            let flush_lsn = shared_state.sk.wal_store.flush_lsn();
+            shared_state.flush_lsn_history.record(now, flush_lsn);
+            if let Some(min_local_age) = shared_state.sk.state.wal_retention.min_local_age() {
+                let wal_seg_size = shared_state.get_wal_seg_size();
+                let old_wal_lsn = now
+                    .checked_sub(min_local_age)
+                    .and_then(|cutoff| shared_state.flush_lsn_history.lsn_at(cutoff))
+                    .unwrap_or(Lsn(0));
+                horizon_segno = min(horizon_segno, old_wal_lsn.segment_number(wal_seg_size));
+            }
+
             remover = shared_state.sk.wal_store.remove_up_to();
             if horizon_segno <= 1 || horizon_segno <= shared_state.last_removed_segno {
                 return Ok(());
@@ -535,6 +560,98 @@ impl Timeline {
         self.mutex.lock().unwrap().last_removed_segno = horizon_segno;
         Ok(())
     }
+
+    pub fn create_slot(&self, name: &str, plugin: &str) -> Result<ReplicationSlot> {
+        self.mutex.lock().unwrap().sk.create_slot(name, plugin)
+    }
+
+    pub fn drop_slot(&self, name: &str) -> Result<()> {
+        self.mutex.lock().unwrap().sk.drop_slot(name)
+    }
+
+    pub fn get_slot(&self, name: &str) -> Option<ReplicationSlot> {
+        self.mutex.lock().unwrap().sk.get_slot(name).cloned()
+    }
+
+    /// Record consumer feedback for the slot.
+    pub fn advance_slot(&self, name: &str, confirmed_flush_lsn: Lsn) -> Result<()> {
+        self.mutex
+            .lock()
+            .unwrap()
+            .sk
+            .advance_slot(name, confirmed_flush_lsn)
+    }
+
+    pub fn get_wal_retention(&self) -> WalRetentionPolicy {
+        self.mutex.lock().unwrap().sk.state.wal_retention.clone()
+    }
+
+    /// Set and persist new WAL retention policy. Takes effect on next WAL
+    /// removal round.
+    pub fn set_wal_retention(&self, wal_retention: WalRetentionPolicy) -> Result<()> {
+        info!(
+            "setting WAL retention of timeline {} to {:?}",
+            self.zttid, wal_retention
+        );
+        self.mutex
+            .lock()
+            .unwrap()
+            .sk
+            .set_wal_retention(wal_retention)
+    }
+
+    /// Returns LSN up to which offloaded WAL was already removed (or never
+    /// existed) and LSN up to which it is not needed by anyone anymore,
+    /// ignoring PITR window.
+    pub fn get_remote_removal_bounds(&self, wal_backup_enabled: bool) -> (Lsn, Lsn) {
+        let shared_state = self.mutex.lock().unwrap();
+        let state = &shared_state.sk.state;
+        (
+            max(state.remote_removed_lsn, state.timeline_start_lsn),
+            shared_state
+                .sk
+                .get_consumers_horizon_lsn(wal_backup_enabled),
+        )
+    }
+
+    pub fn set_remote_removed_lsn(&self, remote_removed_lsn: Lsn) -> Result<()> {
+        self.mutex
+            .lock()
+            .unwrap()
+            .sk
+            .set_remote_removed_lsn(remote_removed_lsn)
+    }
+
+    /// Compress local segments behind commit_lsn. Persisted commit_lsn is
+    /// used, as on restart we look for the end of WAL starting from it, so its
+    /// segment must stay uncompressed.
+    pub fn compress_old_wal(&self) -> Result<()> {
+        let horizon_segno: XLogSegNo;
+        let compressor: Box<dyn Fn(u64) -> Result<(), anyhow::Error>>;
+        {
+            let shared_state = self.mutex.lock().unwrap();
+            // WAL seg size not initialized yet, no WAL exists.
+            if shared_state.get_wal_seg_size() == 0 {
+                return Ok(());
+            }
+            horizon_segno = shared_state
+                .sk
+                .state
+                .commit_lsn
+                .segment_number(shared_state.get_wal_seg_size());
+            compressor = shared_state.sk.wal_store.compress_up_to();
+            if horizon_segno <= 1 || horizon_segno <= shared_state.last_compressed_segno {
+                return Ok(());
+            }
+            // release the lock before compressing
+        }
+        let _enter =
+            info_span!("", timeline = %self.zttid.tenant_id, tenant = %self.zttid.timeline_id)
+                .entered();
+        compressor(horizon_segno - 1)?;
+        self.mutex.lock().unwrap().last_compressed_segno = horizon_segno;
+        Ok(())
+    }
 }
 
 // Utilities needed by various Connection-like objects
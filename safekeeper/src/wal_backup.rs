@@ -6,15 +6,17 @@ use tokio::task::JoinHandle;
 
 use std::cmp::min;
 use std::collections::HashMap;
+use std::io::{self, Cursor};
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 use postgres_ffi::v14::xlog_utils::{XLogFileName, XLogSegNo, XLogSegNoOffsetToRecPtr};
 use postgres_ffi::PG_TLI;
-use remote_storage::{GenericRemoteStorage, RemoteStorage};
+use remote_storage::{Download, DownloadError, GenericRemoteStorage, RemoteStorage};
 use tokio::fs::File;
+use tokio::io::AsyncRead;
 use tokio::runtime::Builder;
 
 use tokio::select;
@@ -27,6 +29,8 @@ use utils::{lsn::Lsn, zid::ZTenantTimelineId};
 
 use crate::broker::{Election, ElectionLeader};
 use crate::timeline::{GlobalTimelines, Timeline};
+use crate::wal_compression::{self, compressed_path};
+use crate::wal_retention::LsnHistory;
 use crate::{broker, SafeKeeperConf};
 
 use once_cell::sync::OnceCell;
@@ -100,8 +104,14 @@ fn consider_start_task(
     let timeline_dir = conf.timeline_dir(&zttid);
 
     let handle = tokio::spawn(
-        backup_task_main(zttid, timeline_dir, shutdown_rx, election)
-            .instrument(info_span!("WAL backup task", zttid = %zttid)),
+        backup_task_main(
+            zttid,
+            timeline_dir,
+            conf.wal_backup_compression,
+            shutdown_rx,
+            election,
+        )
+        .instrument(info_span!("WAL backup task", zttid = %zttid)),
     );
 
     task.handle = Some(WalBackupTaskHandle {
@@ -185,6 +195,10 @@ struct WalBackupTask {
     timeline: Arc<Timeline>,
     timeline_dir: PathBuf,
     wal_seg_size: usize,
+    /// Whether to upload segments compressed.
+    compression: bool,
+    /// When WAL was offloaded, for removing it after PITR window.
+    backup_lsn_history: LsnHistory,
     commit_lsn_watch_rx: watch::Receiver<Lsn>,
     leader: Option<ElectionLeader>,
     election: Election,
@@ -194,6 +208,7 @@ struct WalBackupTask {
 async fn backup_task_main(
     zttid: ZTenantTimelineId,
     timeline_dir: PathBuf,
+    compression: bool,
     mut shutdown_rx: Receiver<()>,
     election: Election,
 ) {
@@ -206,11 +221,18 @@ async fn backup_task_main(
         return;
     };
 
+    // We don't know when already offloaded WAL was uploaded, so consider it
+    // uploaded right now.
+    let mut backup_lsn_history = LsnHistory::new();
+    backup_lsn_history.record(SystemTime::now(), timeline.get_wal_backup_lsn());
+
     let mut wb = WalBackupTask {
         wal_seg_size: timeline.get_wal_seg_size(),
+        backup_lsn_history,
         commit_lsn_watch_rx: timeline.get_commit_lsn_watch_rx(),
         timeline,
         timeline_dir,
+        compression,
         leader: None,
         election,
     };
@@ -316,6 +338,7 @@ impl WalBackupTask {
                     commit_lsn,
                     self.wal_seg_size,
                     &self.timeline_dir,
+                    self.compression,
                 )
                 .await
                 {
@@ -323,6 +346,12 @@ impl WalBackupTask {
                         backup_lsn = backup_lsn_result;
                         self.timeline.set_wal_backup_lsn(backup_lsn_result);
                         retry_attempt = 0;
+
+                        self.backup_lsn_history
+                            .record(SystemTime::now(), backup_lsn_result);
+                        if let Err(e) = self.remove_old_remote_wal().await {
+                            warn!("failed to remove old offloaded WAL: {:?}", e);
+                        }
                     }
                     Err(e) => {
                         error!(
@@ -336,6 +365,43 @@ impl WalBackupTask {
             }
         }
     }
+
+    /// Remove offloaded WAL which is out of PITR window, if retention policy
+    /// of the timeline sets one. WAL still needed by pageserver or peers is
+    /// always kept.
+    async fn remove_old_remote_wal(&mut self) -> Result<()> {
+        let pitr_window = match self.timeline.get_wal_retention().remote_pitr_window() {
+            Some(pitr_window) => pitr_window,
+            None => return Ok(()),
+        };
+        let old_wal_lsn = match SystemTime::now()
+            .checked_sub(pitr_window)
+            .and_then(|cutoff| self.backup_lsn_history.lsn_at(cutoff))
+        {
+            Some(lsn) => lsn,
+            None => return Ok(()),
+        };
+        let (removed_lsn, consumers_horizon_lsn) = self.timeline.get_remote_removal_bounds(true);
+        let horizon_lsn = min(old_wal_lsn, consumers_horizon_lsn);
+
+        let segments = get_segments(removed_lsn, horizon_lsn, self.wal_seg_size);
+        let last_segment = match segments.last() {
+            Some(s) => *s,
+            None => return Ok(()),
+        };
+        for s in &segments {
+            delete_segment(s, &self.timeline_dir)
+                .await
+                .with_context(|| format!("removing offloaded segno {}", s.seg_no))?;
+        }
+        self.timeline.set_remote_removed_lsn(last_segment.end_lsn)?;
+        info!(
+            "removed offloaded segnos {:?} out of PITR window {:?}",
+            segments.iter().map(|&s| s.seg_no).collect::<Vec<_>>(),
+            pitr_window,
+        );
+        Ok(())
+    }
 }
 
 pub async fn backup_lsn_range(
@@ -343,11 +409,12 @@ pub async fn backup_lsn_range(
     end_lsn: Lsn,
     wal_seg_size: usize,
     timeline_dir: &Path,
+    compression: bool,
 ) -> Result<Lsn> {
     let mut res = start_lsn;
     let segments = get_segments(start_lsn, end_lsn, wal_seg_size);
     for s in &segments {
-        backup_single_segment(s, timeline_dir)
+        backup_single_segment(s, timeline_dir, compression)
             .await
             .with_context(|| format!("offloading segno {}", s.seg_no))?;
 
@@ -362,11 +429,55 @@ pub async fn backup_lsn_range(
     Ok(res)
 }
 
-async fn backup_single_segment(seg: &Segment, timeline_dir: &Path) -> Result<()> {
-    let segment_file_name = seg.file_path(timeline_dir)?;
-
-    backup_object(&segment_file_name, seg.size()).await?;
-    debug!("Backup of {} done", segment_file_name.display());
+/// Upload single segment, compressed if requested. Segment might be already
+/// compressed locally, in which case it is uploaded as is or decompressed back.
+async fn backup_single_segment(
+    seg: &Segment,
+    timeline_dir: &Path,
+    compression: bool,
+) -> Result<()> {
+    let segment_file_path = seg.file_path(timeline_dir)?;
+    let compressed_file_path = compressed_path(&segment_file_path);
+
+    if compression {
+        let compressed = match tokio::fs::read(&compressed_file_path).await {
+            Ok(compressed) => compressed,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let data = tokio::fs::read(&segment_file_path).await.with_context(|| {
+                    format!("failed to read segment {}", segment_file_path.display())
+                })?;
+                tokio::task::spawn_blocking(move || wal_compression::compress(&data)).await??
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let size = compressed.len();
+        backup_object(&compressed_file_path, Cursor::new(compressed), size).await?;
+        debug!(
+            "Backup of {} done, compressed {} -> {} bytes",
+            segment_file_path.display(),
+            seg.size(),
+            size
+        );
+    } else {
+        match File::open(&segment_file_path).await {
+            Ok(file) => backup_object(&segment_file_path, file, seg.size()).await?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let compressed =
+                    tokio::fs::read(&compressed_file_path)
+                        .await
+                        .with_context(|| {
+                            format!("failed to read segment {}", compressed_file_path.display())
+                        })?;
+                let data =
+                    tokio::task::spawn_blocking(move || wal_compression::decompress(&compressed))
+                        .await??;
+                let size = data.len();
+                backup_object(&segment_file_path, Cursor::new(data), size).await?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+        debug!("Backup of {} done", segment_file_path.display());
+    }
 
     Ok(())
 }
@@ -416,50 +527,120 @@ fn get_segments(start: Lsn, end: Lsn, seg_size: usize) -> Vec<Segment> {
 
 static REMOTE_STORAGE: OnceCell<Option<GenericRemoteStorage>> = OnceCell::new();
 
-async fn backup_object(source_file: &Path, size: usize) -> Result<()> {
+/// Upload data to the object corresponding to the `target_file` local path.
+async fn backup_object(
+    target_file: &Path,
+    from: impl AsyncRead + Unpin + Send + Sync + 'static,
+    size: usize,
+) -> Result<()> {
     let storage = REMOTE_STORAGE.get().expect("failed to get remote storage");
 
-    let file = File::open(&source_file).await?;
-
     // Storage is initialized by launcher at this point.
     match storage.as_ref().unwrap() {
         GenericRemoteStorage::Local(local_storage) => {
-            let destination = local_storage.remote_object_id(source_file)?;
+            let destination = local_storage.remote_object_id(target_file)?;
 
             debug!(
                 "local upload about to start from {} to {}",
-                source_file.display(),
+                target_file.display(),
                 destination.display()
             );
-            local_storage.upload(file, size, &destination, None).await
+            local_storage.upload(from, size, &destination, None).await
         }
         GenericRemoteStorage::S3(s3_storage) => {
-            let s3key = s3_storage.remote_object_id(source_file)?;
+            let s3key = s3_storage.remote_object_id(target_file)?;
 
             debug!(
                 "S3 upload about to start from {} to {:?}",
-                source_file.display(),
+                target_file.display(),
                 s3key
             );
-            s3_storage.upload(file, size, &s3key, None).await
+            s3_storage.upload(from, size, &s3key, None).await
         }
     }?;
 
     Ok(())
 }
 
+/// Remove offloaded segment. We don't know whether it was uploaded compressed
+/// or not, so try both.
+async fn delete_segment(seg: &Segment, timeline_dir: &Path) -> Result<()> {
+    let storage = REMOTE_STORAGE.get().expect("failed to get remote storage");
+
+    // Storage is initialized by launcher at this point.
+    delete_segment_from(storage.as_ref().unwrap(), seg, timeline_dir).await
+}
+
+async fn delete_segment_from(
+    storage: &GenericRemoteStorage,
+    seg: &Segment,
+    timeline_dir: &Path,
+) -> Result<()> {
+    let segment_file_path = seg.file_path(timeline_dir)?;
+    delete_object(storage, &segment_file_path).await?;
+    delete_object(storage, &compressed_path(&segment_file_path)).await
+}
+
+/// Delete the object if it exists. An object which is already gone, e.g.
+/// removed by the previous backup leader, or never uploaded in this form, is
+/// not an error. S3 doesn't complain about those anyway, unlike LocalFs.
+async fn delete_object(storage: &GenericRemoteStorage, target_file: &Path) -> Result<()> {
+    match storage {
+        GenericRemoteStorage::Local(local_storage) => {
+            let object = local_storage.remote_object_id(target_file)?;
+            if !object.exists() {
+                return Ok(());
+            }
+            local_storage.delete(&object).await
+        }
+        GenericRemoteStorage::S3(s3_storage) => {
+            let s3key = s3_storage.remote_object_id(target_file)?;
+            s3_storage.delete(&s3key).await
+        }
+    }
+}
+
+/// Open stream of the offloaded segment, starting at the given offset. If
+/// segment was offloaded compressed, it is transparently decompressed.
 pub async fn read_object(
     file_path: PathBuf,
     offset: u64,
 ) -> anyhow::Result<Pin<Box<dyn tokio::io::AsyncRead>>> {
-    let download = match REMOTE_STORAGE
+    match download_object(&file_path, offset).await {
+        Ok(download) => Ok(download.download_stream),
+        Err(DownloadError::NotFound) => {
+            let compressed_file_path = compressed_path(&file_path);
+            let download = download_object(&compressed_file_path, 0)
+                .await
+                .with_context(|| {
+                    format!(
+                        "Failed to open WAL segment download stream for local storage path {}",
+                        file_path.display()
+                    )
+                })?;
+            wal_compression::decompressing_reader(download.download_stream, offset).await
+        }
+        Err(e) => Err(e).with_context(|| {
+            format!(
+                "Failed to open WAL segment download stream for local storage path {}",
+                file_path.display()
+            )
+        }),
+    }
+}
+
+async fn download_object(file_path: &Path, offset: u64) -> Result<Download, DownloadError> {
+    let storage = REMOTE_STORAGE
         .get()
-        .context("Failed to get remote storage")?
+        .ok_or_else(|| DownloadError::Other(anyhow::anyhow!("Failed to get remote storage")))?
         .as_ref()
-        .context("No remote storage configured")?
-    {
+        .ok_or_else(|| DownloadError::Other(anyhow::anyhow!("No remote storage configured")))?;
+
+    match storage {
         GenericRemoteStorage::Local(local_storage) => {
-            let source = local_storage.remote_object_id(&file_path)?;
+            let source = local_storage
+                .remote_object_id(file_path)
+                .map_err(DownloadError::BadInput)?;
 
             info!(
                 "local download about to start from {} at offset {}",
@@ -471,7 +652,9 @@ pub async fn read_object(
                 .await
         }
         GenericRemoteStorage::S3(s3_storage) => {
-            let s3key = s3_storage.remote_object_id(&file_path)?;
+            let s3key = s3_storage
+                .remote_object_id(file_path)
+                .map_err(DownloadError::BadInput)?;
 
             info!(
                 "S3 download about to start from {:?} at offset {}",
@@ -480,12 +663,50 @@ pub async fn read_object(
             s3_storage.download_byte_range(&s3key, offset, None).await
         }
     }
-    .with_context(|| {
-        format!(
-            "Failed to open WAL segment download stream for local storage path {}",
-            file_path.display()
-        )
-    })?;
+}
 
-    Ok(download.download_stream)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use remote_storage::LocalFs;
+
+    #[tokio::test]
+    async fn test_delete_missing_segments() {
+        let workdir = tempfile::tempdir().unwrap();
+        let storage_root = tempfile::tempdir().unwrap();
+        let local_fs =
+            LocalFs::new(storage_root.path().to_owned(), workdir.path().to_owned()).unwrap();
+        let timeline_dir = workdir.path().join("tenant").join("timeline");
+
+        let seg_size = 16 * 1024 * 1024;
+        let segments = get_segments(Lsn(0), Lsn(3 * seg_size as u64), seg_size);
+        // The first segment was removed by someone else, the second was
+        // uploaded compressed, and the third one plain.
+        let uploaded = [
+            compressed_path(&segments[1].file_path(&timeline_dir).unwrap()),
+            segments[2].file_path(&timeline_dir).unwrap(),
+        ];
+        for path in &uploaded {
+            let object = local_fs.remote_object_id(path).unwrap();
+            std::fs::create_dir_all(object.parent().unwrap()).unwrap();
+            std::fs::write(&object, b"wal").unwrap();
+        }
+
+        let storage = GenericRemoteStorage::Local(local_fs);
+        for seg in &segments {
+            delete_segment_from(&storage, seg, &timeline_dir)
+                .await
+                .unwrap();
+        }
+        let objects = match &storage {
+            GenericRemoteStorage::Local(local_fs) => local_fs.list().await.unwrap(),
+            GenericRemoteStorage::S3(_) => unreachable!(),
+        };
+        assert!(objects.is_empty(), "{:?}", objects);
+
+        // Removing them again is fine too.
+        delete_segment_from(&storage, &segments[2], &timeline_dir)
+            .await
+            .unwrap();
+    }
 }
@@ -0,0 +1,204 @@
+//! Compression of completed WAL segments.
+//!
+//! Completed segments are mostly zero padding for idle timelines and a lot of
+//! repetitive full page images otherwise, so they compress very well. A
+//! compressed segment keeps the name of the original one with
+//! [`COMPRESSED_SEGMENT_SUFFIX`] appended, both locally and in remote storage,
+//! e.g. `000000010000000000000001.zst`. Partial segments are never compressed.
+
+use anyhow::{Context, Result};
+use postgres_ffi::v14::xlog_utils::IsXLogFileName;
+use std::fs::{self, File};
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tracing::*;
+
+/// Suffix of compressed segment files and objects.
+pub const COMPRESSED_SEGMENT_SUFFIX: &str = ".zst";
+
+/// Suffix of files being written by compression or decompression, which are
+/// renamed into place once complete.
+const TMP_SUFFIX: &str = ".tmp";
+
+/// zstd level used for segments. Low levels are already good at squeezing
+/// zero padding, and higher ones cost a lot of CPU on the backup path.
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// Returns path of the compressed brother of the given segment file.
+pub fn compressed_path(segment_path: &Path) -> PathBuf {
+    let mut path = segment_path.as_os_str().to_owned();
+    path.push(COMPRESSED_SEGMENT_SUFFIX);
+    PathBuf::from(path)
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(TMP_SUFFIX);
+    PathBuf::from(tmp)
+}
+
+/// Strips compression suffix from the segment file name, if any.
+pub fn strip_compressed_suffix(fname: &str) -> &str {
+    fname
+        .strip_suffix(COMPRESSED_SEGMENT_SUFFIX)
+        .unwrap_or(fname)
+}
+
+/// Compress in-memory segment contents.
+pub fn compress(data: &[u8]) -> Result<Vec<u8>> {
+    zstd::stream::encode_all(data, COMPRESSION_LEVEL).context("failed to compress WAL segment")
+}
+
+/// Decompress in-memory segment contents.
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    zstd::stream::decode_all(data).context("failed to decompress WAL segment")
+}
+
+/// Replace completed segment file with its compressed version. The original
+/// file is removed only after compressed one is durably stored, so a crash in
+/// the middle leaves both of them, which readers handle fine.
+pub fn compress_segment_file(segment_path: &Path, no_sync: bool) -> Result<()> {
+    let dst_path = compressed_path(segment_path);
+    let tmp_path = tmp_path(&dst_path);
+
+    let src = File::open(segment_path)
+        .with_context(|| format!("failed to open segment {}", segment_path.display()))?;
+    let mut dst = File::create(&tmp_path)
+        .with_context(|| format!("failed to create {}", tmp_path.display()))?;
+    zstd::stream::copy_encode(BufReader::new(src), &mut dst, COMPRESSION_LEVEL)
+        .with_context(|| format!("failed to compress segment {}", segment_path.display()))?;
+    dst.flush()?;
+    if !no_sync {
+        dst.sync_all()?;
+    }
+    fs::rename(&tmp_path, &dst_path)?;
+    if !no_sync {
+        if let Some(dir) = dst_path.parent() {
+            File::open(dir).and_then(|f| f.sync_all())?;
+        }
+    }
+    fs::remove_file(segment_path)?;
+    Ok(())
+}
+
+/// Restore plain segment file from its compressed version, removing the latter.
+/// Like compression, the plain file appears only once it is complete.
+pub fn decompress_segment_file(segment_path: &Path, no_sync: bool) -> Result<()> {
+    let src_path = compressed_path(segment_path);
+    let tmp_path = tmp_path(segment_path);
+
+    let compressed =
+        fs::read(&src_path).with_context(|| format!("failed to read {}", src_path.display()))?;
+    let mut dst = File::create(&tmp_path)
+        .with_context(|| format!("failed to create {}", tmp_path.display()))?;
+    dst.write_all(&decompress(&compressed)?)?;
+    if !no_sync {
+        dst.sync_all()?;
+    }
+    fs::rename(&tmp_path, segment_path)?;
+    if !no_sync {
+        if let Some(dir) = segment_path.parent() {
+            File::open(dir).and_then(|f| f.sync_all())?;
+        }
+    }
+    fs::remove_file(&src_path)?;
+    Ok(())
+}
+
+/// Remove temporary files left in the timeline directory by compression or
+/// decompression interrupted by a crash. Their sources are still in place.
+pub fn remove_tmp_files(timeline_dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(timeline_dir)? {
+        let path = entry?.path();
+        let fname = match path.file_name().and_then(|f| f.to_str()) {
+            Some(fname) => fname,
+            None => continue,
+        };
+        if let Some(segment) = fname.strip_suffix(TMP_SUFFIX) {
+            if IsXLogFileName(strip_compressed_suffix(segment)) {
+                info!("removing stale temporary file {}", path.display());
+                fs::remove_file(&path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Wrap compressed stream into decompressing one, positioned at `offset` of
+/// the uncompressed data.
+pub async fn decompressing_reader(
+    compressed: Pin<Box<dyn AsyncRead + Send>>,
+    offset: u64,
+) -> Result<Pin<Box<dyn AsyncRead>>> {
+    let mut reader =
+        async_compression::tokio::bufread::ZstdDecoder::new(tokio::io::BufReader::new(compressed));
+    // zstd streams are not seekable, so skip up to the offset.
+    let skipped = tokio::io::copy(&mut (&mut reader).take(offset), &mut tokio::io::sink())
+        .await
+        .context("failed to skip to the requested offset in compressed segment")?;
+    if skipped != offset {
+        anyhow::bail!(
+            "compressed segment is too short: requested offset {}, got {} bytes",
+            offset,
+            skipped
+        );
+    }
+    Ok(Box::pin(reader))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_segment_file_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("000000010000000000000001");
+        let mut data = vec![0u8; 16 * 1024 * 1024];
+        data[..11].copy_from_slice(b"hello world");
+        fs::write(&path, &data).unwrap();
+
+        compress_segment_file(&path, true).unwrap();
+        assert!(!path.exists());
+        let compressed_len = fs::metadata(compressed_path(&path)).unwrap().len();
+        assert!(compressed_len < data.len() as u64 / 100);
+
+        decompress_segment_file(&path, true).unwrap();
+        assert!(!compressed_path(&path).exists());
+        assert_eq!(fs::read(&path).unwrap(), data);
+    }
+
+    #[test]
+    fn test_remove_tmp_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let segment = dir.path().join("000000010000000000000001");
+        let files = [
+            (segment.clone(), true),
+            (tmp_path(&segment), false),
+            (tmp_path(&compressed_path(&segment)), false),
+            (dir.path().join("safekeeper.control.tmp"), true),
+        ];
+        for (path, _) in &files {
+            fs::write(path, b"data").unwrap();
+        }
+
+        remove_tmp_files(dir.path()).unwrap();
+        for (path, kept) in &files {
+            assert_eq!(path.exists(), *kept, "{}", path.display());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_decompressing_reader_offset() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let compressed = compress(&data).unwrap();
+        let stream: Pin<Box<dyn AsyncRead + Send>> = Box::pin(std::io::Cursor::new(compressed));
+
+        let mut reader = decompressing_reader(stream, 1000).await.unwrap();
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, data[1000..]);
+    }
+}
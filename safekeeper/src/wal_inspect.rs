@@ -0,0 +1,271 @@
+//! Inspection of WAL stored on safekeeper: listing of segment files and
+//! decoding of record headers, like pg_waldump does. Exposed through HTTP API
+//! to compare safekeepers remotely when looking for divergence.
+
+use anyhow::{anyhow, bail, Result};
+use serde::Serialize;
+use serde_with::{serde_as, DisplayFromStr};
+use std::cmp::min;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use postgres_ffi::v14::waldecoder::WalStreamDecoder;
+use postgres_ffi::v14::xlog_utils::{
+    IsPartialXLogFileName, IsXLogFileName, MAX_SEND_SIZE, XLOG_SIZE_OF_XLOG_LONG_PHD,
+    XLOG_SIZE_OF_XLOG_RECORD, XLOG_SIZE_OF_XLOG_SHORT_PHD,
+};
+use postgres_ffi::v14::XLogRecord;
+use utils::lsn::Lsn;
+
+use crate::safekeeper::SafeKeeperState;
+use crate::wal_compression::{strip_compressed_suffix, COMPRESSED_SEGMENT_SUFFIX};
+use crate::wal_storage::WalReader;
+
+/// WAL segment file in the timeline directory.
+#[derive(Debug, Serialize)]
+pub struct SegmentInfo {
+    pub name: String,
+    /// Size of the file on disk.
+    pub size: u64,
+    pub partial: bool,
+    pub compressed: bool,
+}
+
+/// List WAL segment files of the timeline, ordered by name.
+pub fn list_segments(timeline_dir: &Path) -> Result<Vec<SegmentInfo>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(timeline_dir)? {
+        let entry = entry?;
+        let fname = match entry.file_name().into_string() {
+            Ok(fname) => fname,
+            Err(_) => continue,
+        };
+        let plain_fname = strip_compressed_suffix(&fname);
+        /* Ignore files that are not XLOG segments */
+        if !IsXLogFileName(plain_fname) && !IsPartialXLogFileName(plain_fname) {
+            continue;
+        }
+        segments.push(SegmentInfo {
+            partial: IsPartialXLogFileName(plain_fname),
+            compressed: fname.ends_with(COMPRESSED_SEGMENT_SUFFIX),
+            size: entry.metadata()?.len(),
+            name: fname,
+        });
+    }
+    segments.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(segments)
+}
+
+/// Header of decoded WAL record.
+#[serde_as]
+#[derive(Debug, Serialize)]
+pub struct RecordHeader {
+    /// Start of the record.
+    #[serde_as(as = "DisplayFromStr")]
+    pub lsn: Lsn,
+    /// Start of the next record.
+    #[serde_as(as = "DisplayFromStr")]
+    pub end_lsn: Lsn,
+    #[serde_as(as = "DisplayFromStr")]
+    pub prev_lsn: Lsn,
+    pub total_len: u32,
+    pub xid: u32,
+    pub rmgr: &'static str,
+    pub info: u8,
+    pub crc: u32,
+}
+
+/// Resource manager names, as in PostgreSQL's rmgrlist.h.
+const RMGR_NAMES: &[&str] = &[
+    "XLOG",
+    "Transaction",
+    "Storage",
+    "CLOG",
+    "Database",
+    "Tablespace",
+    "MultiXact",
+    "RelMap",
+    "Standby",
+    "Heap2",
+    "Heap",
+    "Btree",
+    "Hash",
+    "Gin",
+    "Gist",
+    "Sequence",
+    "SPGist",
+    "BRIN",
+    "CommitTs",
+    "ReplicationOrigin",
+    "Generic",
+    "LogicalMessage",
+];
+
+/// Records never start at page header, skip it if we are at one.
+fn skip_page_header(lsn: Lsn, wal_seg_size: usize) -> Lsn {
+    if lsn.segment_offset(wal_seg_size) == 0 {
+        lsn + XLOG_SIZE_OF_XLOG_LONG_PHD as u64
+    } else if lsn.block_offset() == 0 {
+        lsn + XLOG_SIZE_OF_XLOG_SHORT_PHD as u64
+    } else {
+        lsn
+    }
+}
+
+/// Decode headers of at most `limit` WAL records starting in
+/// [start_lsn, end_lsn). `start_lsn` must point to the beginning of a record.
+/// WAL is read up to `flush_lsn`, the last record started before `end_lsn`
+/// might end after it. Uses remote storage if the WAL is not available
+/// locally and `enable_remote_read` is set.
+///
+/// WalReader is not Send, so this must be run in a blocking thread.
+pub fn decode_records(
+    timeline_dir: PathBuf,
+    state: &SafeKeeperState,
+    start_lsn: Lsn,
+    end_lsn: Lsn,
+    flush_lsn: Lsn,
+    limit: usize,
+    enable_remote_read: bool,
+) -> Result<Vec<RecordHeader>> {
+    if state.server.wal_seg_size == 0 {
+        bail!("WAL segment size is unknown, timeline has no WAL yet");
+    }
+    if start_lsn > flush_lsn {
+        bail!(
+            "start_lsn {} is beyond the end of WAL {}",
+            start_lsn,
+            flush_lsn
+        );
+    }
+    let wal_seg_size = state.server.wal_seg_size as usize;
+    let mut reader = WalReader::new(timeline_dir, state, start_lsn, enable_remote_read)?;
+    let mut decoder = WalStreamDecoder::new(start_lsn);
+    let mut buf = vec![0u8; MAX_SEND_SIZE];
+    let mut pos = start_lsn;
+    let mut rec_start_lsn = skip_page_header(start_lsn, wal_seg_size);
+    let mut records = Vec::new();
+
+    let runtime = tokio::runtime::Handle::current();
+    while rec_start_lsn < end_lsn && records.len() < limit {
+        let res = decoder.poll_decode().map_err(|e| {
+            anyhow!(
+                "failed to decode WAL, is {} the beginning of a record? {}",
+                start_lsn,
+                e
+            )
+        })?;
+        match res {
+            Some((next_lsn, recordbuf)) => {
+                let hdr = XLogRecord::from_slice(&recordbuf[0..XLOG_SIZE_OF_XLOG_RECORD])?;
+                records.push(RecordHeader {
+                    lsn: rec_start_lsn,
+                    end_lsn: next_lsn,
+                    prev_lsn: Lsn(hdr.xl_prev),
+                    total_len: hdr.xl_tot_len,
+                    xid: hdr.xl_xid,
+                    rmgr: RMGR_NAMES
+                        .get(hdr.xl_rmid as usize)
+                        .copied()
+                        .unwrap_or("unknown"),
+                    info: hdr.xl_info,
+                    crc: hdr.xl_crc,
+                });
+                rec_start_lsn = skip_page_header(next_lsn, wal_seg_size);
+            }
+            None => {
+                if pos >= flush_lsn {
+                    // the rest is not written yet
+                    break;
+                }
+                let n = min(buf.len(), (flush_lsn.0 - pos.0) as usize);
+                let n = runtime.block_on(reader.read(&mut buf[..n]))?;
+                decoder.feed_bytes(&buf[..n]);
+                pos += n as u64;
+            }
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use postgres_ffi::v14::pg_constants::WAL_SEGMENT_SIZE;
+    use postgres_ffi::v14::xlog_utils::{
+        encode_logical_message, generate_wal_segment, XLogFileName,
+    };
+    use postgres_ffi::PG_TLI;
+
+    #[test]
+    fn test_list_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("000000010000000000000002.partial"), b"abc").unwrap();
+        fs::write(dir.path().join("000000010000000000000001.zst"), b"a").unwrap();
+        fs::write(dir.path().join("safekeeper.control"), b"ctl").unwrap();
+
+        let segments = list_segments(dir.path()).unwrap();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].name, "000000010000000000000001.zst");
+        assert!(segments[0].compressed && !segments[0].partial);
+        assert_eq!(segments[1].size, 3);
+        assert!(segments[1].partial && !segments[1].compressed);
+    }
+
+    #[test]
+    fn test_decode_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let segno = 1;
+        let start_lsn = Lsn(segno * WAL_SEGMENT_SIZE as u64);
+
+        let mut segment = generate_wal_segment(segno, 42).unwrap().to_vec();
+        let mut end = XLOG_SIZE_OF_XLOG_LONG_PHD;
+        for message in ["first", "second message"] {
+            let record = encode_logical_message("test", message);
+            segment[end..end + record.len()].copy_from_slice(&record);
+            end += record.len();
+        }
+        fs::write(
+            dir.path()
+                .join(XLogFileName(PG_TLI, segno, WAL_SEGMENT_SIZE)),
+            &segment,
+        )
+        .unwrap();
+        let flush_lsn = start_lsn + end as u64;
+
+        let mut state = SafeKeeperState::empty();
+        state.server.wal_seg_size = WAL_SEGMENT_SIZE as u32;
+        state.timeline_start_lsn = start_lsn;
+        state.local_start_lsn = start_lsn;
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _guard = runtime.enter();
+        let decode = |state: &SafeKeeperState, limit| {
+            decode_records(
+                dir.path().to_path_buf(),
+                state,
+                start_lsn,
+                flush_lsn,
+                flush_lsn,
+                limit,
+                false,
+            )
+        };
+
+        let records = decode(&state, 10).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            records[0].lsn,
+            start_lsn + XLOG_SIZE_OF_XLOG_LONG_PHD as u64
+        );
+        assert_eq!(records[0].end_lsn, records[1].lsn);
+        assert_eq!(records[1].end_lsn, flush_lsn);
+        assert!(records.iter().all(|r| r.rmgr == "LogicalMessage"));
+
+        assert_eq!(decode(&state, 1).unwrap().len(), 1);
+
+        // Timeline which hasn't received any WAL yet.
+        state.server.wal_seg_size = 0;
+        assert!(decode(&state, 10).is_err());
+    }
+}
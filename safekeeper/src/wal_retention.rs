@@ -0,0 +1,105 @@
+//! Per timeline WAL retention settings.
+//!
+//! By default WAL is removed as soon as it is not needed by pageserver, peers
+//! and s3 offloading. Retention policy allows to keep more of it locally
+//! (e.g. for faster recovery of lagging consumers) and to keep offloaded WAL
+//! in remote storage only for a PITR window instead of forever.
+
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime};
+use utils::lsn::Lsn;
+
+/// Retention settings of a timeline, persisted in the control file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WalRetentionPolicy {
+    /// Keep at least this many bytes of WAL locally, counting back from
+    /// flush_lsn. 0 means no such limit.
+    #[serde(default)]
+    pub min_local_bytes: u64,
+    /// Keep WAL written during the last that many seconds locally. 0 means no
+    /// such limit.
+    #[serde(default)]
+    pub min_local_age_secs: u64,
+    /// Remove offloaded WAL from remote storage once it is older than that
+    /// many seconds. None means offloaded WAL is kept forever.
+    #[serde(default)]
+    pub remote_pitr_window_secs: Option<u64>,
+}
+
+impl WalRetentionPolicy {
+    pub fn min_local_age(&self) -> Option<Duration> {
+        match self.min_local_age_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+
+    pub fn remote_pitr_window(&self) -> Option<Duration> {
+        self.remote_pitr_window_secs.map(Duration::from_secs)
+    }
+}
+
+/// Remembers when WAL positions were reached, mapping retention time windows
+/// to LSNs. It is not persisted: after restart all existing WAL is considered
+/// to be written at the moment of the first sample, so time based retention
+/// errs on the side of keeping WAL longer.
+#[derive(Debug, Default)]
+pub struct LsnHistory {
+    samples: VecDeque<(SystemTime, Lsn)>,
+}
+
+impl LsnHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Note that WAL up to `lsn` was written by `now`.
+    pub fn record(&mut self, now: SystemTime, lsn: Lsn) {
+        if let Some(&(_, last_lsn)) = self.samples.back() {
+            if last_lsn >= lsn {
+                return;
+            }
+        }
+        self.samples.push_back((now, lsn));
+    }
+
+    /// Returns LSN up to which WAL was written no later than `cutoff`, or None
+    /// if we don't know about any WAL that old. Samples older than the
+    /// returned one are forgotten, so `cutoff` is expected to only grow.
+    pub fn lsn_at(&mut self, cutoff: SystemTime) -> Option<Lsn> {
+        while self.samples.len() >= 2 && self.samples[1].0 <= cutoff {
+            self.samples.pop_front();
+        }
+        match self.samples.front() {
+            Some(&(ts, lsn)) if ts <= cutoff => Some(lsn),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lsn_history() {
+        let t0 = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let secs = Duration::from_secs;
+
+        let mut history = LsnHistory::new();
+        assert_eq!(history.lsn_at(t0), None);
+
+        history.record(t0, Lsn(100));
+        history.record(t0 + secs(10), Lsn(200));
+        // no progress, ignored
+        history.record(t0 + secs(15), Lsn(200));
+        history.record(t0 + secs(20), Lsn(300));
+
+        assert_eq!(history.lsn_at(t0 - secs(1)), None);
+        assert_eq!(history.lsn_at(t0 + secs(5)), Some(Lsn(100)));
+        assert_eq!(history.lsn_at(t0 + secs(15)), Some(Lsn(200)));
+        assert_eq!(history.lsn_at(t0 + secs(100)), Some(Lsn(300)));
+        assert_eq!(history.samples.len(), 1);
+    }
+}
@@ -6,6 +6,9 @@
 //! - 000000010000000000000002.partial
 //!
 //! Note that last file has `.partial` suffix, that's different from postgres.
+//!
+//! Completed segments behind commit_lsn may also be stored compressed, see
+//! `wal_compression`.
 
 use anyhow::{anyhow, bail, Context, Result};
 use std::io::{self, Seek, SeekFrom};
@@ -30,6 +33,7 @@ use utils::{lsn::Lsn, zid::ZTenantTimelineId};
 use crate::safekeeper::SafeKeeperState;
 
 use crate::wal_backup::read_object;
+use crate::wal_compression::{self, compressed_path, strip_compressed_suffix};
 use crate::SafeKeeperConf;
 use postgres_ffi::v14::xlog_utils::XLogFileName;
 use postgres_ffi::XLOG_BLCKSZ;
@@ -116,6 +120,10 @@ pub trait Storage {
     /// Remove all segments <= given segno. Returns closure as we want to do
     /// that without timeline lock.
     fn remove_up_to(&self) -> Box<dyn Fn(XLogSegNo) -> Result<()>>;
+
+    /// Compress all completed segments <= given segno. Returns closure as we
+    /// want to do that without timeline lock.
+    fn compress_up_to(&self) -> Box<dyn Fn(XLogSegNo) -> Result<()>>;
 }
 
 /// PhysicalStorage is a storage that stores WAL on disk. Writes are separated from flushes
@@ -208,6 +216,11 @@ impl PhysicalStorage {
         let (wal_file_path, wal_file_partial_path) =
             wal_file_paths(&self.timeline_dir, segno, wal_seg_size)?;
 
+        // Completed segment might have been compressed, restore it first.
+        if compressed_path(&wal_file_path).exists() {
+            wal_compression::decompress_segment_file(&wal_file_path, self.conf.no_sync)?;
+        }
+
         // Try to open already completed segment
         if let Ok(file) = OpenOptions::new().write(true).open(&wal_file_path) {
             Ok((file, false))
@@ -329,6 +342,11 @@ impl Storage for PhysicalStorage {
         let wal_seg_size = state.server.wal_seg_size as usize;
         self.wal_seg_size = Some(wal_seg_size);
 
+        // Compression or decompression might have been interrupted by a crash.
+        if self.timeline_dir.exists() {
+            wal_compression::remove_tmp_files(&self.timeline_dir)?;
+        }
+
         // Find out where stored WAL ends, starting at commit_lsn which is a
         // known recent record boundary (unless we don't have WAL at all).
         self.write_lsn = if state.commit_lsn == Lsn(0) {
@@ -464,9 +482,12 @@ impl Storage for PhysicalStorage {
             segno += 1;
             let (wal_file_path, wal_file_partial_path) =
                 wal_file_paths(&self.timeline_dir, segno, wal_seg_size)?;
+            let wal_file_compressed_path = compressed_path(&wal_file_path);
             // TODO: better use fs::try_exists which is currently available only in nightly build
             if wal_file_path.exists() {
                 fs::remove_file(&wal_file_path)?;
+            } else if wal_file_compressed_path.exists() {
+                fs::remove_file(&wal_file_compressed_path)?;
             } else if wal_file_partial_path.exists() {
                 fs::remove_file(&wal_file_partial_path)?;
             } else {
@@ -488,6 +509,15 @@ impl Storage for PhysicalStorage {
             remove_up_to(&timeline_dir, wal_seg_size, segno_up_to)
         })
     }
+
+    fn compress_up_to(&self) -> Box<dyn Fn(XLogSegNo) -> Result<()>> {
+        let timeline_dir = self.timeline_dir.clone();
+        let wal_seg_size = self.wal_seg_size.unwrap();
+        let no_sync = self.conf.no_sync;
+        Box::new(move |segno_up_to: XLogSegNo| {
+            compress_up_to(&timeline_dir, wal_seg_size, segno_up_to, no_sync)
+        })
+    }
 }
 
 /// Remove all WAL segments in timeline_dir <= given segno.
@@ -499,6 +529,7 @@ fn remove_up_to(timeline_dir: &Path, wal_seg_size: usize, segno_up_to: XLogSegNo
         let fname = entry_path.file_name().unwrap();
 
         if let Some(fname_str) = fname.to_str() {
+            let fname_str = strip_compressed_suffix(fname_str);
             /* Ignore files that are not XLOG segments */
             if !IsXLogFileName(fname_str) && !IsPartialXLogFileName(fname_str) {
                 continue;
@@ -520,6 +551,159 @@ fn remove_up_to(timeline_dir: &Path, wal_seg_size: usize, segno_up_to: XLogSegNo
     Ok(())
 }
 
+/// Compress all completed WAL segments in timeline_dir <= given segno which
+/// are not compressed yet.
+fn compress_up_to(
+    timeline_dir: &Path,
+    wal_seg_size: usize,
+    segno_up_to: XLogSegNo,
+    no_sync: bool,
+) -> Result<()> {
+    let mut n_compressed = 0;
+    for entry in fs::read_dir(&timeline_dir)? {
+        let entry = entry?;
+        let entry_path = entry.path();
+        let fname = entry_path.file_name().unwrap();
+
+        if let Some(fname_str) = fname.to_str() {
+            /* Only completed plain segments are of interest */
+            if !IsXLogFileName(fname_str) {
+                continue;
+            }
+            let (segno, _) = XLogFromFileName(fname_str, wal_seg_size);
+            if segno <= segno_up_to {
+                wal_compression::compress_segment_file(&entry_path, no_sync)?;
+                n_compressed += 1;
+            }
+        }
+    }
+    if n_compressed > 0 {
+        info!(
+            "compressed {} WAL segments up to {}",
+            n_compressed,
+            XLogFileName(PG_TLI, segno_up_to, wal_seg_size)
+        );
+    }
+    Ok(())
+}
+
+/// InMemoryStorage keeps WAL in a memory buffer. It is used to test consensus
+/// logic deterministically, without touching disk.
+///
+/// Unlike PhysicalStorage, it doesn't decode WAL, so every write is assumed to
+/// end on a record boundary. Data written but not flushed yet can be dropped
+/// with `lose_unflushed`, imitating a crash. WAL removal is not supported,
+/// all of it is kept until truncated.
+#[derive(Debug, Clone)]
+pub struct InMemoryStorage {
+    /// LSN of the first byte in `wal`.
+    start_lsn: Lsn,
+    wal: Vec<u8>,
+    flush_lsn: Lsn,
+}
+
+impl InMemoryStorage {
+    pub fn new() -> Self {
+        InMemoryStorage {
+            start_lsn: Lsn(0),
+            wal: Vec::new(),
+            flush_lsn: Lsn(0),
+        }
+    }
+
+    /// End of written, but not necessarily flushed WAL.
+    pub fn write_lsn(&self) -> Lsn {
+        self.start_lsn + self.wal.len() as u64
+    }
+
+    /// Drop WAL which is not flushed yet, as if we had crashed.
+    pub fn lose_unflushed(&mut self) {
+        let flushed = (self.flush_lsn.0 - self.start_lsn.0) as usize;
+        self.wal.truncate(flushed);
+    }
+
+    /// Get WAL between the given LSNs, which must be stored here.
+    pub fn read(&self, start: Lsn, end: Lsn) -> Result<&[u8]> {
+        if start < self.start_lsn || end > self.write_lsn() || start > end {
+            bail!(
+                "requested WAL {}-{} is not in storage, which has {}-{}",
+                start,
+                end,
+                self.start_lsn,
+                self.write_lsn()
+            );
+        }
+        let off = (start.0 - self.start_lsn.0) as usize;
+        Ok(&self.wal[off..off + (end.0 - start.0) as usize])
+    }
+}
+
+impl Default for InMemoryStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Storage for InMemoryStorage {
+    fn flush_lsn(&self) -> Lsn {
+        self.flush_lsn
+    }
+
+    fn init_storage(&mut self, _state: &SafeKeeperState) -> Result<()> {
+        Ok(())
+    }
+
+    fn write_wal(&mut self, startpos: Lsn, buf: &[u8]) -> Result<()> {
+        let write_lsn = self.write_lsn();
+        if write_lsn == Lsn(0) {
+            // Nothing stored yet, WAL may start anywhere.
+            self.start_lsn = startpos;
+            self.flush_lsn = startpos;
+        } else if write_lsn > startpos {
+            bail!(
+                "write_wal rewrites WAL written before, write_lsn={}, startpos={}",
+                write_lsn,
+                startpos
+            );
+        } else if write_lsn < startpos {
+            bail!(
+                "write_wal creates gap in written WAL, write_lsn={}, startpos={}",
+                write_lsn,
+                startpos
+            );
+        }
+        self.wal.extend_from_slice(buf);
+        Ok(())
+    }
+
+    fn truncate_wal(&mut self, end_pos: Lsn) -> Result<()> {
+        if self.write_lsn() == Lsn(0) {
+            // Nothing stored yet, WAL will start at end_pos.
+            self.start_lsn = end_pos;
+        } else {
+            // Streaming must not create a hole, so truncate cannot be called on non-written lsn
+            assert!(end_pos >= self.start_lsn && end_pos <= self.write_lsn());
+            self.wal.truncate((end_pos.0 - self.start_lsn.0) as usize);
+        }
+        // As in PhysicalStorage, truncation makes the rest of WAL durable.
+        self.flush_lsn = end_pos;
+        Ok(())
+    }
+
+    fn flush_wal(&mut self) -> Result<()> {
+        self.flush_lsn = self.write_lsn();
+        Ok(())
+    }
+
+    fn remove_up_to(&self) -> Box<dyn Fn(XLogSegNo) -> Result<()>> {
+        Box::new(move |_segno_up_to: XLogSegNo| Ok(()))
+    }
+
+    fn compress_up_to(&self) -> Box<dyn Fn(XLogSegNo) -> Result<()>> {
+        Box::new(move |_segno_up_to: XLogSegNo| Ok(()))
+    }
+}
+
 pub struct WalReader {
     timeline_dir: PathBuf,
     wal_seg_size: usize,
@@ -612,9 +796,14 @@ impl WalReader {
                     if !is_not_found {
                         return Err(e);
                     }
-                    // NotFound is expected, fall through to remote read
+                    // NotFound is expected, segment might be compressed
                 }
             };
+
+            if let Ok(file) = tokio::fs::File::open(compressed_path(&wal_file_path)).await {
+                return wal_compression::decompressing_reader(Box::pin(file), xlogoff as u64).await;
+            }
+            // fall through to remote read
         }
 
         // Try to open remote file, if remote reads are enabled